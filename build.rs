@@ -0,0 +1,4 @@
+fn main() {
+    // the migrations are embedded by `sqlx::migrate!`, which cannot tell when they change
+    println!("cargo:rerun-if-changed=migrations");
+}
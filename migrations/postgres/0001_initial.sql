@@ -0,0 +1,64 @@
+-- Schema as of the first release with migrations. `IF NOT EXISTS` lets it run on databases created
+-- before migrations, once their missing columns are added.
+
+CREATE TABLE IF NOT EXISTS chat_messages (
+    id BIGSERIAL PRIMARY KEY,
+    session_id TEXT NOT NULL,
+    user_message TEXT NOT NULL,
+    bot_reply TEXT NOT NULL,
+    timestamp TIMESTAMPTZ NOT NULL,
+    deleted_at TIMESTAMPTZ,
+    tool_results TEXT,
+    assistant_message TEXT,
+    system_prompt TEXT,
+    truncated BOOLEAN NOT NULL DEFAULT FALSE,
+    role TEXT NOT NULL DEFAULT 'turn',
+    latency_ms BIGINT,
+    seed BIGINT,
+    version BIGINT NOT NULL DEFAULT 1,
+    parent_id BIGINT
+);
+
+CREATE TABLE IF NOT EXISTS sessions (
+    session_id TEXT PRIMARY KEY,
+    system_prompt TEXT,
+    title TEXT,
+    created_at TIMESTAMPTZ,
+    updated_at TIMESTAMPTZ,
+    message_count BIGINT NOT NULL DEFAULT 0
+);
+
+CREATE TABLE IF NOT EXISTS session_usage (
+    session_id TEXT PRIMARY KEY,
+    prompt_tokens BIGINT NOT NULL DEFAULT 0,
+    completion_tokens BIGINT NOT NULL DEFAULT 0,
+    total_tokens BIGINT NOT NULL DEFAULT 0,
+    requests BIGINT NOT NULL DEFAULT 0
+);
+
+CREATE TABLE IF NOT EXISTS session_summaries (
+    session_id TEXT PRIMARY KEY,
+    summary TEXT NOT NULL,
+    summarized_until BIGINT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS request_log (
+    id BIGSERIAL PRIMARY KEY,
+    session_id TEXT NOT NULL,
+    model TEXT,
+    server_url TEXT NOT NULL,
+    request TEXT NOT NULL,
+    status INTEGER,
+    latency_ms BIGINT NOT NULL,
+    timestamp TIMESTAMPTZ NOT NULL
+);
+
+-- Histories are read by session in time order, which the (session_id, timestamp) index serves
+-- without a sort; its leading column also serves every other lookup by session. Later versions
+-- of a turn are looked up by their original. Session lists are ordered by recency.
+CREATE INDEX IF NOT EXISTS idx_chat_messages_session_timestamp ON chat_messages (session_id, timestamp);
+
+CREATE INDEX IF NOT EXISTS idx_chat_messages_parent_id ON chat_messages (parent_id);
+
+CREATE INDEX IF NOT EXISTS idx_sessions_updated_at ON sessions (updated_at);
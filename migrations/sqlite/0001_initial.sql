@@ -0,0 +1,81 @@
+-- Schema as of the first release with migrations. `IF NOT EXISTS` lets it run on databases created
+-- before migrations, once their missing columns are added.
+
+CREATE TABLE IF NOT EXISTS chat_messages (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    session_id TEXT NOT NULL,
+    user_message TEXT NOT NULL,
+    bot_reply TEXT NOT NULL,
+    timestamp DATETIME NOT NULL,
+    deleted_at DATETIME,
+    tool_results TEXT,
+    assistant_message TEXT,
+    system_prompt TEXT,
+    truncated BOOLEAN NOT NULL DEFAULT FALSE,
+    role TEXT NOT NULL DEFAULT 'turn',
+    latency_ms INTEGER,
+    seed INTEGER,
+    version INTEGER NOT NULL DEFAULT 1,
+    parent_id INTEGER
+);
+
+CREATE TABLE IF NOT EXISTS sessions (
+    session_id TEXT PRIMARY KEY,
+    system_prompt TEXT,
+    title TEXT,
+    created_at DATETIME,
+    updated_at DATETIME,
+    message_count INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE IF NOT EXISTS session_usage (
+    session_id TEXT PRIMARY KEY,
+    prompt_tokens INTEGER NOT NULL DEFAULT 0,
+    completion_tokens INTEGER NOT NULL DEFAULT 0,
+    total_tokens INTEGER NOT NULL DEFAULT 0,
+    requests INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE IF NOT EXISTS session_summaries (
+    session_id TEXT PRIMARY KEY,
+    summary TEXT NOT NULL,
+    summarized_until INTEGER NOT NULL,
+    updated_at DATETIME NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS request_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    session_id TEXT NOT NULL,
+    model TEXT,
+    server_url TEXT NOT NULL,
+    request TEXT NOT NULL,
+    status INTEGER,
+    latency_ms INTEGER NOT NULL,
+    timestamp DATETIME NOT NULL
+);
+
+CREATE VIRTUAL TABLE IF NOT EXISTS chat_messages_fts USING fts5(
+    user_message,
+    bot_reply,
+    content = 'chat_messages',
+    content_rowid = 'id'
+);
+
+CREATE TRIGGER IF NOT EXISTS chat_messages_fts_insert AFTER INSERT ON chat_messages BEGIN
+    INSERT INTO chat_messages_fts (rowid, user_message, bot_reply)
+    VALUES (new.id, new.user_message, new.bot_reply);
+END;
+
+CREATE TRIGGER IF NOT EXISTS chat_messages_fts_delete AFTER DELETE ON chat_messages BEGIN
+    INSERT INTO chat_messages_fts (chat_messages_fts, rowid, user_message, bot_reply)
+    VALUES ('delete', old.id, old.user_message, old.bot_reply);
+END;
+
+-- Histories are read by session in time order, which the (session_id, timestamp) index serves
+-- without a sort; its leading column also serves every other lookup by session. Later versions
+-- of a turn are looked up by their original. Session lists are ordered by recency.
+CREATE INDEX IF NOT EXISTS idx_chat_messages_session_timestamp ON chat_messages (session_id, timestamp);
+
+CREATE INDEX IF NOT EXISTS idx_chat_messages_parent_id ON chat_messages (parent_id);
+
+CREATE INDEX IF NOT EXISTS idx_sessions_updated_at ON sessions (updated_at);
@@ -0,0 +1,283 @@
+use std::{convert::Infallible, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{FromRequestParts, State},
+    http::{Request, header::AUTHORIZATION, request::Parts},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::{
+    AppState,
+    config::AuthConfig,
+    dual_warn,
+    error::{ServerError, ServerResult},
+};
+
+/// A key accepted by [`authenticate`]
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ApiKey {
+    key: String,
+    /// User whose sessions the key reaches; every session if `None`
+    user: Option<String>,
+}
+impl ApiKey {
+    /// The keys of `api_keys`, then those of `users` sorted by user
+    pub(crate) fn from_config(config: &AuthConfig) -> Vec<Self> {
+        let mut users: Vec<_> = config.users.iter().collect();
+        users.sort();
+        config
+            .api_keys
+            .iter()
+            .map(|key| Self {
+                key: key.clone(),
+                user: None,
+            })
+            .chain(users.into_iter().map(|(user, key)| Self {
+                key: key.clone(),
+                user: Some(user.clone()),
+            }))
+            .collect()
+    }
+}
+
+/// API key a request was authenticated with, stored in the request extensions
+#[derive(Debug, Clone)]
+pub(crate) struct AuthenticatedKey(pub String);
+
+/// Sessions a request can reach, stored in the request extensions.
+///
+/// A request authenticated as a user, by a key of `[auth] users` or by the `sub` of a JWT without
+/// the admin scope, only reaches the sessions of that user: their stored ids are prefixed with the
+/// user id, so two users sending the same session id get separate sessions. Requests with a key of
+/// `api_keys`, an admin token or no authentication reach every session, by the id sent.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SessionNamespace {
+    // user id with `%` and `:` escaped, then `:`
+    prefix: Option<String>,
+}
+impl SessionNamespace {
+    pub(crate) fn for_user(user: &str) -> Self {
+        let escaped = user.replace('%', "%25").replace(':', "%3A");
+        Self {
+            prefix: Some(format!("{escaped}:")),
+        }
+    }
+
+    pub(crate) fn is_scoped(&self) -> bool {
+        self.prefix.is_some()
+    }
+
+    /// Prefix of the stored ids of the sessions in the namespace; `None` if unscoped
+    pub(crate) fn prefix(&self) -> Option<&str> {
+        self.prefix.as_deref()
+    }
+
+    /// Stored id of the session `session_id` of the request
+    pub(crate) fn scope(&self, session_id: &str) -> String {
+        match &self.prefix {
+            Some(prefix) => format!("{prefix}{session_id}"),
+            None => session_id.to_string(),
+        }
+    }
+
+    /// Id seen by the request of the stored session `session_id`; `None` outside the namespace
+    pub(crate) fn unscope<'a>(&self, session_id: &'a str) -> Option<&'a str> {
+        match &self.prefix {
+            Some(prefix) => session_id.strip_prefix(prefix.as_str()),
+            None => Some(session_id),
+        }
+    }
+}
+impl<S: Send + Sync> FromRequestParts<S> for SessionNamespace {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts.extensions.get::<Self>().cloned().unwrap_or_default())
+    }
+}
+
+/// Compares two byte strings in time depending only on their lengths
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Returns the key of `api_keys` matching the bearer token of an `Authorization` header.
+///
+/// Every key is compared, so the time taken does not tell which key came close.
+fn match_api_key<'a>(api_keys: &'a [ApiKey], authorization: Option<&str>) -> Option<&'a ApiKey> {
+    let token = authorization?.strip_prefix("Bearer ")?.trim();
+    let mut matched = None;
+    for key in api_keys {
+        if constant_time_eq(key.key.as_bytes(), token.as_bytes()) {
+            matched = Some(key);
+        }
+    }
+    matched
+}
+
+/// Rejects requests without a configured API key or a valid JWT with 401; all requests pass if
+/// neither is configured.
+///
+/// The [`SessionNamespace`] of the user of the request is stored in the request extensions, with
+/// the claims of a JWT; with a JWT, the `/admin` endpoints need the admin scope, 403 otherwise.
+pub(crate) async fn authenticate(
+    State(state): State<Arc<AppState>>,
+    mut req: Request<Body>,
+    next: Next,
+) -> ServerResult<Response> {
+    if state.api_keys.is_empty() && state.jwt.is_none() {
+        return Ok(next.run(req).await);
+    }
+
+    let authorization = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok());
+    if let Some(key) = match_api_key(&state.api_keys, authorization) {
+        // a user's key reaches that user's sessions only, like a token without the admin scope
+        if key.user.is_some() && req.uri().path().starts_with("/admin") {
+            dual_warn!("Rejected a user key for {}", req.uri().path());
+            return Err(ServerError::Forbidden(
+                "a user key cannot reach the admin endpoints".to_string(),
+            ));
+        }
+        let namespace = key
+            .user
+            .as_deref()
+            .map(SessionNamespace::for_user)
+            .unwrap_or_default();
+        req.extensions_mut()
+            .insert(AuthenticatedKey(key.key.clone()));
+        req.extensions_mut().insert(namespace);
+        return Ok(next.run(req).await);
+    }
+    let token = authorization
+        .and_then(|a| a.strip_prefix("Bearer "))
+        .map(str::trim);
+    let (Some(jwt), Some(token)) = (&state.jwt, token) else {
+        dual_warn!("Rejected unauthenticated request to {}", req.uri().path());
+        return Err(ServerError::Unauthorized(
+            "missing or invalid API key".to_string(),
+        ));
+    };
+
+    let claims = match jwt.validate(&state.http_client, token).await {
+        Ok(claims) => claims,
+        Err(e) => {
+            dual_warn!("Rejected a token for {}: {e}", req.uri().path());
+            return Err(ServerError::Unauthorized(e));
+        }
+    };
+    let admin = claims.has_scope(&jwt.admin_scope);
+    if !admin && req.uri().path().starts_with("/admin") {
+        dual_warn!(
+            "Rejected a token without the `{}` scope for {}",
+            jwt.admin_scope,
+            req.uri().path()
+        );
+        return Err(ServerError::Forbidden(format!(
+            "the `{}` scope is required",
+            jwt.admin_scope
+        )));
+    }
+    let namespace = match (&claims.sub, admin) {
+        (_, true) => SessionNamespace::default(),
+        (Some(sub), false) => SessionNamespace::for_user(sub),
+        (None, false) => return Err(ServerError::Unauthorized("token has no `sub`".to_string())),
+    };
+
+    req.extensions_mut().insert(claims);
+    req.extensions_mut().insert(namespace);
+    Ok(next.run(req).await)
+}
+
+#[test]
+fn test_match_api_key() {
+    let config = AuthConfig {
+        api_keys: vec!["key-one".to_string()],
+        users: std::collections::HashMap::from([("alice".to_string(), "key-two".to_string())]),
+        ..Default::default()
+    };
+    let keys = ApiKey::from_config(&config);
+    assert_eq!(keys[1].user.as_deref(), Some("alice"));
+
+    assert_eq!(match_api_key(&keys, Some("Bearer key-two")), Some(&keys[1]));
+    assert_eq!(match_api_key(&keys, Some("Bearer key-three")), None);
+    assert_eq!(match_api_key(&keys, Some("Bearer key-on")), None);
+    assert_eq!(match_api_key(&keys, Some("key-one")), None);
+    assert_eq!(match_api_key(&keys, None), None);
+
+    assert!(constant_time_eq(b"abc", b"abc"));
+    assert!(!constant_time_eq(b"abc", b"abd"));
+    assert!(!constant_time_eq(b"abc", b"ab"));
+}
+
+#[test]
+fn test_session_namespace() {
+    let open = SessionNamespace::default();
+    assert!(!open.is_scoped());
+    assert_eq!(open.scope("s1"), "s1");
+    assert_eq!(open.unscope("alice:s1"), Some("alice:s1"));
+
+    let alice = SessionNamespace::for_user("alice");
+    assert!(alice.is_scoped());
+    assert_eq!(alice.scope("s1"), "alice:s1");
+    assert_eq!(alice.unscope("alice:s1"), Some("s1"));
+    assert_eq!(alice.unscope("bob:s1"), None);
+    assert_eq!(alice.unscope("alice2:s1"), None);
+    assert_eq!(alice.prefix(), Some("alice:"));
+
+    // user ids with a colon cannot reach the sessions of another user
+    let tricky = SessionNamespace::for_user("alice:b");
+    assert_ne!(tricky.scope("c"), alice.scope("b:c"));
+    assert_eq!(tricky.unscope(&alice.scope("b:c")), None);
+}
+
+#[tokio::test]
+async fn test_user_key_admin_access() {
+    use crate::{config::Config, info::ServerInfo};
+    use tower::ServiceExt;
+
+    let mut config = Config::default();
+    config.auth.api_keys = vec!["key-one".to_string()];
+    config.auth.users =
+        std::collections::HashMap::from([("alice".to_string(), "key-two".to_string())]);
+    let state = Arc::new(AppState::new(config, ServerInfo::default()));
+    let app = axum::Router::new()
+        .route("/admin/logs", axum::routing::get(|| async { "logs" }))
+        .route(
+            "/chat/sessions",
+            axum::routing::get(|| async { "sessions" }),
+        )
+        .route_layer(axum::middleware::from_fn_with_state(
+            Arc::clone(&state),
+            authenticate,
+        ));
+    let status = |uri: &str, key: &str| {
+        let request = Request::builder()
+            .uri(uri)
+            .header(AUTHORIZATION, format!("Bearer {key}"))
+            .body(Body::empty())
+            .unwrap();
+        let app = app.clone();
+        async move { app.oneshot(request).await.unwrap().status() }
+    };
+
+    assert_eq!(
+        status("/admin/logs", "key-one").await,
+        axum::http::StatusCode::OK
+    );
+    assert_eq!(
+        status("/admin/logs", "key-two").await,
+        axum::http::StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        status("/chat/sessions", "key-two").await,
+        axum::http::StatusCode::OK
+    );
+}
@@ -0,0 +1,187 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{config::CircuitBreakerConfig, dual_info, dual_warn};
+
+/// State of a [`CircuitBreaker`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Requests flow normally
+    Closed,
+    /// The server is skipped until the cooldown is over
+    Open,
+    /// The cooldown is over and a single trial request decides whether the breaker closes
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct BreakerState {
+    state: CircuitState,
+    consecutive_failures: u32,
+    opened_at: Instant,
+    /// When the trial request of the half-open state was handed out, if it is in flight
+    trial_started: Option<Instant>,
+}
+
+/// Circuit breaker of one downstream server within a server group.
+///
+/// After `failure_threshold` consecutive failed requests the breaker opens and the server is
+/// skipped for `cooldown`. It then goes half-open and lets a single trial request through: a
+/// success closes it, a failure opens it for another cooldown. A trial whose outcome is never
+/// reported, e.g. because the client went away, is given up after one cooldown.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    server: String,
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(server: impl Into<String>, config: &CircuitBreakerConfig) -> Self {
+        Self {
+            server: server.into(),
+            failure_threshold: config.failure_threshold,
+            cooldown: Duration::from_secs(config.cooldown_secs),
+            state: Mutex::new(BreakerState {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                opened_at: Instant::now(),
+                trial_started: None,
+            }),
+        }
+    }
+
+    /// Whether `try_acquire` would let a request through, without taking the half-open trial
+    pub fn allows_request(&self) -> bool {
+        self.allows_request_at(Instant::now())
+    }
+
+    fn allows_request_at(&self, now: Instant) -> bool {
+        let state = self.state.lock().unwrap();
+        match state.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => now.saturating_duration_since(state.opened_at) >= self.cooldown,
+            CircuitState::HalfOpen => state
+                .trial_started
+                .is_none_or(|started| now.saturating_duration_since(started) >= self.cooldown),
+        }
+    }
+
+    /// Lets a request through if the breaker allows it, taking the trial when half-open
+    pub fn try_acquire(&self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => {
+                if now.saturating_duration_since(state.opened_at) < self.cooldown {
+                    return false;
+                }
+                dual_info!(
+                    "Circuit breaker of server {} is half-open, sending a trial request",
+                    self.server
+                );
+                state.state = CircuitState::HalfOpen;
+                state.trial_started = Some(now);
+                true
+            }
+            CircuitState::HalfOpen => {
+                if state
+                    .trial_started
+                    .is_some_and(|started| now.saturating_duration_since(started) < self.cooldown)
+                {
+                    return false;
+                }
+                state.trial_started = Some(now);
+                true
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.state != CircuitState::Closed {
+            dual_info!("Circuit breaker of server {} closed", self.server);
+        }
+        state.state = CircuitState::Closed;
+        state.consecutive_failures = 0;
+        state.trial_started = None;
+    }
+
+    pub fn record_failure(&self) {
+        self.record_failure_at(Instant::now())
+    }
+
+    fn record_failure_at(&self, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+
+        let trips = match state.state {
+            CircuitState::Closed => {
+                self.failure_threshold > 0 && state.consecutive_failures >= self.failure_threshold
+            }
+            CircuitState::HalfOpen => true,
+            // a request sent before the breaker opened
+            CircuitState::Open => false,
+        };
+        if trips {
+            dual_warn!(
+                "Circuit breaker of server {} opened after {} consecutive failure(s), cooling down \
+                 for {}s",
+                self.server,
+                state.consecutive_failures,
+                self.cooldown.as_secs()
+            );
+            state.state = CircuitState::Open;
+            state.opened_at = now;
+            state.trial_started = None;
+        }
+    }
+}
+
+#[test]
+fn test_circuit_breaker() {
+    let config = CircuitBreakerConfig {
+        failure_threshold: 3,
+        cooldown_secs: 30,
+    };
+    let breaker = CircuitBreaker::new("s1", &config);
+    let start = Instant::now();
+
+    // a success resets the failure count
+    breaker.record_failure_at(start);
+    breaker.record_failure_at(start);
+    breaker.record_success();
+    breaker.record_failure_at(start);
+    breaker.record_failure_at(start);
+    assert_eq!(breaker.state.lock().unwrap().state, CircuitState::Closed);
+    breaker.record_failure_at(start);
+    assert_eq!(breaker.state.lock().unwrap().state, CircuitState::Open);
+    assert!(!breaker.allows_request_at(start + Duration::from_secs(10)));
+    assert!(!breaker.try_acquire_at(start + Duration::from_secs(10)));
+
+    // a single trial once the cooldown is over; its failure opens the breaker again
+    let later = start + Duration::from_secs(30);
+    assert!(breaker.allows_request_at(later));
+    assert!(breaker.try_acquire_at(later));
+    assert_eq!(breaker.state.lock().unwrap().state, CircuitState::HalfOpen);
+    assert!(!breaker.allows_request_at(later));
+    assert!(!breaker.try_acquire_at(later));
+    breaker.record_failure_at(later);
+    assert_eq!(breaker.state.lock().unwrap().state, CircuitState::Open);
+
+    // a trial never reported is given up after a cooldown, and a successful one closes the breaker
+    let later = later + Duration::from_secs(30);
+    assert!(breaker.try_acquire_at(later));
+    assert!(!breaker.try_acquire_at(later + Duration::from_secs(29)));
+    assert!(breaker.try_acquire_at(later + Duration::from_secs(30)));
+    breaker.record_success();
+    assert_eq!(breaker.state.lock().unwrap().state, CircuitState::Closed);
+    assert!(breaker.try_acquire_at(later));
+}
@@ -0,0 +1,162 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{
+    config::ConcurrencyConfig,
+    error::{ServerError, ServerResult},
+    telemetry,
+};
+
+/// Limits the `/responses` requests in flight on each model.
+///
+/// Every model with a limit gets a semaphore of that many permits, created on its first request.
+/// The default limit only covers the models a registered server lists, so the model names clients
+/// send cannot grow the semaphores and the metric series without bound. A request holds a permit
+/// from the moment it is sent downstream until its reply is read, or for a stream, until the
+/// stream ends.
+#[derive(Debug)]
+pub struct ModelLimiter {
+    default_limit: Option<usize>,
+    limits: HashMap<String, usize>,
+    queue_timeout: Duration,
+    semaphores: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl ModelLimiter {
+    /// Builds the limiter configured by `config`; `None` if no model is limited
+    pub fn from_config(config: &ConcurrencyConfig) -> Option<Self> {
+        if config.max_per_model.is_none() && config.models.is_empty() {
+            return None;
+        }
+        Some(Self {
+            default_limit: config.max_per_model,
+            limits: config.models.clone(),
+            queue_timeout: Duration::from_millis(config.queue_timeout_ms),
+            semaphores: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Requests `model` may have in flight; `None` if it is not limited. `listed` tells whether a
+    /// registered server lists `model`; only those models fall under the default limit.
+    fn limit(&self, model: &str, listed: bool) -> Option<usize> {
+        self.limits
+            .get(model)
+            .copied()
+            .or(self.default_limit.filter(|_| listed))
+    }
+
+    /// Takes a permit of `model`, waiting up to the queue timeout for one to be released.
+    ///
+    /// Fails with [`ServerError::Overloaded`] when none is released in time, at once if the
+    /// queue timeout is 0. A model without a limit needs no permit.
+    pub async fn acquire(&self, model: &str, listed: bool) -> ServerResult<Option<ModelPermit>> {
+        let Some(limit) = self.limit(model, listed) else {
+            return Ok(None);
+        };
+        let semaphore = {
+            let mut semaphores = self.semaphores.lock().unwrap();
+            let semaphore = semaphores.entry(model.to_string()).or_insert_with(|| {
+                metrics::gauge!(telemetry::MODEL_CONCURRENCY_LIMIT, "model" => model.to_string())
+                    .set(limit as f64);
+                Arc::new(Semaphore::new(limit))
+            });
+            Arc::clone(semaphore)
+        };
+
+        let permit = if self.queue_timeout.is_zero() {
+            Arc::clone(&semaphore).try_acquire_owned().ok()
+        } else {
+            tokio::time::timeout(self.queue_timeout, Arc::clone(&semaphore).acquire_owned())
+                .await
+                .ok()
+                .and_then(Result::ok)
+        };
+        let Some(permit) = permit else {
+            metrics::counter!(telemetry::MODEL_OVERLOADED_TOTAL, "model" => model.to_string())
+                .increment(1);
+            return Err(ServerError::Overloaded(format!(
+                "model {model} has {limit} requests in flight"
+            )));
+        };
+
+        Ok(Some(ModelPermit::new(model, permit)))
+    }
+}
+
+/// Slot of a request on a limited model, released when dropped
+#[derive(Debug)]
+pub struct ModelPermit {
+    model: String,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl ModelPermit {
+    fn new(model: &str, permit: OwnedSemaphorePermit) -> Self {
+        metrics::gauge!(telemetry::MODEL_IN_FLIGHT, "model" => model.to_string()).increment(1.0);
+        Self {
+            model: model.to_string(),
+            _permit: permit,
+        }
+    }
+}
+
+impl Drop for ModelPermit {
+    fn drop(&mut self) {
+        metrics::gauge!(telemetry::MODEL_IN_FLIGHT, "model" => self.model.clone()).decrement(1.0);
+    }
+}
+
+#[tokio::test]
+async fn test_model_limits() {
+    let config = |queue_timeout_ms| ConcurrencyConfig {
+        max_per_model: Some(2),
+        models: HashMap::from([("small".to_string(), 1)]),
+        queue_timeout_ms,
+    };
+    assert!(ModelLimiter::from_config(&ConcurrencyConfig::default()).is_none());
+
+    // without a queue a request over the limit fails at once
+    let limiter = ModelLimiter::from_config(&config(0)).unwrap();
+    let first = limiter.acquire("small", true).await.unwrap();
+    assert!(matches!(
+        limiter.acquire("small", true).await,
+        Err(ServerError::Overloaded(_))
+    ));
+    // other models have their own permits
+    let _big = [
+        limiter.acquire("big", true).await.unwrap(),
+        limiter.acquire("big", true).await.unwrap(),
+    ];
+    assert!(limiter.acquire("big", true).await.is_err());
+    drop(first);
+    assert!(limiter.acquire("small", true).await.unwrap().is_some());
+    // a model no server lists is only limited if configured, and gets no semaphore otherwise
+    assert!(limiter.acquire("unknown", false).await.unwrap().is_none());
+    assert!(matches!(limiter.acquire("small", false).await, Ok(Some(_))));
+    assert!(!limiter.semaphores.lock().unwrap().contains_key("unknown"));
+
+    // with a queue it waits for a permit to be released, up to the timeout
+    let limiter = Arc::new(ModelLimiter::from_config(&config(1000)).unwrap());
+    let first = limiter.acquire("small", true).await.unwrap();
+    let waiting = tokio::spawn({
+        let limiter = Arc::clone(&limiter);
+        async move {
+            limiter
+                .acquire("small", true)
+                .await
+                .map(|permit| permit.is_some())
+        }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    drop(first);
+    assert!(waiting.await.unwrap().unwrap());
+
+    let limiter = ModelLimiter::from_config(&config(20)).unwrap();
+    let _first = limiter.acquire("small", true).await.unwrap();
+    assert!(limiter.acquire("small", true).await.is_err());
+}
@@ -9,6 +9,8 @@ use axum::{
 use chat_prompts::MergeRagContextPolicy;
 use clap::ValueEnum;
 use endpoints::chat::McpTransport;
+use once_cell::sync::Lazy;
+use ring::hmac;
 use rmcp::{
     model::{ClientCapabilities, ClientInfo, Implementation, Tool as RmcpTool},
     service::ServiceExt,
@@ -29,15 +31,59 @@ use crate::{
     dual_debug, dual_error, dual_info,
     error::{ServerError, ServerResult},
     mcp::{MCP_SERVICES, MCP_TOOLS, McpService},
+    server::{RoutingStrategy, ServerKind},
 };
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct InlineModelConfig {
-    pub id: String,            // logical model id (used by clients)
-    pub kind: String,          // "chat", "embeddings", etc.
-    pub url: String,           // downstream base URL (e.g. https://api.openai.com/v1)
+    pub id: String,   // logical model id (used by clients)
+    pub kind: String, // "chat", "embeddings", etc.
+    pub url: String,  // downstream base URL (e.g. https://api.openai.com/v1)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub api_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_header: Option<String>, // header carrying the key; Authorization if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_format: Option<String>, // header value with {key} for the key; "Bearer {key}" if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>, // share of requests under the "weighted" routing policy
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>, // capabilities matched against `capabilities` of /responses
+    #[serde(skip_serializing_if = "Option::is_none")]
+    // context window in tokens, matched against `min_context_length`
+    pub context_length: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    // chat completions path; relative to `url` unless it starts with `/`
+    pub chat_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    // embeddings path; relative to `url` unless it starts with `/`
+    pub embeddings_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    // text completions path of `completion` servers; relative to `url` unless it starts with `/`
+    pub completions_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    // reply text in non-streamed completions, e.g. `choices[0].text`
+    pub content_path: Option<String>,
+}
+
+/// Defaults applied to `/responses` requests for one model; request parameters take precedence
+#[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ModelDefaults {
+    /// System prompt of sessions without their own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f64>,
 }
 
 const MCP_REDIRECT_URI: &str = "http://localhost:8080/callback";
@@ -57,6 +103,51 @@ pub struct Config {
     pub mcp: Option<McpConfig>,
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub models: Vec<InlineModelConfig>,
+    /// Per-model request defaults, keyed by model id
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub model_defaults: HashMap<String, ModelDefaults>,
+    #[serde(default)]
+    pub routing: RoutingConfig,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    #[serde(default)]
+    pub maintenance: MaintenanceConfig,
+    #[serde(default)]
+    pub responses: ResponsesConfig,
+    #[serde(default)]
+    pub health: HealthConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub concurrency: ConcurrencyConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub database: DatabaseConfig,
+    #[serde(default)]
+    pub redis: RedisConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    #[serde(default)]
+    pub http_client: HttpClientConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub response_cache: ResponseCacheConfig,
+    #[serde(default)]
+    pub idempotency: IdempotencyConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub request_log: RequestLogConfig,
+    #[serde(default)]
+    pub moderation: ModerationConfig,
+    #[serde(default)]
+    pub tracing: TracingConfig,
+    #[serde(default)]
+    pub cors: CorsConfig,
 }
 impl Config {
     pub async fn load(path: impl AsRef<std::path::Path>) -> ServerResult<Self> {
@@ -75,6 +166,24 @@ impl Config {
             ServerError::Operation(err_msg)
         })?;
 
+        if let Err(e) = crate::moderation::blocklist(&config.moderation.blocklist) {
+            let err_msg = format!("Invalid moderation blocklist: {e}");
+            dual_error!("{}", &err_msg);
+            return Err(ServerError::FailedToLoadConfig(err_msg));
+        }
+
+        if let Err(e) = config.responses.validate() {
+            let err_msg = format!("Invalid responses settings: {e}");
+            dual_error!("{}", &err_msg);
+            return Err(ServerError::FailedToLoadConfig(err_msg));
+        }
+
+        if let Err(e) = crate::cors::layer(&config.cors) {
+            let err_msg = format!("Invalid CORS settings: {e}");
+            dual_error!("{}", &err_msg);
+            return Err(ServerError::FailedToLoadConfig(err_msg));
+        }
+
         if let Some(mcp_config) = config.mcp.as_mut()
             && !mcp_config.server.tool_servers.is_empty()
         {
@@ -102,6 +211,28 @@ impl Default for Config {
             server_health_push_url: None,
             mcp: None,
             models: Vec::new(),
+            model_defaults: HashMap::new(),
+            routing: RoutingConfig::default(),
+            retention: RetentionConfig::default(),
+            maintenance: MaintenanceConfig::default(),
+            responses: ResponsesConfig::default(),
+            health: HealthConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            concurrency: ConcurrencyConfig::default(),
+            auth: AuthConfig::default(),
+            database: DatabaseConfig::default(),
+            redis: RedisConfig::default(),
+            storage: StorageConfig::default(),
+            circuit_breaker: CircuitBreakerConfig::default(),
+            http_client: HttpClientConfig::default(),
+            metrics: MetricsConfig::default(),
+            response_cache: ResponseCacheConfig::default(),
+            idempotency: IdempotencyConfig::default(),
+            limits: LimitsConfig::default(),
+            request_log: RequestLogConfig::default(),
+            moderation: ModerationConfig::default(),
+            tracing: TracingConfig::default(),
+            cors: CorsConfig::default(),
         }
     }
 }
@@ -112,6 +243,871 @@ pub struct ServerConfig {
     pub port: u16,
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RoutingConfig {
+    /// Strategy used to pick a downstream server within each server group
+    #[serde(default)]
+    pub policy: RoutingStrategy,
+    /// Strategy of the server groups of single kinds, e.g. `chat`, instead of `policy`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub policies: HashMap<ServerKind, RoutingStrategy>,
+    /// How far each response moves the latency average of its server towards its latency, from 0
+    /// to 1
+    #[serde(default = "RoutingConfig::default_latency_smoothing")]
+    pub latency_smoothing: f64,
+    /// The latency average of a server halves every this many seconds without a response; 0
+    /// keeps it
+    #[serde(default = "RoutingConfig::default_latency_half_life_secs")]
+    pub latency_half_life_secs: u64,
+}
+impl RoutingConfig {
+    /// Strategy of the server group of `kind`
+    pub fn policy_for(&self, kind: ServerKind) -> RoutingStrategy {
+        self.policies.get(&kind).copied().unwrap_or(self.policy)
+    }
+
+    fn default_latency_smoothing() -> f64 {
+        0.2
+    }
+
+    fn default_latency_half_life_secs() -> u64 {
+        30
+    }
+}
+impl Default for RoutingConfig {
+    fn default() -> Self {
+        Self {
+            policy: RoutingStrategy::default(),
+            policies: HashMap::new(),
+            latency_smoothing: Self::default_latency_smoothing(),
+            latency_half_life_secs: Self::default_latency_half_life_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct HealthConfig {
+    /// Path probed on each downstream server by the health check, relative to its url
+    #[serde(default = "HealthConfig::default_check_path")]
+    pub check_path: String,
+    /// Number of servers tried for a chat request before a connection failure is returned
+    #[serde(default = "HealthConfig::default_max_attempts")]
+    pub max_attempts: usize,
+}
+impl HealthConfig {
+    fn default_check_path() -> String {
+        "/models".to_string()
+    }
+
+    fn default_max_attempts() -> usize {
+        3
+    }
+}
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            check_path: Self::default_check_path(),
+            max_attempts: Self::default_max_attempts(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ResponsesConfig {
+    /// Token budget of the prompt assembled by `/responses`; the oldest turns are dropped to fit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_context_tokens: Option<usize>,
+    /// Most recent turns included in the prompt of requests without their own `history_limit`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history_limit: Option<usize>,
+    /// System prompt of sessions without their own or a model default; overridden by the
+    /// `LLAMA_NEXUS_SYSTEM_PROMPT` environment variable
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+    /// Turns of history past which the oldest are summarized; unset disables summaries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summarize_after_turns: Option<usize>,
+    /// Oldest turns folded into the summary at a time
+    #[serde(default = "ResponsesConfig::default_summarize_turns")]
+    pub summarize_turns: usize,
+    /// Attempts made on a downstream 5xx or network error before the request fails
+    #[serde(default = "ResponsesConfig::default_max_attempts")]
+    pub max_attempts: u32,
+    /// Backoff before the first retry, doubled on each further retry
+    #[serde(default = "ResponsesConfig::default_retry_base_delay_ms")]
+    pub retry_base_delay_ms: u64,
+    /// Upper bound of the retry backoff
+    #[serde(default = "ResponsesConfig::default_retry_max_delay_ms")]
+    pub retry_max_delay_ms: u64,
+    /// Also retry a downstream 429, after the delay of its `Retry-After` header if it has one
+    #[serde(default = "ResponsesConfig::default_retry_rate_limited")]
+    pub retry_rate_limited: bool,
+    /// Longest `Retry-After` delay waited before a retry, in seconds; longer ones are cut to it
+    #[serde(default = "ResponsesConfig::default_max_retry_after_secs")]
+    pub max_retry_after_secs: u64,
+    /// Time a streamed reply may go without data from the downstream server, in seconds
+    #[serde(default = "ResponsesConfig::default_attempt_timeout_secs")]
+    pub attempt_timeout_secs: u64,
+    /// Time an attempt may take until the reply is complete, or until its headers for streams,
+    /// in seconds
+    #[serde(default = "ResponsesConfig::default_request_timeout_secs")]
+    pub request_timeout_secs: u64,
+    /// Most replies asked of the chat server for one request; a larger `n` is lowered to it
+    #[serde(default = "ResponsesConfig::default_max_choices")]
+    pub max_choices: u32,
+    /// Server kinds answering turns, in order: a kind is asked only when no server of the kinds
+    /// before it is available. Either `chat` or `completion`, which gets the history as a prompt.
+    #[serde(default = "ResponsesConfig::default_server_kinds")]
+    pub server_kinds: Vec<ServerKind>,
+    /// How the user message and system prompt of each turn are logged
+    #[serde(default)]
+    pub prompt_logging: PromptLogging,
+    /// Estimate the token usage of a stream whose chat server reports none, and send it to the
+    /// client in a final event
+    #[serde(default = "ResponsesConfig::default_estimate_stream_usage")]
+    pub estimate_stream_usage: bool,
+    /// Reply sent, with a 503, instead of an error when no chat server can answer a turn; unset,
+    /// the request fails
+    #[serde(default)]
+    pub fallback_reply: Option<String>,
+    /// Save the fallback reply as the turn's reply, so the history shows the outage
+    #[serde(default)]
+    pub persist_fallback_reply: bool,
+    /// Message sent in place of an empty or whitespace-only `user_message`, asking the model to
+    /// go on; unset, such requests are rejected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub continue_message: Option<String>,
+}
+impl ResponsesConfig {
+    fn default_summarize_turns() -> usize {
+        10
+    }
+
+    fn default_max_attempts() -> u32 {
+        3
+    }
+
+    fn default_retry_base_delay_ms() -> u64 {
+        250
+    }
+
+    fn default_retry_max_delay_ms() -> u64 {
+        4000
+    }
+
+    fn default_retry_rate_limited() -> bool {
+        true
+    }
+
+    fn default_max_retry_after_secs() -> u64 {
+        30
+    }
+
+    fn default_attempt_timeout_secs() -> u64 {
+        120
+    }
+
+    fn default_request_timeout_secs() -> u64 {
+        120
+    }
+
+    fn default_max_choices() -> u32 {
+        4
+    }
+
+    fn default_estimate_stream_usage() -> bool {
+        true
+    }
+
+    fn default_server_kinds() -> Vec<ServerKind> {
+        vec![ServerKind::chat]
+    }
+
+    /// Checks that `server_kinds` names only the kinds able to answer a turn, each on its own
+    pub fn validate(&self) -> Result<(), String> {
+        if self.server_kinds.is_empty() {
+            return Err("`server_kinds` must name at least one kind".to_string());
+        }
+        match self
+            .server_kinds
+            .iter()
+            .find(|kind| **kind != ServerKind::chat && **kind != ServerKind::completion)
+        {
+            Some(kind) => Err(format!(
+                "`server_kinds` may only list `chat` and `completion`, not `{kind}`"
+            )),
+            None => Ok(()),
+        }
+    }
+}
+impl Default for ResponsesConfig {
+    fn default() -> Self {
+        Self {
+            max_context_tokens: None,
+            history_limit: None,
+            system_prompt: None,
+            summarize_after_turns: None,
+            summarize_turns: Self::default_summarize_turns(),
+            max_attempts: Self::default_max_attempts(),
+            retry_base_delay_ms: Self::default_retry_base_delay_ms(),
+            retry_max_delay_ms: Self::default_retry_max_delay_ms(),
+            retry_rate_limited: Self::default_retry_rate_limited(),
+            max_retry_after_secs: Self::default_max_retry_after_secs(),
+            attempt_timeout_secs: Self::default_attempt_timeout_secs(),
+            request_timeout_secs: Self::default_request_timeout_secs(),
+            max_choices: Self::default_max_choices(),
+            server_kinds: Self::default_server_kinds(),
+            prompt_logging: PromptLogging::default(),
+            estimate_stream_usage: Self::default_estimate_stream_usage(),
+            fallback_reply: None,
+            persist_fallback_reply: false,
+            continue_message: None,
+        }
+    }
+}
+
+/// Key of the prompt hashes of [`PromptLogging::Redacted`], drawn once per process
+static PROMPT_HASH_KEY: Lazy<hmac::Key> = Lazy::new(|| {
+    hmac::Key::generate(hmac::HMAC_SHA256, &ring::rand::SystemRandom::new())
+        .expect("no system randomness")
+});
+
+/// How much of the prompts of `/responses` turns is written to the logs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PromptLogging {
+    /// The text as sent, which may hold personal data
+    Full,
+    /// Only the length and a keyed hash of the text, enough to tell prompts apart within a run of
+    /// the server; the key is random and never leaves the process, so the hash of a guessed prompt
+    /// cannot be computed from the logs
+    #[default]
+    Redacted,
+    /// Nothing
+    None,
+}
+impl PromptLogging {
+    /// `text` as it may be logged; `None` if it must not be
+    pub fn render(self, text: &str) -> Option<String> {
+        match self {
+            PromptLogging::Full => Some(text.to_string()),
+            PromptLogging::Redacted => {
+                let tag = hmac::sign(&PROMPT_HASH_KEY, text.as_bytes());
+                let hash: String = tag.as_ref()[..8]
+                    .iter()
+                    .map(|byte| format!("{byte:02x}"))
+                    .collect();
+                Some(format!("<{} chars, hash {hash}>", text.chars().count()))
+            }
+            PromptLogging::None => None,
+        }
+    }
+}
+
+/// Limits of the `/responses` requests in flight on each model
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct ConcurrencyConfig {
+    /// Requests in flight allowed on a listed model without its own limit; unset leaves them
+    /// unlimited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_per_model: Option<usize>,
+    /// Requests in flight allowed on single models, by model id
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub models: HashMap<String, usize>,
+    /// How long a request waits for a model at its limit, in milliseconds; 0 fails it at once
+    #[serde(default)]
+    pub queue_timeout_ms: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RateLimitConfig {
+    /// Sustained `/responses` requests per second allowed per session; unset disables rate limiting
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requests_per_second: Option<f64>,
+    /// Requests a session may make at once before being throttled to `requests_per_second`
+    #[serde(default = "RateLimitConfig::default_burst")]
+    pub burst: u32,
+    /// Also limit all requests authenticated with the same API key of `[auth]` together
+    #[serde(default)]
+    pub by_api_key: bool,
+    /// Rate limit state of a session idle for this many seconds is dropped
+    #[serde(default = "RateLimitConfig::default_idle_secs")]
+    pub idle_secs: u64,
+}
+impl RateLimitConfig {
+    fn default_burst() -> u32 {
+        10
+    }
+
+    fn default_idle_secs() -> u64 {
+        600
+    }
+}
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            requests_per_second: None,
+            burst: Self::default_burst(),
+            by_api_key: false,
+            idle_secs: Self::default_idle_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failed requests that open the breaker of a server; 0 disables the breakers
+    #[serde(default = "CircuitBreakerConfig::default_failure_threshold")]
+    pub failure_threshold: u32,
+    /// How long an open breaker keeps its server out of rotation before a trial request, in seconds
+    #[serde(default = "CircuitBreakerConfig::default_cooldown_secs")]
+    pub cooldown_secs: u64,
+}
+impl CircuitBreakerConfig {
+    fn default_failure_threshold() -> u32 {
+        5
+    }
+
+    fn default_cooldown_secs() -> u64 {
+        30
+    }
+}
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: Self::default_failure_threshold(),
+            cooldown_secs: Self::default_cooldown_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct AuthConfig {
+    /// Keys accepted as `Authorization: Bearer <key>`; empty leaves the server open unless JWTs
+    /// are configured
+    #[serde(default, skip_serializing)]
+    pub api_keys: Vec<String>,
+    /// Keys of users by user id; a request with one of them only reaches the sessions of its user
+    #[serde(default, skip_serializing)]
+    pub users: HashMap<String, String>,
+    #[serde(default)]
+    pub jwt: JwtConfig,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct JwtConfig {
+    /// Shared secret of HS256 tokens
+    #[serde(default, skip_serializing)]
+    pub hs256_secret: Option<String>,
+    /// URL of the JSON Web Key Set holding the public keys of RS256 tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jwks_url: Option<String>,
+    /// The key set is fetched again after this many seconds, or sooner for an unknown key id
+    #[serde(default = "JwtConfig::default_jwks_refresh_secs")]
+    pub jwks_refresh_secs: u64,
+    /// Required `iss` claim, if set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,
+    /// Required `aud` claim, if set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audience: Option<String>,
+    /// Scope needed for the `/admin` endpoints; tokens with it also reach every session
+    #[serde(default = "JwtConfig::default_admin_scope")]
+    pub admin_scope: String,
+    /// Clock skew tolerated when checking `exp` and `nbf`, in seconds
+    #[serde(default = "JwtConfig::default_leeway_secs")]
+    pub leeway_secs: u64,
+}
+impl JwtConfig {
+    fn default_jwks_refresh_secs() -> u64 {
+        3600
+    }
+
+    fn default_admin_scope() -> String {
+        "admin".to_string()
+    }
+
+    fn default_leeway_secs() -> u64 {
+        60
+    }
+}
+impl Default for JwtConfig {
+    fn default() -> Self {
+        Self {
+            hs256_secret: None,
+            jwks_url: None,
+            jwks_refresh_secs: Self::default_jwks_refresh_secs(),
+            issuer: None,
+            audience: None,
+            admin_scope: Self::default_admin_scope(),
+            leeway_secs: Self::default_leeway_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DatabaseConfig {
+    /// Maximum number of pooled connections to the chat history database
+    #[serde(default = "DatabaseConfig::default_max_connections")]
+    pub max_connections: u32,
+    /// How long a query waits for a free connection before failing, in seconds
+    #[serde(default = "DatabaseConfig::default_acquire_timeout_secs")]
+    pub acquire_timeout_secs: u64,
+    /// Idle connections are closed after this many seconds; 0 keeps them open
+    #[serde(default = "DatabaseConfig::default_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+    /// How long SQLite waits for a lock held by another connection, in milliseconds
+    #[serde(default = "DatabaseConfig::default_busy_timeout_ms")]
+    pub busy_timeout_ms: u64,
+}
+impl DatabaseConfig {
+    fn default_max_connections() -> u32 {
+        5
+    }
+
+    fn default_acquire_timeout_secs() -> u64 {
+        30
+    }
+
+    fn default_idle_timeout_secs() -> u64 {
+        600
+    }
+
+    fn default_busy_timeout_ms() -> u64 {
+        5000
+    }
+}
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: Self::default_max_connections(),
+            acquire_timeout_secs: Self::default_acquire_timeout_secs(),
+            idle_timeout_secs: Self::default_idle_timeout_secs(),
+            busy_timeout_ms: Self::default_busy_timeout_ms(),
+        }
+    }
+}
+
+/// Storage of the chat history in Redis, used when `--database-url` is a `redis://` or `rediss://`
+/// URL
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RedisConfig {
+    /// Seconds a session is kept after its last write; 0 keeps sessions until they are deleted
+    #[serde(default = "RedisConfig::default_ttl_secs")]
+    pub ttl_secs: u64,
+    /// Prefix of every key, so several deployments can share one Redis database
+    #[serde(default = "RedisConfig::default_key_prefix")]
+    pub key_prefix: String,
+    /// How long a command may wait for its reply, so a stalled server fails requests instead of
+    /// holding them
+    #[serde(default = "RedisConfig::default_command_timeout_ms")]
+    pub command_timeout_ms: u64,
+}
+impl RedisConfig {
+    fn default_ttl_secs() -> u64 {
+        86400
+    }
+
+    fn default_key_prefix() -> String {
+        "llama-nexus:".to_string()
+    }
+
+    fn default_command_timeout_ms() -> u64 {
+        5000
+    }
+}
+impl Default for RedisConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: Self::default_ttl_secs(),
+            key_prefix: Self::default_key_prefix(),
+            command_timeout_ms: Self::default_command_timeout_ms(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct HttpClientConfig {
+    /// How long connecting to a downstream server may take, in seconds
+    #[serde(default = "HttpClientConfig::default_connect_timeout_secs")]
+    pub connect_timeout_secs: u64,
+    /// Idle connections kept open per downstream host for reuse
+    #[serde(default = "HttpClientConfig::default_pool_max_idle_per_host")]
+    pub pool_max_idle_per_host: usize,
+    /// Idle pooled connections are closed after this many seconds
+    #[serde(default = "HttpClientConfig::default_pool_idle_timeout_secs")]
+    pub pool_idle_timeout_secs: u64,
+}
+impl HttpClientConfig {
+    fn default_connect_timeout_secs() -> u64 {
+        10
+    }
+
+    fn default_pool_max_idle_per_host() -> usize {
+        32
+    }
+
+    fn default_pool_idle_timeout_secs() -> u64 {
+        90
+    }
+}
+impl Default for HttpClientConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout_secs: Self::default_connect_timeout_secs(),
+            pool_max_idle_per_host: Self::default_pool_max_idle_per_host(),
+            pool_idle_timeout_secs: Self::default_pool_idle_timeout_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct MetricsConfig {
+    /// Upper bounds of the downstream latency histogram buckets, in seconds
+    #[serde(default = "MetricsConfig::default_latency_buckets_secs")]
+    pub latency_buckets_secs: Vec<f64>,
+}
+impl MetricsConfig {
+    fn default_latency_buckets_secs() -> Vec<f64> {
+        vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0]
+    }
+}
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            latency_buckets_secs: Self::default_latency_buckets_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TracingConfig {
+    /// Export spans over OTLP and propagate W3C `traceparent` headers
+    #[serde(default)]
+    pub enabled: bool,
+    /// OTLP/HTTP endpoint receiving the spans
+    #[serde(default = "TracingConfig::default_endpoint")]
+    pub endpoint: String,
+    /// `service.name` of the exported spans
+    #[serde(default = "TracingConfig::default_service_name")]
+    pub service_name: String,
+}
+impl TracingConfig {
+    fn default_endpoint() -> String {
+        "http://localhost:4318/v1/traces".to_string()
+    }
+
+    fn default_service_name() -> String {
+        "llama-nexus".to_string()
+    }
+}
+impl Default for TracingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            endpoint: Self::default_endpoint(),
+            service_name: Self::default_service_name(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CorsConfig {
+    /// Answer cross-origin requests of browsers from `allowed_origins`
+    #[serde(default)]
+    pub enabled: bool,
+    /// Origins allowed to call the API, e.g. `https://chat.example.com`; `*` allows any
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    /// Methods allowed in cross-origin requests
+    #[serde(default = "CorsConfig::default_allowed_methods")]
+    pub allowed_methods: Vec<String>,
+    /// Request headers allowed in cross-origin requests
+    #[serde(default = "CorsConfig::default_allowed_headers")]
+    pub allowed_headers: Vec<String>,
+    /// Response headers readable by the scripts of allowed origins
+    #[serde(default = "CorsConfig::default_exposed_headers")]
+    pub exposed_headers: Vec<String>,
+    /// Let browsers send cookies and `Authorization` headers; needs explicit origins
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight response, in seconds
+    #[serde(default = "CorsConfig::default_max_age_secs")]
+    pub max_age_secs: u64,
+}
+impl CorsConfig {
+    fn default_allowed_methods() -> Vec<String> {
+        ["GET", "POST", "PUT", "PATCH", "DELETE"]
+            .map(String::from)
+            .to_vec()
+    }
+
+    fn default_allowed_headers() -> Vec<String> {
+        [
+            "authorization",
+            "content-type",
+            "idempotency-key",
+            "last-event-id",
+            "traceparent",
+        ]
+        .map(String::from)
+        .to_vec()
+    }
+
+    fn default_exposed_headers() -> Vec<String> {
+        ["x-request-id", "x-model", "x-server", "x-dropped-turns"]
+            .map(String::from)
+            .to_vec()
+    }
+
+    fn default_max_age_secs() -> u64 {
+        600
+    }
+}
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            allowed_origins: Vec::new(),
+            allowed_methods: Self::default_allowed_methods(),
+            allowed_headers: Self::default_allowed_headers(),
+            exposed_headers: Self::default_exposed_headers(),
+            allow_credentials: false,
+            max_age_secs: Self::default_max_age_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct LimitsConfig {
+    /// Largest request body accepted, in bytes; larger requests get a 413
+    #[serde(default = "LimitsConfig::default_max_body_bytes")]
+    pub max_body_bytes: usize,
+    /// Longest `user_message` or tool result content of a `/responses` request, in characters;
+    /// 0 for no limit
+    #[serde(default = "LimitsConfig::default_max_message_chars")]
+    pub max_message_chars: usize,
+    /// Most recent turns of a session loaded into a prompt, whatever the token budget; 0 loads
+    /// them all
+    #[serde(default = "LimitsConfig::default_max_history_turns")]
+    pub max_history_turns: usize,
+}
+impl LimitsConfig {
+    fn default_max_body_bytes() -> usize {
+        2 * 1024 * 1024
+    }
+
+    fn default_max_message_chars() -> usize {
+        65_536
+    }
+
+    fn default_max_history_turns() -> usize {
+        200
+    }
+}
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: Self::default_max_body_bytes(),
+            max_message_chars: Self::default_max_message_chars(),
+            max_history_turns: Self::default_max_history_turns(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct ModerationConfig {
+    /// Check each `/responses` user message before it is sent to a chat server
+    #[serde(default)]
+    pub enabled: bool,
+    /// Regular expressions, matched case-insensitively, of messages to reject
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blocklist: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct RequestLogConfig {
+    /// Store every request sent to a downstream chat server in the `request_log` table
+    #[serde(default)]
+    pub enabled: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct IdempotencyConfig {
+    /// Replies to requests with an `Idempotency-Key` are replayed for this many seconds; 0
+    /// ignores the header
+    #[serde(default = "IdempotencyConfig::default_ttl_secs")]
+    pub ttl_secs: u64,
+    /// Replies kept at most; the least recently used is evicted first
+    #[serde(default = "IdempotencyConfig::default_max_entries")]
+    pub max_entries: usize,
+}
+impl IdempotencyConfig {
+    fn default_ttl_secs() -> u64 {
+        3600
+    }
+
+    fn default_max_entries() -> usize {
+        10_000
+    }
+}
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: Self::default_ttl_secs(),
+            max_entries: Self::default_max_entries(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ResponseCacheConfig {
+    /// Answer repeated identical non-streamed `/responses` requests from the cache
+    #[serde(default)]
+    pub enabled: bool,
+    /// Cached responses expire this many seconds after they were stored
+    #[serde(default = "ResponseCacheConfig::default_ttl_secs")]
+    pub ttl_secs: u64,
+    /// Responses kept at most; the least recently used is evicted first
+    #[serde(default = "ResponseCacheConfig::default_max_entries")]
+    pub max_entries: usize,
+}
+impl ResponseCacheConfig {
+    fn default_ttl_secs() -> u64 {
+        300
+    }
+
+    fn default_max_entries() -> usize {
+        1000
+    }
+}
+impl Default for ResponseCacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ttl_secs: Self::default_ttl_secs(),
+            max_entries: Self::default_max_entries(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct StorageConfig {
+    /// Chat turns buffered before they are written to the database in one transaction
+    #[serde(default = "StorageConfig::default_batch_size")]
+    pub batch_size: usize,
+    /// How often buffered chat turns are written regardless of the batch size, in milliseconds
+    #[serde(default = "StorageConfig::default_flush_interval_ms")]
+    pub flush_interval_ms: u64,
+    /// Sessions kept in the in-memory history at most; 0 for no limit
+    #[serde(default = "StorageConfig::default_memory_max_sessions")]
+    pub memory_max_sessions: usize,
+    /// Messages kept in the in-memory history at most, across all sessions; 0 for no limit
+    #[serde(default = "StorageConfig::default_memory_max_messages")]
+    pub memory_max_messages: usize,
+}
+impl StorageConfig {
+    fn default_batch_size() -> usize {
+        16
+    }
+
+    fn default_flush_interval_ms() -> u64 {
+        500
+    }
+
+    fn default_memory_max_sessions() -> usize {
+        10000
+    }
+
+    fn default_memory_max_messages() -> usize {
+        200000
+    }
+}
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            batch_size: Self::default_batch_size(),
+            flush_interval_ms: Self::default_flush_interval_ms(),
+            memory_max_sessions: Self::default_memory_max_sessions(),
+            memory_max_messages: Self::default_memory_max_messages(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RetentionConfig {
+    /// Chat messages older than this many seconds are pruned; unset keeps history forever
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age_secs: Option<u64>,
+    /// Deleted sessions are purged for good this many seconds after deletion; unset keeps them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub purge_deleted_after_secs: Option<u64>,
+    /// How often the prune job of the maintenance runs, in seconds
+    #[serde(default = "RetentionConfig::default_interval_secs")]
+    pub interval_secs: u64,
+}
+impl RetentionConfig {
+    fn default_interval_secs() -> u64 {
+        3600
+    }
+
+    /// Whether the prune job has anything to do
+    pub fn is_enabled(&self) -> bool {
+        self.max_age_secs.is_some() || self.purge_deleted_after_secs.is_some()
+    }
+}
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            max_age_secs: None,
+            purge_deleted_after_secs: None,
+            interval_secs: Self::default_interval_secs(),
+        }
+    }
+}
+
+/// Jobs keeping a long-running database healthy, run every `interval_secs` when enabled and on
+/// `POST /admin/maintenance`
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct MaintenanceConfig {
+    /// Run the recount and vacuum jobs on a schedule; pruning follows `[retention] interval_secs`
+    #[serde(default)]
+    pub enabled: bool,
+    /// How often the scheduled jobs run, in seconds
+    #[serde(default = "MaintenanceConfig::default_interval_secs")]
+    pub interval_secs: u64,
+    /// Prune the history past `[retention] max_age_secs` and purge the sessions deleted more than
+    /// `purge_deleted_after_secs` ago, every `[retention] interval_secs`
+    #[serde(default = "MaintenanceConfig::default_job")]
+    pub prune: bool,
+    /// Reclaim the space of deleted rows with `VACUUM`
+    #[serde(default = "MaintenanceConfig::default_job")]
+    pub vacuum: bool,
+    /// Recount the messages of every session, fixing counts that drifted from the history
+    #[serde(default = "MaintenanceConfig::default_job")]
+    pub recount: bool,
+}
+impl MaintenanceConfig {
+    fn default_interval_secs() -> u64 {
+        86_400
+    }
+
+    fn default_job() -> bool {
+        true
+    }
+}
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: Self::default_interval_secs(),
+            prune: Self::default_job(),
+            vacuum: Self::default_job(),
+            recount: Self::default_job(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Clone)]
 pub struct RagConfig {
     pub enable: bool,
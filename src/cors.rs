@@ -0,0 +1,175 @@
+use std::time::Duration;
+
+use axum::http::{HeaderName, HeaderValue, Method};
+use tower_http::cors::{AllowHeaders, AllowOrigin, CorsLayer};
+
+use crate::config::CorsConfig;
+
+/// Builds the CORS layer configured by `config`; `None` if CORS is disabled, in which case
+/// browsers refuse cross-origin calls.
+///
+/// The headers are added to every response, streamed ones included, so a page of an allowed
+/// origin can read `/responses` streams with `fetch` or an `EventSource`.
+pub fn layer(config: &CorsConfig) -> Result<Option<CorsLayer>, String> {
+    if !config.enabled {
+        return Ok(None);
+    }
+
+    let any_origin = config.allowed_origins.iter().any(|origin| origin == "*");
+    let any_header = config.allowed_headers.iter().any(|header| header == "*");
+    if config.allow_credentials && (any_origin || any_header) {
+        return Err(
+            "`allow_credentials` needs explicit `allowed_origins` and `allowed_headers`"
+                .to_string(),
+        );
+    }
+
+    let origins = if any_origin {
+        AllowOrigin::any()
+    } else {
+        let origins = config
+            .allowed_origins
+            .iter()
+            .map(|origin| {
+                HeaderValue::from_str(origin).map_err(|e| format!("origin `{origin}`: {e}"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        AllowOrigin::list(origins)
+    };
+    let methods = config
+        .allowed_methods
+        .iter()
+        .map(|method| {
+            Method::from_bytes(method.as_bytes()).map_err(|e| format!("method `{method}`: {e}"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let headers = if any_header {
+        AllowHeaders::any()
+    } else {
+        AllowHeaders::list(header_names(&config.allowed_headers)?)
+    };
+
+    Ok(Some(
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(methods)
+            .allow_headers(headers)
+            .expose_headers(header_names(&config.exposed_headers)?)
+            .allow_credentials(config.allow_credentials)
+            .max_age(Duration::from_secs(config.max_age_secs)),
+    ))
+}
+
+fn header_names(names: &[String]) -> Result<Vec<HeaderName>, String> {
+    names
+        .iter()
+        .map(|name| {
+            HeaderName::from_bytes(name.as_bytes()).map_err(|e| format!("header `{name}`: {e}"))
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_cors_layer() {
+    use axum::{Router, body::Body, http::Request, routing::post};
+    use tower::ServiceExt;
+
+    assert!(layer(&CorsConfig::default()).unwrap().is_none());
+
+    let config = CorsConfig {
+        enabled: true,
+        allowed_origins: vec!["https://chat.example.com".to_string()],
+        allow_credentials: true,
+        ..Default::default()
+    };
+    let app = Router::new()
+        .route(
+            "/responses",
+            post(|| async { ([("x-request-id", "r1")], "data: hi\n\n") }),
+        )
+        .layer(layer(&config).unwrap().unwrap());
+
+    // preflight of a streamed request
+    let preflight = Request::options("/responses")
+        .header("origin", "https://chat.example.com")
+        .header("access-control-request-method", "POST")
+        .header(
+            "access-control-request-headers",
+            "authorization,content-type",
+        )
+        .body(Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(preflight).await.unwrap();
+    let headers = response.headers();
+    assert_eq!(
+        headers["access-control-allow-origin"],
+        "https://chat.example.com"
+    );
+    assert_eq!(headers["access-control-allow-credentials"], "true");
+    assert!(
+        headers["access-control-allow-methods"]
+            .to_str()
+            .unwrap()
+            .contains("POST")
+    );
+    assert!(
+        headers["access-control-allow-headers"]
+            .to_str()
+            .unwrap()
+            .contains("authorization")
+    );
+
+    let request = |origin: &str| {
+        Request::post("/responses")
+            .header("origin", origin)
+            .body(Body::empty())
+            .unwrap()
+    };
+    let response = app
+        .clone()
+        .oneshot(request("https://chat.example.com"))
+        .await
+        .unwrap();
+    let headers = response.headers();
+    assert_eq!(
+        headers["access-control-allow-origin"],
+        "https://chat.example.com"
+    );
+    assert!(
+        headers["access-control-expose-headers"]
+            .to_str()
+            .unwrap()
+            .contains("x-request-id")
+    );
+
+    // other origins get no CORS headers, so browsers refuse the response
+    let response = app
+        .oneshot(request("https://evil.example.com"))
+        .await
+        .unwrap();
+    assert!(
+        response
+            .headers()
+            .get("access-control-allow-origin")
+            .is_none()
+    );
+
+    let wildcard = CorsConfig {
+        allowed_origins: vec!["*".to_string()],
+        ..config.clone()
+    };
+    assert!(layer(&wildcard).is_err());
+    assert!(
+        layer(&CorsConfig {
+            allow_credentials: false,
+            ..wildcard
+        })
+        .unwrap()
+        .is_some()
+    );
+    let invalid = CorsConfig {
+        allowed_headers: vec!["bad header".to_string()],
+        ..config
+    };
+    assert!(layer(&invalid).is_err());
+}
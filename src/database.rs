@@ -1,237 +1,4660 @@
-use sqlx::{sqlite::{SqlitePool, SqlitePoolOptions}, Row};
-use serde::{Deserialize, Serialize};
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
 use chrono::{DateTime, Utc};
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use std::collections::HashMap;
-use anyhow::Result;
+use endpoints::common::Usage;
+use futures_util::{StreamExt, stream::BoxStream};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    migrate::Migrator,
+    postgres::{PgPool, PgPoolOptions},
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions},
+};
+use std::collections::{BTreeMap, HashMap};
+use std::{borrow::Cow, ops::Range, str::FromStr, sync::Arc, time::Duration};
+use tokio::sync::{Mutex, mpsc};
+
+use crate::{
+    config::{DatabaseConfig, StorageConfig},
+    dual_warn,
+};
+
+/// Serializes times as RFC 3339 strings in UTC, e.g. `2025-01-31T09:30:00.123456Z`, keeping every
+/// fractional digit so a time read back compares equal. Any RFC 3339 offset is accepted when
+/// deserializing and converted to UTC.
+pub(crate) mod rfc3339 {
+    use chrono::{DateTime, SecondsFormat, Utc};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(crate) fn format(time: &DateTime<Utc>) -> String {
+        time.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+    }
+
+    pub(crate) fn serialize<S: Serializer>(
+        time: &DateTime<Utc>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format(time))
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DateTime<Utc>, D::Error> {
+        let time = String::deserialize(deserializer)?;
+        DateTime::parse_from_rfc3339(&time)
+            .map(|time| time.with_timezone(&Utc))
+            .map_err(serde::de::Error::custom)
+    }
+
+    /// [`serialize`] for optional times, `null` when unset
+    pub(crate) fn serialize_option<S: Serializer>(
+        time: &Option<DateTime<Utc>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match time {
+            Some(time) => serialize(time, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+}
+
+/// What a stored message holds: a whole turn, or one message on its own
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageRole {
+    /// A user message and the reply to it
+    #[default]
+    Turn,
+    /// A user message on its own, in `user_message`
+    User,
+    /// A reply on its own, in `bot_reply`, with its tool calls in `assistant_message`
+    Assistant,
+    /// A system notice, in `bot_reply`
+    System,
+    /// Tool results on their own, in `tool_results`
+    Tool,
+}
+impl MessageRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageRole::Turn => "turn",
+            MessageRole::User => "user",
+            MessageRole::Assistant => "assistant",
+            MessageRole::System => "system",
+            MessageRole::Tool => "tool",
+        }
+    }
+
+    pub fn is_turn(&self) -> bool {
+        *self == MessageRole::Turn
+    }
+}
+impl TryFrom<String> for MessageRole {
+    type Error = String;
+
+    fn try_from(role: String) -> Result<Self, Self::Error> {
+        serde_json::from_value(serde_json::Value::String(role)).map_err(|e| e.to_string())
+    }
+}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
 pub struct ChatMessage {
     pub id: Option<i64>,
     pub session_id: String,
     pub user_message: String,
     pub bot_reply: String,
+    /// When the turn was saved. SQLite stores it as RFC 3339 text with a `+00:00` offset and
+    /// Postgres as `TIMESTAMPTZ`, so it reads back in UTC from either.
+    #[serde(with = "rfc3339")]
     pub timestamp: DateTime<Utc>,
+    /// JSON array of the tool results the client sent with the turn
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_results: Option<String>,
+    /// Raw JSON of the assistant message, kept when the reply calls tools
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub assistant_message: Option<String>,
+    /// System prompt the turn was answered with, as resolved at the time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+    /// Whether the reply was cut short, by a client disconnect, a downstream timeout or the token
+    /// limit
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    /// Whether this is a whole turn or a single message; a turn unless set
+    #[serde(default, skip_serializing_if = "MessageRole::is_turn")]
+    #[sqlx(try_from = "String")]
+    pub role: MessageRole,
+    /// Milliseconds the downstream server took to complete the reply, if it was timed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<i64>,
+    /// Sampler seed the reply was generated with, sent again when the turn is regenerated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    /// Version of the turn, from 1; regenerating a turn saves the next version
+    #[serde(default = "ChatMessage::first_version")]
+    pub version: i64,
+    /// Id of the original turn of which this is a later version; `None` for an original
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<i64>,
 }
+impl ChatMessage {
+    /// A turn without tool calls, stamped with the current time
+    pub fn new(session_id: &str, user_message: &str, bot_reply: &str) -> Self {
+        Self {
+            id: None,
+            session_id: session_id.to_string(),
+            user_message: user_message.to_string(),
+            bot_reply: bot_reply.to_string(),
+            timestamp: Utc::now(),
+            tool_results: None,
+            assistant_message: None,
+            system_prompt: None,
+            truncated: false,
+            role: MessageRole::Turn,
+            latency_ms: None,
+            seed: None,
+            version: Self::first_version(),
+            parent_id: None,
+        }
+    }
 
-#[derive(Debug)]
-pub struct DatabaseManager {
-    pool: SqlitePool,
-}
+    fn first_version() -> i64 {
+        1
+    }
 
-impl DatabaseManager {
-    pub async fn new(database_url: &str) -> Result<Self> {
-        // Accept either a full sqlx URL (e.g. sqlite:history.db) or a bare file path (history.db)
-        let mut url = if database_url.starts_with("sqlite:") || database_url.starts_with("file:") {
-            database_url.to_string()
-        } else {
-            // ensure parent directory exists if path contains one
-            if let Some(parent) = std::path::Path::new(database_url).parent() {
-                if !parent.as_os_str().is_empty() && !parent.exists() {
-                    std::fs::create_dir_all(parent)?;
-                }
-            }
-            format!("sqlite:{}", database_url)
-        };
-        // Ensure mode=rwc so file is created if missing
-        if !url.contains("mode=") {
-            if url.contains('?') { url.push_str("&mode=rwc"); } else { url.push_str("?mode=rwc"); }
+    /// This turn saved as the version after `previous`, linked to the original turn of `previous`
+    pub fn next_version_of(self, previous: &ChatMessage) -> Self {
+        Self {
+            version: previous.version + 1,
+            parent_id: previous.parent_id.or(previous.id),
+            ..self
         }
-        let pool = SqlitePoolOptions::new()
-            .max_connections(5)
-            .connect(&url)
-            .await?;
-        
-        // Create tables if they don't exist
-        sqlx::query(
-            r#"
-            CREATE TABLE IF NOT EXISTS chat_messages (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                session_id TEXT NOT NULL,
-                user_message TEXT NOT NULL,
-                bot_reply TEXT NOT NULL,
-                timestamp DATETIME NOT NULL
-            )
-            "#,
-        )
-        .execute(&pool)
-        .await?;
+    }
 
-    Ok(Self { pool })
+    /// A single message of `role`, stamped with the current time.
+    ///
+    /// User messages keep `content` in `user_message`, the others in `bot_reply`; tool results
+    /// go in `tool_results` as a JSON array of `{"tool_call_id", "content"}` objects.
+    pub fn single(session_id: &str, role: MessageRole, content: &str) -> Self {
+        let (user_message, bot_reply) = match role {
+            MessageRole::User => (content, ""),
+            MessageRole::Tool => ("", ""),
+            _ => ("", content),
+        };
+        Self {
+            tool_results: (role == MessageRole::Tool).then(|| content.to_string()),
+            role,
+            ..Self::new(session_id, user_message, bot_reply)
+        }
     }
 
-    pub async fn save_message(&self, message: &ChatMessage) -> Result<()> {
-        sqlx::query(
-            r#"
-            INSERT INTO chat_messages (session_id, user_message, bot_reply, timestamp)
-            VALUES (?, ?, ?, ?)
-            "#,
-        )
-        .bind(&message.session_id)
-        .bind(&message.user_message)
-        .bind(&message.bot_reply)
-        .bind(message.timestamp)
-        .execute(&self.pool)
-        .await?;
+    /// Title given to the session by its first message; only user messages have one
+    pub(crate) fn session_title(&self) -> Option<String> {
+        matches!(self.role, MessageRole::Turn | MessageRole::User)
+            .then(|| session_title(&self.user_message))
+    }
+}
 
-        Ok(())
+/// Pairs stored messages up into `(user message, reply)` turns, in order.
+///
+/// A single user message followed by a single reply make one turn; a single message without its
+/// counterpart makes a turn with an empty half. System notices and tool results are left out.
+pub(crate) fn message_pairs(messages: Vec<ChatMessage>) -> Vec<(String, String)> {
+    let mut pairs: Vec<(String, String)> = Vec::new();
+    // whether the last pair is a single user message still waiting for its reply
+    let mut awaiting_reply = false;
+    for message in messages {
+        match message.role {
+            MessageRole::Turn => pairs.push((message.user_message, message.bot_reply)),
+            MessageRole::User => pairs.push((message.user_message, String::new())),
+            MessageRole::Assistant if awaiting_reply => {
+                pairs.last_mut().unwrap().1 = message.bot_reply
+            }
+            MessageRole::Assistant => pairs.push((String::new(), message.bot_reply)),
+            MessageRole::System | MessageRole::Tool => continue,
+        }
+        awaiting_reply = message.role == MessageRole::User;
     }
+    pairs
+}
 
-    pub async fn get_session_history(&self, session_id: &str) -> Result<Vec<ChatMessage>> {
-        let rows = sqlx::query(
-            r#"
-            SELECT id, session_id, user_message, bot_reply, timestamp
-            FROM chat_messages
-            WHERE session_id = ?
-            ORDER BY timestamp ASC
-            "#,
+/// Title and recency of a session with stored messages
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct SessionMetadata {
+    pub session_id: String,
+    pub title: Option<String>,
+    #[serde(with = "rfc3339")]
+    pub created_at: DateTime<Utc>,
+    #[serde(with = "rfc3339")]
+    pub updated_at: DateTime<Utc>,
+    pub message_count: i64,
+    /// Start of the last reply, when listed with `preview`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[sqlx(default)]
+    pub preview: Option<String>,
+}
+
+/// Running summary of the oldest turns of a session, standing in for them in prompts
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct SessionSummary {
+    pub session_id: String,
+    pub summary: String,
+    /// Id of the newest turn covered by the summary
+    pub summarized_until: i64,
+    #[serde(with = "rfc3339")]
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A request sent to a downstream chat server, kept for audit when `[request_log]` is enabled
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct RequestLogEntry {
+    pub id: Option<i64>,
+    pub session_id: String,
+    pub model: Option<String>,
+    pub server_url: String,
+    /// JSON body exactly as sent
+    pub request: String,
+    /// HTTP status of the response; `None` if the request failed or timed out before one arrived
+    pub status: Option<i32>,
+    pub latency_ms: i64,
+    #[serde(with = "rfc3339")]
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Rows of a streamed history read ahead of the client
+const HISTORY_STREAM_BUFFER: usize = 32;
+
+/// SQL condition keeping only the latest version of each message of `$table`: a message is
+/// superseded once a later version of its original turn exists. Expands to a literal, so queries
+/// can `concat!` it.
+macro_rules! latest_version {
+    ($table:literal) => {
+        concat!(
+            "NOT EXISTS (SELECT 1 FROM chat_messages newer WHERE newer.parent_id = COALESCE(",
+            $table,
+            ".parent_id, ",
+            $table,
+            ".id) AND newer.version > ",
+            $table,
+            ".version)"
         )
-        .bind(session_id)
-        .fetch_all(&self.pool)
-        .await?;
+    };
+}
 
-        let messages = rows
-            .into_iter()
-            .map(|row| ChatMessage {
-                id: Some(row.get("id")),
-                session_id: row.get("session_id"),
-                user_message: row.get("user_message"),
-                bot_reply: row.get("bot_reply"),
-                timestamp: row.get("timestamp"),
-            })
-            .collect();
+/// Drops the summary of a session once a turn it covers is deleted
+const SUMMARY_INVALIDATE: &str =
+    "DELETE FROM session_summaries WHERE session_id = ? AND summarized_until >= ?";
+/// Drops the summary of a session whose history is deleted
+const SUMMARY_DELETE: &str = "DELETE FROM session_summaries WHERE session_id = ?";
+/// Marks the live messages of a session deleted at a given time
+const SESSION_SOFT_DELETE: &str =
+    "UPDATE chat_messages SET deleted_at = ? WHERE session_id = ? AND deleted_at IS NULL";
+/// Resets the metadata of a session whose messages were erased
+const SESSION_METADATA_RESET: &str = r#"
+    UPDATE sessions
+    SET title = NULL, created_at = NULL, updated_at = NULL, message_count = 0
+    WHERE session_id = ?
+"#;
 
-        Ok(messages)
+/// Order of listed sessions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionSort {
+    /// Most recently updated first
+    #[default]
+    Recent,
+    /// Least recently updated first
+    Oldest,
+    /// Most messages first
+    MessageCount,
+}
+
+/// Which sessions to list and in what order; unset bounds match every session
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SessionFilter {
+    /// Only sessions last updated at or after this time
+    #[serde(default)]
+    pub updated_after: Option<DateTime<Utc>>,
+    /// Only sessions last updated before this time
+    #[serde(default)]
+    pub updated_before: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub sort: SessionSort,
+    /// Include the start of the last reply of each session
+    #[serde(default)]
+    pub preview: bool,
+    /// Only sessions whose id starts with this, the namespace of the user listing them
+    #[serde(skip)]
+    pub prefix: Option<String>,
+}
+impl SessionFilter {
+    pub(crate) fn matches(&self, metadata: &SessionMetadata) -> bool {
+        metadata.message_count > 0
+            && self.in_namespace(&metadata.session_id)
+            && self
+                .updated_after
+                .is_none_or(|after| metadata.updated_at >= after)
+            && self
+                .updated_before
+                .is_none_or(|before| metadata.updated_at < before)
     }
 
-    pub async fn delete_session_history(&self, session_id: &str) -> Result<()> {
-        sqlx::query("DELETE FROM chat_messages WHERE session_id = ?")
-            .bind(session_id)
-            .execute(&self.pool)
-            .await?;
+    pub(crate) fn in_namespace(&self, session_id: &str) -> bool {
+        self.prefix
+            .as_deref()
+            .is_none_or(|prefix| session_id.starts_with(prefix))
+    }
 
-        Ok(())
+    fn order_by(&self) -> &'static str {
+        match self.sort {
+            SessionSort::Recent => "updated_at DESC, session_id ASC",
+            SessionSort::Oldest => "updated_at ASC, session_id ASC",
+            SessionSort::MessageCount => "message_count DESC, updated_at DESC, session_id ASC",
+        }
     }
 
-    pub async fn get_all_sessions(&self) -> Result<Vec<String>> {
-        let rows = sqlx::query("SELECT DISTINCT session_id FROM chat_messages")
-            .fetch_all(&self.pool)
-            .await?;
+    pub(crate) fn sort(&self, sessions: &mut [SessionMetadata]) {
+        sessions.sort_by(|a, b| {
+            let order = match self.sort {
+                SessionSort::Recent => b.updated_at.cmp(&a.updated_at),
+                SessionSort::Oldest => a.updated_at.cmp(&b.updated_at),
+                SessionSort::MessageCount => b
+                    .message_count
+                    .cmp(&a.message_count)
+                    .then_with(|| b.updated_at.cmp(&a.updated_at)),
+            };
+            order.then_with(|| a.session_id.cmp(&b.session_id))
+        });
+    }
+}
 
-        let sessions = rows
-            .into_iter()
-            .map(|row| row.get("session_id"))
-            .collect();
+/// Maximum number of characters of a title generated from the first user message
+const SESSION_TITLE_MAX_CHARS: usize = 60;
+/// Maximum number of characters of the preview of the last reply of a session
+pub(crate) const SESSION_PREVIEW_MAX_CHARS: usize = 100;
 
-        Ok(sessions)
+/// Collapses the whitespace of `text` and cuts it to `max_chars`, marking the cut with "..."
+pub(crate) fn shorten(text: &str, max_chars: usize) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.chars().count() <= max_chars {
+        return text;
     }
+
+    let truncated: String = text.chars().take(max_chars).collect();
+    format!("{}...", truncated.trim_end())
 }
 
-// In-memory fallback for when database is not available
-pub type ChatHistory = Arc<Mutex<HashMap<String, Vec<String>>>>;
+/// Generates a session title from the first user message of the session
+pub(crate) fn session_title(user_message: &str) -> String {
+    shorten(user_message, SESSION_TITLE_MAX_CHARS)
+}
 
-pub struct ChatStorage {
-    database: Option<DatabaseManager>,
-    memory_fallback: ChatHistory,
+/// Migrations of SQLite databases, from `migrations/sqlite`. Each file runs once, in order, and is
+/// recorded in the `_sqlx_migrations` table; a schema change is a new file, never an edit.
+static SQLITE_MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+/// Migrations of Postgres databases, from `migrations/postgres`, kept in step with the SQLite ones
+static POSTGRES_MIGRATOR: Migrator = sqlx::migrate!("./migrations/postgres");
+
+/// Columns that Postgres databases created before migrations may lack
+const POSTGRES_ADDED_COLUMNS: &[&str] = &[
+    "ALTER TABLE sessions ADD COLUMN IF NOT EXISTS title TEXT",
+    "ALTER TABLE sessions ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ",
+    "ALTER TABLE sessions ADD COLUMN IF NOT EXISTS updated_at TIMESTAMPTZ",
+    "ALTER TABLE sessions ADD COLUMN IF NOT EXISTS message_count BIGINT NOT NULL DEFAULT 0",
+    "ALTER TABLE chat_messages ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ",
+    "ALTER TABLE chat_messages ADD COLUMN IF NOT EXISTS tool_results TEXT",
+    "ALTER TABLE chat_messages ADD COLUMN IF NOT EXISTS assistant_message TEXT",
+    "ALTER TABLE chat_messages ADD COLUMN IF NOT EXISTS system_prompt TEXT",
+    "ALTER TABLE chat_messages ADD COLUMN IF NOT EXISTS truncated BOOLEAN NOT NULL DEFAULT FALSE",
+    "ALTER TABLE chat_messages ADD COLUMN IF NOT EXISTS role TEXT NOT NULL DEFAULT 'turn'",
+    "ALTER TABLE chat_messages ADD COLUMN IF NOT EXISTS latency_ms BIGINT",
+    "ALTER TABLE chat_messages ADD COLUMN IF NOT EXISTS seed BIGINT",
+    "ALTER TABLE chat_messages ADD COLUMN IF NOT EXISTS version BIGINT NOT NULL DEFAULT 1",
+    "ALTER TABLE chat_messages ADD COLUMN IF NOT EXISTS parent_id BIGINT",
+];
+
+/// Columns that SQLite databases created before migrations may lack, as
+/// `(table, column, definition)`; they are added ahead of the first migration, whose indexes cover
+/// some of them
+const SQLITE_ADDED_COLUMNS: &[(&str, &str, &str)] = &[
+    ("sessions", "title", "TEXT"),
+    ("sessions", "created_at", "DATETIME"),
+    ("sessions", "updated_at", "DATETIME"),
+    ("sessions", "message_count", "INTEGER NOT NULL DEFAULT 0"),
+    ("chat_messages", "deleted_at", "DATETIME"),
+    ("chat_messages", "tool_results", "TEXT"),
+    ("chat_messages", "assistant_message", "TEXT"),
+    ("chat_messages", "system_prompt", "TEXT"),
+    (
+        "chat_messages",
+        "truncated",
+        "BOOLEAN NOT NULL DEFAULT FALSE",
+    ),
+    ("chat_messages", "role", "TEXT NOT NULL DEFAULT 'turn'"),
+    ("chat_messages", "latency_ms", "INTEGER"),
+    ("chat_messages", "seed", "INTEGER"),
+    ("chat_messages", "version", "INTEGER NOT NULL DEFAULT 1"),
+    ("chat_messages", "parent_id", "INTEGER"),
+];
+
+/// Fills in the metadata of sessions whose messages were saved before the metadata was tracked
+const SESSION_METADATA_BACKFILL: &[&str] = &[
+    r#"
+    INSERT INTO sessions (session_id)
+    SELECT DISTINCT session_id FROM chat_messages
+    WHERE session_id NOT IN (SELECT session_id FROM sessions)
+    "#,
+    concat!(
+        r#"
+    UPDATE sessions SET
+        created_at = (
+            SELECT MIN(timestamp) FROM chat_messages c WHERE c.session_id = sessions.session_id
+        ),
+        updated_at = (
+            SELECT MAX(timestamp) FROM chat_messages c WHERE c.session_id = sessions.session_id
+        ),
+        message_count = (
+            SELECT COUNT(*) FROM chat_messages c
+            WHERE c.session_id = sessions.session_id AND c.deleted_at IS NULL AND "#,
+        latest_version!("c"),
+        r#"
+        ),
+        title = COALESCE(title, (
+            SELECT substr(user_message, 1, 60) FROM chat_messages c
+            WHERE c.session_id = sessions.session_id
+            ORDER BY timestamp ASC, id ASC
+            LIMIT 1
+        ))
+    WHERE created_at IS NULL
+    "#
+    ),
+];
+
+/// Recounts the live messages of the sessions after messages were deleted or restored
+const SESSION_MESSAGE_RECOUNT: &str = concat!(
+    r#"
+    UPDATE sessions SET
+        message_count = (
+            SELECT COUNT(*) FROM chat_messages c
+            WHERE c.session_id = sessions.session_id AND c.deleted_at IS NULL AND "#,
+    latest_version!("c"),
+    r#"
+        )
+"#
+);
+
+#[derive(Debug, Clone)]
+enum DatabasePool {
+    Sqlite(SqlitePool),
+    Postgres(PgPool),
 }
 
-impl ChatStorage {
-    pub fn new_memory_only() -> Self {
-        Self {
-            database: None,
-            memory_fallback: Arc::new(Mutex::new(HashMap::new())),
+/// Runs the same sqlx call against whichever pool backs the manager.
+///
+/// The body is expanded once per backend, so it may use the generic sqlx APIs and decode rows
+/// with `FromRow`. Query text should go through [`DatabaseManager::sql`] first.
+macro_rules! with_pool {
+    ($self:ident, $pool:ident => $body:expr) => {
+        match &$self.pool {
+            DatabasePool::Sqlite($pool) => $body,
+            DatabasePool::Postgres($pool) => $body,
         }
+    };
+}
+
+/// Persistent store of the chat history behind [`ChatStorage`].
+///
+/// [`DatabaseManager`] implements it for SQLite and Postgres. Another backend plugs in with
+/// [`ChatStorage::with_backend`] without changes to the routes. The in-memory fallback, used
+/// without a backend or when a write fails, is part of `ChatStorage` itself.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Waits for the checked-out connections to be returned, then closes every connection
+    async fn close(&self);
+
+    /// Checks that the database answers a `SELECT 1`
+    async fn ping(&self) -> Result<()>;
+
+    /// Stores the system prompt of a session; `None` clears it
+    async fn set_system_prompt(&self, session_id: &str, system_prompt: Option<&str>) -> Result<()>;
+
+    /// Returns the system prompt of a session, if one is set
+    async fn get_system_prompt(&self, session_id: &str) -> Result<Option<String>>;
+
+    /// Stores a message and updates the metadata of its session in the same transaction
+    async fn save_message(&self, message: &ChatMessage) -> Result<()>;
+
+    /// Stores a single message of `role`, e.g. a system notice; see [`ChatMessage::single`]
+    async fn save_single_message(
+        &self,
+        session_id: &str,
+        role: MessageRole,
+        content: &str,
+    ) -> Result<()> {
+        self.save_message(&ChatMessage::single(session_id, role, content))
+            .await
     }
 
-    pub async fn new_with_database(database_url: &str) -> Result<Self> {
-        let database = DatabaseManager::new(database_url).await?;
-        Ok(Self {
-            database: Some(database),
-            memory_fallback: Arc::new(Mutex::new(HashMap::new())),
-        })
+    /// Saves `messages` in order in a single transaction, so either all of them are stored or none
+    async fn save_messages_batch(&self, messages: &[ChatMessage]) -> Result<()>;
+
+    /// Adds the token usage of one downstream request to the totals of a session
+    async fn add_session_usage(&self, session_id: &str, usage: &Usage) -> Result<()>;
+
+    /// Returns the token usage totals of a session; `None` if none was recorded
+    async fn get_session_usage(&self, session_id: &str) -> Result<Option<SessionUsage>>;
+
+    /// Returns the running summary of a session, if its oldest turns were summarized
+    async fn get_session_summary(&self, session_id: &str) -> Result<Option<SessionSummary>>;
+
+    /// Stores the running summary of a session, replacing the previous one
+    async fn set_session_summary(&self, summary: &SessionSummary) -> Result<()>;
+
+    /// Sets the title of a session; `None` lets the next saved message generate one
+    async fn set_session_title(&self, session_id: &str, title: Option<&str>) -> Result<()>;
+
+    /// Returns the metadata of the sessions with stored messages matching `filter`, in its order
+    async fn list_sessions(&self, filter: &SessionFilter) -> Result<Vec<SessionMetadata>>;
+
+    /// Returns the live messages of a session, oldest first
+    async fn get_session_history(&self, session_id: &str) -> Result<Vec<ChatMessage>>;
+
+    /// Streams the messages of a session saved after `since`, oldest first, as they are read
+    fn stream_session_history(
+        &self,
+        session_id: &str,
+        since: Option<DateTime<Utc>>,
+    ) -> BoxStream<'static, Result<ChatMessage>>;
+
+    /// Returns one page of a session's messages, oldest first
+    async fn get_session_history_paginated(
+        &self,
+        session_id: &str,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<ChatMessage>>;
+
+    /// Returns the number of live messages of a session
+    async fn count_session_messages(&self, session_id: &str) -> Result<i64>;
+
+    /// Marks the live messages of a session deleted; [`Self::restore_session`] brings them back
+    async fn delete_session_history(&self, session_id: &str) -> Result<()>;
+
+    /// Restores the deleted messages of a session and returns the number restored
+    async fn restore_session(&self, session_id: &str) -> Result<u64>;
+
+    /// Permanently deletes the messages deleted before `cutoff` and returns the number removed
+    async fn purge_deleted_before(&self, cutoff: DateTime<Utc>) -> Result<u64>;
+
+    /// Permanently deletes the messages of a session, deleted or not, and resets its metadata
+    async fn erase_session_history(&self, session_id: &str) -> Result<()>;
+
+    /// Deletes the sessions last updated before `cutoff` in one transaction and returns their ids
+    async fn delete_sessions_before(
+        &self,
+        cutoff: DateTime<Utc>,
+        hard: bool,
+    ) -> Result<Vec<String>>;
+
+    /// Deletes all but the newest `keep_last` messages of a session and returns the number removed
+    async fn prune_session(&self, session_id: &str, keep_last: i64) -> Result<u64>;
+
+    /// Returns the live turn `id` of a session
+    async fn get_message_by_id(&self, session_id: &str, id: i64) -> Result<Option<ChatMessage>>;
+
+    /// Deletes the turn `id` of a session for good; `false` if the session has no such turn
+    async fn delete_message_by_id(&self, session_id: &str, id: i64) -> Result<bool>;
+
+    /// Deletes every turn after turn `id` of a session, and the turn itself if `inclusive`, and
+    /// returns the number removed
+    async fn truncate_after_id(&self, session_id: &str, id: i64, inclusive: bool) -> Result<u64>;
+
+    /// Whether a regenerated turn is saved as a new version that keeps the earlier ones
+    fn keeps_versions(&self) -> bool {
+        false
     }
 
-    pub async fn save_conversation(&self, session_id: &str, user_message: &str, bot_reply: &str) -> Result<()> {
-        let message = ChatMessage {
-            id: None,
-            session_id: session_id.to_string(),
-            user_message: user_message.to_string(),
-            bot_reply: bot_reply.to_string(),
-            timestamp: Utc::now(),
-        };
+    /// Returns every version of the turn `id` of a session, oldest first; without
+    /// [`Self::keeps_versions`] that is only the turn itself
+    async fn get_message_versions(&self, session_id: &str, id: i64) -> Result<Vec<ChatMessage>> {
+        Ok(self
+            .get_message_by_id(session_id, id)
+            .await?
+            .into_iter()
+            .collect())
+    }
 
-        if let Some(db) = &self.database {
-            db.save_message(&message).await?;
-        } else {
-            // Fallback to memory storage
-            let mut history = self.memory_fallback.lock().await;
-            let conversation = history.entry(session_id.to_string()).or_default();
-            conversation.push(format!("User: {}", user_message));
-            conversation.push(format!("Bot: {}", bot_reply));
-        }
+    /// Copies the live turns of `src`, up to and including turn `until_id` if given, into the new
+    /// session `dst` in one transaction, and returns the number copied
+    async fn fork_session(
+        &self,
+        src: &str,
+        dst: &str,
+        until_id: Option<i64>,
+    ) -> Result<Option<u64>>;
+
+    /// Deletes every message older than `cutoff` and returns the number removed
+    async fn prune_messages_before(&self, cutoff: DateTime<Utc>) -> Result<u64>;
 
+    /// Reclaims the space left by deleted rows; does nothing unless the backend needs it
+    async fn vacuum(&self) -> Result<()> {
         Ok(())
     }
 
-    pub async fn get_conversation_history(&self, session_id: &str) -> Result<Vec<String>> {
-        if let Some(db) = &self.database {
-            let messages = db.get_session_history(session_id).await?;
-            let mut history = Vec::new();
-            
-            for message in messages {
-                history.push(format!("User: {}", message.user_message));
-                history.push(format!("Bot: {}", message.bot_reply));
+    /// Recounts the live messages of every session and returns the number of sessions recounted
+    async fn recount_sessions(&self) -> Result<u64> {
+        Ok(0)
+    }
+
+    /// Returns the messages whose user message or reply contains every word of `query`, oldest
+    /// first
+    async fn search_messages(
+        &self,
+        query: &str,
+        session_id: Option<&str>,
+    ) -> Result<Vec<ChatMessage>>;
+
+    /// Appends a downstream request to the request log
+    async fn insert_request_log(&self, entry: &RequestLogEntry) -> Result<()>;
+
+    /// Returns the oldest `limit` logged requests, of one session and from `since` on if given
+    async fn list_request_logs(
+        &self,
+        session_id: Option<&str>,
+        since: Option<DateTime<Utc>>,
+        limit: i64,
+    ) -> Result<Vec<RequestLogEntry>>;
+}
+
+#[derive(Debug)]
+pub struct DatabaseManager {
+    pool: DatabasePool,
+}
+
+impl DatabaseManager {
+    /// Connects to the database at `database_url` with a pool sized by `config`.
+    ///
+    /// `postgres://` and `postgresql://` URLs use Postgres. Anything else is treated as SQLite,
+    /// either a full sqlx URL (e.g. sqlite:history.db) or a bare file path (history.db). SQLite
+    /// databases are opened in WAL mode, so readers do not block the writer, and wait up to
+    /// `busy_timeout_ms` for a lock instead of failing with "database is locked".
+    ///
+    /// The schema is brought up to date by the migrations not yet applied to the database.
+    pub async fn new(database_url: &str, config: &DatabaseConfig) -> Result<Self> {
+        let acquire_timeout = Duration::from_secs(config.acquire_timeout_secs);
+        let idle_timeout =
+            (config.idle_timeout_secs > 0).then(|| Duration::from_secs(config.idle_timeout_secs));
+
+        let pool = if database_url.starts_with("postgres://")
+            || database_url.starts_with("postgresql://")
+        {
+            let pool = PgPoolOptions::new()
+                .max_connections(config.max_connections.max(1))
+                .acquire_timeout(acquire_timeout)
+                .idle_timeout(idle_timeout)
+                .connect(database_url)
+                .await?;
+
+            let unmigrated: bool = sqlx::query_scalar(
+                "SELECT to_regclass('chat_messages') IS NOT NULL \
+                 AND to_regclass('_sqlx_migrations') IS NULL",
+            )
+            .fetch_one(&pool)
+            .await?;
+            if unmigrated {
+                for statement in POSTGRES_ADDED_COLUMNS {
+                    sqlx::query(statement).execute(&pool).await?;
+                }
+            }
+            POSTGRES_MIGRATOR.run(&pool).await?;
+            for statement in SESSION_METADATA_BACKFILL {
+                sqlx::query(statement).execute(&pool).await?;
             }
-            
-            Ok(history)
+
+            DatabasePool::Postgres(pool)
         } else {
-            // Fallback to memory storage
-            let history = self.memory_fallback.lock().await;
-            Ok(history.get(session_id).cloned().unwrap_or_default())
-        }
+            let mut url =
+                if database_url.starts_with("sqlite:") || database_url.starts_with("file:") {
+                    database_url.to_string()
+                } else {
+                    // ensure parent directory exists if path contains one
+                    if let Some(parent) = std::path::Path::new(database_url).parent() {
+                        if !parent.as_os_str().is_empty() && !parent.exists() {
+                            std::fs::create_dir_all(parent)?;
+                        }
+                    }
+                    format!("sqlite:{database_url}")
+                };
+            // Ensure mode=rwc so file is created if missing
+            if !url.contains("mode=") {
+                if url.contains('?') {
+                    url.push_str("&mode=rwc");
+                } else {
+                    url.push_str("?mode=rwc");
+                }
+            }
+            let options = SqliteConnectOptions::from_str(&url)?
+                .journal_mode(SqliteJournalMode::Wal)
+                .busy_timeout(Duration::from_millis(config.busy_timeout_ms));
+            let pool = SqlitePoolOptions::new()
+                .max_connections(config.max_connections.max(1))
+                .acquire_timeout(acquire_timeout)
+                .idle_timeout(idle_timeout)
+                .connect_with(options)
+                .await?;
+
+            let tables: Vec<String> =
+                sqlx::query_scalar("SELECT name FROM sqlite_master WHERE type = 'table'")
+                    .fetch_all(&pool)
+                    .await?;
+            let has_table = |name: &str| tables.iter().any(|table| table == name);
+            // The search index of a database created before it existed starts empty
+            let has_fts = has_table("chat_messages_fts");
+
+            // a database created before migrations gets the columns added since, as the first
+            // migration would have created them; SQLite has no `ADD COLUMN IF NOT EXISTS`
+            if has_table("chat_messages") && !has_table("_sqlx_migrations") {
+                for (table, column, definition) in SQLITE_ADDED_COLUMNS {
+                    let exists: bool = sqlx::query_scalar(
+                        "SELECT EXISTS (SELECT 1 FROM pragma_table_info(?) WHERE name = ?)",
+                    )
+                    .bind(table)
+                    .bind(column)
+                    .fetch_one(&pool)
+                    .await?;
+                    if !exists {
+                        sqlx::query(&format!(
+                            "ALTER TABLE {table} ADD COLUMN {column} {definition}"
+                        ))
+                        .execute(&pool)
+                        .await?;
+                    }
+                }
+            }
+            SQLITE_MIGRATOR.run(&pool).await?;
+
+            if !has_fts {
+                sqlx::query("INSERT INTO chat_messages_fts (chat_messages_fts) VALUES ('rebuild')")
+                    .execute(&pool)
+                    .await?;
+            }
+            for statement in SESSION_METADATA_BACKFILL {
+                sqlx::query(statement).execute(&pool).await?;
+            }
+
+            DatabasePool::Sqlite(pool)
+        };
+
+        Ok(Self { pool })
     }
 
-    /// Returns conversation as ordered (user, bot) pairs for structured prompt construction
-    pub async fn get_session_pairs(&self, session_id: &str) -> Result<Vec<(String,String)>> {
-        if let Some(db) = &self.database {
-            let messages = db.get_session_history(session_id).await?;
-            Ok(messages.into_iter().map(|m| (m.user_message, m.bot_reply)).collect())
-        } else {
-            let history = self.memory_fallback.lock().await;
-            let Some(lines) = history.get(session_id) else { return Ok(vec![]); };
-            let mut pairs = Vec::new();
-            let mut i = 0;
-            while i + 1 < lines.len() { // expect User:, Bot: alternating
-                let user = lines[i].strip_prefix("User: ").unwrap_or(&lines[i]).to_string();
-                let bot = lines[i+1].strip_prefix("Bot: ").unwrap_or(&lines[i+1]).to_string();
-                pairs.push((user, bot));
-                i += 2;
+    /// Adapts a query written with `?` placeholders to the syntax of the backend
+    fn sql<'a>(&self, query: &'a str) -> Cow<'a, str> {
+        match self.pool {
+            DatabasePool::Sqlite(_) => Cow::Borrowed(query),
+            DatabasePool::Postgres(_) => {
+                let mut sql = String::with_capacity(query.len() + 8);
+                let mut index = 0;
+                for c in query.chars() {
+                    if c == '?' {
+                        index += 1;
+                        sql.push_str(&format!("${index}"));
+                    } else {
+                        sql.push(c);
+                    }
+                }
+                Cow::Owned(sql)
             }
-            Ok(pairs)
         }
     }
 
-    pub async fn delete_session(&self, session_id: &str) -> Result<()> {
-        if let Some(db) = &self.database {
-            db.delete_session_history(session_id).await?;
-        } else {
-            // Fallback to memory storage
-            let mut history = self.memory_fallback.lock().await;
-            history.remove(session_id);
+    /// Statement opening a transaction that writes.
+    ///
+    /// A deferred SQLite transaction starts as a reader, and when it upgrades to a writer after
+    /// another connection committed, SQLite fails it with "database is locked" right away instead
+    /// of waiting `busy_timeout`. Taking the write lock up front makes writers queue instead.
+    fn begin_write(&self) -> &'static str {
+        match self.pool {
+            DatabasePool::Sqlite(_) => "BEGIN IMMEDIATE",
+            DatabasePool::Postgres(_) => "BEGIN",
         }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for DatabaseManager {
+    /// Waits for the checked-out connections to be returned, then closes every connection.
+    ///
+    /// Queries issued afterwards fail. For SQLite, closing the last connection checkpoints the
+    /// write-ahead log into the database file.
+    async fn close(&self) {
+        with_pool!(self, pool => pool.close().await)
+    }
+
+    /// Checks that the database answers a `SELECT 1`
+    async fn ping(&self) -> Result<()> {
+        with_pool!(self, pool => {
+            sqlx::query("SELECT 1").execute(pool).await?;
+        });
 
         Ok(())
     }
 
-    pub async fn get_all_sessions(&self) -> Result<Vec<String>> {
-        if let Some(db) = &self.database {
-            db.get_all_sessions().await
-        } else {
-            // Fallback to memory storage
-            let history = self.memory_fallback.lock().await;
-            Ok(history.keys().cloned().collect())
+    /// Stores the system prompt of a session; `None` clears it
+    async fn set_system_prompt(&self, session_id: &str, system_prompt: Option<&str>) -> Result<()> {
+        let sql = self.sql(
+            r#"
+            INSERT INTO sessions (session_id, system_prompt)
+            VALUES (?, ?)
+            ON CONFLICT(session_id) DO UPDATE SET system_prompt = excluded.system_prompt
+            "#,
+        );
+        with_pool!(self, pool => {
+            sqlx::query(&sql)
+                .bind(session_id)
+                .bind(system_prompt)
+                .execute(pool)
+                .await?;
+        });
+
+        Ok(())
+    }
+
+    async fn get_system_prompt(&self, session_id: &str) -> Result<Option<String>> {
+        let sql = self.sql("SELECT system_prompt FROM sessions WHERE session_id = ?");
+        let system_prompt: Option<Option<String>> = with_pool!(self, pool => {
+            sqlx::query_scalar(&sql)
+                .bind(session_id)
+                .fetch_optional(pool)
+                .await?
+        });
+
+        Ok(system_prompt.flatten())
+    }
+
+    /// Stores a message and updates the metadata of its session in the same transaction.
+    ///
+    /// The first message of a session sets its title unless one was set before.
+    async fn save_message(&self, message: &ChatMessage) -> Result<()> {
+        self.save_messages_batch(std::slice::from_ref(message))
+            .await
+    }
+
+    /// Saves `messages` in order in a single transaction, so either all of them are stored or none
+    async fn save_messages_batch(&self, messages: &[ChatMessage]) -> Result<()> {
+        if messages.is_empty() {
+            return Ok(());
         }
+
+        let insert_sql = self.sql(
+            r#"
+            INSERT INTO chat_messages (session_id, user_message, bot_reply, timestamp, tool_results,
+                                       assistant_message, system_prompt, truncated, role,
+                                       latency_ms, seed, version, parent_id)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        );
+        // a later version of a turn takes its place in the count
+        let session_sql = self.sql(
+            r#"
+            INSERT INTO sessions (session_id, title, created_at, updated_at, message_count)
+            VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT(session_id) DO UPDATE SET
+                title = COALESCE(sessions.title, excluded.title),
+                created_at = COALESCE(sessions.created_at, excluded.created_at),
+                updated_at = excluded.updated_at,
+                message_count = sessions.message_count + excluded.message_count
+            "#,
+        );
+        with_pool!(self, pool => {
+            let mut tx = pool.begin_with(self.begin_write()).await?;
+            for message in messages {
+                sqlx::query(&insert_sql)
+                    .bind(&message.session_id)
+                    .bind(&message.user_message)
+                    .bind(&message.bot_reply)
+                    .bind(message.timestamp)
+                    .bind(&message.tool_results)
+                    .bind(&message.assistant_message)
+                    .bind(&message.system_prompt)
+                    .bind(message.truncated)
+                    .bind(message.role.as_str())
+                    .bind(message.latency_ms)
+                    .bind(message.seed)
+                    .bind(message.version)
+                    .bind(message.parent_id)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query(&session_sql)
+                    .bind(&message.session_id)
+                    .bind(message.session_title())
+                    .bind(message.timestamp)
+                    .bind(message.timestamp)
+                    .bind(if message.parent_id.is_some() { 0_i64 } else { 1 })
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await?;
+        });
+
+        Ok(())
+    }
+
+    /// Adds the token usage of one downstream request to the totals of a session
+    async fn add_session_usage(&self, session_id: &str, usage: &Usage) -> Result<()> {
+        let sql = self.sql(
+            r#"
+            INSERT INTO session_usage (session_id, prompt_tokens, completion_tokens, total_tokens,
+                                       requests)
+            VALUES (?, ?, ?, ?, 1)
+            ON CONFLICT(session_id) DO UPDATE SET
+                prompt_tokens = session_usage.prompt_tokens + excluded.prompt_tokens,
+                completion_tokens = session_usage.completion_tokens + excluded.completion_tokens,
+                total_tokens = session_usage.total_tokens + excluded.total_tokens,
+                requests = session_usage.requests + 1
+            "#,
+        );
+        with_pool!(self, pool => {
+            sqlx::query(&sql)
+                .bind(session_id)
+                .bind(usage.prompt_tokens as i64)
+                .bind(usage.completion_tokens as i64)
+                .bind(usage.total_tokens as i64)
+                .execute(pool)
+                .await?;
+        });
+
+        Ok(())
+    }
+
+    /// Returns the token usage totals of a session; `None` if none was recorded
+    async fn get_session_usage(&self, session_id: &str) -> Result<Option<SessionUsage>> {
+        let sql = self.sql(
+            r#"
+            SELECT session_id, prompt_tokens, completion_tokens, total_tokens, requests
+            FROM session_usage
+            WHERE session_id = ?
+            "#,
+        );
+        let usage = with_pool!(self, pool => {
+            sqlx::query_as::<_, SessionUsage>(&sql)
+                .bind(session_id)
+                .fetch_optional(pool)
+                .await?
+        });
+
+        Ok(usage)
+    }
+
+    /// Returns the running summary of a session, if its oldest turns were summarized
+    async fn get_session_summary(&self, session_id: &str) -> Result<Option<SessionSummary>> {
+        let sql = self.sql(
+            r#"
+            SELECT session_id, summary, summarized_until, updated_at
+            FROM session_summaries
+            WHERE session_id = ?
+            "#,
+        );
+        let summary = with_pool!(self, pool => {
+            sqlx::query_as::<_, SessionSummary>(&sql)
+                .bind(session_id)
+                .fetch_optional(pool)
+                .await?
+        });
+
+        Ok(summary)
+    }
+
+    /// Stores the running summary of a session, replacing the previous one
+    async fn set_session_summary(&self, summary: &SessionSummary) -> Result<()> {
+        let sql = self.sql(
+            r#"
+            INSERT INTO session_summaries (session_id, summary, summarized_until, updated_at)
+            VALUES (?, ?, ?, ?)
+            ON CONFLICT(session_id) DO UPDATE SET
+                summary = excluded.summary,
+                summarized_until = excluded.summarized_until,
+                updated_at = excluded.updated_at
+            "#,
+        );
+        with_pool!(self, pool => {
+            sqlx::query(&sql)
+                .bind(&summary.session_id)
+                .bind(&summary.summary)
+                .bind(summary.summarized_until)
+                .bind(summary.updated_at)
+                .execute(pool)
+                .await?;
+        });
+
+        Ok(())
     }
-}
 
+    /// Sets the title of a session; `None` lets the next saved message generate one
+    async fn set_session_title(&self, session_id: &str, title: Option<&str>) -> Result<()> {
+        let sql = self.sql(
+            r#"
+            INSERT INTO sessions (session_id, title)
+            VALUES (?, ?)
+            ON CONFLICT(session_id) DO UPDATE SET title = excluded.title
+            "#,
+        );
+        with_pool!(self, pool => {
+            sqlx::query(&sql)
+                .bind(session_id)
+                .bind(title)
+                .execute(pool)
+                .await?;
+        });
+
+        Ok(())
+    }
+
+    /// Returns the metadata of the sessions with stored messages matching `filter`, in its order
+    async fn list_sessions(&self, filter: &SessionFilter) -> Result<Vec<SessionMetadata>> {
+        let mut sql =
+            String::from("SELECT session_id, title, created_at, updated_at, message_count");
+        if filter.preview {
+            sql.push_str(concat!(
+                r#",
+                (
+                    SELECT bot_reply FROM chat_messages c
+                    WHERE c.session_id = sessions.session_id AND c.deleted_at IS NULL AND "#,
+                latest_version!("c"),
+                r#"
+                    ORDER BY c.timestamp DESC, c.id DESC
+                    LIMIT 1
+                ) AS preview"#,
+            ));
+        }
+        sql.push_str(" FROM sessions WHERE message_count > 0");
+        if filter.updated_after.is_some() {
+            sql.push_str(" AND updated_at >= ?");
+        }
+        if filter.updated_before.is_some() {
+            sql.push_str(" AND updated_at < ?");
+        }
+        if filter.prefix.is_some() {
+            sql.push_str(" AND substr(session_id, 1, ?) = ?");
+        }
+        sql.push_str(" ORDER BY ");
+        sql.push_str(filter.order_by());
+        let sql = self.sql(&sql);
+        let sessions = with_pool!(self, pool => {
+            let mut query = sqlx::query_as::<_, SessionMetadata>(&sql);
+            for bound in [filter.updated_after, filter.updated_before].into_iter().flatten() {
+                query = query.bind(bound);
+            }
+            if let Some(prefix) = &filter.prefix {
+                // substr counts characters
+                query = query.bind(prefix.chars().count() as i32).bind(prefix);
+            }
+            query.fetch_all(pool).await?
+        });
+
+        Ok(sessions
+            .into_iter()
+            .map(|metadata| SessionMetadata {
+                preview: metadata
+                    .preview
+                    .as_deref()
+                    .map(|reply| shorten(reply, SESSION_PREVIEW_MAX_CHARS)),
+                ..metadata
+            })
+            .collect())
+    }
+
+    async fn get_session_history(&self, session_id: &str) -> Result<Vec<ChatMessage>> {
+        let sql = self.sql(concat!(
+            r#"
+            SELECT id, session_id, user_message, bot_reply, timestamp, tool_results,
+                   assistant_message, system_prompt, truncated, role, latency_ms, seed, version,
+                   parent_id
+            FROM chat_messages
+            WHERE session_id = ? AND deleted_at IS NULL AND "#,
+            latest_version!("chat_messages"),
+            r#"
+            ORDER BY timestamp ASC
+            "#,
+        ));
+        let messages = with_pool!(self, pool => {
+            sqlx::query_as::<_, ChatMessage>(&sql)
+                .bind(session_id)
+                .fetch_all(pool)
+                .await?
+        });
+
+        Ok(messages)
+    }
+
+    /// Streams the messages of a session saved after `since`, oldest first, as they are read.
+    ///
+    /// Rows are fetched by a task that waits while the receiver is full, so a long history is
+    /// never held in memory at once. Dropping the stream stops the query.
+    fn stream_session_history(
+        &self,
+        session_id: &str,
+        since: Option<DateTime<Utc>>,
+    ) -> BoxStream<'static, Result<ChatMessage>> {
+        let mut sql = String::from(concat!(
+            r#"
+            SELECT id, session_id, user_message, bot_reply, timestamp, tool_results,
+                   assistant_message, system_prompt, truncated, role, latency_ms, seed, version,
+                   parent_id
+            FROM chat_messages
+            WHERE session_id = ? AND deleted_at IS NULL AND "#,
+            latest_version!("chat_messages"),
+            r#"
+            "#,
+        ));
+        if since.is_some() {
+            sql.push_str(" AND timestamp > ?");
+        }
+        sql.push_str(" ORDER BY timestamp ASC, id ASC");
+        let sql = self.sql(&sql).into_owned();
+
+        let (tx, mut rx) = mpsc::channel(HISTORY_STREAM_BUFFER);
+        let reader = Self {
+            pool: self.pool.clone(),
+        };
+        let session_id = session_id.to_string();
+        tokio::spawn(async move {
+            with_pool!(reader, pool => {
+                let mut query = sqlx::query_as::<_, ChatMessage>(&sql).bind(&session_id);
+                if let Some(since) = since {
+                    query = query.bind(since);
+                }
+                let mut rows = query.fetch(pool);
+                while let Some(row) = rows.next().await {
+                    // the receiver is gone once the client disconnects
+                    if tx.send(row.map_err(Into::into)).await.is_err() {
+                        break;
+                    }
+                }
+            })
+        });
+
+        futures_util::stream::poll_fn(move |cx| rx.poll_recv(cx)).boxed()
+    }
+
+    /// Returns one page of a session's messages, oldest first.
+    ///
+    /// Rows are ordered by `(timestamp, id)` and the offset counts from the oldest message, so
+    /// turns appended while a client is paging never shift the rows of earlier pages.
+    async fn get_session_history_paginated(
+        &self,
+        session_id: &str,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<ChatMessage>> {
+        let sql = self.sql(concat!(
+            r#"
+            SELECT id, session_id, user_message, bot_reply, timestamp, tool_results,
+                   assistant_message, system_prompt, truncated, role, latency_ms, seed, version,
+                   parent_id
+            FROM chat_messages
+            WHERE session_id = ? AND deleted_at IS NULL AND "#,
+            latest_version!("chat_messages"),
+            r#"
+            ORDER BY timestamp ASC, id ASC
+            LIMIT ? OFFSET ?
+            "#,
+        ));
+        let messages = with_pool!(self, pool => {
+            sqlx::query_as::<_, ChatMessage>(&sql)
+                .bind(session_id)
+                .bind(limit)
+                .bind(offset)
+                .fetch_all(pool)
+                .await?
+        });
+
+        Ok(messages)
+    }
+
+    async fn count_session_messages(&self, session_id: &str) -> Result<i64> {
+        let sql = self.sql(concat!(
+            "SELECT COUNT(*) FROM chat_messages WHERE session_id = ? AND deleted_at IS NULL AND ",
+            latest_version!("chat_messages"),
+        ));
+        let count = with_pool!(self, pool => {
+            sqlx::query_scalar(&sql)
+                .bind(session_id)
+                .fetch_one(pool)
+                .await?
+        });
+
+        Ok(count)
+    }
+
+    /// Marks the live messages of a session deleted; [`Self::restore_session`] brings them back.
+    ///
+    /// The session keeps its title and system prompt, but is not listed until it has live
+    /// messages again.
+    async fn delete_session_history(&self, session_id: &str) -> Result<()> {
+        let delete_sql = self.sql(SESSION_SOFT_DELETE);
+        let summary_sql = self.sql(SUMMARY_DELETE);
+        let recount_sql = format!("{SESSION_MESSAGE_RECOUNT} WHERE session_id = ?");
+        let recount_sql = self.sql(&recount_sql);
+        with_pool!(self, pool => {
+            let mut tx = pool.begin_with(self.begin_write()).await?;
+            sqlx::query(&delete_sql)
+                .bind(Utc::now())
+                .bind(session_id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query(&summary_sql)
+                .bind(session_id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query(&recount_sql)
+                .bind(session_id)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+        });
+
+        Ok(())
+    }
+
+    /// Restores the deleted messages of a session and returns the number restored
+    async fn restore_session(&self, session_id: &str) -> Result<u64> {
+        let restore_sql = self.sql(
+            r#"
+            UPDATE chat_messages SET deleted_at = NULL
+            WHERE session_id = ? AND deleted_at IS NOT NULL
+            "#,
+        );
+        let recount_sql = format!("{SESSION_MESSAGE_RECOUNT} WHERE session_id = ?");
+        let recount_sql = self.sql(&recount_sql);
+        let restored = with_pool!(self, pool => {
+            let mut tx = pool.begin_with(self.begin_write()).await?;
+            let restored = sqlx::query(&restore_sql)
+                .bind(session_id)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+            sqlx::query(&recount_sql)
+                .bind(session_id)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            restored
+        });
+
+        Ok(restored)
+    }
+
+    /// Permanently deletes the messages deleted before `cutoff` and returns the number removed
+    async fn purge_deleted_before(&self, cutoff: DateTime<Utc>) -> Result<u64> {
+        let sql = self.sql("DELETE FROM chat_messages WHERE deleted_at < ?");
+        let purged = with_pool!(self, pool => {
+            sqlx::query(&sql)
+                .bind(cutoff)
+                .execute(pool)
+                .await?
+                .rows_affected()
+        });
+
+        Ok(purged)
+    }
+
+    /// Permanently deletes the messages of a session, deleted or not, and resets its metadata.
+    ///
+    /// The system prompt is kept.
+    async fn erase_session_history(&self, session_id: &str) -> Result<()> {
+        let delete_sql = self.sql("DELETE FROM chat_messages WHERE session_id = ?");
+        let summary_sql = self.sql(SUMMARY_DELETE);
+        let session_sql = self.sql(SESSION_METADATA_RESET);
+        with_pool!(self, pool => {
+            let mut tx = pool.begin_with(self.begin_write()).await?;
+            sqlx::query(&delete_sql)
+                .bind(session_id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query(&summary_sql)
+                .bind(session_id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query(&session_sql)
+                .bind(session_id)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+        });
+
+        Ok(())
+    }
+
+    /// Deletes the sessions last updated before `cutoff` in one transaction and returns their ids.
+    ///
+    /// Sessions are soft-deleted as by [`Self::delete_session_history`], or erased as by
+    /// [`Self::erase_session_history`] if `hard`.
+    async fn delete_sessions_before(
+        &self,
+        cutoff: DateTime<Utc>,
+        hard: bool,
+    ) -> Result<Vec<String>> {
+        let select_sql = self.sql(
+            r#"
+            SELECT session_id FROM sessions
+            WHERE message_count > 0 AND updated_at < ?
+            ORDER BY updated_at, session_id
+            "#,
+        );
+        let delete_sql = match hard {
+            true => self.sql("DELETE FROM chat_messages WHERE session_id = ?"),
+            false => self.sql(SESSION_SOFT_DELETE),
+        };
+        let summary_sql = self.sql(SUMMARY_DELETE);
+        let recount_sql = format!("{SESSION_MESSAGE_RECOUNT} WHERE session_id = ?");
+        let session_sql = match hard {
+            true => self.sql(SESSION_METADATA_RESET),
+            false => self.sql(&recount_sql),
+        };
+        let now = Utc::now();
+        let deleted = with_pool!(self, pool => {
+            let mut tx = pool.begin_with(self.begin_write()).await?;
+            let session_ids: Vec<String> = sqlx::query_scalar(&select_sql)
+                .bind(cutoff)
+                .fetch_all(&mut *tx)
+                .await?;
+            for session_id in &session_ids {
+                let mut delete = sqlx::query(&delete_sql);
+                if !hard {
+                    delete = delete.bind(now);
+                }
+                delete.bind(session_id).execute(&mut *tx).await?;
+                sqlx::query(&summary_sql)
+                    .bind(session_id)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query(&session_sql)
+                    .bind(session_id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await?;
+            session_ids
+        });
+
+        Ok(deleted)
+    }
+
+    /// Deletes all but the newest `keep_last` messages of a session and returns the number removed.
+    ///
+    /// The rows to keep are picked by a subquery of the same `DELETE`, so the whole prune is one
+    /// statement: a message saved concurrently is either fully kept or never seen by it. Earlier
+    /// versions go with their turn; they count among the removed rows.
+    async fn prune_session(&self, session_id: &str, keep_last: i64) -> Result<u64> {
+        let sql = self.sql(concat!(
+            r#"
+            DELETE FROM chat_messages
+            WHERE session_id = ? AND deleted_at IS NULL
+              AND COALESCE(parent_id, id) NOT IN (
+                  SELECT COALESCE(parent_id, id) FROM chat_messages
+                  WHERE session_id = ? AND deleted_at IS NULL AND "#,
+            latest_version!("chat_messages"),
+            r#"
+                  ORDER BY timestamp DESC, id DESC
+                  LIMIT ?
+              )
+            "#,
+        ));
+        let recount_sql = format!("{SESSION_MESSAGE_RECOUNT} WHERE session_id = ?");
+        let recount_sql = self.sql(&recount_sql);
+        let deleted = with_pool!(self, pool => {
+            let mut tx = pool.begin_with(self.begin_write()).await?;
+            let deleted = sqlx::query(&sql)
+                .bind(session_id)
+                .bind(session_id)
+                .bind(keep_last)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+            sqlx::query(&recount_sql)
+                .bind(session_id)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            deleted
+        });
+
+        Ok(deleted)
+    }
+
+    /// Returns the live turn `id` of a session; `None` once a later version supersedes it
+    async fn get_message_by_id(&self, session_id: &str, id: i64) -> Result<Option<ChatMessage>> {
+        let sql = self.sql(concat!(
+            r#"
+            SELECT id, session_id, user_message, bot_reply, timestamp, tool_results,
+                   assistant_message, system_prompt, truncated, role, latency_ms, seed, version,
+                   parent_id
+            FROM chat_messages
+            WHERE session_id = ? AND id = ? AND deleted_at IS NULL AND "#,
+            latest_version!("chat_messages"),
+            r#"
+            "#,
+        ));
+        let message = with_pool!(self, pool => {
+            sqlx::query_as::<_, ChatMessage>(&sql)
+                .bind(session_id)
+                .bind(id)
+                .fetch_optional(pool)
+                .await?
+        });
+
+        Ok(message)
+    }
+
+    /// Deletes the turn `id` of a session for good, with its earlier versions; `false` if the
+    /// session has no such turn
+    async fn delete_message_by_id(&self, session_id: &str, id: i64) -> Result<bool> {
+        let sql = self.sql(concat!(
+            r#"
+            DELETE FROM chat_messages
+            WHERE session_id = ? AND deleted_at IS NULL
+              AND COALESCE(parent_id, id) = (
+                  SELECT COALESCE(parent_id, id) FROM chat_messages
+                  WHERE session_id = ? AND id = ? AND deleted_at IS NULL AND "#,
+            latest_version!("chat_messages"),
+            r#"
+              )
+            "#,
+        ));
+        let summary_sql = self.sql(SUMMARY_INVALIDATE);
+        let recount_sql = format!("{SESSION_MESSAGE_RECOUNT} WHERE session_id = ?");
+        let recount_sql = self.sql(&recount_sql);
+        let deleted = with_pool!(self, pool => {
+            let mut tx = pool.begin_with(self.begin_write()).await?;
+            let deleted = sqlx::query(&sql)
+                .bind(session_id)
+                .bind(session_id)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+            if deleted > 0 {
+                sqlx::query(&summary_sql)
+                    .bind(session_id)
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            sqlx::query(&recount_sql)
+                .bind(session_id)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            deleted
+        });
+
+        Ok(deleted > 0)
+    }
+
+    /// Deletes every turn after turn `id` of a session, and the turn itself if `inclusive`, and
+    /// returns the number removed.
+    ///
+    /// Turns are ordered by timestamp then id, as in the history, and removed by a single
+    /// `DELETE`. Earlier versions of turn `id` are older than it and stay. Nothing is removed if
+    /// the session has no turn `id`.
+    async fn truncate_after_id(&self, session_id: &str, id: i64, inclusive: bool) -> Result<u64> {
+        let sql = self.sql(
+            r#"
+            DELETE FROM chat_messages
+            WHERE session_id = ? AND deleted_at IS NULL
+              AND EXISTS (
+                  SELECT 1 FROM chat_messages m
+                  WHERE m.session_id = chat_messages.session_id AND m.id = ?
+                    AND m.deleted_at IS NULL
+                    AND (chat_messages.timestamp > m.timestamp
+                         OR (chat_messages.timestamp = m.timestamp AND chat_messages.id > m.id)
+                         OR (? AND chat_messages.id = m.id))
+              )
+            "#,
+        );
+        let summary_sql = self.sql(SUMMARY_INVALIDATE);
+        let recount_sql = format!("{SESSION_MESSAGE_RECOUNT} WHERE session_id = ?");
+        let recount_sql = self.sql(&recount_sql);
+        let deleted = with_pool!(self, pool => {
+            let mut tx = pool.begin_with(self.begin_write()).await?;
+            let deleted = sqlx::query(&sql)
+                .bind(session_id)
+                .bind(id)
+                .bind(inclusive)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+            // a turn kept is about to get a new version, which its summary would not cover
+            if deleted > 0 || !inclusive {
+                sqlx::query(&summary_sql)
+                    .bind(session_id)
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            sqlx::query(&recount_sql)
+                .bind(session_id)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            deleted
+        });
+
+        Ok(deleted)
+    }
+
+    fn keeps_versions(&self) -> bool {
+        true
+    }
+
+    /// Returns every version of the turn `id` of a session, oldest first; `id` may be any of
+    /// them. Empty if the session has no such message.
+    async fn get_message_versions(&self, session_id: &str, id: i64) -> Result<Vec<ChatMessage>> {
+        let sql = self.sql(
+            r#"
+            SELECT id, session_id, user_message, bot_reply, timestamp, tool_results,
+                   assistant_message, system_prompt, truncated, role, latency_ms, seed, version,
+                   parent_id
+            FROM chat_messages
+            WHERE session_id = ? AND deleted_at IS NULL
+              AND COALESCE(parent_id, id) = (
+                  SELECT COALESCE(parent_id, id) FROM chat_messages
+                  WHERE session_id = ? AND id = ? AND deleted_at IS NULL
+              )
+            ORDER BY version ASC
+            "#,
+        );
+        let versions = with_pool!(self, pool => {
+            sqlx::query_as::<_, ChatMessage>(&sql)
+                .bind(session_id)
+                .bind(session_id)
+                .bind(id)
+                .fetch_all(pool)
+                .await?
+        });
+
+        Ok(versions)
+    }
+
+    /// Copies the live turns of `src`, up to and including turn `until_id` if given, into the new
+    /// session `dst` in one transaction, and returns the number copied.
+    ///
+    /// Only the latest version of each turn is copied, as an original. The copies get new ids and
+    /// keep their timestamps, tool calls and tool results; `dst` gets the title and system prompt
+    /// of `src` but no summary or token usage. `None` if `src` has no turn `until_id` or no turns
+    /// at all. Fails if `dst` already has messages or metadata.
+    async fn fork_session(
+        &self,
+        src: &str,
+        dst: &str,
+        until_id: Option<i64>,
+    ) -> Result<Option<u64>> {
+        let exists_sql = self.sql(
+            r#"
+            SELECT (SELECT COUNT(*) FROM chat_messages WHERE session_id = ?)
+                 + (SELECT COUNT(*) FROM sessions WHERE session_id = ?)
+            "#,
+        );
+        let mut copy_sql = String::from(concat!(
+            r#"
+            INSERT INTO chat_messages (session_id, user_message, bot_reply, timestamp, tool_results,
+                                       assistant_message, system_prompt, truncated, role,
+                                       latency_ms, seed)
+            SELECT ?, c.user_message, c.bot_reply, c.timestamp, c.tool_results, c.assistant_message,
+                   c.system_prompt, c.truncated, c.role, c.latency_ms, c.seed
+            FROM chat_messages c
+            WHERE c.session_id = ? AND c.deleted_at IS NULL AND "#,
+            latest_version!("c"),
+            r#"
+            "#,
+        ));
+        if until_id.is_some() {
+            copy_sql.push_str(
+                r#"
+              AND EXISTS (
+                  SELECT 1 FROM chat_messages m
+                  WHERE m.session_id = c.session_id AND m.id = ? AND m.deleted_at IS NULL
+                    AND (c.timestamp < m.timestamp OR (c.timestamp = m.timestamp AND c.id <= m.id))
+              )
+                "#,
+            );
+        }
+        copy_sql.push_str("ORDER BY c.timestamp ASC, c.id ASC");
+        let copy_sql = self.sql(&copy_sql);
+        let session_sql = self.sql(
+            r#"
+            INSERT INTO sessions (session_id, system_prompt, title, created_at, updated_at,
+                                  message_count)
+            SELECT ?, system_prompt, title,
+                (SELECT MIN(timestamp) FROM chat_messages WHERE session_id = ?),
+                (SELECT MAX(timestamp) FROM chat_messages WHERE session_id = ?),
+                ?
+            FROM sessions
+            WHERE session_id = ?
+            "#,
+        );
+        let copied = with_pool!(self, pool => {
+            let mut tx = pool.begin_with(self.begin_write()).await?;
+            let existing: i64 = sqlx::query_scalar(&exists_sql)
+                .bind(dst)
+                .bind(dst)
+                .fetch_one(&mut *tx)
+                .await?;
+            if existing > 0 {
+                anyhow::bail!("session {dst} already exists");
+            }
+
+            let mut copy = sqlx::query(&copy_sql).bind(dst).bind(src);
+            if let Some(id) = until_id {
+                copy = copy.bind(id);
+            }
+            let copied = copy.execute(&mut *tx).await?.rows_affected();
+            if copied == 0 {
+                return Ok(None);
+            }
+            sqlx::query(&session_sql)
+                .bind(dst)
+                .bind(dst)
+                .bind(dst)
+                .bind(copied as i64)
+                .bind(src)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            copied
+        });
+
+        Ok(Some(copied))
+    }
+
+    /// Deletes every message older than `cutoff` and returns the number removed
+    async fn prune_messages_before(&self, cutoff: DateTime<Utc>) -> Result<u64> {
+        let sql = self.sql("DELETE FROM chat_messages WHERE timestamp < ?");
+        let deleted = with_pool!(self, pool => {
+            let mut tx = pool.begin_with(self.begin_write()).await?;
+            let deleted = sqlx::query(&sql)
+                .bind(cutoff)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+            sqlx::query(SESSION_MESSAGE_RECOUNT)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            deleted
+        });
+
+        Ok(deleted)
+    }
+
+    /// Rewrites the database without the space of deleted rows. `VACUUM` cannot run in a
+    /// transaction, so SQLite waits for the writers in progress to finish.
+    async fn vacuum(&self) -> Result<()> {
+        with_pool!(self, pool => {
+            sqlx::raw_sql("VACUUM").execute(pool).await?;
+        });
+
+        Ok(())
+    }
+
+    async fn recount_sessions(&self) -> Result<u64> {
+        let recounted = with_pool!(self, pool => {
+            sqlx::query(SESSION_MESSAGE_RECOUNT)
+                .execute(pool)
+                .await?
+                .rows_affected()
+        });
+
+        Ok(recounted)
+    }
+
+    /// Returns the messages whose user message or reply contains every word of `query`, oldest
+    /// first.
+    ///
+    /// SQLite matches against the `chat_messages_fts` index and Postgres uses `to_tsvector`. Each
+    /// word is matched as a literal term, so FTS operators in `query` have no effect.
+    async fn search_messages(
+        &self,
+        query: &str,
+        session_id: Option<&str>,
+    ) -> Result<Vec<ChatMessage>> {
+        let session_filter = if session_id.is_some() {
+            "AND m.session_id = ?"
+        } else {
+            ""
+        };
+        let sql = match self.pool {
+            DatabasePool::Sqlite(_) => format!(
+                concat!(
+                    r#"
+                SELECT m.id, m.session_id, m.user_message, m.bot_reply, m.timestamp, m.tool_results,
+                       m.assistant_message, m.system_prompt, m.truncated, m.role, m.latency_ms,
+                       m.seed, m.version, m.parent_id
+                FROM chat_messages_fts
+                JOIN chat_messages m ON m.id = chat_messages_fts.rowid
+                WHERE chat_messages_fts MATCH ? AND m.deleted_at IS NULL AND "#,
+                    latest_version!("m"),
+                    r#" {session_filter}
+                ORDER BY m.timestamp ASC, m.id ASC
+                "#
+                ),
+                session_filter = session_filter,
+            ),
+            DatabasePool::Postgres(_) => format!(
+                concat!(
+                    r#"
+                SELECT m.id, m.session_id, m.user_message, m.bot_reply, m.timestamp, m.tool_results,
+                       m.assistant_message, m.system_prompt, m.truncated, m.role, m.latency_ms,
+                       m.seed, m.version, m.parent_id
+                FROM chat_messages m
+                WHERE to_tsvector('simple', m.user_message || ' ' || m.bot_reply)
+                      @@ plainto_tsquery('simple', ?) AND m.deleted_at IS NULL AND "#,
+                    latest_version!("m"),
+                    r#" {session_filter}
+                ORDER BY m.timestamp ASC, m.id ASC
+                "#
+                ),
+                session_filter = session_filter,
+            ),
+        };
+        let sql = self.sql(&sql);
+
+        let query = match self.pool {
+            // quote every word so it is matched as a term instead of parsed as FTS5 syntax
+            DatabasePool::Sqlite(_) => query
+                .split_whitespace()
+                .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+                .collect::<Vec<_>>()
+                .join(" "),
+            DatabasePool::Postgres(_) => query.to_string(),
+        };
+        let messages = with_pool!(self, pool => {
+            let mut search = sqlx::query_as::<_, ChatMessage>(&sql).bind(&query);
+            if let Some(session_id) = session_id {
+                search = search.bind(session_id);
+            }
+            search.fetch_all(pool).await?
+        });
+
+        Ok(messages)
+    }
+
+    /// Appends a downstream request to the request log
+    async fn insert_request_log(&self, entry: &RequestLogEntry) -> Result<()> {
+        let sql = self.sql(
+            r#"
+            INSERT INTO request_log (session_id, model, server_url, request, status, latency_ms,
+                                     timestamp)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            "#,
+        );
+        with_pool!(self, pool => {
+            sqlx::query(&sql)
+                .bind(&entry.session_id)
+                .bind(&entry.model)
+                .bind(&entry.server_url)
+                .bind(&entry.request)
+                .bind(entry.status)
+                .bind(entry.latency_ms)
+                .bind(entry.timestamp)
+                .execute(pool)
+                .await?;
+        });
+
+        Ok(())
+    }
+
+    /// Returns the oldest `limit` logged requests, of one session and from `since` on if given
+    async fn list_request_logs(
+        &self,
+        session_id: Option<&str>,
+        since: Option<DateTime<Utc>>,
+        limit: i64,
+    ) -> Result<Vec<RequestLogEntry>> {
+        let mut sql = String::from(
+            "SELECT id, session_id, model, server_url, request, status, latency_ms, timestamp \
+             FROM request_log WHERE 1 = 1",
+        );
+        if session_id.is_some() {
+            sql.push_str(" AND session_id = ?");
+        }
+        if since.is_some() {
+            sql.push_str(" AND timestamp >= ?");
+        }
+        sql.push_str(" ORDER BY timestamp ASC, id ASC LIMIT ?");
+        let sql = self.sql(&sql);
+        let entries = with_pool!(self, pool => {
+            let mut query = sqlx::query_as::<_, RequestLogEntry>(&sql);
+            if let Some(session_id) = session_id {
+                query = query.bind(session_id);
+            }
+            if let Some(since) = since {
+                query = query.bind(since);
+            }
+            query.bind(limit).fetch_all(pool).await?
+        });
+
+        Ok(entries)
+    }
+}
+
+/// Tokens used by the downstream requests of a session, as reported by the chat servers
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, sqlx::FromRow)]
+pub struct SessionUsage {
+    pub session_id: String,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub total_tokens: i64,
+    /// Number of requests whose usage was recorded
+    pub requests: i64,
+}
+impl SessionUsage {
+    fn add(&mut self, usage: &Usage) {
+        self.prompt_tokens += usage.prompt_tokens as i64;
+        self.completion_tokens += usage.completion_tokens as i64;
+        self.total_tokens += usage.total_tokens as i64;
+        self.requests += 1;
+    }
+}
+
+/// A stored turn matching a search
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchMatch {
+    pub session_id: String,
+    pub user_message: String,
+    pub bot_reply: String,
+    /// When the turn was saved; `None` for the in-memory fallback, which keeps no timestamps
+    #[serde(serialize_with = "rfc3339::serialize_option")]
+    pub timestamp: Option<DateTime<Utc>>,
+}
+
+/// Format of a session exported by [`ChatStorage::export_session`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// JSON array of the stored [`ChatMessage`]s
+    #[default]
+    Json,
+    /// Markdown transcript
+    Markdown,
+}
+impl ExportFormat {
+    /// File extension of an export in this format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Markdown => "md",
+        }
+    }
+}
+
+/// Splits the lines of an in-memory session into `(user message, reply)` turns, in order, each
+/// with the range of lines it was read from.
+///
+/// Lines are told apart by their `User: `/`Bot: ` prefix rather than their position, so a run of
+/// replies or a user message without one still keeps every message in its own role, the same way
+/// [`message_pairs`] pairs stored messages. An unprefixed line is taken as a user message.
+fn memory_turns(lines: &[String]) -> Vec<(Range<usize>, (String, String))> {
+    let mut turns: Vec<(Range<usize>, (String, String))> = Vec::new();
+    // whether the last turn is a user message still waiting for its reply
+    let mut awaiting_reply = false;
+    for (i, line) in lines.iter().enumerate() {
+        match line.strip_prefix("Bot: ") {
+            Some(reply) if awaiting_reply => {
+                let (range, (_, bot)) = turns.last_mut().unwrap();
+                range.end = i + 1;
+                *bot = reply.to_string();
+                awaiting_reply = false;
+            }
+            Some(reply) => turns.push((i..i + 1, (String::new(), reply.to_string()))),
+            None => {
+                let user = line.strip_prefix("User: ").unwrap_or(line);
+                turns.push((i..i + 1, (user.to_string(), String::new())));
+                awaiting_reply = true;
+            }
+        }
+    }
+    turns
+}
+
+/// Lines of the in-memory turn `id`, the 1-based position of the turn
+fn memory_turn_lines(lines: &[String], id: i64) -> Option<Range<usize>> {
+    let index = usize::try_from(id).ok()?.checked_sub(1)?;
+    memory_turns(lines)
+        .into_iter()
+        .nth(index)
+        .map(|(range, _)| range)
+}
+
+/// Renders the turns of a session as a Markdown transcript, with the system prompt before the
+/// first turn answered with it. Single messages are headed by their role.
+fn render_markdown_transcript(session_id: &str, messages: &[ChatMessage]) -> String {
+    let mut transcript = format!("# Session {session_id}\n");
+    let mut system_prompt: Option<&str> = None;
+    for message in messages {
+        if let Some(prompt) = message
+            .system_prompt
+            .as_deref()
+            .filter(|prompt| Some(*prompt) != system_prompt)
+        {
+            transcript.push_str(&format!("\n**System:**\n\n{}\n", prompt.trim_end()));
+            system_prompt = Some(prompt);
+        }
+        let timestamp = message.timestamp.format("%Y-%m-%d %H:%M:%S UTC");
+        let (heading, text) = match message.role {
+            MessageRole::Turn => {
+                transcript.push_str(&format!(
+                    "\n**User:** _{timestamp}_\n\n{}\n\n**Assistant:**\n\n{}\n",
+                    message.user_message.trim_end(),
+                    message.bot_reply.trim_end(),
+                ));
+                continue;
+            }
+            MessageRole::User => ("User", message.user_message.as_str()),
+            MessageRole::Assistant => ("Assistant", message.bot_reply.as_str()),
+            MessageRole::System => ("System", message.bot_reply.as_str()),
+            MessageRole::Tool => ("Tool", message.tool_results.as_deref().unwrap_or_default()),
+        };
+        transcript.push_str(&format!(
+            "\n**{heading}:** _{timestamp}_\n\n{}\n",
+            text.trim_end()
+        ));
+    }
+    transcript
+}
+
+// In-memory fallback for when database is not available
+pub type ChatHistory = Arc<Mutex<HashMap<String, Vec<String>>>>;
+
+pub struct ChatStorage {
+    database: Option<Box<dyn StorageBackend>>,
+    memory_fallback: ChatHistory,
+    // Per-session system prompts for the in-memory fallback
+    memory_system_prompts: Arc<Mutex<HashMap<String, String>>>,
+    // Per-session metadata for the in-memory fallback
+    memory_sessions: Arc<Mutex<HashMap<String, SessionMetadata>>>,
+    // Titles set before the first message of a session, for the in-memory fallback
+    memory_titles: Arc<Mutex<HashMap<String, String>>>,
+    // Soft-deleted sessions of the in-memory fallback
+    memory_deleted: Arc<Mutex<HashMap<String, DeletedSession>>>,
+    // Per-session token usage for the in-memory fallback
+    memory_usage: Arc<Mutex<HashMap<String, SessionUsage>>>,
+    // Order in which the sessions of the in-memory fallback were last used
+    memory_recency: Arc<Mutex<MemoryRecency>>,
+    // Sessions and messages kept in memory at most; 0 for no limit
+    memory_max_sessions: usize,
+    memory_max_messages: usize,
+    // Turns waiting to be written to the database in one batch
+    pending: Arc<Mutex<Vec<ChatMessage>>>,
+    // Number of buffered turns that triggers a write
+    batch_size: usize,
+}
+
+/// A soft-deleted session of the in-memory fallback
+struct DeletedSession {
+    lines: Vec<String>,
+    metadata: Option<SessionMetadata>,
+    deleted_at: DateTime<Utc>,
+}
+
+/// Appends `message` to the lines of a session of the in-memory fallback.
+///
+/// Single messages are kept as turns with an empty half; a single reply completes the turn of a
+/// single user message before it. System notices and tool results are not kept.
+fn push_memory_lines(lines: &mut Vec<String>, message: &ChatMessage) {
+    match message.role {
+        MessageRole::Turn => {
+            lines.push(format!("User: {}", message.user_message));
+            lines.push(format!("Bot: {}", message.bot_reply));
+        }
+        MessageRole::User => {
+            lines.push(format!("User: {}", message.user_message));
+            lines.push("Bot: ".to_string());
+        }
+        MessageRole::Assistant => match lines.last_mut() {
+            Some(last) if last == "Bot: " => *last = format!("Bot: {}", message.bot_reply),
+            _ => {
+                lines.push("User: ".to_string());
+                lines.push(format!("Bot: {}", message.bot_reply));
+            }
+        },
+        MessageRole::System | MessageRole::Tool => {}
+    }
+}
+
+/// Recency order of the sessions of the in-memory fallback
+#[derive(Default)]
+struct MemoryRecency {
+    // tick of the last use of each session
+    by_session: HashMap<String, u64>,
+    // sessions by the tick of their last use, least recently used first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl MemoryRecency {
+    fn touch(&mut self, session_id: &str) {
+        self.tick += 1;
+        if let Some(previous) = self.by_session.insert(session_id.to_string(), self.tick) {
+            self.order.remove(&previous);
+        }
+        self.order.insert(self.tick, session_id.to_string());
+    }
+
+    fn remove(&mut self, session_id: &str) {
+        if let Some(tick) = self.by_session.remove(session_id) {
+            self.order.remove(&tick);
+        }
+    }
+
+    /// Removes and returns the least recently used session
+    fn pop_oldest(&mut self) -> Option<String> {
+        let (_, session_id) = self.order.pop_first()?;
+        self.by_session.remove(&session_id);
+        Some(session_id)
+    }
+}
+
+impl ChatStorage {
+    /// Storage keeping the chat history in memory, within the limits of `config`
+    pub fn new_memory_only(config: &StorageConfig) -> Self {
+        Self::with_database(None)
+            .with_memory_limits(config.memory_max_sessions, config.memory_max_messages)
+    }
+
+    pub async fn new_with_database(database_url: &str, config: &DatabaseConfig) -> Result<Self> {
+        let database = DatabaseManager::new(database_url, config).await?;
+        Ok(Self::with_backend(Box::new(database)))
+    }
+
+    /// Storage persisting the chat history to `backend`
+    pub fn with_backend(backend: Box<dyn StorageBackend>) -> Self {
+        Self::with_database(Some(backend))
+    }
+
+    fn with_database(database: Option<Box<dyn StorageBackend>>) -> Self {
+        Self {
+            database,
+            memory_fallback: Arc::new(Mutex::new(HashMap::new())),
+            memory_system_prompts: Arc::new(Mutex::new(HashMap::new())),
+            memory_sessions: Arc::new(Mutex::new(HashMap::new())),
+            memory_titles: Arc::new(Mutex::new(HashMap::new())),
+            memory_deleted: Arc::new(Mutex::new(HashMap::new())),
+            memory_usage: Arc::new(Mutex::new(HashMap::new())),
+            memory_recency: Arc::new(Mutex::new(MemoryRecency::default())),
+            memory_max_sessions: 0,
+            memory_max_messages: 0,
+            pending: Arc::new(Mutex::new(Vec::new())),
+            batch_size: 1,
+        }
+    }
+
+    /// Caps the sessions and the messages, user messages and replies, kept in memory; 0 for no
+    /// limit.
+    ///
+    /// Past either limit the least recently used sessions are evicted. A single session holding
+    /// more than `max_messages` loses its oldest turns instead.
+    pub fn with_memory_limits(mut self, max_sessions: usize, max_messages: usize) -> Self {
+        self.memory_max_sessions = max_sessions;
+        self.memory_max_messages = max_messages;
+        self
+    }
+
+    /// Marks `session_id` as used and evicts the least recently used sessions of the in-memory
+    /// fallback until it is back within its limits
+    async fn evict_from_memory(
+        &self,
+        history: &mut HashMap<String, Vec<String>>,
+        session_id: &str,
+    ) {
+        let mut recency = self.memory_recency.lock().await;
+        recency.touch(session_id);
+
+        let over_sessions = |history: &HashMap<String, Vec<String>>| {
+            self.memory_max_sessions > 0 && history.len() > self.memory_max_sessions
+        };
+        let mut messages: usize = history.values().map(Vec::len).sum();
+        let over_messages =
+            |messages: usize| self.memory_max_messages > 0 && messages > self.memory_max_messages;
+
+        let mut evicted = Vec::new();
+        while over_sessions(history) || over_messages(messages) {
+            let Some(oldest) = recency.pop_oldest() else {
+                break;
+            };
+            if oldest == session_id {
+                // the only session left: drop its oldest turns
+                recency.touch(session_id);
+                if let Some(lines) = history.get_mut(session_id) {
+                    let excess = (messages - self.memory_max_messages).div_ceil(2) * 2;
+                    lines.drain(..excess.min(lines.len()));
+                }
+                break;
+            }
+            if let Some(lines) = history.remove(&oldest) {
+                messages -= lines.len();
+                evicted.push(oldest);
+            }
+        }
+        drop(recency);
+
+        if evicted.is_empty() {
+            return;
+        }
+        dual_warn!(
+            "Evicted {} sessions from the in-memory history",
+            evicted.len()
+        );
+        let mut sessions = self.memory_sessions.lock().await;
+        let mut titles = self.memory_titles.lock().await;
+        let mut prompts = self.memory_system_prompts.lock().await;
+        let mut usage = self.memory_usage.lock().await;
+        for session_id in &evicted {
+            sessions.remove(session_id);
+            titles.remove(session_id);
+            prompts.remove(session_id);
+            usage.remove(session_id);
+        }
+    }
+
+    /// Buffers up to `batch_size` turns before writing them to the database in one transaction.
+    ///
+    /// The default of 1 writes every turn as soon as it is saved. Buffered turns are also written
+    /// by `flush_pending`, which every read of the database calls first.
+    pub fn with_write_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Writes the buffered turns to the database and returns the number of turns written.
+    ///
+    /// If the write fails the turns stay buffered for the next flush and the error is returned.
+    pub async fn flush_pending(&self) -> Result<usize> {
+        let Some(db) = &self.database else {
+            return Ok(0);
+        };
+
+        // held during the write, so a concurrent read waits until the turns are stored
+        let mut pending = self.pending.lock().await;
+        if pending.is_empty() {
+            return Ok(0);
+        }
+
+        match db.save_messages_batch(&pending).await {
+            Ok(()) => Ok(std::mem::take(&mut *pending).len()),
+            Err(e) => {
+                dual_warn!(
+                    "Failed to save {} buffered turns, keeping them for the next flush: {e}",
+                    pending.len()
+                );
+                Err(e)
+            }
+        }
+    }
+
+    /// Writes the buffered turns and closes the database; returns the number of turns written.
+    ///
+    /// Called once on shutdown, after the last request is done. If the turns cannot be written
+    /// the error tells how many are lost when the process exits.
+    pub async fn close(&self) -> Result<usize> {
+        let flushed = match self.flush_pending().await {
+            Ok(flushed) => Ok(flushed),
+            Err(e) => {
+                let unwritten = self.pending.lock().await.len();
+                Err(anyhow!(
+                    "{unwritten} buffered turn(s) were not written: {e}"
+                ))
+            }
+        };
+        if let Some(db) = &self.database {
+            db.close().await;
+        }
+        flushed
+    }
+
+    /// Checks that the database answers; always succeeds without a database
+    pub async fn ping(&self) -> Result<()> {
+        match &self.database {
+            Some(db) => db.ping().await,
+            None => Ok(()),
+        }
+    }
+
+    /// The database, once the buffered turns are written to it
+    async fn database(&self) -> Result<Option<&dyn StorageBackend>> {
+        self.flush_pending().await?;
+        Ok(self.database.as_deref())
+    }
+
+    pub async fn set_system_prompt(
+        &self,
+        session_id: &str,
+        system_prompt: Option<&str>,
+    ) -> Result<()> {
+        if let Some(db) = &self.database {
+            db.set_system_prompt(session_id, system_prompt).await?;
+        } else {
+            let mut prompts = self.memory_system_prompts.lock().await;
+            match system_prompt {
+                Some(prompt) => {
+                    prompts.insert(session_id.to_string(), prompt.to_string());
+                }
+                None => {
+                    prompts.remove(session_id);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn get_system_prompt(&self, session_id: &str) -> Result<Option<String>> {
+        if let Some(db) = &self.database {
+            db.get_system_prompt(session_id).await
+        } else {
+            let prompts = self.memory_system_prompts.lock().await;
+            Ok(prompts.get(session_id).cloned())
+        }
+    }
+
+    /// Saves a single message of `role`, e.g. a system notice; see [`ChatMessage::single`]
+    pub async fn save_single_message(
+        &self,
+        session_id: &str,
+        role: MessageRole,
+        content: &str,
+    ) -> Result<()> {
+        match self.database().await? {
+            Some(db) => db.save_single_message(session_id, role, content).await,
+            None => {
+                self.save_turn(ChatMessage::single(session_id, role, content))
+                    .await
+            }
+        }
+    }
+
+    /// Saves a turn, or a single message, along with its tool results and tool calls.
+    ///
+    /// The in-memory fallback only keeps the user message and the reply text.
+    pub async fn save_turn(&self, message: ChatMessage) -> Result<()> {
+        if self.database.is_some() {
+            let buffered = {
+                let mut pending = self.pending.lock().await;
+                pending.push(message);
+                pending.len()
+            };
+            if buffered >= self.batch_size {
+                self.flush_pending().await?;
+            }
+        } else {
+            // Fallback to memory storage
+            if matches!(message.role, MessageRole::System | MessageRole::Tool) {
+                return Ok(());
+            }
+            let session_id = message.session_id.as_str();
+            let mut history = self.memory_fallback.lock().await;
+            push_memory_lines(history.entry(session_id.to_string()).or_default(), &message);
+
+            let mut sessions = self.memory_sessions.lock().await;
+            let metadata =
+                sessions
+                    .entry(session_id.to_string())
+                    .or_insert_with(|| SessionMetadata {
+                        session_id: session_id.to_string(),
+                        title: None,
+                        created_at: message.timestamp,
+                        updated_at: message.timestamp,
+                        message_count: 0,
+                        preview: None,
+                    });
+            if metadata.title.is_none() {
+                metadata.title = self
+                    .memory_titles
+                    .lock()
+                    .await
+                    .remove(session_id)
+                    .or_else(|| message.session_title());
+            }
+            metadata.updated_at = message.timestamp;
+            metadata.message_count += 1;
+            drop(sessions);
+
+            self.evict_from_memory(&mut history, session_id).await;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the turns held in memory to the database and returns the number of turns written.
+    ///
+    /// Each session is removed from memory once all its turns are written, so a second flush, or
+    /// one retried after a failure, never stores a turn twice. The in-memory fallback keeps no
+    /// timestamps, so flushed turns are stamped with the time of the flush.
+    pub async fn flush_memory_to_database(&self) -> Result<usize> {
+        let Some(db) = self.database().await? else {
+            return Ok(0);
+        };
+
+        // held for the whole flush so concurrent flushes cannot write the same turns
+        let mut history = self.memory_fallback.lock().await;
+        let session_ids: Vec<String> = history.keys().cloned().collect();
+
+        let mut flushed = 0;
+        for session_id in session_ids {
+            for (range, (user, bot)) in memory_turns(&history[&session_id]) {
+                let message = ChatMessage::new(&session_id, &user, &bot);
+                if let Err(e) = db.save_message(&message).await {
+                    // drop the turns already written so a retry starts after them
+                    history.get_mut(&session_id).unwrap().drain(..range.start);
+                    return Err(e);
+                }
+                flushed += 1;
+            }
+
+            history.remove(&session_id);
+            self.memory_sessions.lock().await.remove(&session_id);
+            self.memory_recency.lock().await.remove(&session_id);
+        }
+
+        Ok(flushed)
+    }
+
+    /// Sets the title of a session; `None` lets the next saved message generate one
+    pub async fn set_session_title(&self, session_id: &str, title: Option<&str>) -> Result<()> {
+        if let Some(db) = self.database().await? {
+            db.set_session_title(session_id, title).await?;
+        } else {
+            let mut sessions = self.memory_sessions.lock().await;
+            let mut titles = self.memory_titles.lock().await;
+            match sessions.get_mut(session_id) {
+                Some(metadata) => metadata.title = title.map(|t| t.to_string()),
+                None => match title {
+                    Some(title) => {
+                        titles.insert(session_id.to_string(), title.to_string());
+                    }
+                    None => {
+                        titles.remove(session_id);
+                    }
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the metadata of the sessions with stored messages matching `filter`, in its order
+    pub async fn list_sessions(&self, filter: &SessionFilter) -> Result<Vec<SessionMetadata>> {
+        if let Some(db) = self.database().await? {
+            db.list_sessions(filter).await
+        } else {
+            let history = self.memory_fallback.lock().await;
+            let sessions = self.memory_sessions.lock().await;
+            let mut sessions: Vec<SessionMetadata> = sessions
+                .values()
+                .filter(|metadata| filter.matches(metadata))
+                .map(|metadata| SessionMetadata {
+                    preview: history
+                        .get(&metadata.session_id)
+                        .filter(|_| filter.preview)
+                        .and_then(|lines| {
+                            lines
+                                .iter()
+                                .rev()
+                                .find_map(|line| line.strip_prefix("Bot: "))
+                        })
+                        .map(|reply| shorten(reply, SESSION_PREVIEW_MAX_CHARS)),
+                    ..metadata.clone()
+                })
+                .collect();
+            filter.sort(&mut sessions);
+            Ok(sessions)
+        }
+    }
+
+    /// Deletes the sessions inactive for longer than `older_than` and returns their ids; softly,
+    /// so they can be restored, unless `hard`
+    pub async fn delete_sessions_older_than(
+        &self,
+        older_than: std::time::Duration,
+        hard: bool,
+    ) -> Result<Vec<String>> {
+        let cutoff = Utc::now() - chrono::Duration::from_std(older_than)?;
+        if let Some(db) = self.database().await? {
+            return db.delete_sessions_before(cutoff, hard).await;
+        }
+
+        let filter = SessionFilter {
+            updated_before: Some(cutoff),
+            sort: SessionSort::Oldest,
+            ..Default::default()
+        };
+        let session_ids: Vec<String> = self
+            .list_sessions(&filter)
+            .await?
+            .into_iter()
+            .map(|metadata| metadata.session_id)
+            .collect();
+        for session_id in &session_ids {
+            match hard {
+                true => self.erase_session(session_id).await?,
+                false => self.delete_session(session_id).await?,
+            }
+        }
+        Ok(session_ids)
+    }
+
+    /// Number of stored messages of a session, counted without reading them; 0 if it has none
+    pub async fn count_messages(&self, session_id: &str) -> Result<i64> {
+        if let Some(db) = self.database().await? {
+            db.count_session_messages(session_id).await
+        } else {
+            let history = self.memory_fallback.lock().await;
+            Ok(history
+                .get(session_id)
+                .map_or(0, |lines| memory_turns(lines).len() as i64))
+        }
+    }
+
+    /// Returns one page of the stored turns together with the total number of turns.
+    ///
+    /// `offset` counts turns from the oldest one; when it is `None` the page holds the most recent
+    /// `limit` turns. See [`Self::memory_messages`] for the ids and timestamps of in-memory turns.
+    pub async fn get_messages_page(
+        &self,
+        session_id: &str,
+        limit: i64,
+        offset: Option<i64>,
+    ) -> Result<(Vec<ChatMessage>, i64, i64)> {
+        if let Some(db) = self.database().await? {
+            let total = db.count_session_messages(session_id).await?;
+            let offset = offset.unwrap_or((total - limit).max(0));
+            let messages = db
+                .get_session_history_paginated(session_id, limit, offset)
+                .await?;
+
+            Ok((messages, total, offset))
+        } else {
+            let messages = self.memory_messages(session_id).await?;
+            let total = messages.len() as i64;
+            let offset = offset.unwrap_or((total - limit).max(0));
+
+            let page = messages
+                .into_iter()
+                .skip(offset.max(0) as usize)
+                .take(limit.max(0) as usize)
+                .collect();
+            Ok((page, total, offset))
+        }
+    }
+
+    /// Returns one page of the flattened history together with the total number of turns.
+    ///
+    /// Same paging as [`Self::get_messages_page`], with two "User: "/"Bot: " lines per turn.
+    pub async fn get_conversation_history_page(
+        &self,
+        session_id: &str,
+        limit: i64,
+        offset: Option<i64>,
+    ) -> Result<(Vec<String>, i64, i64)> {
+        let (messages, total, offset) = self.get_messages_page(session_id, limit, offset).await?;
+
+        let mut history = Vec::new();
+        for message in messages {
+            history.push(format!("User: {}", message.user_message));
+            history.push(format!("Bot: {}", message.bot_reply));
+        }
+
+        Ok((history, total, offset))
+    }
+
+    /// Turns of a session in the in-memory fallback as [`ChatMessage`]s.
+    ///
+    /// The fallback keeps neither ids nor timestamps, so the id of a turn is its 1-based position
+    /// in the session and every turn is stamped with the current time.
+    async fn memory_messages(&self, session_id: &str) -> Result<Vec<ChatMessage>> {
+        let now = Utc::now();
+        Ok(self
+            .get_session_pairs(session_id)
+            .await?
+            .into_iter()
+            .enumerate()
+            .map(|(i, (user_message, bot_reply))| ChatMessage {
+                id: Some(i as i64 + 1),
+                session_id: session_id.to_string(),
+                user_message,
+                bot_reply,
+                timestamp: now,
+                tool_results: None,
+                assistant_message: None,
+                system_prompt: None,
+                truncated: false,
+                role: MessageRole::Turn,
+                latency_ms: None,
+                seed: None,
+                version: ChatMessage::first_version(),
+                parent_id: None,
+            })
+            .collect())
+    }
+
+    /// Streams the turns of a session saved after `since`, oldest first; see
+    /// [`StorageBackend::stream_session_history`]. In-memory turns are stamped with the current
+    /// time, see [`Self::memory_messages`].
+    pub async fn stream_session_turns(
+        &self,
+        session_id: &str,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<BoxStream<'static, Result<ChatMessage>>> {
+        if let Some(db) = self.database().await? {
+            return Ok(db.stream_session_history(session_id, since));
+        }
+        let messages = self.memory_messages(session_id).await?;
+        let messages = messages
+            .into_iter()
+            .filter(move |message| since.is_none_or(|since| message.timestamp > since))
+            .map(Ok);
+        Ok(futures_util::stream::iter(messages).boxed())
+    }
+
+    /// Returns the turns of a session, oldest first, with their tool results and tool calls
+    pub async fn get_session_turns(&self, session_id: &str) -> Result<Vec<ChatMessage>> {
+        match self.database().await? {
+            Some(db) => db.get_session_history(session_id).await,
+            None => self.memory_messages(session_id).await,
+        }
+    }
+
+    /// Returns conversation as ordered (user, bot) pairs for structured prompt construction
+    pub async fn get_session_pairs(&self, session_id: &str) -> Result<Vec<(String, String)>> {
+        if let Some(db) = self.database().await? {
+            let messages = db.get_session_history(session_id).await?;
+            Ok(message_pairs(messages))
+        } else {
+            let history = self.memory_fallback.lock().await;
+            let Some(lines) = history.get(session_id) else {
+                return Ok(vec![]);
+            };
+            self.memory_recency.lock().await.touch(session_id);
+            Ok(memory_turns(lines)
+                .into_iter()
+                .map(|(_, pair)| pair)
+                .collect())
+        }
+    }
+
+    /// Adds the token usage of one downstream request to the totals of a session.
+    ///
+    /// Usage is kept apart from the history, so deleting a session leaves its totals in place.
+    pub async fn record_usage(&self, session_id: &str, usage: &Usage) -> Result<()> {
+        if let Some(db) = &self.database {
+            db.add_session_usage(session_id, usage).await
+        } else {
+            let mut sessions = self.memory_usage.lock().await;
+            sessions
+                .entry(session_id.to_string())
+                .or_insert_with(|| SessionUsage {
+                    session_id: session_id.to_string(),
+                    ..Default::default()
+                })
+                .add(usage);
+            Ok(())
+        }
+    }
+
+    /// Returns the token usage totals of a session, all zero if none was recorded
+    pub async fn get_session_usage(&self, session_id: &str) -> Result<SessionUsage> {
+        let usage = if let Some(db) = &self.database {
+            db.get_session_usage(session_id).await?
+        } else {
+            self.memory_usage.lock().await.get(session_id).cloned()
+        };
+
+        Ok(usage.unwrap_or_else(|| SessionUsage {
+            session_id: session_id.to_string(),
+            ..Default::default()
+        }))
+    }
+
+    /// Whether history is stored in a database, which is required to summarize it
+    pub fn has_database(&self) -> bool {
+        self.database.is_some()
+    }
+
+    /// Returns the running summary of a session; always `None` without a database
+    pub async fn get_session_summary(&self, session_id: &str) -> Result<Option<SessionSummary>> {
+        match self.database().await? {
+            Some(db) => db.get_session_summary(session_id).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Stores the running summary of a session; does nothing without a database, whose turn ids
+    /// the summary refers to
+    pub async fn set_session_summary(&self, summary: &SessionSummary) -> Result<()> {
+        match self.database().await? {
+            Some(db) => db.set_session_summary(summary).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Exports the history of a session in `format`; `None` if the session has no stored turns.
+    ///
+    /// See [`Self::memory_messages`] for the ids and timestamps of in-memory turns.
+    pub async fn export_session(
+        &self,
+        session_id: &str,
+        format: ExportFormat,
+    ) -> Result<Option<String>> {
+        let messages = if let Some(db) = self.database().await? {
+            db.get_session_history(session_id).await?
+        } else {
+            self.memory_messages(session_id).await?
+        };
+        if messages.is_empty() {
+            return Ok(None);
+        }
+
+        let export = match format {
+            ExportFormat::Json => serde_json::to_string_pretty(&messages)?,
+            ExportFormat::Markdown => render_markdown_transcript(session_id, &messages),
+        };
+        Ok(Some(export))
+    }
+
+    /// Soft-deletes the history of a session; it can be brought back with [`Self::restore_session`]
+    pub async fn delete_session(&self, session_id: &str) -> Result<()> {
+        if let Some(db) = self.database().await? {
+            db.delete_session_history(session_id).await?;
+        } else {
+            // Fallback to memory storage
+            let mut history = self.memory_fallback.lock().await;
+            let Some(lines) = history.remove(session_id) else {
+                return Ok(());
+            };
+            let metadata = self.memory_sessions.lock().await.remove(session_id);
+            self.memory_recency.lock().await.remove(session_id);
+
+            let mut deleted = self.memory_deleted.lock().await;
+            let entry = deleted
+                .entry(session_id.to_string())
+                .or_insert_with(|| DeletedSession {
+                    lines: Vec::new(),
+                    metadata: None,
+                    deleted_at: Utc::now(),
+                });
+            entry.lines.extend(lines);
+            entry.metadata = entry.metadata.take().or(metadata);
+            entry.deleted_at = Utc::now();
+        }
+
+        Ok(())
+    }
+
+    /// Restores the soft-deleted history of a session and returns the number of turns restored.
+    ///
+    /// Restored turns come before any turn saved since the deletion.
+    pub async fn restore_session(&self, session_id: &str) -> Result<u64> {
+        if let Some(db) = self.database().await? {
+            db.restore_session(session_id).await
+        } else {
+            let mut history = self.memory_fallback.lock().await;
+            let Some(deleted) = self.memory_deleted.lock().await.remove(session_id) else {
+                return Ok(0);
+            };
+            let restored = memory_turns(&deleted.lines).len() as u64;
+
+            let lines = history.entry(session_id.to_string()).or_default();
+            lines.splice(0..0, deleted.lines);
+            self.memory_recency.lock().await.touch(session_id);
+
+            let mut sessions = self.memory_sessions.lock().await;
+            match (sessions.get_mut(session_id), deleted.metadata) {
+                (Some(metadata), Some(old)) => {
+                    metadata.title = old.title.or(metadata.title.take());
+                    metadata.created_at = old.created_at;
+                    metadata.message_count += restored as i64;
+                }
+                (None, Some(old)) => {
+                    sessions.insert(session_id.to_string(), old);
+                }
+                (_, None) => {}
+            }
+
+            Ok(restored)
+        }
+    }
+
+    /// Permanently deletes the history of a session, including soft-deleted turns
+    pub async fn erase_session(&self, session_id: &str) -> Result<()> {
+        if let Some(db) = self.database().await? {
+            db.erase_session_history(session_id).await?;
+        } else {
+            let mut history = self.memory_fallback.lock().await;
+            history.remove(session_id);
+            self.memory_sessions.lock().await.remove(session_id);
+            self.memory_deleted.lock().await.remove(session_id);
+            self.memory_recency.lock().await.remove(session_id);
+        }
+
+        Ok(())
+    }
+
+    /// Permanently deletes the turns soft-deleted more than `older_than` ago and returns the
+    /// number of turns removed
+    pub async fn purge_deleted(&self, older_than: std::time::Duration) -> Result<u64> {
+        let cutoff = Utc::now() - chrono::Duration::from_std(older_than)?;
+        if let Some(db) = self.database().await? {
+            db.purge_deleted_before(cutoff).await
+        } else {
+            let mut deleted = self.memory_deleted.lock().await;
+            let mut purged = 0;
+            deleted.retain(|_, session| {
+                let expired = session.deleted_at < cutoff;
+                if expired {
+                    purged += memory_turns(&session.lines).len() as u64;
+                }
+                !expired
+            });
+            Ok(purged)
+        }
+    }
+
+    /// Keeps only the newest `keep_last` turns of a session and returns the number of turns removed
+    pub async fn prune_session(&self, session_id: &str, keep_last: usize) -> Result<u64> {
+        if let Some(db) = self.database().await? {
+            db.prune_session(session_id, keep_last as i64).await
+        } else {
+            // Fallback to memory storage
+            let mut history = self.memory_fallback.lock().await;
+            let Some(lines) = history.get_mut(session_id) else {
+                return Ok(0);
+            };
+            let turns = memory_turns(lines);
+            let excess = turns.len().saturating_sub(keep_last);
+            let end = turns
+                .get(excess)
+                .map_or(lines.len(), |(range, _)| range.start);
+            lines.drain(..end);
+            if let Some(metadata) = self.memory_sessions.lock().await.get_mut(session_id) {
+                metadata.message_count = (turns.len() - excess) as i64;
+            }
+            Ok(excess as u64)
+        }
+    }
+
+    /// Returns the turn `id` of a session; see [`Self::memory_messages`] for in-memory ids
+    pub async fn get_message(&self, session_id: &str, id: i64) -> Result<Option<ChatMessage>> {
+        if let Some(db) = self.database().await? {
+            db.get_message_by_id(session_id, id).await
+        } else {
+            let messages = self.memory_messages(session_id).await?;
+            Ok(messages.into_iter().find(|m| m.id == Some(id)))
+        }
+    }
+
+    /// Deletes the turn `id` of a session; `false` if the session has no such turn.
+    ///
+    /// In memory the later turns move up, so their ids go down by one.
+    pub async fn delete_message(&self, session_id: &str, id: i64) -> Result<bool> {
+        if let Some(db) = self.database().await? {
+            db.delete_message_by_id(session_id, id).await
+        } else {
+            let mut history = self.memory_fallback.lock().await;
+            let Some(lines) = history.get_mut(session_id) else {
+                return Ok(false);
+            };
+            let Some(range) = memory_turn_lines(lines, id) else {
+                return Ok(false);
+            };
+            lines.drain(range);
+            if let Some(metadata) = self.memory_sessions.lock().await.get_mut(session_id) {
+                metadata.message_count = memory_turns(lines).len() as i64;
+            }
+            Ok(true)
+        }
+    }
+
+    /// Deletes every turn after turn `id` of a session, and the turn itself if `inclusive`, and
+    /// returns the number removed
+    pub async fn truncate_after(&self, session_id: &str, id: i64, inclusive: bool) -> Result<u64> {
+        if let Some(db) = self.database().await? {
+            db.truncate_after_id(session_id, id, inclusive).await
+        } else {
+            let mut history = self.memory_fallback.lock().await;
+            let Some(lines) = history.get_mut(session_id) else {
+                return Ok(0);
+            };
+            let Some(range) = memory_turn_lines(lines, id) else {
+                return Ok(0);
+            };
+            let before = memory_turns(lines).len();
+            lines.truncate(if inclusive { range.start } else { range.end });
+            let remaining = memory_turns(lines).len();
+            if let Some(metadata) = self.memory_sessions.lock().await.get_mut(session_id) {
+                metadata.message_count = remaining as i64;
+            }
+            Ok((before - remaining) as u64)
+        }
+    }
+
+    /// Whether a regenerated turn is saved as a new version of it; see
+    /// [`StorageBackend::keeps_versions`]. The in-memory fallback only keeps the latest.
+    pub async fn keeps_versions(&self) -> Result<bool> {
+        Ok(self.database().await?.is_some_and(|db| db.keeps_versions()))
+    }
+
+    /// Returns every version of the turn `id` of a session, oldest first
+    pub async fn get_message_versions(
+        &self,
+        session_id: &str,
+        id: i64,
+    ) -> Result<Vec<ChatMessage>> {
+        match self.database().await? {
+            Some(db) => db.get_message_versions(session_id, id).await,
+            None => Ok(self
+                .get_message(session_id, id)
+                .await?
+                .into_iter()
+                .collect()),
+        }
+    }
+
+    /// Copies the turns of `src`, up to and including turn `until_id` if given, into the new
+    /// session `dst` and returns the number copied; see [`StorageBackend::fork_session`].
+    ///
+    /// Both sessions then evolve independently. In memory only the text of the turns is copied.
+    pub async fn fork_session(
+        &self,
+        src: &str,
+        dst: &str,
+        until_id: Option<i64>,
+    ) -> Result<Option<u64>> {
+        if let Some(db) = self.database().await? {
+            return db.fork_session(src, dst, until_id).await;
+        }
+
+        let mut history = self.memory_fallback.lock().await;
+        let mut sessions = self.memory_sessions.lock().await;
+        let mut prompts = self.memory_system_prompts.lock().await;
+        if history.contains_key(dst) || sessions.contains_key(dst) || prompts.contains_key(dst) {
+            anyhow::bail!("session {dst} already exists");
+        }
+        let Some(lines) = history.get(src) else {
+            return Ok(None);
+        };
+        let end = match until_id {
+            Some(id) => match memory_turn_lines(lines, id) {
+                Some(range) => range.end,
+                None => return Ok(None),
+            },
+            None => lines.len(),
+        };
+        if end == 0 {
+            return Ok(None);
+        }
+
+        let lines = lines[..end].to_vec();
+        let copied = memory_turns(&lines).len() as u64;
+        history.insert(dst.to_string(), lines);
+        self.memory_recency.lock().await.touch(dst);
+        if let Some(metadata) = sessions.get(src).cloned() {
+            sessions.insert(
+                dst.to_string(),
+                SessionMetadata {
+                    session_id: dst.to_string(),
+                    message_count: copied as i64,
+                    ..metadata
+                },
+            );
+        }
+        if let Some(prompt) = prompts.get(src).cloned() {
+            prompts.insert(dst.to_string(), prompt);
+        }
+        Ok(Some(copied))
+    }
+
+    /// Deletes every stored turn older than `max_age` and returns the number removed.
+    ///
+    /// The in-memory fallback keeps no timestamps, so only database storage is pruned.
+    pub async fn prune_older_than(&self, max_age: std::time::Duration) -> Result<u64> {
+        let Some(db) = self.database().await? else {
+            return Ok(0);
+        };
+        let cutoff = Utc::now() - chrono::Duration::from_std(max_age)?;
+        db.prune_messages_before(cutoff).await
+    }
+
+    /// Reclaims the space of the rows deleted from the database; `false` without one
+    pub async fn vacuum(&self) -> Result<bool> {
+        let Some(db) = self.database().await? else {
+            return Ok(false);
+        };
+        db.vacuum().await?;
+        Ok(true)
+    }
+
+    /// Recounts the messages of every session and returns the number of sessions recounted.
+    ///
+    /// In memory the count of each session is rebuilt from its history.
+    pub async fn recount_sessions(&self) -> Result<u64> {
+        if let Some(db) = self.database().await? {
+            return db.recount_sessions().await;
+        }
+        let history = self.memory_fallback.lock().await;
+        let mut sessions = self.memory_sessions.lock().await;
+        for (session_id, metadata) in sessions.iter_mut() {
+            metadata.message_count = history
+                .get(session_id)
+                .map_or(0, |lines| memory_turns(lines).len() as i64);
+        }
+        Ok(sessions.len() as u64)
+    }
+
+    /// Searches the stored turns, optionally within one session.
+    ///
+    /// The in-memory fallback does a case-insensitive substring scan for the whole `query`.
+    pub async fn search_messages(
+        &self,
+        query: &str,
+        session_id: Option<&str>,
+    ) -> Result<Vec<SearchMatch>> {
+        if let Some(db) = self.database().await? {
+            let messages = db.search_messages(query, session_id).await?;
+            Ok(messages
+                .into_iter()
+                .map(|m| SearchMatch {
+                    session_id: m.session_id,
+                    user_message: m.user_message,
+                    bot_reply: m.bot_reply,
+                    timestamp: Some(m.timestamp),
+                })
+                .collect())
+        } else {
+            let needle = query.to_lowercase();
+            let mut session_ids: Vec<String> = {
+                let history = self.memory_fallback.lock().await;
+                history
+                    .keys()
+                    .filter(|id| session_id.is_none_or(|s| s == id.as_str()))
+                    .cloned()
+                    .collect()
+            };
+            session_ids.sort();
+
+            let mut matches = Vec::new();
+            for id in session_ids {
+                for (user, bot) in self.get_session_pairs(&id).await? {
+                    if user.to_lowercase().contains(&needle) || bot.to_lowercase().contains(&needle)
+                    {
+                        matches.push(SearchMatch {
+                            session_id: id.clone(),
+                            user_message: user,
+                            bot_reply: bot,
+                            timestamp: None,
+                        });
+                    }
+                }
+            }
+            Ok(matches)
+        }
+    }
+
+    /// Appends a downstream request to the request log; nothing is logged without a database
+    pub async fn log_request(&self, entry: &RequestLogEntry) -> Result<()> {
+        match &self.database {
+            Some(db) => db.insert_request_log(entry).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the oldest `limit` logged requests, of one session and from `since` on if given;
+    /// empty without a database
+    pub async fn request_logs(
+        &self,
+        session_id: Option<&str>,
+        since: Option<DateTime<Utc>>,
+        limit: i64,
+    ) -> Result<Vec<RequestLogEntry>> {
+        match &self.database {
+            Some(db) => db.list_request_logs(session_id, since, limit).await,
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+/// A SQLite database file of a test, removed with its `-wal` and `-shm` files when dropped
+#[cfg(test)]
+pub(crate) struct TempDb(std::path::PathBuf);
+#[cfg(test)]
+impl TempDb {
+    pub(crate) fn new() -> Self {
+        Self(std::env::temp_dir().join(format!("llama-nexus-{}.db", uuid::Uuid::new_v4())))
+    }
+}
+#[cfg(test)]
+impl std::ops::Deref for TempDb {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+#[cfg(test)]
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = self.0.clone().into_os_string();
+            file.push(suffix);
+            let _ = std::fs::remove_file(file);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_prune_session_keeps_newest_turns() {
+    let path = TempDb::new();
+    let storage =
+        ChatStorage::new_with_database(path.to_str().unwrap(), &DatabaseConfig::default())
+            .await
+            .unwrap();
+
+    for i in 0..5 {
+        storage
+            .save_turn(ChatMessage::new("s1", &format!("q{i}"), &format!("a{i}")))
+            .await
+            .unwrap();
+    }
+    storage
+        .save_turn(ChatMessage::new("s2", "other", "reply"))
+        .await
+        .unwrap();
+
+    assert_eq!(storage.prune_session("s1", 2).await.unwrap(), 3);
+    let pairs = storage.get_session_pairs("s1").await.unwrap();
+    assert_eq!(
+        pairs,
+        vec![
+            ("q3".to_string(), "a3".to_string()),
+            ("q4".to_string(), "a4".to_string())
+        ]
+    );
+    assert_eq!(storage.get_session_pairs("s2").await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_search_messages() {
+    let path = TempDb::new();
+    let storage =
+        ChatStorage::new_with_database(path.to_str().unwrap(), &DatabaseConfig::default())
+            .await
+            .unwrap();
+
+    storage
+        .save_turn(ChatMessage::new(
+            "s1",
+            "How do I bake bread?",
+            "Mix flour and water.",
+        ))
+        .await
+        .unwrap();
+    storage
+        .save_turn(ChatMessage::new("s1", "And pizza?", "Use more yeast."))
+        .await
+        .unwrap();
+    storage
+        .save_turn(ChatMessage::new("s2", "Bread or rice?", "Either works."))
+        .await
+        .unwrap();
+
+    let matches = storage.search_messages("bread", None).await.unwrap();
+    assert_eq!(matches.len(), 2);
+    assert!(matches.iter().all(|m| m.timestamp.is_some()));
+
+    let matches = storage.search_messages("bread", Some("s2")).await.unwrap();
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0].session_id, "s2");
+
+    // FTS syntax is matched literally and deleted turns leave the index
+    assert!(
+        storage
+            .search_messages("yeast\" OR \"flour", None)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+    storage.prune_session("s1", 1).await.unwrap();
+    assert!(
+        storage
+            .search_messages("flour", None)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    let memory = ChatStorage::new_memory_only(&StorageConfig::default());
+    memory
+        .save_turn(ChatMessage::new("s1", "How do I bake BREAD?", "Mix flour."))
+        .await
+        .unwrap();
+    let matches = memory.search_messages("bread", None).await.unwrap();
+    assert_eq!(matches.len(), 1);
+    assert!(matches[0].timestamp.is_none());
+}
+
+#[tokio::test]
+async fn test_list_sessions_with_metadata() {
+    let path = TempDb::new();
+    let database =
+        ChatStorage::new_with_database(path.to_str().unwrap(), &DatabaseConfig::default())
+            .await
+            .unwrap();
+
+    for storage in [
+        database,
+        ChatStorage::new_memory_only(&StorageConfig::default()),
+    ] {
+        storage
+            .set_session_title("s2", Some("Custom title"))
+            .await
+            .unwrap();
+        storage
+            .save_turn(ChatMessage::new(
+                "s1",
+                "How   do I bake bread?",
+                "Mix flour.",
+            ))
+            .await
+            .unwrap();
+        storage
+            .save_turn(ChatMessage::new("s1", "And pizza?", "Use more yeast."))
+            .await
+            .unwrap();
+        storage
+            .save_turn(ChatMessage::new("s2", "Hello", "Hi"))
+            .await
+            .unwrap();
+
+        let sessions = storage
+            .list_sessions(&SessionFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].session_id, "s2");
+        assert_eq!(sessions[0].title.as_deref(), Some("Custom title"));
+        assert_eq!(sessions[1].title.as_deref(), Some("How do I bake bread?"));
+        assert_eq!(sessions[1].message_count, 2);
+        assert!(sessions[1].created_at <= sessions[1].updated_at);
+        assert!(sessions.iter().all(|m| m.preview.is_none()));
+
+        let with_preview = SessionFilter {
+            preview: true,
+            ..Default::default()
+        };
+        let sessions = storage.list_sessions(&with_preview).await.unwrap();
+        assert_eq!(sessions[0].preview.as_deref(), Some("Hi"));
+        assert_eq!(sessions[1].preview.as_deref(), Some("Use more yeast."));
+
+        storage.prune_session("s1", 1).await.unwrap();
+        storage.delete_session("s2").await.unwrap();
+        let sessions = storage
+            .list_sessions(&SessionFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].message_count, 1);
+    }
+
+    let title = session_title(&"word ".repeat(20));
+    assert!(title.ends_with("...") && title.chars().count() <= SESSION_TITLE_MAX_CHARS + 3);
+}
+
+#[tokio::test]
+async fn test_filter_and_bulk_delete_sessions() {
+    let path = TempDb::new();
+    let database =
+        ChatStorage::new_with_database(path.to_str().unwrap(), &DatabaseConfig::default())
+            .await
+            .unwrap();
+
+    let now = Utc::now();
+    let hours = chrono::Duration::hours;
+    let turn = |session_id: &str, age: i64| ChatMessage {
+        timestamp: now - hours(age),
+        ..ChatMessage::new(session_id, "q", "a")
+    };
+    let ids = |sessions: Vec<SessionMetadata>| {
+        sessions
+            .into_iter()
+            .map(|s| s.session_id)
+            .collect::<Vec<_>>()
+    };
+
+    for storage in [
+        database,
+        ChatStorage::new_memory_only(&StorageConfig::default()),
+    ] {
+        storage.save_turn(turn("old", 48)).await.unwrap();
+        storage.save_turn(turn("busy", 5)).await.unwrap();
+        storage.save_turn(turn("busy", 4)).await.unwrap();
+        storage.save_turn(turn("new", 1)).await.unwrap();
+
+        let sessions = storage
+            .list_sessions(&SessionFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(ids(sessions), ["new", "busy", "old"]);
+        let filter = SessionFilter {
+            updated_after: Some(now - hours(24)),
+            sort: SessionSort::MessageCount,
+            ..Default::default()
+        };
+        assert_eq!(
+            ids(storage.list_sessions(&filter).await.unwrap()),
+            ["busy", "new"]
+        );
+        let filter = SessionFilter {
+            updated_before: Some(now - hours(2)),
+            sort: SessionSort::Oldest,
+            ..Default::default()
+        };
+        assert_eq!(
+            ids(storage.list_sessions(&filter).await.unwrap()),
+            ["old", "busy"]
+        );
+        let filter = SessionFilter {
+            prefix: Some("bu".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(ids(storage.list_sessions(&filter).await.unwrap()), ["busy"]);
+        let filter = SessionFilter {
+            prefix: Some("ü".to_string()),
+            ..Default::default()
+        };
+        assert!(storage.list_sessions(&filter).await.unwrap().is_empty());
+
+        // a soft bulk delete can be undone session by session
+        let three_hours = std::time::Duration::from_secs(3 * 3600);
+        assert_eq!(
+            storage
+                .delete_sessions_older_than(three_hours, false)
+                .await
+                .unwrap(),
+            ["old", "busy"]
+        );
+        assert_eq!(
+            ids(storage
+                .list_sessions(&SessionFilter::default())
+                .await
+                .unwrap()),
+            ["new"]
+        );
+        assert_eq!(storage.restore_session("old").await.unwrap(), 1);
+
+        let day = std::time::Duration::from_secs(24 * 3600);
+        assert_eq!(
+            storage.delete_sessions_older_than(day, true).await.unwrap(),
+            ["old"]
+        );
+        assert_eq!(storage.restore_session("old").await.unwrap(), 0);
+        assert_eq!(
+            ids(storage
+                .list_sessions(&SessionFilter::default())
+                .await
+                .unwrap()),
+            ["new"]
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_flush_memory_to_database() {
+    let path = TempDb::new();
+    let storage =
+        ChatStorage::new_with_database(path.to_str().unwrap(), &DatabaseConfig::default())
+            .await
+            .unwrap();
+
+    {
+        let mut history = storage.memory_fallback.lock().await;
+        history.insert(
+            "s1".to_string(),
+            vec![
+                "User: q0".to_string(),
+                "Bot: a0".to_string(),
+                "User: q1".to_string(),
+                "Bot: a1".to_string(),
+            ],
+        );
+    }
+
+    assert_eq!(storage.flush_memory_to_database().await.unwrap(), 2);
+    assert_eq!(storage.flush_memory_to_database().await.unwrap(), 0);
+    let pairs = storage.get_session_pairs("s1").await.unwrap();
+    assert_eq!(
+        pairs,
+        vec![
+            ("q0".to_string(), "a0".to_string()),
+            ("q1".to_string(), "a1".to_string())
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_memory_eviction() {
+    let config = StorageConfig {
+        memory_max_sessions: 3,
+        memory_max_messages: 8,
+        ..Default::default()
+    };
+    let storage = ChatStorage::new_memory_only(&config);
+    let session_ids = |storage: &ChatStorage| {
+        let storage = storage.memory_fallback.clone();
+        async move {
+            let mut ids: Vec<String> = storage.lock().await.keys().cloned().collect();
+            ids.sort();
+            ids
+        }
+    };
+
+    for id in ["s1", "s2", "s3"] {
+        storage
+            .save_turn(ChatMessage::new(id, "q", "a"))
+            .await
+            .unwrap();
+    }
+    storage
+        .set_system_prompt("s1", Some("be brief"))
+        .await
+        .unwrap();
+    // reading s1 makes s2 the least recently used
+    assert_eq!(storage.get_session_pairs("s1").await.unwrap().len(), 1);
+
+    storage
+        .save_turn(ChatMessage::new("s4", "q", "a"))
+        .await
+        .unwrap();
+    assert_eq!(session_ids(&storage).await, ["s1", "s3", "s4"]);
+    assert!(storage.get_session_pairs("s2").await.unwrap().is_empty());
+    let sessions = storage
+        .list_sessions(&SessionFilter::default())
+        .await
+        .unwrap();
+    assert_eq!(sessions.len(), 3);
+
+    assert_eq!(storage.count_messages("s4").await.unwrap(), 1);
+    assert_eq!(storage.count_messages("s2").await.unwrap(), 0);
+
+    // past 8 messages the least recently used sessions go
+    storage
+        .save_turn(ChatMessage::new("s4", "q1", "a1"))
+        .await
+        .unwrap();
+    storage
+        .save_turn(ChatMessage::new("s4", "q2", "a2"))
+        .await
+        .unwrap();
+    assert_eq!(session_ids(&storage).await, ["s1", "s4"]);
+    storage
+        .save_turn(ChatMessage::new("s4", "q3", "a3"))
+        .await
+        .unwrap();
+    assert_eq!(session_ids(&storage).await, ["s4"]);
+    assert_eq!(storage.get_system_prompt("s1").await.unwrap(), None);
+
+    // a session alone over the limit loses its oldest turns
+    storage
+        .save_turn(ChatMessage::new("s4", "q4", "a4"))
+        .await
+        .unwrap();
+    let pairs = storage.get_session_pairs("s4").await.unwrap();
+    assert_eq!(pairs.first(), Some(&("q1".to_string(), "a1".to_string())));
+    assert_eq!(pairs.len(), 4);
+}
+
+#[tokio::test]
+async fn test_soft_delete_and_restore_session() {
+    let path = TempDb::new();
+    let database =
+        ChatStorage::new_with_database(path.to_str().unwrap(), &DatabaseConfig::default())
+            .await
+            .unwrap();
+
+    for storage in [
+        database,
+        ChatStorage::new_memory_only(&StorageConfig::default()),
+    ] {
+        storage
+            .save_turn(ChatMessage::new("s1", "q0", "a0"))
+            .await
+            .unwrap();
+        storage
+            .save_turn(ChatMessage::new("s2", "q0", "a0"))
+            .await
+            .unwrap();
+
+        storage.delete_session("s1").await.unwrap();
+        assert!(storage.get_session_pairs("s1").await.unwrap().is_empty());
+        let sessions = storage
+            .list_sessions(&SessionFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            sessions
+                .iter()
+                .map(|s| s.session_id.as_str())
+                .collect::<Vec<_>>(),
+            ["s2"]
+        );
+
+        // turns saved after the deletion follow the restored ones
+        storage
+            .save_turn(ChatMessage::new("s1", "q1", "a1"))
+            .await
+            .unwrap();
+        assert_eq!(storage.restore_session("s1").await.unwrap(), 1);
+        assert_eq!(storage.restore_session("s1").await.unwrap(), 0);
+        let pairs = storage.get_session_pairs("s1").await.unwrap();
+        assert_eq!(
+            pairs,
+            vec![
+                ("q0".to_string(), "a0".to_string()),
+                ("q1".to_string(), "a1".to_string())
+            ]
+        );
+        let sessions = storage
+            .list_sessions(&SessionFilter::default())
+            .await
+            .unwrap();
+        let s1 = sessions.iter().find(|s| s.session_id == "s1").unwrap();
+        assert_eq!((s1.title.as_deref(), s1.message_count), (Some("q0"), 2));
+
+        // purging only removes turns deleted long enough ago
+        storage.delete_session("s2").await.unwrap();
+        assert_eq!(
+            storage
+                .purge_deleted(std::time::Duration::from_secs(3600))
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            storage
+                .purge_deleted(std::time::Duration::ZERO)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(storage.restore_session("s2").await.unwrap(), 0);
+
+        // erasing is permanent
+        storage.erase_session("s1").await.unwrap();
+        assert_eq!(storage.restore_session("s1").await.unwrap(), 0);
+        assert!(storage.get_session_pairs("s1").await.unwrap().is_empty());
+    }
+}
+
+#[tokio::test]
+async fn test_buffered_writes() {
+    let path = TempDb::new();
+    let storage =
+        ChatStorage::new_with_database(path.to_str().unwrap(), &DatabaseConfig::default())
+            .await
+            .unwrap()
+            .with_write_batch_size(3);
+
+    // buffered turns are written before a read
+    storage
+        .save_turn(ChatMessage::new("s1", "q0", "a0"))
+        .await
+        .unwrap();
+    storage
+        .save_turn(ChatMessage::new("s1", "q1", "a1"))
+        .await
+        .unwrap();
+    assert_eq!(storage.pending.lock().await.len(), 2);
+    let pairs = storage.get_session_pairs("s1").await.unwrap();
+    assert_eq!(
+        pairs,
+        vec![
+            ("q0".to_string(), "a0".to_string()),
+            ("q1".to_string(), "a1".to_string())
+        ]
+    );
+    assert!(storage.pending.lock().await.is_empty());
+
+    // a full batch is written right away
+    for i in 0..3 {
+        storage
+            .save_turn(ChatMessage::new("s2", &format!("q{i}"), &format!("a{i}")))
+            .await
+            .unwrap();
+    }
+    assert!(storage.pending.lock().await.is_empty());
+    let db = storage.database.as_ref().unwrap();
+    assert_eq!(db.count_session_messages("s2").await.unwrap(), 3);
+    assert_eq!(storage.flush_pending().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_close_flushes_buffered_writes() {
+    let path = TempDb::new();
+    let url = path.to_str().unwrap();
+    let storage = ChatStorage::new_with_database(url, &DatabaseConfig::default())
+        .await
+        .unwrap()
+        .with_write_batch_size(10);
+    storage
+        .save_turn(ChatMessage::new("s1", "q0", "a0"))
+        .await
+        .unwrap();
+    storage
+        .save_turn(ChatMessage::new("s1", "q1", "a1"))
+        .await
+        .unwrap();
+    assert!(storage.ping().await.is_ok());
+
+    assert_eq!(storage.close().await.unwrap(), 2);
+    let db = storage.database.as_ref().unwrap();
+    assert!(db.count_session_messages("s1").await.is_err());
+    assert!(storage.ping().await.is_err());
+
+    // the turns are in the database file once it is opened again
+    let reopened = DatabaseManager::new(url, &DatabaseConfig::default())
+        .await
+        .unwrap();
+    assert_eq!(reopened.count_session_messages("s1").await.unwrap(), 2);
+    reopened.close().await;
+}
+
+#[tokio::test]
+async fn test_failed_flush_keeps_turns() {
+    let path = TempDb::new();
+    let storage =
+        ChatStorage::new_with_database(path.to_str().unwrap(), &DatabaseConfig::default())
+            .await
+            .unwrap()
+            .with_write_batch_size(10);
+    storage
+        .save_turn(ChatMessage::new("s1", "q0", "a0"))
+        .await
+        .unwrap();
+    storage
+        .save_turn(ChatMessage::new("s1", "q1", "a1"))
+        .await
+        .unwrap();
+
+    // a failed write keeps the turns for the next flush, and closing reports them
+    storage.database.as_ref().unwrap().close().await;
+    assert!(storage.flush_pending().await.is_err());
+    assert_eq!(storage.pending.lock().await.len(), 2);
+    assert!(storage.memory_fallback.lock().await.is_empty());
+    let err = storage.close().await.unwrap_err();
+    assert!(
+        err.to_string()
+            .starts_with("2 buffered turn(s) were not written"),
+        "{err}"
+    );
+}
+
+#[tokio::test]
+async fn test_save_turn_with_tool_calls() {
+    let path = TempDb::new();
+    let storage =
+        ChatStorage::new_with_database(path.to_str().unwrap(), &DatabaseConfig::default())
+            .await
+            .unwrap();
+
+    let assistant_message = concat!(
+        r#"{"role":"assistant","content":null,"tool_calls":[{"id":"call_1","type":"function","#,
+        r#""function":{"name":"weather","arguments":"{}"}}]}"#,
+    );
+    storage
+        .save_turn(ChatMessage {
+            assistant_message: Some(assistant_message.to_string()),
+            ..ChatMessage::new("s1", "Weather in Paris?", "")
+        })
+        .await
+        .unwrap();
+    storage
+        .save_turn(ChatMessage {
+            tool_results: Some(r#"[{"tool_call_id":"call_1","content":"sunny"}]"#.to_string()),
+            ..ChatMessage::new("s1", "", "It is sunny.")
+        })
+        .await
+        .unwrap();
+
+    let turns = storage.get_session_turns("s1").await.unwrap();
+    assert_eq!(turns.len(), 2);
+    assert_eq!(
+        turns[0].assistant_message.as_deref(),
+        Some(assistant_message)
+    );
+    assert!(turns[0].tool_results.is_none());
+    assert!(turns[1].tool_results.as_deref().unwrap().contains("call_1"));
+    assert!(turns[1].assistant_message.is_none());
+}
+
+#[tokio::test]
+async fn test_single_messages() {
+    let path = TempDb::new();
+    let database =
+        ChatStorage::new_with_database(path.to_str().unwrap(), &DatabaseConfig::default())
+            .await
+            .unwrap();
+
+    for storage in [
+        database,
+        ChatStorage::new_memory_only(&StorageConfig::default()),
+    ] {
+        storage
+            .save_single_message("s1", MessageRole::System, "The user joined.")
+            .await
+            .unwrap();
+        storage
+            .save_single_message("s1", MessageRole::User, "Hi")
+            .await
+            .unwrap();
+        storage
+            .save_single_message("s1", MessageRole::Assistant, "Hello!")
+            .await
+            .unwrap();
+        storage
+            .save_turn(ChatMessage::new("s1", "Bye", "Goodbye!"))
+            .await
+            .unwrap();
+        storage
+            .save_single_message("s1", MessageRole::Assistant, "Anything else?")
+            .await
+            .unwrap();
+        let results = r#"[{"tool_call_id":"call_1","content":"sunny"}]"#;
+        storage
+            .save_single_message("s1", MessageRole::Tool, results)
+            .await
+            .unwrap();
+
+        // a single user message and the reply after it make one turn
+        let pairs = storage.get_session_pairs("s1").await.unwrap();
+        let pair = |user: &str, bot: &str| (user.to_string(), bot.to_string());
+        assert_eq!(
+            pairs,
+            vec![
+                pair("Hi", "Hello!"),
+                pair("Bye", "Goodbye!"),
+                pair("", "Anything else?")
+            ]
+        );
+        let sessions = storage
+            .list_sessions(&SessionFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(sessions[0].title.as_deref(), Some("Hi"));
+    }
+
+    // the database keeps every message with its role, in order
+    let db = DatabaseManager::new(path.to_str().unwrap(), &DatabaseConfig::default())
+        .await
+        .unwrap();
+    let messages = db.get_session_history("s1").await.unwrap();
+    let roles: Vec<MessageRole> = messages.iter().map(|m| m.role).collect();
+    use MessageRole::*;
+    assert_eq!(roles, [System, User, Assistant, Turn, Assistant, Tool]);
+    assert_eq!(messages[0].bot_reply, "The user joined.");
+    assert!(
+        messages[5]
+            .tool_results
+            .as_deref()
+            .unwrap()
+            .contains("call_1")
+    );
+    assert!(
+        serde_json::to_value(&messages[3])
+            .unwrap()
+            .get("role")
+            .is_none()
+    );
+    assert_eq!(
+        serde_json::to_value(&messages[0]).unwrap()["role"],
+        "system"
+    );
+
+    let transcript = render_markdown_transcript("s1", &messages);
+    assert!(transcript.contains("**System:** _"));
+    assert!(transcript.contains("The user joined."));
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn test_turn_system_prompt() {
+    let path = TempDb::new();
+    let storage =
+        ChatStorage::new_with_database(path.to_str().unwrap(), &DatabaseConfig::default())
+            .await
+            .unwrap();
+
+    let turn = |user_message: &str, system_prompt: &str| ChatMessage {
+        system_prompt: Some(system_prompt.to_string()),
+        ..ChatMessage::new("s1", user_message, "ok")
+    };
+    storage.save_turn(turn("q0", "Be brief.")).await.unwrap();
+    storage.save_turn(turn("q1", "Be brief.")).await.unwrap();
+    storage
+        .save_turn(turn("q2", "Answer in French."))
+        .await
+        .unwrap();
+
+    let turns = storage.get_session_turns("s1").await.unwrap();
+    assert_eq!(turns[0].system_prompt.as_deref(), Some("Be brief."));
+    assert_eq!(turns[2].system_prompt.as_deref(), Some("Answer in French."));
+
+    // the prompt is shown before the first turn answered with it
+    let markdown = storage
+        .export_session("s1", ExportFormat::Markdown)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(markdown.matches("**System:**").count(), 2);
+    assert!(markdown.find("Be brief.").unwrap() < markdown.find("q0").unwrap());
+    assert!(markdown.find("q1").unwrap() < markdown.find("Answer in French.").unwrap());
+
+    let json = storage
+        .export_session("s1", ExportFormat::Json)
+        .await
+        .unwrap()
+        .unwrap();
+    let messages: Vec<ChatMessage> = serde_json::from_str(&json).unwrap();
+    assert_eq!(messages[1].system_prompt.as_deref(), Some("Be brief."));
+}
+
+#[tokio::test]
+async fn test_export_session() {
+    let storage = ChatStorage::new_memory_only(&StorageConfig::default());
+    assert!(
+        storage
+            .export_session("s1", ExportFormat::Json)
+            .await
+            .unwrap()
+            .is_none()
+    );
+
+    storage
+        .save_turn(ChatMessage::new("s1", "What is rye?", "A grain."))
+        .await
+        .unwrap();
+    storage
+        .save_turn(ChatMessage::new("s1", "And spelt?", "Another grain."))
+        .await
+        .unwrap();
+
+    let json = storage
+        .export_session("s1", ExportFormat::Json)
+        .await
+        .unwrap()
+        .unwrap();
+    let messages: Vec<ChatMessage> = serde_json::from_str(&json).unwrap();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[1].user_message, "And spelt?");
+    assert_eq!(messages[1].session_id, "s1");
+
+    let markdown = storage
+        .export_session("s1", ExportFormat::Markdown)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(markdown.starts_with("# Session s1\n"));
+    assert_eq!(markdown.matches("**User:**").count(), 2);
+    assert_eq!(markdown.matches("**Assistant:**").count(), 2);
+    assert!(markdown.find("What is rye?").unwrap() < markdown.find("Another grain.").unwrap());
+}
+
+#[tokio::test]
+async fn test_session_usage() {
+    let path = TempDb::new();
+    let db_storage =
+        ChatStorage::new_with_database(path.to_str().unwrap(), &DatabaseConfig::default())
+            .await
+            .unwrap();
+
+    for storage in [
+        ChatStorage::new_memory_only(&StorageConfig::default()),
+        db_storage,
+    ] {
+        assert_eq!(storage.get_session_usage("s1").await.unwrap().requests, 0);
+
+        let usage = |prompt_tokens, completion_tokens| Usage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        };
+        storage.record_usage("s1", &usage(10, 5)).await.unwrap();
+        storage.record_usage("s1", &usage(20, 7)).await.unwrap();
+        storage.record_usage("s2", &usage(1, 1)).await.unwrap();
+
+        let totals = storage.get_session_usage("s1").await.unwrap();
+        assert_eq!(
+            totals,
+            SessionUsage {
+                session_id: "s1".to_string(),
+                prompt_tokens: 30,
+                completion_tokens: 12,
+                total_tokens: 42,
+                requests: 2,
+            }
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_concurrent_sqlite_saves() {
+    let path = TempDb::new();
+    let db = Arc::new(
+        DatabaseManager::new(path.to_str().unwrap(), &DatabaseConfig::default())
+            .await
+            .unwrap(),
+    );
+
+    // the pragmas are set on every pooled connection, not only the first one
+    let DatabasePool::Sqlite(pool) = &db.pool else {
+        unreachable!()
+    };
+    let mut connections = Vec::new();
+    for _ in 0..DatabaseConfig::default().max_connections {
+        let mut conn = pool.acquire().await.unwrap();
+        let journal_mode: String = sqlx::query_scalar("PRAGMA journal_mode")
+            .fetch_one(&mut *conn)
+            .await
+            .unwrap();
+        let busy_timeout: i64 = sqlx::query_scalar("PRAGMA busy_timeout")
+            .fetch_one(&mut *conn)
+            .await
+            .unwrap();
+        assert_eq!(journal_mode, "wal");
+        assert_eq!(busy_timeout, 5000);
+        connections.push(conn);
+    }
+    drop(connections);
+
+    let saves: Vec<_> = (0..50)
+        .map(|i| {
+            let db = Arc::clone(&db);
+            tokio::spawn(async move {
+                let message =
+                    ChatMessage::new(&format!("s{}", i % 5), &format!("q{i}"), &format!("a{i}"));
+                db.save_message(&message).await
+            })
+        })
+        .collect();
+    for save in saves {
+        save.await.unwrap().unwrap();
+    }
+
+    let mut saved = 0;
+    for i in 0..5 {
+        saved += db.count_session_messages(&format!("s{i}")).await.unwrap();
+    }
+    assert_eq!(saved, 50);
+}
+
+#[tokio::test]
+async fn test_get_messages_page() {
+    let path = TempDb::new();
+    let db_storage =
+        ChatStorage::new_with_database(path.to_str().unwrap(), &DatabaseConfig::default())
+            .await
+            .unwrap();
+
+    for storage in [
+        ChatStorage::new_memory_only(&StorageConfig::default()),
+        db_storage,
+    ] {
+        for i in 0..5 {
+            storage
+                .save_turn(ChatMessage::new("s1", &format!("q{i}"), &format!("a{i}")))
+                .await
+                .unwrap();
+        }
+
+        // the most recent page by default
+        let (messages, total, offset) = storage.get_messages_page("s1", 2, None).await.unwrap();
+        assert_eq!((total, offset), (5, 3));
+        let users: Vec<&str> = messages.iter().map(|m| m.user_message.as_str()).collect();
+        assert_eq!(users, ["q3", "q4"]);
+        assert!(messages.iter().all(|m| m.id.is_some()));
+        assert!(messages[0].id < messages[1].id);
+
+        let (messages, _, offset) = storage.get_messages_page("s1", 2, Some(4)).await.unwrap();
+        assert_eq!(offset, 4);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].bot_reply, "a4");
+
+        let (lines, _, _) = storage
+            .get_conversation_history_page("s1", 1, Some(0))
+            .await
+            .unwrap();
+        assert_eq!(lines, ["User: q0", "Bot: a0"]);
+    }
+}
+
+#[tokio::test]
+async fn test_stream_session_turns() {
+    let path = TempDb::new();
+    let storage =
+        ChatStorage::new_with_database(path.to_str().unwrap(), &DatabaseConfig::default())
+            .await
+            .unwrap();
+
+    let start = Utc::now() - chrono::Duration::hours(1);
+    for i in 0..100 {
+        let turn = ChatMessage {
+            timestamp: start + chrono::Duration::seconds(i),
+            ..ChatMessage::new("s1", &format!("q{i}"), &format!("a{i}"))
+        };
+        storage.save_turn(turn).await.unwrap();
+    }
+    storage
+        .save_turn(ChatMessage::new("s2", "other", "session"))
+        .await
+        .unwrap();
+
+    let users = |turns: Vec<Result<ChatMessage>>| {
+        turns
+            .into_iter()
+            .map(|turn| turn.unwrap().user_message)
+            .collect::<Vec<_>>()
+    };
+    let turns: Vec<_> = storage
+        .stream_session_turns("s1", None)
+        .await
+        .unwrap()
+        .collect()
+        .await;
+    let users_all = users(turns);
+    assert_eq!(users_all.len(), 100);
+    assert_eq!(
+        (users_all[0].as_str(), users_all[99].as_str()),
+        ("q0", "q99")
+    );
+
+    // resuming after the timestamp of a turn
+    let since = start + chrono::Duration::seconds(97);
+    let turns: Vec<_> = storage
+        .stream_session_turns("s1", Some(since))
+        .await
+        .unwrap()
+        .collect()
+        .await;
+    assert_eq!(users(turns), ["q98", "q99"]);
+
+    // dropping the stream early stops reading
+    let first = storage
+        .stream_session_turns("s1", None)
+        .await
+        .unwrap()
+        .next()
+        .await;
+    assert_eq!(first.unwrap().unwrap().user_message, "q0");
+
+    let memory = ChatStorage::new_memory_only(&StorageConfig::default());
+    memory
+        .save_turn(ChatMessage::new("s1", "q0", "a0"))
+        .await
+        .unwrap();
+    let turns: Vec<_> = memory
+        .stream_session_turns("s1", None)
+        .await
+        .unwrap()
+        .collect()
+        .await;
+    assert_eq!(users(turns), ["q0"]);
+}
+
+#[tokio::test]
+async fn test_delete_and_truncate_messages() {
+    let path = TempDb::new();
+    let db_storage =
+        ChatStorage::new_with_database(path.to_str().unwrap(), &DatabaseConfig::default())
+            .await
+            .unwrap();
+
+    // the in-memory fallback numbers the turns of each session from 1, the database all of them
+    for (storage, s2_id) in [
+        (ChatStorage::new_memory_only(&StorageConfig::default()), 1),
+        (db_storage, 6),
+    ] {
+        for i in 0..5 {
+            storage
+                .save_turn(ChatMessage::new("s1", &format!("q{i}"), &format!("a{i}")))
+                .await
+                .unwrap();
+        }
+        storage
+            .save_turn(ChatMessage::new("s2", "other", "session"))
+            .await
+            .unwrap();
+        let ids =
+            |messages: &[ChatMessage]| messages.iter().map(|m| m.id.unwrap()).collect::<Vec<_>>();
+        let (messages, _, _) = storage.get_messages_page("s1", 10, None).await.unwrap();
+        let s1_ids = ids(&messages);
+        let (s2_messages, _, _) = storage.get_messages_page("s2", 10, None).await.unwrap();
+
+        assert_eq!(s1_ids, [1, 2, 3, 4, 5]);
+        assert_eq!(ids(&s2_messages), [s2_id]);
+
+        // an id of another session, or of no turn, is not found
+        assert!(!storage.delete_message("s1", 6).await.unwrap());
+        assert_eq!(
+            storage
+                .get_message("s1", s1_ids[1])
+                .await
+                .unwrap()
+                .unwrap()
+                .user_message,
+            "q1"
+        );
+        assert!(storage.delete_message("s1", s1_ids[1]).await.unwrap());
+        assert!(storage.get_message("s1", 999).await.unwrap().is_none());
+
+        let pairs = storage.get_session_pairs("s1").await.unwrap();
+        let users: Vec<&str> = pairs.iter().map(|(user, _)| user.as_str()).collect();
+        assert_eq!(users, ["q0", "q2", "q3", "q4"]);
+
+        // truncate from the turn now second, "q2"
+        let (messages, _, _) = storage.get_messages_page("s1", 10, None).await.unwrap();
+        assert_eq!(
+            storage
+                .truncate_after("s1", messages[1].id.unwrap(), true)
+                .await
+                .unwrap(),
+            3
+        );
+        assert_eq!(storage.truncate_after("s1", 999, true).await.unwrap(), 0);
+        assert_eq!(
+            storage.get_session_pairs("s1").await.unwrap(),
+            vec![("q0".to_string(), "a0".to_string())]
+        );
+        assert_eq!(storage.get_session_pairs("s2").await.unwrap().len(), 1);
+
+        let sessions = storage
+            .list_sessions(&SessionFilter::default())
+            .await
+            .unwrap();
+        let s1 = sessions.iter().find(|m| m.session_id == "s1").unwrap();
+        assert_eq!(s1.message_count, 1);
+    }
+}
+
+#[tokio::test]
+async fn test_session_summary() {
+    let path = TempDb::new();
+    let storage =
+        ChatStorage::new_with_database(path.to_str().unwrap(), &DatabaseConfig::default())
+            .await
+            .unwrap();
+    for i in 0..4 {
+        storage
+            .save_turn(ChatMessage::new("s1", &format!("q{i}"), &format!("a{i}")))
+            .await
+            .unwrap();
+    }
+    let ids: Vec<i64> = storage
+        .get_session_turns("s1")
+        .await
+        .unwrap()
+        .iter()
+        .map(|m| m.id.unwrap())
+        .collect();
+    let summary = |until: i64| SessionSummary {
+        session_id: "s1".to_string(),
+        summary: format!("until {until}"),
+        summarized_until: until,
+        updated_at: Utc::now(),
+    };
+
+    assert!(storage.get_session_summary("s1").await.unwrap().is_none());
+    storage.set_session_summary(&summary(ids[0])).await.unwrap();
+    storage.set_session_summary(&summary(ids[1])).await.unwrap();
+    assert_eq!(
+        storage
+            .get_session_summary("s1")
+            .await
+            .unwrap()
+            .unwrap()
+            .summarized_until,
+        ids[1]
+    );
+
+    // deleting turns after the summary keeps it, deleting a summarized turn drops it
+    assert_eq!(storage.truncate_after("s1", ids[3], true).await.unwrap(), 1);
+    assert!(storage.get_session_summary("s1").await.unwrap().is_some());
+    assert!(storage.delete_message("s1", ids[0]).await.unwrap());
+    assert!(storage.get_session_summary("s1").await.unwrap().is_none());
+
+    // as does deleting the session
+    storage.set_session_summary(&summary(ids[1])).await.unwrap();
+    storage.delete_session("s1").await.unwrap();
+    assert!(storage.get_session_summary("s1").await.unwrap().is_none());
+
+    // in memory nothing is summarized
+    let memory = ChatStorage::new_memory_only(&StorageConfig::default());
+    memory.set_session_summary(&summary(1)).await.unwrap();
+    assert!(memory.get_session_summary("s1").await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_message_versions() {
+    let path = TempDb::new();
+    let storage =
+        ChatStorage::new_with_database(path.to_str().unwrap(), &DatabaseConfig::default())
+            .await
+            .unwrap();
+    assert!(storage.keeps_versions().await.unwrap());
+    for i in 0..3 {
+        storage
+            .save_turn(ChatMessage::new("s1", &format!("q{i}"), &format!("a{i}")))
+            .await
+            .unwrap();
+    }
+    let turns = storage.get_session_turns("s1").await.unwrap();
+
+    // regenerating the second turn drops the third and saves two versions after the original
+    assert_eq!(
+        storage
+            .truncate_after("s1", turns[1].id.unwrap(), false)
+            .await
+            .unwrap(),
+        1
+    );
+    let second = ChatMessage::new("s1", "q1 again", "b1").next_version_of(&turns[1]);
+    storage.save_turn(second).await.unwrap();
+    let second = storage
+        .get_session_turns("s1")
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let third = ChatMessage::new("s1", "q1 once more", "c1").next_version_of(&second);
+    storage.save_turn(third).await.unwrap();
+
+    // the history and the counts only see the latest version
+    let history = storage.get_session_turns("s1").await.unwrap();
+    let replies: Vec<_> = history.iter().map(|m| m.bot_reply.as_str()).collect();
+    assert_eq!(replies, ["a0", "c1"]);
+    assert_eq!((history[1].version, history[1].parent_id), (3, turns[1].id));
+    assert_eq!(storage.count_messages("s1").await.unwrap(), 2);
+    let sessions = storage
+        .list_sessions(&SessionFilter::default())
+        .await
+        .unwrap();
+    assert_eq!(sessions[0].message_count, 2);
+    assert!(
+        storage
+            .get_message("s1", turns[1].id.unwrap())
+            .await
+            .unwrap()
+            .is_none()
+    );
+
+    // any version lists them all, oldest first
+    for id in [turns[1].id, second.id, history[1].id] {
+        let versions = storage
+            .get_message_versions("s1", id.unwrap())
+            .await
+            .unwrap();
+        let replies: Vec<_> = versions.iter().map(|m| m.bot_reply.as_str()).collect();
+        assert_eq!(replies, ["a1", "b1", "c1"]);
+    }
+    assert!(
+        storage
+            .get_message_versions("s1", 999)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    // a fork copies the latest version as an original
+    assert_eq!(
+        storage.fork_session("s1", "fork", None).await.unwrap(),
+        Some(2)
+    );
+    let fork = storage.get_session_turns("fork").await.unwrap();
+    assert_eq!(
+        (
+            fork[1].bot_reply.as_str(),
+            fork[1].version,
+            fork[1].parent_id
+        ),
+        ("c1", 1, None)
+    );
+
+    // deleting the turn deletes every version
+    assert!(
+        storage
+            .delete_message("s1", history[1].id.unwrap())
+            .await
+            .unwrap()
+    );
+    assert!(
+        storage
+            .get_message_versions("s1", turns[1].id.unwrap())
+            .await
+            .unwrap()
+            .is_empty()
+    );
+    assert_eq!(storage.count_messages("s1").await.unwrap(), 1);
+
+    // in memory only the latest is kept
+    let memory = ChatStorage::new_memory_only(&StorageConfig::default());
+    assert!(!memory.keeps_versions().await.unwrap());
+    memory
+        .save_turn(ChatMessage::new("s1", "q", "a"))
+        .await
+        .unwrap();
+    assert_eq!(memory.get_message_versions("s1", 1).await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_fork_session() {
+    let path = TempDb::new();
+    let db_storage =
+        ChatStorage::new_with_database(path.to_str().unwrap(), &DatabaseConfig::default())
+            .await
+            .unwrap();
+
+    for storage in [
+        ChatStorage::new_memory_only(&StorageConfig::default()),
+        db_storage,
+    ] {
+        storage
+            .set_system_prompt("s1", Some("Be brief."))
+            .await
+            .unwrap();
+        for i in 0..3 {
+            storage
+                .save_turn(ChatMessage::new("s1", &format!("q{i}"), &format!("a{i}")))
+                .await
+                .unwrap();
+        }
+        let ids: Vec<i64> = storage
+            .get_session_turns("s1")
+            .await
+            .unwrap()
+            .iter()
+            .map(|m| m.id.unwrap())
+            .collect();
+
+        // the whole history, or up to a turn
+        assert_eq!(
+            storage.fork_session("s1", "all", None).await.unwrap(),
+            Some(3)
+        );
+        assert_eq!(
+            storage
+                .fork_session("s1", "two", Some(ids[1]))
+                .await
+                .unwrap(),
+            Some(2)
+        );
+        let users = |pairs: Vec<(String, String)>| {
+            pairs.into_iter().map(|(user, _)| user).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            users(storage.get_session_pairs("two").await.unwrap()),
+            ["q0", "q1"]
+        );
+        assert_eq!(
+            storage.get_system_prompt("two").await.unwrap().as_deref(),
+            Some("Be brief.")
+        );
+
+        // nothing to fork from
+        assert_eq!(
+            storage.fork_session("s1", "none", Some(999)).await.unwrap(),
+            None
+        );
+        assert_eq!(
+            storage.fork_session("missing", "none", None).await.unwrap(),
+            None
+        );
+        assert!(storage.fork_session("s1", "two", None).await.is_err());
+
+        // the branches evolve independently
+        storage
+            .save_turn(ChatMessage::new("two", "q2'", "a2'"))
+            .await
+            .unwrap();
+        assert_eq!(
+            users(storage.get_session_pairs("two").await.unwrap()),
+            ["q0", "q1", "q2'"]
+        );
+        assert_eq!(
+            users(storage.get_session_pairs("s1").await.unwrap()),
+            ["q0", "q1", "q2"]
+        );
+
+        let sessions = storage
+            .list_sessions(&SessionFilter::default())
+            .await
+            .unwrap();
+        let count = |id: &str| {
+            sessions
+                .iter()
+                .find(|m| m.session_id == id)
+                .unwrap()
+                .message_count
+        };
+        assert_eq!((count("s1"), count("all"), count("two")), (3, 3, 3));
+        assert!(sessions.iter().all(|m| m.session_id != "none"));
+    }
+}
+
+#[tokio::test]
+async fn test_request_log() {
+    let path = TempDb::new();
+    let storage =
+        ChatStorage::new_with_database(path.to_str().unwrap(), &DatabaseConfig::default())
+            .await
+            .unwrap();
+
+    let start = Utc::now();
+    let entry = |session_id: &str, status: Option<i32>, timestamp: DateTime<Utc>| RequestLogEntry {
+        id: None,
+        session_id: session_id.to_string(),
+        model: Some("llama".to_string()),
+        server_url: "http://localhost:8080/v1".to_string(),
+        request: r#"{"model":"llama","messages":[]}"#.to_string(),
+        status,
+        latency_ms: 12,
+        timestamp,
+    };
+    storage
+        .log_request(&entry("s1", Some(200), start))
+        .await
+        .unwrap();
+    storage
+        .log_request(&entry("s2", None, start + chrono::Duration::seconds(1)))
+        .await
+        .unwrap();
+    storage
+        .log_request(&entry(
+            "s1",
+            Some(503),
+            start + chrono::Duration::seconds(2),
+        ))
+        .await
+        .unwrap();
+
+    let logs = storage.request_logs(Some("s1"), None, 100).await.unwrap();
+    assert_eq!(
+        logs.iter().map(|e| e.status).collect::<Vec<_>>(),
+        [Some(200), Some(503)]
+    );
+    assert_eq!(logs[0].request, r#"{"model":"llama","messages":[]}"#);
+
+    let since = start + chrono::Duration::seconds(1);
+    let logs = storage.request_logs(None, Some(since), 100).await.unwrap();
+    assert_eq!(
+        logs.iter()
+            .map(|e| e.session_id.as_str())
+            .collect::<Vec<_>>(),
+        ["s2", "s1"]
+    );
+    assert_eq!(storage.request_logs(None, None, 1).await.unwrap().len(), 1);
+
+    // nothing is logged in memory
+    let memory = ChatStorage::new_memory_only(&StorageConfig::default());
+    memory
+        .log_request(&entry("s1", Some(200), start))
+        .await
+        .unwrap();
+    assert!(
+        memory
+            .request_logs(None, None, 100)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[tokio::test]
+async fn test_timestamps_round_trip_in_utc() {
+    let path = TempDb::new();
+    let db = DatabaseManager::new(path.to_str().unwrap(), &DatabaseConfig::default())
+        .await
+        .unwrap();
+
+    // saved from another offset, read back as the same instant in UTC
+    let timestamp = DateTime::parse_from_rfc3339("2025-01-31T11:30:15.123456+02:00")
+        .unwrap()
+        .with_timezone(&Utc);
+    db.save_message(&ChatMessage {
+        timestamp,
+        ..ChatMessage::new("s1", "hi", "hello")
+    })
+    .await
+    .unwrap();
+    let turns = db.get_session_history("s1").await.unwrap();
+    assert_eq!(turns[0].timestamp, timestamp);
+    assert_eq!(turns[0].timestamp.timestamp(), timestamp.timestamp());
+
+    let DatabasePool::Sqlite(pool) = &db.pool else {
+        unreachable!()
+    };
+    let stored: String = sqlx::query_scalar("SELECT timestamp FROM chat_messages")
+        .fetch_one(pool)
+        .await
+        .unwrap();
+    assert_eq!(stored, "2025-01-31T09:30:15.123456+00:00");
+
+    // API responses carry RFC 3339 strings in UTC
+    let json = serde_json::to_value(&turns[0]).unwrap();
+    assert_eq!(json["timestamp"], "2025-01-31T09:30:15.123456Z");
+    let parsed: ChatMessage = serde_json::from_value(json).unwrap();
+    assert_eq!(parsed.timestamp, timestamp);
+    let search = SearchMatch {
+        session_id: "s1".into(),
+        user_message: "hi".into(),
+        bot_reply: "hello".into(),
+        timestamp: None,
+    };
+    assert!(serde_json::to_value(&search).unwrap()["timestamp"].is_null());
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn test_history_queries_use_indexes() {
+    let path = TempDb::new();
+    let db = DatabaseManager::new(path.to_str().unwrap(), &DatabaseConfig::default())
+        .await
+        .unwrap();
+
+    let messages: Vec<ChatMessage> = (0..2000)
+        .map(|i| ChatMessage::new(&format!("s{}", i % 50), &format!("q{i}"), &format!("a{i}")))
+        .collect();
+    db.save_messages_batch(&messages).await.unwrap();
+
+    let DatabasePool::Sqlite(pool) = &db.pool else {
+        unreachable!()
+    };
+    let indexes: Vec<String> = sqlx::query_scalar(
+        "SELECT name FROM sqlite_master WHERE type = 'index' AND name LIKE 'idx_%'",
+    )
+    .fetch_all(pool)
+    .await
+    .unwrap();
+    assert!(indexes.contains(&"idx_chat_messages_session_timestamp".to_string()));
+    assert!(indexes.contains(&"idx_sessions_updated_at".to_string()));
+
+    let plan = |sql: &'static str, param: &'static str| async move {
+        let rows: Vec<(i64, i64, i64, String)> =
+            sqlx::query_as(&format!("EXPLAIN QUERY PLAN {sql}"))
+                .bind(param)
+                .fetch_all(pool)
+                .await
+                .unwrap();
+        rows.into_iter()
+            .map(|(_, _, _, detail)| detail)
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    for sql in [
+        "SELECT * FROM chat_messages WHERE session_id = ? AND deleted_at IS NULL \
+         ORDER BY timestamp ASC",
+        "SELECT * FROM chat_messages WHERE session_id = ? AND deleted_at IS NULL \
+         ORDER BY timestamp ASC, id ASC LIMIT 10",
+    ] {
+        let plan = plan(sql, "s7").await;
+        // read through the index, already in order
+        assert!(
+            plan.contains("USING INDEX idx_chat_messages_session_timestamp"),
+            "{plan}"
+        );
+        assert!(!plan.contains("TEMP B-TREE"), "{plan}");
+    }
+    let plan = plan(
+        "SELECT * FROM sessions WHERE message_count > 0 AND updated_at >= ? \
+         ORDER BY updated_at DESC, session_id ASC",
+        "2025-01-01T00:00:00+00:00",
+    )
+    .await;
+    assert!(
+        plan.contains("USING INDEX idx_sessions_updated_at"),
+        "{plan}"
+    );
+
+    let start = std::time::Instant::now();
+    assert_eq!(db.get_session_history("s7").await.unwrap().len(), 40);
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn test_migrations() {
+    let path = TempDb::new();
+    let url = format!("sqlite:{}?mode=rwc", path.display());
+
+    // a database from before migrations, its sessions table without metadata
+    {
+        let pool = SqlitePool::connect(&url).await.unwrap();
+        for statement in [
+            "CREATE TABLE chat_messages (id INTEGER PRIMARY KEY AUTOINCREMENT, \
+             session_id TEXT NOT NULL, user_message TEXT NOT NULL, bot_reply TEXT NOT NULL, \
+             timestamp DATETIME NOT NULL)",
+            "CREATE TABLE sessions (session_id TEXT PRIMARY KEY, system_prompt TEXT)",
+            "INSERT INTO chat_messages (session_id, user_message, bot_reply, timestamp) \
+             VALUES ('s1', 'hello', 'hi', '2024-01-01T00:00:00Z')",
+        ] {
+            sqlx::query(statement).execute(&pool).await.unwrap();
+        }
+        pool.close().await;
+    }
+
+    // gets the columns added since, then every migration, and keeps its history
+    let storage =
+        ChatStorage::new_with_database(path.to_str().unwrap(), &DatabaseConfig::default())
+            .await
+            .unwrap();
+    storage
+        .save_turn(ChatMessage::new("s1", "again", "sure"))
+        .await
+        .unwrap();
+    let turns = storage.get_session_turns("s1").await.unwrap();
+    assert_eq!(
+        turns
+            .iter()
+            .map(|turn| turn.user_message.as_str())
+            .collect::<Vec<_>>(),
+        ["hello", "again"]
+    );
+    assert_eq!(
+        storage.search_messages("hello", None).await.unwrap().len(),
+        1
+    );
+    storage.close().await.unwrap();
+
+    // each migration is recorded once, so opening the database again applies none
+    let storage =
+        ChatStorage::new_with_database(path.to_str().unwrap(), &DatabaseConfig::default())
+            .await
+            .unwrap();
+    storage.close().await.unwrap();
+    let pool = SqlitePool::connect(&url).await.unwrap();
+    let applied: Vec<i64> =
+        sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success ORDER BY version")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+    let expected: Vec<i64> = SQLITE_MIGRATOR
+        .iter()
+        .map(|migration| migration.version)
+        .collect();
+    assert_eq!(applied, expected);
+    pool.close().await;
+}
+
+#[tokio::test]
+async fn test_memory_turns() {
+    let lines = |lines: &[&str]| {
+        lines
+            .iter()
+            .map(|line| line.to_string())
+            .collect::<Vec<_>>()
+    };
+    let pairs = |lines: &[String]| {
+        memory_turns(lines)
+            .into_iter()
+            .map(|(_, pair)| pair)
+            .collect::<Vec<_>>()
+    };
+    let pair = |user: &str, bot: &str| (user.to_string(), bot.to_string());
+
+    // replies in a row, a user message without one and an odd number of lines keep their roles
+    let irregular = lines(&[
+        "User: a", "Bot: b", "Bot: c", "User: d", "User: e", "Bot: f", "User: g",
+    ]);
+    assert_eq!(
+        pairs(&irregular),
+        [
+            pair("a", "b"),
+            pair("", "c"),
+            pair("d", ""),
+            pair("e", "f"),
+            pair("g", "")
+        ]
+    );
+    assert_eq!(
+        pairs(&lines(&["Bot: x", "User: y", "Bot: z"])),
+        [pair("", "x"), pair("y", "z")]
+    );
+    assert_eq!(
+        pairs(&lines(&["plain", "Bot: reply"])),
+        [pair("plain", "reply")]
+    );
+    assert!(pairs(&[]).is_empty());
+
+    // ids follow the turns, not the line count
+    let storage = ChatStorage::new_memory_only(&StorageConfig::default());
+    storage
+        .memory_fallback
+        .lock()
+        .await
+        .insert("s1".to_string(), irregular);
+    assert_eq!(storage.get_session_pairs("s1").await.unwrap().len(), 5);
+    assert!(storage.delete_message("s1", 2).await.unwrap());
+    assert_eq!(
+        storage.get_session_pairs("s1").await.unwrap()[1],
+        pair("d", "")
+    );
+    assert_eq!(storage.truncate_after("s1", 2, false).await.unwrap(), 2);
+    assert_eq!(
+        storage.get_session_pairs("s1").await.unwrap(),
+        [pair("a", "b"), pair("d", "")]
+    );
+    assert_eq!(storage.prune_session("s1", 1).await.unwrap(), 1);
+    assert_eq!(
+        storage.get_session_pairs("s1").await.unwrap(),
+        [pair("d", "")]
+    );
+}
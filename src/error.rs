@@ -1,4 +1,14 @@
-use axum::{Json, http::StatusCode, response::IntoResponse};
+use axum::{
+    Json,
+    body::Body,
+    extract::Request,
+    http::{
+        StatusCode,
+        header::{CONTENT_LENGTH, CONTENT_TYPE},
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
 use thiserror::Error;
 
 pub type ServerResult<T> = std::result::Result<T, ServerError>;
@@ -13,6 +23,38 @@ pub enum ServerError {
     NotFoundServer(String),
     #[error("Invalid server kind: {0}")]
     InvalidServerKind(String),
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("Not found: {0}")]
+    NotFound(String),
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    #[error("No healthy {0} server available")]
+    NoServerAvailable(String),
+    #[error("Downstream server rejected the request with {0}: {1}")]
+    UpstreamRejected(u16, String),
+    #[error("Downstream server failed with {0}: {1}")]
+    UpstreamFailed(u16, String),
+    #[error("Downstream server returned an error: {0}")]
+    UpstreamError(String),
+    #[error("Downstream server returned a malformed response: {0}")]
+    UpstreamMalformed(String),
+    #[error("Too many concurrent requests: {0}")]
+    Overloaded(String),
+    #[error("Downstream server is unreachable: {0}")]
+    Unreachable(String),
+    #[error("Downstream server timed out: {0}")]
+    Timeout(String),
+    #[error("Message flagged by moderation: {0}")]
+    Flagged(String),
+    #[error("Rate limit exceeded: {0}")]
+    RateLimited(String),
+    #[error("Payload too large: {0}")]
+    PayloadTooLarge(String),
+    #[error("Not implemented: {0}")]
+    NotImplemented(String),
     #[error("Failed to load config: {0}")]
     FailedToLoadConfig(String),
     #[error("Mcp server returned empty content")]
@@ -22,24 +64,348 @@ pub enum ServerError {
     #[error("Mcp operation failed: {0}")]
     McpOperation(String),
 }
+impl ServerError {
+    /// Status code and OpenAI-style error `type` of the response
+    fn status_and_type(&self) -> (StatusCode, &'static str) {
+        match self {
+            ServerError::Operation(_) => (StatusCode::INTERNAL_SERVER_ERROR, "server_error"),
+            ServerError::NotFoundServer(_)
+            | ServerError::NoServerAvailable(_)
+            | ServerError::Unreachable(_)
+            | ServerError::Overloaded(_) => {
+                (StatusCode::SERVICE_UNAVAILABLE, "service_unavailable")
+            }
+            ServerError::InvalidServerKind(_)
+            | ServerError::InvalidRequest(_)
+            | ServerError::Flagged(_) => (StatusCode::BAD_REQUEST, "invalid_request_error"),
+            ServerError::NotFound(_) => (StatusCode::NOT_FOUND, "not_found_error"),
+            ServerError::Unauthorized(_) => (StatusCode::UNAUTHORIZED, "authentication_error"),
+            ServerError::Forbidden(_) => (StatusCode::FORBIDDEN, "permission_error"),
+            // the request was valid for this server but not for the downstream one
+            ServerError::UpstreamRejected(..) => (StatusCode::BAD_GATEWAY, "upstream_error"),
+            ServerError::UpstreamFailed(503, _) => {
+                (StatusCode::SERVICE_UNAVAILABLE, "upstream_error")
+            }
+            ServerError::UpstreamFailed(..) => (StatusCode::BAD_GATEWAY, "upstream_error"),
+            // the server answered with a success status but no usable completion
+            ServerError::UpstreamError(_) | ServerError::UpstreamMalformed(_) => {
+                (StatusCode::BAD_GATEWAY, "upstream_error")
+            }
+            ServerError::Timeout(_) => (StatusCode::GATEWAY_TIMEOUT, "timeout_error"),
+            ServerError::RateLimited(_) => (StatusCode::TOO_MANY_REQUESTS, "rate_limit_error"),
+            ServerError::PayloadTooLarge(_) => {
+                (StatusCode::PAYLOAD_TOO_LARGE, "invalid_request_error")
+            }
+            ServerError::NotImplemented(_) => {
+                (StatusCode::NOT_IMPLEMENTED, "invalid_request_error")
+            }
+            ServerError::FailedToLoadConfig(_) => {
+                (StatusCode::BAD_REQUEST, "invalid_request_error")
+            }
+            ServerError::McpEmptyContent
+            | ServerError::McpNotFoundClient
+            | ServerError::McpOperation(_) => (StatusCode::INTERNAL_SERVER_ERROR, "server_error"),
+        }
+    }
+}
+impl ServerError {
+    /// Whether the error means no downstream server could take the request, as opposed to the
+    /// request or a reply being wrong
+    pub(crate) fn is_unavailable(&self) -> bool {
+        self.status_and_type().0 == StatusCode::SERVICE_UNAVAILABLE
+    }
+
+    /// OpenAI-style error body, `{"error": {"message", "type", "code"}}`, `code` being the HTTP
+    /// status of the response
+    pub(crate) fn body(&self) -> serde_json::Value {
+        let (status, ty) = self.status_and_type();
+        let message = match self {
+            ServerError::Operation(e)
+            | ServerError::InvalidServerKind(e)
+            | ServerError::InvalidRequest(e)
+            | ServerError::NotFound(e)
+            | ServerError::Unauthorized(e)
+            | ServerError::Forbidden(e)
+            | ServerError::Timeout(e)
+            | ServerError::RateLimited(e)
+            | ServerError::PayloadTooLarge(e)
+            | ServerError::NotImplemented(e)
+            | ServerError::FailedToLoadConfig(e)
+            | ServerError::McpOperation(e) => e.to_string(),
+            _ => self.to_string(),
+        };
+
+        error_body(&message, ty, status)
+    }
+}
 impl IntoResponse for ServerError {
     fn into_response(self) -> axum::response::Response {
-        let (status, err_response) = match &self {
-            ServerError::Operation(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
-            ServerError::NotFoundServer(e) => (StatusCode::NOT_FOUND, e.to_string()),
-            ServerError::InvalidServerKind(e) => (StatusCode::BAD_REQUEST, e.to_string()),
-            ServerError::FailedToLoadConfig(e) => (StatusCode::BAD_REQUEST, e.to_string()),
-            ServerError::McpEmptyContent => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Mcp server returned empty content".to_string(),
-            ),
-            ServerError::McpNotFoundClient => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Mcp server not found".to_string(),
-            ),
-            ServerError::McpOperation(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
-        };
+        let (status, ty) = self.status_and_type();
+        metrics::counter!(crate::telemetry::ERRORS_TOTAL, "type" => ty).increment(1);
+        (status, Json(self.body())).into_response()
+    }
+}
+
+fn error_body(message: &str, ty: &str, status: StatusCode) -> serde_json::Value {
+    serde_json::json!({
+        "error": {
+            "message": message,
+            "type": ty,
+            "code": status.as_u16(),
+        }
+    })
+}
+
+/// Error `type` of a response with `status` that was not built from a [`ServerError`]
+fn status_type(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::UNAUTHORIZED => "authentication_error",
+        StatusCode::FORBIDDEN => "permission_error",
+        StatusCode::NOT_FOUND => "not_found_error",
+        StatusCode::TOO_MANY_REQUESTS => "rate_limit_error",
+        StatusCode::SERVICE_UNAVAILABLE => "service_unavailable",
+        StatusCode::GATEWAY_TIMEOUT => "timeout_error",
+        status if status.is_server_error() => "server_error",
+        _ => "invalid_request_error",
+    }
+}
+
+/// Longest body of a plain error response kept as the message of its JSON body
+const MAX_ERROR_MESSAGE_BYTES: usize = 4096;
+
+/// Extension marking a response proxied from a downstream server, returned to the client as is
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Proxied;
 
-        (status, Json(err_response)).into_response()
+/// Gives the error responses without a JSON body, such as the rejections of axum extractors
+/// and unknown routes, the body of a [`ServerError`]; the text of the original body, if any,
+/// becomes the message. [`Proxied`] responses keep the body of their downstream server.
+pub(crate) async fn json_errors(req: Request<Body>, next: Next) -> Response {
+    let response = next.run(req).await;
+    if response.extensions().get::<Proxied>().is_some() {
+        return response;
     }
+    let status = response.status();
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    if !(status.is_client_error() || status.is_server_error()) || is_json {
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let text = axum::body::to_bytes(body, MAX_ERROR_MESSAGE_BYTES)
+        .await
+        .map(|bytes| String::from_utf8_lossy(&bytes).trim().to_string())
+        .unwrap_or_default();
+    let message = match text.is_empty() {
+        true => status.canonical_reason().unwrap_or("Error").to_string(),
+        false => text,
+    };
+    let ty = status_type(status);
+    metrics::counter!(crate::telemetry::ERRORS_TOTAL, "type" => ty).increment(1);
+    // other headers, e.g. `Retry-After` or `WWW-Authenticate`, still apply
+    let mut response = (status, Json(error_body(&message, ty, status))).into_response();
+    for (name, value) in &parts.headers {
+        if name != CONTENT_TYPE && name != CONTENT_LENGTH {
+            response.headers_mut().append(name, value.clone());
+        }
+    }
+    response
+}
+
+#[tokio::test]
+async fn test_error_response() {
+    let cases = [
+        (
+            ServerError::UpstreamRejected(400, "bad model".into()),
+            StatusCode::BAD_GATEWAY,
+        ),
+        (
+            ServerError::UpstreamFailed(500, "oops".into()),
+            StatusCode::BAD_GATEWAY,
+        ),
+        (
+            ServerError::UpstreamFailed(503, "loading".into()),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ),
+        (
+            ServerError::UpstreamError("model not loaded".into()),
+            StatusCode::BAD_GATEWAY,
+        ),
+        (
+            ServerError::UpstreamMalformed("missing choices".into()),
+            StatusCode::BAD_GATEWAY,
+        ),
+        (
+            ServerError::Timeout("slow".into()),
+            StatusCode::GATEWAY_TIMEOUT,
+        ),
+        (
+            ServerError::NoServerAvailable("chat".into()),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ),
+        (
+            ServerError::Unreachable("connection refused".into()),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ),
+        (
+            ServerError::Overloaded("busy".into()),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ),
+        (
+            ServerError::InvalidRequest("no".into()),
+            StatusCode::BAD_REQUEST,
+        ),
+        (ServerError::Flagged("hate".into()), StatusCode::BAD_REQUEST),
+        (
+            ServerError::Unauthorized("expired".into()),
+            StatusCode::UNAUTHORIZED,
+        ),
+        (
+            ServerError::Forbidden("admin".into()),
+            StatusCode::FORBIDDEN,
+        ),
+        (
+            ServerError::PayloadTooLarge("long".into()),
+            StatusCode::PAYLOAD_TOO_LARGE,
+        ),
+        (
+            ServerError::NotImplemented("zip".into()),
+            StatusCode::NOT_IMPLEMENTED,
+        ),
+    ];
+    for (err, status) in cases {
+        assert_eq!(err.clone().into_response().status(), status, "{err}");
+    }
+
+    let response = ServerError::UpstreamRejected(400, "unknown model".into()).into_response();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["error"]["type"], "upstream_error");
+    assert_eq!(body["error"]["code"], 502);
+    assert_eq!(
+        body["error"]["message"],
+        "Downstream server rejected the request with 400: unknown model"
+    );
+}
+
+#[tokio::test]
+async fn test_json_errors() {
+    use tower::ServiceExt;
+
+    let app = axum::Router::new()
+        .route(
+            "/items/{id}",
+            axum::routing::get(
+                |axum::extract::Path(id): axum::extract::Path<u32>| async move { id.to_string() },
+            ),
+        )
+        .route("/gone", axum::routing::get(|| async { StatusCode::GONE }))
+        .route(
+            "/typed",
+            axum::routing::get(|| async { ServerError::NotFound("no such item".into()) }),
+        )
+        .layer(axum::middleware::from_fn(json_errors));
+    let get = |uri: &str| {
+        let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        app.clone().oneshot(request)
+    };
+    let body = |response: Response| async move {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
+    };
+
+    // successes are left alone
+    let response = get("/items/7").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap(),
+        "7"
+    );
+
+    // an extractor rejection keeps its text as the message
+    let response = get("/items/seven").await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error = body(response).await;
+    assert_eq!(
+        (
+            error["error"]["type"].as_str(),
+            error["error"]["code"].as_u64()
+        ),
+        (Some("invalid_request_error"), Some(400))
+    );
+    assert!(
+        error["error"]["message"]
+            .as_str()
+            .unwrap()
+            .contains("Cannot parse"),
+        "{error}"
+    );
+
+    // a bare status and an unknown route get the reason of their status
+    let error = body(get("/gone").await.unwrap()).await;
+    assert_eq!(error["error"]["message"], "Gone");
+    let response = get("/nowhere").await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(body(response).await["error"]["type"], "not_found_error");
+
+    // an error body is not wrapped twice
+    let error = body(get("/typed").await.unwrap()).await;
+    assert_eq!(error["error"]["message"], "no such item");
+    assert_eq!(error["error"]["code"], 404);
+}
+
+#[tokio::test]
+async fn test_json_errors_keep_proxied_errors() {
+    use crate::routes::responses::{mock_chat_server, state_with_chat_server};
+    use tower::ServiceExt;
+
+    // a chat server failing with a plain text body
+    let downstream = axum::Router::new().route(
+        "/v1/chat/completions",
+        axum::routing::post(|| async {
+            (
+                StatusCode::BAD_GATEWAY,
+                [(CONTENT_TYPE, "text/plain")],
+                "model crashed\n",
+            )
+        }),
+    );
+    let state = state_with_chat_server(&mock_chat_server(downstream).await).await;
+    let app = axum::Router::new()
+        .route(
+            "/v1/chat/completions",
+            axum::routing::post(crate::handlers::chat_handler),
+        )
+        .layer(axum::Extension(tokio_util::sync::CancellationToken::new()))
+        .layer(axum::middleware::from_fn(json_errors))
+        .with_state(state);
+
+    // the passthrough returns the error of the chat server byte for byte
+    let request = Request::builder()
+        .method("POST")
+        .uri("/v1/chat/completions")
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            r#"{"model": "m", "messages": [{"role": "user", "content": "hi"}]}"#,
+        ))
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    assert_eq!(response.headers()[CONTENT_TYPE], "text/plain");
+    assert_eq!(
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap(),
+        "model crashed\n"
+    );
 }
@@ -1,10 +1,14 @@
-use std::{sync::Arc, time::SystemTime};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
 
 use axum::{
     Json,
     body::Body,
-    extract::{Extension, State},
+    extract::{Extension, Path, State},
     http::{HeaderMap, Response, StatusCode},
+    response::IntoResponse,
 };
 use bytes::Bytes;
 use endpoints::{
@@ -21,13 +25,18 @@ use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
 use rmcp::model::{CallToolRequestParam, RawContent};
 use tokio::select;
 use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 
 use crate::{
     AppState, dual_debug, dual_error, dual_info, dual_warn,
-    error::{ServerError, ServerResult},
+    error::{Proxied, ServerError, ServerResult},
     info::ApiServer,
     mcp::{DEFAULT_SEARCH_FALLBACK_MESSAGE, MCP_SERVICES, MCP_TOOLS, SEARCH_MCP_SERVER_NAMES},
-    server::{RoutingPolicy, Server, ServerIdToRemove, ServerKind, TargetServerInfo},
+    server::{
+        RoutingPolicy, Server, ServerAuthUpdate, ServerId, ServerIdToRemove, ServerKind,
+        TargetServerInfo,
+    },
+    telemetry,
 };
 
 pub(crate) async fn chat_handler(
@@ -137,24 +146,45 @@ pub(crate) async fn chat(
 ) -> ServerResult<axum::response::Response> {
     let request_id = request_id.as_ref();
 
-    // Get target server
-    let chat_server = get_chat_server(&state, request_id).await?;
-
-    // Send request and handle response
-    let response = send_request_with_retry(
-        &chat_server,
-        &mut request,
-        &headers,
-        request_id,
-        cancel_token.clone(),
-    )
-    .await?;
+    // Send the request, failing over to the next healthy chat server if a server cannot be reached
+    let max_attempts = state.config.read().await.health.max_attempts.max(1);
+    let mut attempt = 1;
+    let (chat_server, response) = loop {
+        let chat_server = get_chat_server(&state, request_id).await?;
+
+        match send_request_with_retry(
+            &state.http_client,
+            &chat_server,
+            &mut request,
+            &headers,
+            request_id,
+            cancel_token.clone(),
+        )
+        .await
+        {
+            Ok(response) => break (chat_server, response),
+            // a connection failure quarantines the server, so `next()` skips it on the retry
+            Err(_) if attempt < max_attempts && !chat_server.health.is_available() => {
+                dual_warn!(
+                    "Chat server {} is unreachable, retrying with the next server ({}/{}) - \
+                     request_id: {}",
+                    chat_server.id,
+                    attempt,
+                    max_attempts,
+                    request_id
+                );
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    };
 
     // Handle response based on stream mode
     match request.stream {
         Some(true) => {
             // Handle stream response
             handle_stream_response(
+                &state.http_client,
                 response,
                 &mut request,
                 &headers,
@@ -167,6 +197,7 @@ pub(crate) async fn chat(
         Some(false) | None => {
             // Handle non-stream response
             handle_non_stream_response(
+                &state.http_client,
                 response,
                 &mut request,
                 &headers,
@@ -199,9 +230,10 @@ pub(crate) async fn embeddings_handler(
 
     // get the embeddings server
     let servers = state.server_group.read().await;
+    // the group outlives its last server, so an empty group means none is registered either
     let embeddings_servers = match servers.get(&ServerKind::embeddings) {
-        Some(servers) => servers,
-        None => {
+        Some(servers) if !servers.is_empty().await => servers,
+        _ => {
             let err_msg = "No embeddings server available";
             dual_error!("{} - request_id: {}", err_msg, request_id);
             return Err(ServerError::Operation(err_msg.to_string()));
@@ -216,8 +248,7 @@ pub(crate) async fn embeddings_handler(
             return Err(ServerError::Operation(err_msg));
         }
     };
-    let embeddings_service_url =
-        format!("{}/embeddings", embedding_server.url.trim_end_matches('/'));
+    let embeddings_service_url = embedding_server.embeddings_url.clone();
     dual_info!(
         "Forward the embeddings request to {} - request_id: {}",
         embeddings_service_url,
@@ -241,13 +272,12 @@ pub(crate) async fn embeddings_handler(
     );
 
     // Create request client
-    let ds_request = if let Some(api_key) = &embedding_server.api_key
-        && !api_key.is_empty()
-    {
-        reqwest::Client::new()
+    let ds_request = if let Some((name, value)) = &embedding_server.auth {
+        state
+            .http_client
             .post(embeddings_service_url)
             .header("Content-Type", content_type)
-            .header(AUTHORIZATION, api_key)
+            .header(name, value)
             .json(&request)
     } else if headers.contains_key("authorization") {
         let authorization = headers
@@ -257,13 +287,15 @@ pub(crate) async fn embeddings_handler(
             .unwrap()
             .to_string();
 
-        reqwest::Client::new()
+        state
+            .http_client
             .post(embeddings_service_url)
             .header("Content-Type", content_type)
             .header("Authorization", authorization)
             .json(&request)
     } else {
-        reqwest::Client::new()
+        state
+            .http_client
             .post(embeddings_service_url)
             .header("Content-Type", content_type)
             .json(&request)
@@ -376,11 +408,9 @@ pub(crate) async fn audio_transcriptions_handler(
     );
 
     // Create request client
-    let mut ds_request = reqwest::Client::new().post(transcription_server_url);
-    if let Some(api_key) = &transcription_server.api_key
-        && !api_key.is_empty()
-    {
-        ds_request = ds_request.header(AUTHORIZATION, api_key);
+    let mut ds_request = state.http_client.post(transcription_server_url);
+    if let Some((name, value)) = &transcription_server.auth {
+        ds_request = ds_request.header(name, value);
     }
     for (name, value) in req.headers().iter() {
         ds_request = ds_request.header(name, value);
@@ -503,11 +533,9 @@ pub(crate) async fn audio_translations_handler(
     );
 
     // Create request client
-    let mut ds_request = reqwest::Client::new().post(translation_server_url);
-    if let Some(api_key) = &translation_server.api_key
-        && !api_key.is_empty()
-    {
-        ds_request = ds_request.header(AUTHORIZATION, api_key);
+    let mut ds_request = state.http_client.post(translation_server_url);
+    if let Some((name, value)) = &translation_server.auth {
+        ds_request = ds_request.header(name, value);
     }
     for (name, value) in req.headers().iter() {
         ds_request = ds_request.header(name, value);
@@ -627,11 +655,9 @@ pub(crate) async fn audio_tts_handler(
     );
 
     // Create request client
-    let mut ds_request = reqwest::Client::new().post(tts_server_url);
-    if let Some(api_key) = &tts_server.api_key
-        && !api_key.is_empty()
-    {
-        ds_request = ds_request.header(AUTHORIZATION, api_key);
+    let mut ds_request = state.http_client.post(tts_server_url);
+    if let Some((name, value)) = &tts_server.auth {
+        ds_request = ds_request.header(name, value);
     }
     for (name, value) in req.headers().iter() {
         ds_request = ds_request.header(name, value);
@@ -750,11 +776,9 @@ pub(crate) async fn image_handler(
     );
 
     // Create request client
-    let mut ds_request = reqwest::Client::new().post(image_server_url);
-    if let Some(api_key) = &image_server.api_key
-        && !api_key.is_empty()
-    {
-        ds_request = ds_request.header(AUTHORIZATION, api_key);
+    let mut ds_request = state.http_client.post(image_server_url);
+    if let Some((name, value)) = &image_server.auth {
+        ds_request = ds_request.header(name, value);
     }
     for (name, value) in req.headers().iter() {
         ds_request = ds_request.header(name, value);
@@ -826,6 +850,73 @@ pub(crate) async fn image_handler(
     }
 }
 
+/// Entry of `GET /v1/models`: an OpenAI model object with what the servers serving it declare
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct ModelEntry {
+    #[serde(flatten)]
+    pub(crate) model: Model,
+    /// Kinds and tags of the servers serving the model, sorted
+    pub(crate) capabilities: Vec<String>,
+    /// Largest context length, in tokens, declared by a server serving the model
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) context_length: Option<u64>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct ModelList {
+    object: &'static str,
+    data: Vec<ModelEntry>,
+}
+
+/// Models served by the downstream servers, once each and sorted by id
+pub(crate) async fn list_models(state: &AppState) -> ServerResult<Vec<ModelEntry>> {
+    let servers: HashMap<ServerId, Server> = state
+        .list_downstream_servers()
+        .await?
+        .into_values()
+        .flatten()
+        .map(|server| (server.id.clone(), server))
+        .collect();
+
+    let mut entries: BTreeMap<String, ModelEntry> = BTreeMap::new();
+    for (server_id, models) in state.models.read().await.iter() {
+        for model in models {
+            let entry = entries
+                .entry(model.id.clone())
+                .or_insert_with(|| ModelEntry {
+                    model: model.clone(),
+                    capabilities: vec![],
+                    context_length: None,
+                });
+            let Some(server) = servers.get(server_id) else {
+                continue;
+            };
+            let kinds = server.kind.to_string();
+            for tag in kinds
+                .split(',')
+                .chain(server.tags.iter().map(String::as_str))
+            {
+                if !entry
+                    .capabilities
+                    .iter()
+                    .any(|own| own.eq_ignore_ascii_case(tag))
+                {
+                    entry.capabilities.push(tag.to_string());
+                }
+            }
+            entry.context_length = entry.context_length.max(server.context_length);
+        }
+    }
+
+    Ok(entries
+        .into_values()
+        .map(|mut entry| {
+            entry.capabilities.sort();
+            entry
+        })
+        .collect())
+}
+
 pub(crate) async fn models_handler(
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
@@ -836,10 +927,9 @@ pub(crate) async fn models_handler(
         .unwrap_or("unknown")
         .to_string();
 
-    let models = state.models.read().await;
-    let list_response = ListModelsResponse {
-        object: String::from("list"),
-        data: models.values().flatten().cloned().collect(),
+    let list_response = ModelList {
+        object: "list",
+        data: list_models(&state).await?,
     };
 
     let json_body = serde_json::to_string(&list_response).map_err(|e| {
@@ -919,13 +1009,50 @@ pub(crate) async fn info_handler(
         })
 }
 
+/// `GET /healthz`: 200 while the gateway serves requests, whatever the state of its downstream
+/// servers and database
+pub(crate) async fn liveness_handler() -> &'static str {
+    "OK"
+}
+
+/// `GET /readyz`: 200 if a chat server is available and the database, if any, answers a
+/// `SELECT 1`; 503 otherwise. The body reports both checks.
+pub(crate) async fn readiness_handler(
+    State(state): State<Arc<AppState>>,
+) -> axum::response::Response {
+    let chat_server = match state.server_group.read().await.get(&ServerKind::chat) {
+        Some(group) => group.has_available_server().await,
+        None => false,
+    };
+    let database = match state.chat_storage.ping().await {
+        Ok(()) => true,
+        Err(e) => {
+            dual_warn!("Readiness check failed to reach the database: {e}");
+            false
+        }
+    };
+
+    let status = if chat_server && database {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let json_body = serde_json::json!({
+        "ready": status == StatusCode::OK,
+        "chat_server": chat_server,
+        "database": database,
+    });
+
+    (status, Json(json_body)).into_response()
+}
+
 pub(crate) mod admin {
     use super::*;
 
     pub(crate) async fn register_downstream_server_handler(
         State(state): State<Arc<AppState>>,
         headers: HeaderMap,
-        Json(mut server): Json<Server>,
+        Json(server): Json<Server>,
     ) -> ServerResult<axum::response::Response> {
         // Get request ID from headers
         let request_id = headers
@@ -945,6 +1072,7 @@ pub(crate) mod admin {
             || server_kind.contains(ServerKind::transcribe)
             || server_kind.contains(ServerKind::translate)
             || server_kind.contains(ServerKind::tts)
+            || server_kind.contains(ServerKind::moderation)
         {
             dual_warn!(
                 "Ignore the server verification for: {server_id} - request_id: {request_id}"
@@ -956,8 +1084,7 @@ pub(crate) mod admin {
         update_model_list(State(state.clone()), &headers, &request_id, &server).await?;
 
         // update health status of the server
-        server.health_status.is_healthy = true;
-        server.health_status.last_check = SystemTime::now();
+        server.health_status.record_success();
 
         // register the server
         state.register_downstream_server(server).await?;
@@ -1001,14 +1128,12 @@ pub(crate) mod admin {
 
         let server_info_url = format!("{server_url}/info");
 
-        let client = reqwest::Client::new();
-        let response = if let Some(api_key) = &server.api_key
-            && !api_key.is_empty()
-        {
+        let client = &state.http_client;
+        let response = if let Some((name, value)) = server.auth() {
             client
                 .get(&server_info_url)
                 .header(CONTENT_TYPE, "application/json")
-                .header(AUTHORIZATION, api_key)
+                .header(name, value)
                 .send()
                 .await
                 .map_err(|e| {
@@ -1123,13 +1248,12 @@ pub(crate) mod admin {
         // get the models from the downstream server
         let list_models_url = format!("{server_url}/models");
         dual_debug!("list_models_url: {}", list_models_url);
-        let response = if let Some(api_key) = &server.api_key
-            && !api_key.is_empty()
-        {
-            reqwest::Client::new()
+        let response = if let Some((name, value)) = server.auth() {
+            state
+                .http_client
                 .get(&list_models_url)
                 .header(CONTENT_TYPE, "application/json")
-                .header(AUTHORIZATION, api_key)
+                .header(name, value)
                 .send()
                 .await
                 .map_err(|e| {
@@ -1145,7 +1269,8 @@ pub(crate) mod admin {
                 .to_str()
                 .unwrap()
                 .to_string();
-            reqwest::Client::new()
+            state
+                .http_client
                 .get(&list_models_url)
                 .header(CONTENT_TYPE, "application/json")
                 .header(AUTHORIZATION, authorization)
@@ -1158,7 +1283,8 @@ pub(crate) mod admin {
                     ServerError::Operation(err_msg)
                 })?
         } else {
-            reqwest::Client::new()
+            state
+                .http_client
                 .get(&list_models_url)
                 .send()
                 .await
@@ -1206,8 +1332,7 @@ pub(crate) mod admin {
                             .collect::<Vec<Model>>();
 
                         // update the models
-                        let mut models = state.models.write().await;
-                        models.insert(server_id.to_string(), model_info_vec);
+                        state.set_server_models(server_id, model_info_vec).await;
                     }
                     None => {
                         let err_msg = format!(
@@ -1228,8 +1353,9 @@ pub(crate) mod admin {
                     })?;
 
                 // update the models
-                let mut models = state.models.write().await;
-                models.insert(server_id.to_string(), list_models_response.data);
+                state
+                    .set_server_models(server_id, list_models_response.data)
+                    .await;
             }
         }
 
@@ -1240,6 +1366,23 @@ pub(crate) mod admin {
         State(state): State<Arc<AppState>>,
         headers: HeaderMap,
         Json(server_id): Json<ServerIdToRemove>,
+    ) -> ServerResult<axum::response::Response> {
+        remove_downstream_server(state, &headers, server_id.server_id).await
+    }
+
+    /// `DELETE /admin/servers/{server_id}`; 404 if no server has the id
+    pub(crate) async fn remove_downstream_server_by_id_handler(
+        State(state): State<Arc<AppState>>,
+        headers: HeaderMap,
+        Path(server_id): Path<String>,
+    ) -> ServerResult<axum::response::Response> {
+        remove_downstream_server(state, &headers, server_id).await
+    }
+
+    async fn remove_downstream_server(
+        state: Arc<AppState>,
+        headers: &HeaderMap,
+        server_id: String,
     ) -> ServerResult<axum::response::Response> {
         // Get request ID from headers
         let request_id = headers
@@ -1248,14 +1391,12 @@ pub(crate) mod admin {
             .unwrap_or("unknown")
             .to_string();
 
-        state
-            .unregister_downstream_server(&server_id.server_id)
-            .await?;
+        state.unregister_downstream_server(&server_id).await?;
 
         // create a response with status code 200. Content-Type is JSON
         let json_body = serde_json::json!({
             "message": "Server unregistered successfully.",
-            "id": server_id.server_id,
+            "id": server_id,
         });
 
         let response = Response::builder()
@@ -1271,6 +1412,155 @@ pub(crate) mod admin {
         Ok(response)
     }
 
+    /// `PATCH /admin/servers/{server_id}`: rotates the `api_key` of a server or changes its
+    /// `auth_header` and `auth_format`; 404 if no server has the id
+    pub(crate) async fn update_downstream_server_auth_handler(
+        State(state): State<Arc<AppState>>,
+        headers: HeaderMap,
+        Path(server_id): Path<String>,
+        Json(update): Json<ServerAuthUpdate>,
+    ) -> ServerResult<axum::response::Response> {
+        // Get request ID from headers
+        let request_id = headers
+            .get("x-request-id")
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("unknown")
+            .to_string();
+
+        let server = state
+            .update_downstream_server_auth(&server_id, &update)
+            .await?;
+
+        // the key itself is not echoed back
+        let json_body = serde_json::json!({
+            "message": "Server updated successfully.",
+            "id": server.id,
+            "auth_header": server.auth_header,
+            "auth_format": server.auth_format,
+        });
+
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json_body.to_string()))
+            .map_err(|e| {
+                let err_msg = format!("Failed to create response: {e}");
+                dual_error!("{err_msg} - request_id: {request_id}");
+                ServerError::Operation(err_msg)
+            })?;
+
+        Ok(response)
+    }
+
+    pub(crate) async fn flush_memory_handler(
+        State(state): State<Arc<AppState>>,
+        headers: HeaderMap,
+    ) -> ServerResult<axum::response::Response> {
+        // Get request ID from headers
+        let request_id = headers
+            .get("x-request-id")
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("unknown")
+            .to_string();
+
+        let flushed = state
+            .chat_storage
+            .flush_memory_to_database()
+            .await
+            .map_err(|e| {
+                let err_msg = format!("Failed to flush the in-memory chat history: {e}");
+                dual_error!("{err_msg} - request_id: {request_id}");
+                ServerError::Operation(err_msg)
+            })?;
+        dual_info!(
+            "Flushed {} in-memory turn(s) to the database - request_id: {}",
+            flushed,
+            request_id
+        );
+
+        let json_body = serde_json::json!({
+            "flushed": flushed,
+        });
+
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json_body.to_string()))
+            .map_err(|e| {
+                let err_msg = format!("Failed to create response: {e}");
+                dual_error!("{err_msg} - request_id: {request_id}");
+                ServerError::Operation(err_msg)
+            })?;
+
+        Ok(response)
+    }
+
+    /// `GET /admin/routing`: the default routing strategy and the strategy of each server kind
+    pub(crate) async fn routing_policies_handler(
+        State(state): State<Arc<AppState>>,
+    ) -> axum::response::Response {
+        let default = state.config.read().await.routing.policy;
+        let policies = state.routing_policies().await;
+
+        Json(serde_json::json!({ "default": default, "policies": policies })).into_response()
+    }
+
+    /// `POST /admin/maintenance`: runs the maintenance jobs enabled in `[maintenance]` now and
+    /// returns what they did; a failed job is listed in `errors`
+    pub(crate) async fn run_maintenance_handler(
+        State(state): State<Arc<AppState>>,
+    ) -> axum::response::Response {
+        let report = crate::maintenance::run(&state).await;
+
+        Json(report).into_response()
+    }
+
+    /// Requests returned by `/admin/logs` when no `limit` is given
+    const DEFAULT_REQUEST_LOG_LIMIT: i64 = 100;
+    /// Most requests returned by `/admin/logs` at once
+    const MAX_REQUEST_LOG_LIMIT: i64 = 1000;
+
+    #[derive(Debug, serde::Deserialize)]
+    pub(crate) struct RequestLogQuery {
+        #[serde(default)]
+        session_id: Option<String>,
+        /// Only requests sent at or after this time
+        #[serde(default)]
+        since: Option<chrono::DateTime<chrono::Utc>>,
+        #[serde(default)]
+        limit: Option<i64>,
+    }
+
+    /// `GET /admin/logs`: the requests sent to downstream chat servers, oldest first
+    pub(crate) async fn list_request_logs_handler(
+        State(state): State<Arc<AppState>>,
+        headers: HeaderMap,
+        axum::extract::Query(query): axum::extract::Query<RequestLogQuery>,
+    ) -> ServerResult<axum::response::Response> {
+        // Get request ID from headers
+        let request_id = headers
+            .get("x-request-id")
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("unknown")
+            .to_string();
+
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_REQUEST_LOG_LIMIT)
+            .clamp(1, MAX_REQUEST_LOG_LIMIT);
+        let logs = state
+            .chat_storage
+            .request_logs(query.session_id.as_deref(), query.since, limit)
+            .await
+            .map_err(|e| {
+                let err_msg = format!("Failed to read the request log: {e}");
+                dual_error!("{err_msg} - request_id: {request_id}");
+                ServerError::Operation(err_msg)
+            })?;
+
+        Ok(Json(serde_json::json!({ "logs": logs })).into_response())
+    }
+
     pub(crate) async fn list_downstream_servers_handler(
         State(state): State<Arc<AppState>>,
         headers: HeaderMap,
@@ -1321,18 +1611,21 @@ async fn get_chat_server(
     let chat_servers = match servers.get(&ServerKind::chat) {
         Some(servers) => servers,
         None => {
-            let err_msg = "No chat server available";
-            dual_error!("{} - request_id: {}", err_msg, request_id);
-            return Err(ServerError::Operation(err_msg.to_string()));
+            let err = ServerError::NotFoundServer(ServerKind::chat.to_string());
+            dual_error!("{} - request_id: {}", err, request_id);
+            return Err(err);
         }
     };
 
     match chat_servers.next().await {
         Ok(target_server_info) => Ok(target_server_info),
         Err(e) => {
-            let err_msg = format!("Failed to get the chat server: {e}");
-            dual_error!("{} - request_id: {}", err_msg, request_id);
-            Err(ServerError::Operation(err_msg))
+            dual_error!(
+                "Failed to get the chat server: {} - request_id: {}",
+                e,
+                request_id
+            );
+            Err(e)
         }
     }
 }
@@ -1349,6 +1642,7 @@ async fn get_chat_server(
 ///
 /// # Arguments
 ///
+/// * `client` - Shared HTTP client of the downstream requests
 /// * `chat_server` - The downstream chat server to send request to
 /// * `request` - Chat completion request, may be modified (e.g., reset tool choice)
 /// * `headers` - HTTP request headers, including authentication info
@@ -1364,6 +1658,7 @@ async fn get_chat_server(
 /// * Other errors: Return error directly, no retry
 /// * Retry logic: Maximum one retry to avoid infinite loops
 async fn send_request_with_retry(
+    client: &reqwest::Client,
     chat_server: &TargetServerInfo,
     request: &mut ChatCompletionRequest,
     headers: &HeaderMap,
@@ -1372,6 +1667,7 @@ async fn send_request_with_retry(
 ) -> ServerResult<reqwest::Response> {
     // First attempt to send request to downstream server
     let response = build_and_send_request(
+        client,
         chat_server,
         request,
         headers,
@@ -1409,6 +1705,7 @@ async fn send_request_with_retry(
 
                         // Re-send with reset request
                         let response = build_and_send_request(
+                            client,
                             chat_server,
                             request,
                             headers,
@@ -1438,13 +1735,14 @@ async fn send_request_with_retry(
 /// Build and send HTTP request to downstream server with cancellation support
 ///
 /// This function implements the following features:
-/// 1. Build HTTP client and set necessary request headers
+/// 1. Build the request on the shared HTTP client and set necessary request headers
 /// 2. Send JSON-formatted chat completion request to downstream server
 /// 3. Support cancellation of ongoing requests via CancellationToken
 /// 4. Provide detailed error information and cancellation logs
 ///
 /// # Arguments
 ///
+/// * `client` - Shared HTTP client of the downstream requests
 /// * `chat_server` - The downstream chat server to send request to
 /// * `request` - Chat completion request object
 /// * `headers` - HTTP request headers, including authentication info
@@ -1459,23 +1757,21 @@ async fn send_request_with_retry(
 /// * Cancellation logs warning messages for debugging and monitoring
 /// * Cancellation operation releases related resources to prevent leaks
 async fn build_and_send_request(
+    client: &reqwest::Client,
     chat_server: &TargetServerInfo,
     request: &ChatCompletionRequest,
     headers: &HeaderMap,
     cancel_token: CancellationToken,
     request_id: &str,
 ) -> ServerResult<reqwest::Response> {
-    let url = format!("{}/chat/completions", chat_server.url.trim_end_matches('/'));
-    let mut client = reqwest::Client::new().post(&url);
+    let mut client = client.post(&chat_server.chat_url);
 
     // Add common headers
     client = client.header(CONTENT_TYPE, "application/json");
 
     // Add authorization header
-    if let Some(api_key) = &chat_server.api_key
-        && !api_key.is_empty()
-    {
-        client = client.header(AUTHORIZATION, api_key);
+    if let Some((name, value)) = &chat_server.auth {
+        client = client.header(name, value);
     } else if let Some(auth) = headers.get("authorization")
         && let Ok(auth_str) = auth.to_str()
     {
@@ -1488,10 +1784,52 @@ async fn build_and_send_request(
         serde_json::to_string_pretty(request).unwrap()
     );
 
+    let span = tracing::info_span!(
+        "downstream",
+        server = %chat_server.url,
+        model = request.model.as_deref(),
+        status = tracing::field::Empty,
+        otel.kind = "client",
+        otel.status_code = tracing::field::Empty,
+    );
+    // the downstream server continues the trace of the request
+    client = client.headers(telemetry::trace_headers(&span));
+
     // Use select! to support cancellation
+    let start = std::time::Instant::now();
     select! {
-        response = client.json(request).send() => {
-            response.map_err(|e| ServerError::Operation(format!("Failed to forward request: {e}")))
+        response = client.json(request).send().instrument(span.clone()) => {
+            if let Ok(response) = &response {
+                span.record("status", response.status().as_u16());
+            }
+            if !response.as_ref().is_ok_and(|response| response.status().is_success()) {
+                span.record("otel.status_code", "ERROR");
+            }
+            match response {
+                Ok(response) => {
+                    chat_server.health.record_success();
+                    chat_server.latency.record(start.elapsed());
+                    if response.status().is_server_error() {
+                        chat_server.breaker.record_failure();
+                    } else {
+                        chat_server.breaker.record_success();
+                    }
+                    Ok(response)
+                }
+                Err(e) => {
+                    chat_server.breaker.record_failure();
+                    if e.is_connect() {
+                        let quarantine = chat_server.health.record_failure();
+                        dual_warn!(
+                            "Chat server {} is down, quarantined for {}s - request_id: {}",
+                            chat_server.id,
+                            quarantine.as_secs(),
+                            request_id
+                        );
+                    }
+                    Err(ServerError::Operation(format!("Failed to forward request: {e}")))
+                }
+            }
         }
         _ = cancel_token.cancelled() => {
             let warn_msg = "Request was cancelled by client";
@@ -1509,6 +1847,7 @@ async fn build_and_send_request(
 ///
 /// # Arguments
 ///
+/// * `client` - Shared HTTP client of the downstream requests
 /// * `response` - HTTP response from downstream server
 /// * `request` - Chat request, may be modified
 /// * `headers` - HTTP request headers
@@ -1516,6 +1855,7 @@ async fn build_and_send_request(
 /// * `request_id` - Request ID
 /// * `cancel_token` - Cancellation token
 async fn handle_stream_response(
+    client: &reqwest::Client,
     response: reqwest::Response,
     request: &mut ChatCompletionRequest,
     headers: &HeaderMap,
@@ -1536,6 +1876,7 @@ async fn handle_stream_response(
             if requires_tool_call {
                 // Handle tool call in stream mode
                 handle_tool_call_stream(
+                    client,
                     response,
                     request,
                     headers,
@@ -1550,14 +1891,7 @@ async fn handle_stream_response(
                     .await
             }
         }
-        _ => {
-            let err = response.error_for_status().unwrap_err();
-            let err_msg = format!("{err}");
-
-            dual_error!("{} - request_id: {}", err_msg, request_id);
-
-            return Err(ServerError::Operation(err_msg));
-        }
+        _ => forward_error_response(response, request_id, cancel_token).await,
     }
 }
 
@@ -1598,6 +1932,7 @@ async fn handle_stream_response(
 /// * Tool call error: Decide whether to continue based on error type
 /// * Response building error: Return build failure error
 async fn handle_non_stream_response(
+    client: &reqwest::Client,
     response: reqwest::Response,
     request: &mut ChatCompletionRequest,
     headers: &HeaderMap,
@@ -1621,6 +1956,7 @@ async fn handle_non_stream_response(
 
             if requires_tool_call {
                 call_mcp_server(
+                    client,
                     chat_completion.choices[0].message.tool_calls.as_slice(),
                     request,
                     headers,
@@ -1634,14 +1970,7 @@ async fn handle_non_stream_response(
                 build_response(status, response_headers, bytes, request_id)
             }
         }
-        _ => {
-            let err = response.error_for_status().unwrap_err();
-            let err_msg = format!("{err}");
-
-            dual_error!("{} - request_id: {}", err_msg, request_id);
-
-            return Err(ServerError::Operation(err_msg));
-        }
+        _ => forward_error_response(response, request_id, cancel_token).await,
     }
 }
 
@@ -1652,6 +1981,7 @@ async fn handle_non_stream_response(
 ///
 /// # Arguments
 ///
+/// * `client` - Shared HTTP client of the downstream requests
 /// * `response` - HTTP response from downstream server
 /// * `request` - Chat request, will be modified to include tool call results
 /// * `headers` - HTTP request headers
@@ -1659,6 +1989,7 @@ async fn handle_non_stream_response(
 /// * `request_id` - Request ID
 /// * `cancel_token` - Cancellation token
 async fn handle_tool_call_stream(
+    client: &reqwest::Client,
     response: reqwest::Response,
     request: &mut ChatCompletionRequest,
     headers: &HeaderMap,
@@ -1668,6 +1999,7 @@ async fn handle_tool_call_stream(
 ) -> ServerResult<axum::response::Response> {
     let tool_calls = extract_tool_calls_from_stream(response, request_id).await?;
     call_mcp_server(
+        client,
         tool_calls.as_slice(),
         request,
         headers,
@@ -1800,6 +2132,35 @@ fn build_response(
     }
 }
 
+/// Forward a downstream error response to the client unchanged
+///
+/// OpenAI clients parse the error object of the body, so the status code, headers and body of the
+/// downstream server are returned as they are instead of being wrapped in a gateway error.
+async fn forward_error_response(
+    response: reqwest::Response,
+    request_id: &str,
+    cancel_token: CancellationToken,
+) -> ServerResult<axum::response::Response> {
+    let status = response.status();
+    let response_headers = response.headers().clone();
+    let bytes = read_response_bytes(response, request_id, cancel_token).await?;
+
+    dual_error!(
+        "Downstream chat server returned {}: {} - request_id: {}",
+        status,
+        String::from_utf8_lossy(&bytes),
+        request_id
+    );
+
+    let mut response_builder = Response::builder().status(status).extension(Proxied);
+    response_builder = copy_response_headers(response_builder, &response_headers);
+    response_builder.body(Body::from(bytes)).map_err(|e| {
+        let err_msg = format!("Failed to create the response: {e}");
+        dual_error!("{} - request_id: {}", err_msg, request_id);
+        ServerError::Operation(err_msg)
+    })
+}
+
 /// Extract tool call information from streaming response
 ///
 /// Parse streaming response data and extract tool call information.
@@ -1919,6 +2280,7 @@ fn copy_response_headers(
 }
 
 async fn call_mcp_server(
+    client: &reqwest::Client,
     tool_calls: &[ToolCall],
     request: &mut ChatCompletionRequest,
     headers: &HeaderMap,
@@ -1928,7 +2290,7 @@ async fn call_mcp_server(
 ) -> ServerResult<axum::response::Response> {
     let request_id = request_id.as_ref();
     // let chat_service_url = chat_service_url.as_ref();
-    let chat_service_url = format!("{}/chat/completions", chat_server.url.trim_end_matches('/'));
+    let chat_service_url = chat_server.chat_url.clone();
 
     dual_debug!(
         "tool calls:\n{}",
@@ -2092,14 +2454,13 @@ async fn call_mcp_server(
                                                 }
 
                                                 // Create a request client that can be cancelled
-                                                let ds_request = if let Some(api_key) =
-                                                    &chat_server.api_key
-                                                    && !api_key.is_empty()
+                                                let ds_request = if let Some((name, value)) =
+                                                    &chat_server.auth
                                                 {
-                                                    reqwest::Client::new()
+                                                    client
                                                         .post(&chat_service_url)
                                                         .header(CONTENT_TYPE, "application/json")
-                                                        .header(AUTHORIZATION, api_key)
+                                                        .header(name, value)
                                                         .json(&request)
                                                 } else if headers.contains_key("authorization") {
                                                     let authorization = headers
@@ -2109,13 +2470,13 @@ async fn call_mcp_server(
                                                         .unwrap()
                                                         .to_string();
 
-                                                    reqwest::Client::new()
+                                                    client
                                                         .post(&chat_service_url)
                                                         .header(CONTENT_TYPE, "application/json")
                                                         .header(AUTHORIZATION, authorization)
                                                         .json(&request)
                                                 } else {
-                                                    reqwest::Client::new()
+                                                    client
                                                         .post(&chat_service_url)
                                                         .header(CONTENT_TYPE, "application/json")
                                                         .json(&request)
@@ -2285,14 +2646,13 @@ async fn call_mcp_server(
                                                 }
 
                                                 // Create a request client that can be cancelled
-                                                let ds_request = if let Some(api_key) =
-                                                    &chat_server.api_key
-                                                    && !api_key.is_empty()
+                                                let ds_request = if let Some((name, value)) =
+                                                    &chat_server.auth
                                                 {
-                                                    reqwest::Client::new()
+                                                    client
                                                         .post(&chat_service_url)
                                                         .header(CONTENT_TYPE, "application/json")
-                                                        .header(AUTHORIZATION, api_key)
+                                                        .header(name, value)
                                                         .json(&request)
                                                 } else if headers.contains_key("authorization") {
                                                     let authorization = headers
@@ -2302,13 +2662,13 @@ async fn call_mcp_server(
                                                         .unwrap()
                                                         .to_string();
 
-                                                    reqwest::Client::new()
+                                                    client
                                                         .post(&chat_service_url)
                                                         .header(CONTENT_TYPE, "application/json")
                                                         .header(AUTHORIZATION, authorization)
                                                         .json(&request)
                                                 } else {
-                                                    reqwest::Client::new()
+                                                    client
                                                         .post(&chat_service_url)
                                                         .header(CONTENT_TYPE, "application/json")
                                                         .json(&request)
@@ -2419,3 +2779,78 @@ async fn call_mcp_server(
         Err(ServerError::Operation(err_msg.to_string()))
     }
 }
+
+#[tokio::test]
+async fn test_health_probes() {
+    use crate::{config::Config, info::ServerInfo};
+    use tower::ServiceExt;
+
+    let path = crate::database::TempDb::new();
+    let state = Arc::new(
+        AppState::new_with_database(
+            Config::default(),
+            ServerInfo::default(),
+            path.to_str().unwrap(),
+        )
+        .await
+        .unwrap(),
+    );
+    let app = axum::Router::new()
+        .route("/healthz", axum::routing::get(liveness_handler))
+        .route("/readyz", axum::routing::get(readiness_handler))
+        .with_state(Arc::clone(&state));
+    let probe = |uri: &str| {
+        let request = axum::http::Request::builder()
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+        let app = app.clone();
+        async move {
+            let response = app.oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            (status, body)
+        }
+    };
+    let readiness = || async {
+        let (status, body) = probe("/readyz").await;
+        (
+            status,
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+        )
+    };
+
+    // the gateway is alive without any server, but not ready
+    assert_eq!(probe("/healthz").await, (StatusCode::OK, "OK".into()));
+    let (status, body) = readiness().await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(
+        body,
+        serde_json::json!({ "ready": false, "chat_server": false, "database": true })
+    );
+
+    // nor with its only chat server quarantined
+    let server: Server =
+        serde_json::from_str(r#"{"url": "http://127.0.0.1:1/v1", "kind": "chat"}"#).unwrap();
+    let health = Arc::clone(&server.health_status);
+    health.record_failure();
+    state.register_downstream_server(server).await.unwrap();
+    assert_eq!(readiness().await.0, StatusCode::SERVICE_UNAVAILABLE);
+
+    health.record_success();
+    let (status, body) = readiness().await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["ready"], true);
+
+    // a database that cannot be reached makes it unready again, but not dead
+    state.chat_storage.close().await.unwrap();
+    let (status, body) = readiness().await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(
+        (&body["chat_server"], &body["database"]),
+        (&true.into(), &false.into())
+    );
+    assert_eq!(probe("/healthz").await.0, StatusCode::OK);
+}
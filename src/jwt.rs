@@ -0,0 +1,494 @@
+use std::time::{Duration, Instant};
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use ring::{hmac, signature};
+use serde_json::Value;
+use tokio::sync::RwLock;
+
+use crate::{config::JwtConfig, dual_info, dual_warn};
+
+/// Shortest time between two fetches of the key set, so tokens with unknown key ids cannot make
+/// the server hammer the identity provider
+const MIN_JWKS_REFETCH: Duration = Duration::from_secs(60);
+
+/// Claims of a validated token, stored in the request extensions
+#[derive(Debug, Clone)]
+pub struct Claims {
+    /// Subject of the token, which namespaces the sessions it reaches
+    pub sub: Option<String>,
+    /// Scopes granted by the `scope` or `scp` claim
+    pub scopes: Vec<String>,
+}
+impl Claims {
+    fn from_payload(claims: &Value) -> Self {
+        let sub = claims
+            .get("sub")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        let mut scopes = Vec::new();
+        for claim in ["scope", "scp"] {
+            match claims.get(claim) {
+                Some(Value::String(scope)) => {
+                    scopes.extend(scope.split_whitespace().map(str::to_string))
+                }
+                Some(Value::Array(scope)) => {
+                    scopes.extend(scope.iter().filter_map(Value::as_str).map(str::to_string))
+                }
+                _ => {}
+            }
+        }
+        Self { sub, scopes }
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+}
+
+/// A token split into its parts, before its signature is checked
+#[derive(Debug)]
+struct Token<'a> {
+    alg: String,
+    kid: Option<String>,
+    /// The encoded header and payload, which the signature covers
+    signed: &'a str,
+    payload: Value,
+    signature: Vec<u8>,
+}
+impl<'a> Token<'a> {
+    fn parse(token: &'a str) -> Result<Self, String> {
+        let mut parts = token.split('.');
+        let (Some(header), Some(payload), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err("malformed token".to_string());
+        };
+        let decode = |part: &str| {
+            URL_SAFE_NO_PAD
+                .decode(part)
+                .map_err(|_| "malformed token".to_string())
+        };
+        let header: Value = serde_json::from_slice(&decode(header)?)
+            .map_err(|_| "malformed token header".to_string())?;
+        let payload: Value = serde_json::from_slice(&decode(payload)?)
+            .map_err(|_| "malformed token payload".to_string())?;
+        if !payload.is_object() {
+            return Err("malformed token payload".to_string());
+        }
+
+        Ok(Self {
+            alg: header
+                .get("alg")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+            kid: header
+                .get("kid")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            signed: &token[..token.rfind('.').unwrap_or_default()],
+            payload,
+            signature: decode(signature)?,
+        })
+    }
+}
+
+/// RSA public key of the key set
+#[derive(Debug, Clone)]
+struct RsaKey {
+    kid: Option<String>,
+    n: Vec<u8>,
+    e: Vec<u8>,
+}
+
+/// RSA signing keys of a JSON Web Key Set, `{"keys": [{"kty": "RSA", "kid", "n", "e"}]}`
+fn rsa_keys(jwks: &Value) -> Vec<RsaKey> {
+    let Some(keys) = jwks.get("keys").and_then(Value::as_array) else {
+        return Vec::new();
+    };
+    keys.iter()
+        .filter(|key| key.get("kty").and_then(Value::as_str) == Some("RSA"))
+        .filter(|key| {
+            key.get("use")
+                .and_then(Value::as_str)
+                .is_none_or(|u| u == "sig")
+        })
+        .filter_map(|key| {
+            let component = |name| URL_SAFE_NO_PAD.decode(key.get(name)?.as_str()?).ok();
+            Some(RsaKey {
+                kid: key.get("kid").and_then(Value::as_str).map(str::to_string),
+                n: component("n")?,
+                e: component("e")?,
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, Default)]
+struct KeySet {
+    keys: Vec<RsaKey>,
+    fetched: Option<Instant>,
+}
+
+/// Validator of the bearer JWTs of `[auth.jwt]`: HS256 tokens signed with the shared secret and
+/// RS256 tokens signed with a key of the JWKS.
+#[derive(Debug)]
+pub struct JwtValidator {
+    hs256_key: Option<hmac::Key>,
+    jwks_url: Option<String>,
+    jwks_refresh: Duration,
+    issuer: Option<String>,
+    audience: Option<String>,
+    leeway_secs: i64,
+    /// Scope needed for the `/admin` endpoints
+    pub admin_scope: String,
+    jwks: RwLock<KeySet>,
+}
+
+impl JwtValidator {
+    /// Builds the validator configured by `config`; `None` if neither a secret nor a JWKS is set
+    pub fn from_config(config: &JwtConfig) -> Option<Self> {
+        if config.hs256_secret.is_none() && config.jwks_url.is_none() {
+            return None;
+        }
+        Some(Self {
+            hs256_key: config
+                .hs256_secret
+                .as_ref()
+                .map(|secret| hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes())),
+            jwks_url: config.jwks_url.clone(),
+            jwks_refresh: Duration::from_secs(config.jwks_refresh_secs),
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+            leeway_secs: config.leeway_secs as i64,
+            admin_scope: config.admin_scope.clone(),
+            jwks: RwLock::new(KeySet::default()),
+        })
+    }
+
+    /// Checks the signature and claims of `token`, fetching the key set with `client` if needed
+    pub async fn validate(&self, client: &reqwest::Client, token: &str) -> Result<Claims, String> {
+        let token = Token::parse(token)?;
+        let keys = match (token.alg.as_str(), &self.jwks_url) {
+            ("RS256", Some(url)) => self.rsa_keys(client, url, token.kid.as_deref()).await,
+            _ => Vec::new(),
+        };
+        self.check(&token, &keys, chrono::Utc::now().timestamp())
+    }
+
+    /// Checks a parsed token against the RSA keys `keys` at the unix time `now`
+    fn check(&self, token: &Token, keys: &[RsaKey], now: i64) -> Result<Claims, String> {
+        match token.alg.as_str() {
+            "HS256" => {
+                let key = self
+                    .hs256_key
+                    .as_ref()
+                    .ok_or_else(|| "HS256 tokens are not accepted".to_string())?;
+                hmac::verify(key, token.signed.as_bytes(), &token.signature)
+                    .map_err(|_| "invalid token signature".to_string())?;
+            }
+            "RS256" => {
+                if self.jwks_url.is_none() {
+                    return Err("RS256 tokens are not accepted".to_string());
+                }
+                let verified = keys
+                    .iter()
+                    .filter(|key| token.kid.is_none() || key.kid == token.kid)
+                    .any(|key| {
+                        signature::RsaPublicKeyComponents {
+                            n: &key.n,
+                            e: &key.e,
+                        }
+                        .verify(
+                            &signature::RSA_PKCS1_2048_8192_SHA256,
+                            token.signed.as_bytes(),
+                            &token.signature,
+                        )
+                        .is_ok()
+                    });
+                if !verified {
+                    return Err("invalid token signature".to_string());
+                }
+            }
+            alg => return Err(format!("unsupported token algorithm `{alg}`")),
+        }
+
+        let claims = &token.payload;
+        let time = |name| {
+            claims
+                .get(name)
+                .and_then(|t| t.as_i64().or_else(|| t.as_f64().map(|t| t as i64)))
+        };
+        match time("exp") {
+            Some(exp) if exp + self.leeway_secs <= now => return Err("token expired".to_string()),
+            Some(_) => {}
+            None => return Err("token has no `exp`".to_string()),
+        }
+        if time("nbf").is_some_and(|nbf| nbf - self.leeway_secs > now) {
+            return Err("token not valid yet".to_string());
+        }
+        if let Some(issuer) = &self.issuer
+            && claims.get("iss").and_then(Value::as_str) != Some(issuer.as_str())
+        {
+            return Err("unexpected token issuer".to_string());
+        }
+        if let Some(audience) = &self.audience {
+            let matches = match claims.get("aud") {
+                Some(Value::String(aud)) => aud == audience,
+                Some(Value::Array(aud)) => aud
+                    .iter()
+                    .any(|aud| aud.as_str() == Some(audience.as_str())),
+                _ => false,
+            };
+            if !matches {
+                return Err("unexpected token audience".to_string());
+            }
+        }
+
+        Ok(Claims::from_payload(&token.payload))
+    }
+
+    /// The keys of the JWKS, fetched again once stale or when none has the id `kid`
+    async fn rsa_keys(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+        kid: Option<&str>,
+    ) -> Vec<RsaKey> {
+        let known = |set: &KeySet| {
+            kid.is_none_or(|kid| set.keys.iter().any(|key| key.kid.as_deref() == Some(kid)))
+        };
+        {
+            let set = self.jwks.read().await;
+            match set.fetched.map(|fetched| fetched.elapsed()) {
+                Some(age) if age < self.jwks_refresh && (known(&set) || age < MIN_JWKS_REFETCH) => {
+                    return set.keys.clone();
+                }
+                _ => {}
+            }
+        }
+
+        let mut set = self.jwks.write().await;
+        // another request may have fetched the keys while this one waited
+        if set
+            .fetched
+            .is_some_and(|fetched| fetched.elapsed() < MIN_JWKS_REFETCH)
+        {
+            return set.keys.clone();
+        }
+        match fetch_jwks(client, url).await {
+            Ok(keys) => {
+                dual_info!("Fetched {} signing key(s) from {url}", keys.len());
+                set.keys = keys;
+            }
+            // keep the known keys until the identity provider answers again
+            Err(e) => dual_warn!("Failed to fetch the signing keys from {url}: {e}"),
+        }
+        set.fetched = Some(Instant::now());
+        set.keys.clone()
+    }
+}
+
+async fn fetch_jwks(client: &reqwest::Client, url: &str) -> Result<Vec<RsaKey>, reqwest::Error> {
+    let jwks: Value = client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(rsa_keys(&jwks))
+}
+
+#[cfg(test)]
+const TEST_RSA_KEY: &str = concat!(
+    "MIIEowIBAAKCAQEAvq4Yfb9LErdxcHQd2UHteKpwEylMzsr+xG4LDDJF1dAtUpdO7ds1w3G3CkJ+l70hA+x2JDYF",
+    "BShZRb2YvpoZePyC8mmDGEHwLCI/lDbJ/6+aFSUGl72mU2zub/FyExqI4dAD0/Egxz+4BDiAZaizLqiKGSWWIbSb",
+    "sjI6K7oV68wlNWQ+1D/+gpcql6ux9eZr1vbVpGpcXPklkOWLaUvkSs6JQpL9CqBZquuIZ7jJkIU1JY3wosDmAyfy",
+    "QgDCrO3q69S18T5R+iB6uhNT+bZKWr8ufNUM1ZlbXkkq96kBAc9u70/DH/GifhvkndOdmxJR/PSl7xsXwf6egBwD",
+    "WtxTjwIDAQABAoIBABITWN9njrG260cFiuRsgbSkAfA4qwvmmS4WLG/uJONqH56Qf2GNTtRsAQKbX3rGgzNNa1Zv",
+    "B7ZmDWICFISFASmQVMcVrEeHzXR+YdgmaKtKgmj2U8cQqfK6ODdeaqAklMzeaW57xJqx9jsk/zu9bHb3PSTst9J4",
+    "8R8V8DomIsVxlw9/p0w1sK/vh4NWtsC6wLIG8ahnm2Dju5OGJWAjMvxKoy4Csow2dZtdYFvFpZPFwa6fA83woE8H",
+    "5i+ZuPzDISvOnZR3XUJzqBGmadQVg8MU+v/L0S9NdH6h3e9l8mozXe+h/zB8XhnOFYxbECjs8ceUeghTp48k1WFL",
+    "hxj1zwECgYEA3PWWVK6JXZvEhZkh3JjjC5hJGtB952PCVPnsR10wlHLkTFC25eAvlK/p01KSrBtho5U+5wwYwftG",
+    "gEpsFRYUtT5UpJCJlRaWdTUGlaYAUH1iqsgVNNYCK3J8FnlModI1zG7UDvLej20dqWG7Qkt/G5rSwNTMa/DZh2/C",
+    "kzJUNYECgYEA3Os+Zofov4qzYhjC3A2xxPiVqWIRNKVjGdYSia7XO8ne77BOHF6oPa6LX5sWJ1rHFy5bUOLQRAVu",
+    "wiJwe5PTzEHm9hRDCRRBzEVqSZOVWMWWaiR3zqO7MZDVxIq6R/SxJEDQRnj2WqKVtW1WJ08AiZMEf2WDk62gB2iG",
+    "6fxvsQ8CgYEAyN5RzaXlpr2GiFVPnTU3okDMpJ3I92OPNKUHEVE8apePx3jQ7GVDnA3XqY61W9SAjK+OwYgTsbT2",
+    "wIXnf7vVE3cYh4oGKQyhtud/lueT/Zge7FZ8QM4Upt6qw9t9uNZapgBSs0xC22w8vNDl5Tywzq2OzlIljDMl7aPB",
+    "5QKp3wECgYA7st5j+qosyKB9C8/sg07cuYhIA5Y+scq7+pt71WGYaI70GqKhC/tO39IN/ezYqbFPVFmc3A4Hs6gg",
+    "kgnvex0q/0wYh5Ut6Ol29/Mm+tjK6mohOMWfc6NsW4VI3WxFcZxE/bbKtHluOK1YH7m+WZz9kFsQyi/qQmsSVW7P",
+    "4EWWhQKBgAHgPZg6eitldXCr2+FEi9+IHaPuTg4Le9SG9jz93N6rXmYpGpM2FaUvW9z5Xwi7wvNDWxGJ3YV55F4k",
+    "78Vs+rw1pG4zxsoNEzmctVCXRvQ90r8UdtN/ZJd5hn/7phbPjO23xuHXbFSPBaZai9K0WQ0cT1jwqh5zQes4Wytg",
+    "9UAQ",
+);
+
+#[cfg(test)]
+fn encode_token(
+    alg: &str,
+    kid: Option<&str>,
+    claims: &Value,
+    sign: impl Fn(&[u8]) -> Vec<u8>,
+) -> String {
+    let header = serde_json::json!({ "alg": alg, "typ": "JWT", "kid": kid });
+    let signed = format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(header.to_string()),
+        URL_SAFE_NO_PAD.encode(claims.to_string())
+    );
+    let signature = URL_SAFE_NO_PAD.encode(sign(signed.as_bytes()));
+    format!("{signed}.{signature}")
+}
+
+#[test]
+fn test_hs256_tokens() {
+    let config = JwtConfig {
+        hs256_secret: Some("secret".to_string()),
+        issuer: Some("https://idp.example".to_string()),
+        audience: Some("nexus".to_string()),
+        ..Default::default()
+    };
+    let validator = JwtValidator::from_config(&config).unwrap();
+    let now = 1_700_000_000;
+    let key = hmac::Key::new(hmac::HMAC_SHA256, b"secret");
+    let hs256 = |claims: &Value| {
+        encode_token("HS256", None, claims, |data| {
+            hmac::sign(&key, data).as_ref().to_vec()
+        })
+    };
+    let check = |token: &str| validator.check(&Token::parse(token)?, &[], now);
+
+    let claims = serde_json::json!({
+        "sub": "alice", "scope": "chat admin", "exp": now + 600,
+        "iss": "https://idp.example", "aud": ["other", "nexus"],
+    });
+    let valid = check(&hs256(&claims)).unwrap();
+    assert_eq!(valid.sub.as_deref(), Some("alice"));
+    assert!(valid.has_scope("admin"));
+    assert!(!valid.has_scope("ad"));
+
+    // expired, beyond the leeway
+    assert!(
+        check(&hs256(
+            &serde_json::json!({ "exp": now - 30, "iss": "https://idp.example", "aud": "nexus" })
+        ))
+        .is_ok()
+    );
+    assert_eq!(
+        check(&hs256(
+            &serde_json::json!({ "exp": now - 61, "iss": "https://idp.example", "aud": "nexus" })
+        ))
+        .unwrap_err(),
+        "token expired"
+    );
+    assert!(
+        check(&hs256(
+            &serde_json::json!({ "iss": "https://idp.example", "aud": "nexus" })
+        ))
+        .is_err()
+    );
+    let not_yet_valid = serde_json::json!({
+        "exp": now + 600,
+        "nbf": now + 600,
+        "iss": "https://idp.example",
+        "aud": "nexus",
+    });
+    assert!(check(&hs256(&not_yet_valid)).is_err());
+    // wrong issuer or audience
+    assert!(
+        check(&hs256(
+            &serde_json::json!({ "exp": now + 600, "iss": "https://evil.example", "aud": "nexus" })
+        ))
+        .is_err()
+    );
+    assert!(
+        check(&hs256(
+            &serde_json::json!({ "exp": now + 600, "iss": "https://idp.example", "aud": "other" })
+        ))
+        .is_err()
+    );
+
+    // signed with another secret, tampered with, unsigned or malformed
+    let other = hmac::Key::new(hmac::HMAC_SHA256, b"guess");
+    assert!(
+        check(&encode_token("HS256", None, &claims, |data| hmac::sign(
+            &other, data
+        )
+        .as_ref()
+        .to_vec()))
+        .is_err()
+    );
+    let token = hs256(&claims);
+    let (signed, signature) = token.rsplit_once('.').unwrap();
+    let (header, _) = signed.split_once('.').unwrap();
+    let forged = URL_SAFE_NO_PAD.encode(
+        serde_json::json!({ "sub": "root", "scope": "admin", "exp": now + 600 }).to_string(),
+    );
+    assert!(check(&format!("{header}.{forged}.{signature}")).is_err());
+    assert!(check(&encode_token("none", None, &claims, |_| Vec::new())).is_err());
+    assert!(check(&encode_token("RS256", None, &claims, |_| Vec::new())).is_err());
+    assert!(check("not-a-token").is_err());
+
+    assert!(JwtValidator::from_config(&JwtConfig::default()).is_none());
+}
+
+#[test]
+fn test_rs256_tokens() {
+    let der = base64::engine::general_purpose::STANDARD
+        .decode(TEST_RSA_KEY)
+        .unwrap();
+    let key_pair = signature::RsaKeyPair::from_der(&der).unwrap();
+    let public = signature::RsaPublicKeyComponents::<Vec<u8>>::from(key_pair.public());
+    let jwks = serde_json::json!({ "keys": [
+        { "kty": "EC", "kid": "ec", "crv": "P-256" },
+        {
+            "kty": "RSA",
+            "kid": "k1",
+            "use": "sig",
+            "n": URL_SAFE_NO_PAD.encode(&public.n),
+            "e": URL_SAFE_NO_PAD.encode(&public.e),
+        },
+    ]});
+    let keys = rsa_keys(&jwks);
+    assert_eq!(keys.len(), 1);
+
+    let config = JwtConfig {
+        jwks_url: Some("https://idp.example/.well-known/jwks.json".to_string()),
+        ..Default::default()
+    };
+    let validator = JwtValidator::from_config(&config).unwrap();
+    let now = 1_700_000_000;
+    let rs256 = |kid: Option<&str>, claims: &Value| {
+        encode_token("RS256", kid, claims, |data| {
+            let mut signature = vec![0; key_pair.public().modulus_len()];
+            key_pair
+                .sign(
+                    &signature::RSA_PKCS1_SHA256,
+                    &ring::rand::SystemRandom::new(),
+                    data,
+                    &mut signature,
+                )
+                .unwrap();
+            signature
+        })
+    };
+    let check = |token: &str| validator.check(&Token::parse(token)?, &keys, now);
+
+    let claims = serde_json::json!({ "sub": "bob", "scp": ["chat"], "exp": now + 600 });
+    let valid = check(&rs256(Some("k1"), &claims)).unwrap();
+    assert_eq!(valid.sub.as_deref(), Some("bob"));
+    assert_eq!(valid.scopes, ["chat"]);
+    // without a key id every key is tried
+    assert!(check(&rs256(None, &claims)).is_ok());
+    assert!(check(&rs256(Some("k2"), &claims)).is_err());
+    // HS256 tokens need a secret
+    let key = hmac::Key::new(hmac::HMAC_SHA256, b"");
+    assert!(
+        check(&encode_token("HS256", None, &claims, |data| hmac::sign(
+            &key, data
+        )
+        .as_ref()
+        .to_vec()))
+        .is_err()
+    );
+}
@@ -0,0 +1,129 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug)]
+struct Estimate {
+    average_ms: f64,
+    updated: Instant,
+}
+
+/// Response latency of one downstream server within a server group, as an exponentially weighted
+/// moving average.
+///
+/// Each response moves the average `smoothing` of the way towards its latency. Between responses
+/// the average halves every `half_life`, so a server that was briefly slow, and is picked less
+/// often because of it, wins its share back instead of being judged on old responses.
+#[derive(Debug)]
+pub struct LatencyTracker {
+    smoothing: f64,
+    half_life: Duration,
+    estimate: Mutex<Option<Estimate>>,
+}
+
+impl LatencyTracker {
+    pub fn new(smoothing: f64, half_life: Duration) -> Self {
+        Self {
+            smoothing: smoothing.clamp(0.0, 1.0),
+            half_life,
+            estimate: Mutex::new(None),
+        }
+    }
+
+    /// Folds the latency of a response into the average
+    pub fn record(&self, latency: Duration) {
+        self.record_at(latency, Instant::now())
+    }
+
+    fn record_at(&self, latency: Duration, now: Instant) {
+        let latency_ms = latency.as_secs_f64() * 1000.0;
+        let mut estimate = self.estimate.lock().unwrap();
+        let average_ms = match estimate.as_ref() {
+            Some(estimate) => {
+                let average = self.decayed(estimate, now);
+                average + self.smoothing * (latency_ms - average)
+            }
+            None => latency_ms,
+        };
+        *estimate = Some(Estimate {
+            average_ms,
+            updated: now,
+        });
+    }
+
+    /// Average latency in milliseconds, decayed since the last response; `None` before the first
+    pub fn average_ms(&self) -> Option<f64> {
+        self.average_ms_at(Instant::now())
+    }
+
+    fn average_ms_at(&self, now: Instant) -> Option<f64> {
+        let estimate = self.estimate.lock().unwrap();
+        estimate
+            .as_ref()
+            .map(|estimate| self.decayed(estimate, now))
+    }
+
+    fn decayed(&self, estimate: &Estimate, now: Instant) -> f64 {
+        if self.half_life.is_zero() {
+            return estimate.average_ms;
+        }
+        let idle = now.saturating_duration_since(estimate.updated);
+        estimate.average_ms * 0.5f64.powf(idle.as_secs_f64() / self.half_life.as_secs_f64())
+    }
+}
+
+/// Weights in proportion to the inverse of the average latencies, the fastest server getting
+/// `1000`; servers without a response yet count as the fastest.
+pub fn inverse_weights(averages: &[Option<f64>]) -> Vec<u32> {
+    let fastest = averages
+        .iter()
+        .flatten()
+        .copied()
+        .fold(f64::INFINITY, f64::min)
+        .max(0.001);
+    averages
+        .iter()
+        .map(|average| match average {
+            Some(average) => (1000.0 * fastest / average.max(0.001))
+                .round()
+                .clamp(1.0, 1000.0) as u32,
+            None => 1000,
+        })
+        .collect()
+}
+
+#[test]
+fn test_latency_tracker() {
+    let tracker = LatencyTracker::new(0.5, Duration::from_secs(10));
+    let start = Instant::now();
+    assert_eq!(tracker.average_ms_at(start), None);
+
+    tracker.record_at(Duration::from_millis(100), start);
+    assert_eq!(tracker.average_ms_at(start), Some(100.0));
+    tracker.record_at(Duration::from_millis(300), start);
+    assert_eq!(tracker.average_ms_at(start), Some(200.0));
+
+    // the average halves every half-life without responses
+    assert_eq!(
+        tracker.average_ms_at(start + Duration::from_secs(10)),
+        Some(100.0)
+    );
+    assert_eq!(
+        tracker.average_ms_at(start + Duration::from_secs(20)),
+        Some(50.0)
+    );
+    tracker.record_at(Duration::from_millis(150), start + Duration::from_secs(10));
+    assert_eq!(
+        tracker.average_ms_at(start + Duration::from_secs(10)),
+        Some(125.0)
+    );
+
+    // a server twice as slow gets half the share
+    assert_eq!(
+        inverse_weights(&[Some(100.0), Some(200.0), None]),
+        vec![1000, 500, 1000]
+    );
+    assert_eq!(inverse_weights(&[Some(1.0), Some(1e9)]), vec![1000, 1]);
+    assert_eq!(inverse_weights(&[None, None]), vec![1000, 1000]);
+}
@@ -0,0 +1,164 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::{AppState, dual_error, dual_info};
+
+/// What one maintenance run did; jobs turned off in `[maintenance]` or with nothing configured
+/// to do are left out
+#[derive(Debug, Default, Serialize)]
+pub struct MaintenanceReport {
+    /// Messages pruned past `[retention] max_age_secs`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pruned: Option<u64>,
+    /// Deleted messages purged past `[retention] purge_deleted_after_secs`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub purged: Option<u64>,
+    /// Whether the database was vacuumed; `false` for the in-memory fallback, which has no space
+    /// to reclaim
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vacuumed: Option<bool>,
+    /// Sessions whose message count was recomputed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recounted: Option<u64>,
+    /// Errors of the jobs that failed; the jobs after them still run
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<String>,
+}
+
+impl MaintenanceReport {
+    /// Keeps the result of the job `job`, or its error
+    fn record<T>(&mut self, job: &str, result: anyhow::Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                dual_error!("Maintenance job {job} failed: {e}");
+                self.errors.push(format!("{job}: {e}"));
+                None
+            }
+        }
+    }
+}
+
+/// Jobs of a maintenance run, each still skipped when turned off in `[maintenance]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Jobs {
+    /// Every job, as `POST /admin/maintenance` runs them
+    All,
+    /// Pruning and purging the history past `[retention]`, scheduled every `[retention]
+    /// interval_secs`
+    Prune,
+    /// Recounting and vacuuming, scheduled every `[maintenance] interval_secs`
+    Upkeep,
+}
+
+/// Runs every maintenance job enabled in `[maintenance]`: pruning and purging the history past
+/// `[retention]`, then recounting the messages of the sessions, then vacuuming the space freed.
+pub(crate) async fn run(state: &AppState) -> MaintenanceReport {
+    run_jobs(state, Jobs::All).await
+}
+
+/// Runs the maintenance jobs of `jobs` enabled in `[maintenance]`.
+///
+/// Runs are serialized, so a manual run waits for a scheduled one in progress.
+pub(crate) async fn run_jobs(state: &AppState, jobs: Jobs) -> MaintenanceReport {
+    let _running = state.maintenance_lock.lock().await;
+    let (config, retention) = {
+        let config = state.config.read().await;
+        (config.maintenance.clone(), config.retention.clone())
+    };
+    let storage = &state.chat_storage;
+    let mut report = MaintenanceReport::default();
+    let (prune, upkeep) = (jobs != Jobs::Upkeep, jobs != Jobs::Prune);
+
+    if prune && config.prune {
+        if let Some(max_age) = retention.max_age_secs {
+            let pruned = storage.prune_older_than(Duration::from_secs(max_age)).await;
+            report.pruned = report.record("prune", pruned);
+        }
+        if let Some(purge_after) = retention.purge_deleted_after_secs {
+            let purged = storage
+                .purge_deleted(Duration::from_secs(purge_after))
+                .await;
+            report.purged = report.record("purge", purged);
+        }
+    }
+    if upkeep && config.recount {
+        let recounted = storage.recount_sessions().await;
+        report.recounted = report.record("recount", recounted);
+    }
+    if upkeep && config.vacuum {
+        let vacuumed = storage.vacuum().await;
+        report.vacuumed = report.record("vacuum", vacuumed);
+    }
+
+    dual_info!(
+        "Maintenance done: {} pruned, {} purged, {} session(s) recounted, vacuumed: {}, {} \
+         error(s)",
+        report.pruned.unwrap_or(0),
+        report.purged.unwrap_or(0),
+        report.recounted.unwrap_or(0),
+        report.vacuumed.unwrap_or(false),
+        report.errors.len()
+    );
+    report
+}
+
+#[tokio::test]
+async fn test_maintenance() {
+    use crate::{config::Config, database::ChatMessage, info::ServerInfo};
+
+    let path = crate::database::TempDb::new();
+    let mut config = Config::default();
+    config.retention.max_age_secs = Some(3600);
+    let state = AppState::new_with_database(config, ServerInfo::default(), path.to_str().unwrap())
+        .await
+        .unwrap();
+    for (session_id, user) in [("s1", "a"), ("s1", "b"), ("s2", "c")] {
+        state
+            .chat_storage
+            .save_turn(ChatMessage::new(session_id, user, "ok"))
+            .await
+            .unwrap();
+    }
+
+    // every job runs, and only the ones with something configured report
+    let report = run(&state).await;
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert_eq!((report.pruned, report.purged), (Some(0), None));
+    assert_eq!((report.recounted, report.vacuumed), (Some(2), Some(true)));
+    assert_eq!(
+        state
+            .chat_storage
+            .get_session_pairs("s1")
+            .await
+            .unwrap()
+            .len(),
+        2
+    );
+
+    // a job turned off is skipped
+    state.config.write().await.maintenance.vacuum = false;
+    let report = run(&state).await;
+    assert_eq!((report.recounted, report.vacuumed), (Some(2), None));
+    assert_eq!(
+        serde_json::to_value(&report).unwrap(),
+        serde_json::json!({ "pruned": 0, "recounted": 2 })
+    );
+    state.chat_storage.close().await.unwrap();
+
+    // the scheduled runs split the jobs between them
+    let state = AppState::new(Config::default(), ServerInfo::default());
+    state.config.write().await.retention.max_age_secs = Some(3600);
+    let report = run_jobs(&state, Jobs::Prune).await;
+    assert_eq!(
+        (report.pruned, report.recounted, report.vacuumed),
+        (Some(0), None, None)
+    );
+    let report = run_jobs(&state, Jobs::Upkeep).await;
+    assert_eq!((report.pruned, report.recounted), (None, Some(0)));
+
+    // the in-memory fallback has nothing to vacuum
+    let state = AppState::new(Config::default(), ServerInfo::default());
+    assert_eq!(run(&state).await.vacuumed, Some(false));
+}
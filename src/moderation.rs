@@ -0,0 +1,99 @@
+use regex::{RegexSet, RegexSetBuilder};
+use serde_json::Value;
+
+use crate::{config::ModerationConfig, dual_error};
+
+/// Checks of user messages before they are sent to a chat server.
+///
+/// A message is rejected if it matches any pattern of the blocklist, or if a registered
+/// `moderation` server flags it.
+#[derive(Debug)]
+pub struct Moderator {
+    blocklist: RegexSet,
+}
+
+impl Moderator {
+    /// Builds the moderator configured by `config`; `None` if moderation is disabled
+    pub fn from_config(config: &ModerationConfig) -> Option<Self> {
+        if !config.enabled {
+            return None;
+        }
+        // the patterns were checked when the config was loaded
+        let blocklist = match blocklist(&config.blocklist) {
+            Ok(blocklist) => blocklist,
+            Err(e) => {
+                dual_error!("Ignoring the moderation blocklist: {e}");
+                RegexSet::empty()
+            }
+        };
+        Some(Self { blocklist })
+    }
+
+    /// Whether `text` matches a pattern of the blocklist
+    pub fn is_blocked(&self, text: &str) -> bool {
+        self.blocklist.is_match(text)
+    }
+}
+
+/// Compiles blocklist patterns, matched case-insensitively
+pub fn blocklist(patterns: &[String]) -> Result<RegexSet, regex::Error> {
+    RegexSetBuilder::new(patterns)
+        .case_insensitive(true)
+        .build()
+}
+
+/// Categories flagged in an OpenAI-style `/moderations` reply, `{"results": [{"flagged",
+/// "categories"}]}`; `None` if the input was not flagged
+pub fn flagged_categories(reply: &Value) -> Result<Option<Vec<String>>, String> {
+    let results = reply
+        .get("results")
+        .and_then(Value::as_array)
+        .ok_or_else(|| "missing `results`".to_string())?;
+
+    let mut categories = Vec::new();
+    let mut flagged = false;
+    for result in results {
+        if result.get("flagged").and_then(Value::as_bool) != Some(true) {
+            continue;
+        }
+        flagged = true;
+        if let Some(flags) = result.get("categories").and_then(Value::as_object) {
+            categories.extend(
+                flags
+                    .iter()
+                    .filter(|(_, flagged)| flagged.as_bool() == Some(true))
+                    .map(|(category, _)| category.clone()),
+            );
+        }
+    }
+    Ok(flagged.then_some(categories))
+}
+
+#[test]
+fn test_moderation() {
+    let config = ModerationConfig {
+        enabled: true,
+        blocklist: vec![r"\bforbidden\b".to_string(), "secret code".to_string()],
+    };
+    let moderator = Moderator::from_config(&config).unwrap();
+    assert!(moderator.is_blocked("this is FORBIDDEN"));
+    assert!(moderator.is_blocked("tell me the secret code"));
+    assert!(!moderator.is_blocked("forbiddenness is fine"));
+    assert!(!moderator.is_blocked("hello"));
+
+    // disabled unless opted in
+    assert!(Moderator::from_config(&ModerationConfig::default()).is_none());
+    assert!(blocklist(&["(".to_string()]).is_err());
+
+    let reply = serde_json::json!({
+        "results": [{"flagged": true, "categories": {"hate": true, "violence": false}}]
+    });
+    assert_eq!(
+        flagged_categories(&reply),
+        Ok(Some(vec!["hate".to_string()]))
+    );
+    let reply =
+        serde_json::json!({ "results": [{"flagged": false, "categories": {"hate": false}}] });
+    assert_eq!(flagged_categories(&reply), Ok(None));
+    assert!(flagged_categories(&serde_json::json!({})).is_err());
+}
@@ -435,7 +435,7 @@ async fn perform_keyword_search(
             .unwrap()
             .to_string();
 
-        reqwest::Client::new()
+        state.http_client
             .post(&chat_service_url)
             .header(reqwest::header::CONTENT_TYPE, "application/json")
             .header(reqwest::header::AUTHORIZATION, authorization)
@@ -448,7 +448,7 @@ async fn perform_keyword_search(
                 ServerError::Operation(err_msg)
             })?
     } else {
-        reqwest::Client::new()
+        state.http_client
             .post(&chat_service_url)
             .header(reqwest::header::CONTENT_TYPE, "application/json")
             .json(&request)
@@ -799,7 +799,7 @@ async fn retrieve_context_with_single_qdrant_config(
                 .to_string();
 
             // Create a request client
-            reqwest::Client::new()
+            state.http_client
                 .post(&chat_service_url)
                 .header(reqwest::header::CONTENT_TYPE, "application/json")
                 .header(reqwest::header::AUTHORIZATION, authorization)
@@ -813,7 +813,7 @@ async fn retrieve_context_with_single_qdrant_config(
                 })?
         } else {
             // Create a request client
-            reqwest::Client::new()
+            state.http_client
                 .post(&chat_service_url)
                 .header(reqwest::header::CONTENT_TYPE, "application/json")
                 .json(&request)
@@ -0,0 +1,117 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::config::RateLimitConfig;
+
+/// Token bucket of one rate-limited key
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Token-bucket rate limiter keyed by arbitrary strings, e.g. a session id or an API key.
+///
+/// Each key gets a bucket of `burst` tokens refilled at `requests_per_second`. A request takes
+/// one token from every key it is made under, so it is rejected if any of them is empty.
+#[derive(Debug)]
+pub struct RateLimiter {
+    requests_per_second: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Builds the limiter configured by `config`; `None` if rate limiting is disabled
+    pub fn from_config(config: &RateLimitConfig) -> Option<Self> {
+        let requests_per_second = config.requests_per_second.filter(|rate| *rate > 0.0)?;
+        Some(Self::new(requests_per_second, config.burst))
+    }
+
+    pub fn new(requests_per_second: f64, burst: u32) -> Self {
+        Self {
+            requests_per_second,
+            burst: f64::from(burst.max(1)),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from the bucket of every key in `keys`; `false` if any of them is empty
+    pub fn try_acquire(&self, keys: &[String]) -> bool {
+        self.try_acquire_at(keys, Instant::now())
+    }
+
+    fn try_acquire_at(&self, keys: &[String], now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        for key in keys {
+            let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+                tokens: self.burst,
+                last_refill: now,
+            });
+            let elapsed = now
+                .saturating_duration_since(bucket.last_refill)
+                .as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * self.requests_per_second).min(self.burst);
+            bucket.last_refill = now;
+        }
+
+        // check every bucket before taking from any, so a rejected request costs nothing
+        if keys.iter().any(|key| buckets[key].tokens < 1.0) {
+            return false;
+        }
+        for key in keys {
+            buckets.get_mut(key).unwrap().tokens -= 1.0;
+        }
+
+        true
+    }
+
+    /// Drops the buckets unused for `idle` and returns how many were dropped.
+    ///
+    /// A bucket idle that long has refilled anyway unless the rate is tiny, so dropping it only
+    /// frees memory.
+    pub fn remove_idle(&self, idle: Duration) -> usize {
+        self.remove_idle_at(idle, Instant::now())
+    }
+
+    fn remove_idle_at(&self, idle: Duration, now: Instant) -> usize {
+        let mut buckets = self.buckets.lock().unwrap();
+        let before = buckets.len();
+        buckets.retain(|_, bucket| now.saturating_duration_since(bucket.last_refill) < idle);
+        before - buckets.len()
+    }
+}
+
+#[test]
+fn test_rate_limiter() {
+    let limiter = RateLimiter::new(2.0, 3);
+    let start = Instant::now();
+    let s1 = vec!["session:s1".to_string()];
+    let s2 = vec!["session:s2".to_string()];
+
+    // the burst is used up, then a token comes back every half second
+    for _ in 0..3 {
+        assert!(limiter.try_acquire_at(&s1, start));
+    }
+    assert!(!limiter.try_acquire_at(&s1, start));
+    assert!(limiter.try_acquire_at(&s2, start));
+    assert!(limiter.try_acquire_at(&s1, start + Duration::from_millis(500)));
+    assert!(!limiter.try_acquire_at(&s1, start + Duration::from_millis(600)));
+
+    // an empty shared key rejects the request without taking from the other key
+    let shared = vec!["session:s3".to_string(), "api_key:k".to_string()];
+    for _ in 0..3 {
+        assert!(limiter.try_acquire_at(&["api_key:k".to_string()], start));
+    }
+    assert!(!limiter.try_acquire_at(&shared, start));
+    assert_eq!(limiter.buckets.lock().unwrap()["session:s3"].tokens, 3.0);
+
+    let later = start + Duration::from_secs(60);
+    assert!(limiter.try_acquire_at(&s2, later));
+    assert_eq!(limiter.remove_idle_at(Duration::from_secs(30), later), 3);
+    assert_eq!(limiter.buckets.lock().unwrap().len(), 1);
+}
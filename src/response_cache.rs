@@ -0,0 +1,206 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::config::{IdempotencyConfig, ResponseCacheConfig};
+
+/// Cached downstream response
+#[derive(Debug)]
+struct Entry {
+    response: Value,
+    inserted: Instant,
+    // position in the recency order
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    by_key: HashMap<u64, Entry>,
+    // keys by the tick of their last use, least recently used first
+    recency: BTreeMap<u64, u64>,
+    tick: u64,
+}
+
+impl Entries {
+    fn remove(&mut self, key: u64) {
+        if let Some(entry) = self.by_key.remove(&key) {
+            self.recency.remove(&entry.last_used);
+        }
+    }
+}
+
+/// LRU cache of JSON responses keyed by a hash: non-streamed downstream chat responses by the
+/// request sent, and `/responses` replies by their idempotency key.
+///
+/// Entries expire `ttl` after they were stored; once `max_entries` are stored, storing another
+/// one evicts the least recently used.
+#[derive(Debug)]
+pub struct ResponseCache {
+    ttl: Duration,
+    max_entries: usize,
+    entries: Mutex<Entries>,
+}
+
+impl ResponseCache {
+    /// Builds the cache configured by `config`; `None` if caching is disabled
+    pub fn from_config(config: &ResponseCacheConfig) -> Option<Self> {
+        if !config.enabled || config.max_entries == 0 {
+            return None;
+        }
+        Some(Self::new(
+            Duration::from_secs(config.ttl_secs),
+            config.max_entries,
+        ))
+    }
+
+    /// Builds the store of replies to requests with an idempotency key; `None` if disabled
+    pub fn for_idempotency(config: &IdempotencyConfig) -> Option<Self> {
+        if config.ttl_secs == 0 || config.max_entries == 0 {
+            return None;
+        }
+        Some(Self::new(
+            Duration::from_secs(config.ttl_secs),
+            config.max_entries,
+        ))
+    }
+
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            ttl,
+            max_entries: max_entries.max(1),
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// Key of a request: a hash of its JSON serialization
+    pub fn key(request: &impl Serialize) -> Option<u64> {
+        let serialized = serde_json::to_string(request).ok()?;
+        let mut hasher = DefaultHasher::new();
+        serialized.hash(&mut hasher);
+        Some(hasher.finish())
+    }
+
+    /// Key of the idempotency key of a request, scoped to its session
+    pub fn idempotency_key(session_id: &str, key: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        (session_id, key).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// The response stored under `key`, unless it expired
+    pub fn get(&self, key: u64) -> Option<Value> {
+        self.get_at(key, Instant::now())
+    }
+
+    fn get_at(&self, key: u64, now: Instant) -> Option<Value> {
+        let mut entries = self.entries.lock().unwrap();
+        let expired = now.saturating_duration_since(entries.by_key.get(&key)?.inserted) >= self.ttl;
+        if expired {
+            entries.remove(key);
+            return None;
+        }
+
+        entries.tick += 1;
+        let tick = entries.tick;
+        let entry = entries.by_key.get_mut(&key)?;
+        let previous = std::mem::replace(&mut entry.last_used, tick);
+        let response = entry.response.clone();
+        entries.recency.remove(&previous);
+        entries.recency.insert(tick, key);
+        Some(response)
+    }
+
+    /// Drops every stored response
+    pub fn clear(&self) {
+        *self.entries.lock().unwrap() = Entries::default();
+    }
+
+    /// Stores `response` under `key`, evicting the least recently used entry if the cache is full
+    pub fn insert(&self, key: u64, response: Value) {
+        self.insert_at(key, response, Instant::now())
+    }
+
+    fn insert_at(&self, key: u64, response: Value, now: Instant) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(key);
+        while entries.by_key.len() >= self.max_entries {
+            let Some((_, oldest)) = entries.recency.pop_first() else {
+                break;
+            };
+            entries.by_key.remove(&oldest);
+        }
+
+        entries.tick += 1;
+        let tick = entries.tick;
+        entries.recency.insert(tick, key);
+        entries.by_key.insert(
+            key,
+            Entry {
+                response,
+                inserted: now,
+                last_used: tick,
+            },
+        );
+    }
+}
+
+#[test]
+fn test_response_cache() {
+    let cache = ResponseCache::new(Duration::from_secs(60), 2);
+    let start = Instant::now();
+
+    cache.insert_at(1, Value::from("one"), start);
+    cache.insert_at(2, Value::from("two"), start);
+    assert_eq!(cache.get_at(1, start), Some(Value::from("one")));
+
+    // 2 is the least recently used
+    cache.insert_at(3, Value::from("three"), start);
+    assert_eq!(cache.get_at(2, start), None);
+    assert_eq!(cache.get_at(1, start), Some(Value::from("one")));
+    assert_eq!(cache.get_at(3, start), Some(Value::from("three")));
+
+    // entries expire
+    assert_eq!(cache.get_at(1, start + Duration::from_secs(60)), None);
+    assert_eq!(cache.entries.lock().unwrap().by_key.len(), 1);
+
+    // identical requests share a key
+    let request = |content: &str| endpoints::chat::ChatCompletionRequest {
+        model: Some("llama".to_string()),
+        messages: vec![
+            endpoints::chat::ChatCompletionRequestMessage::new_user_message(
+                endpoints::chat::ChatCompletionUserMessageContent::Text(content.to_string()),
+                None,
+            ),
+        ],
+        ..Default::default()
+    };
+    assert_eq!(
+        ResponseCache::key(&request("hi")),
+        ResponseCache::key(&request("hi"))
+    );
+    assert_ne!(
+        ResponseCache::key(&request("hi")),
+        ResponseCache::key(&request("ho"))
+    );
+
+    cache.insert_at(4, Value::from("four"), start);
+    cache.clear();
+    assert_eq!(cache.get_at(4, start), None);
+    assert!(cache.entries.lock().unwrap().recency.is_empty());
+
+    // idempotency keys only match within a session
+    assert_eq!(
+        ResponseCache::idempotency_key("s1", b"k"),
+        ResponseCache::idempotency_key("s1", b"k")
+    );
+    assert_ne!(
+        ResponseCache::idempotency_key("s1", b"k"),
+        ResponseCache::idempotency_key("s2", b"k")
+    );
+}
@@ -1,159 +1,5705 @@
-use axum::{Json, extract::State, http::StatusCode};
-use serde::{Deserialize, Serialize};
-use std::sync::Arc;
-use endpoints::chat::{
-    ChatCompletionRequest, ChatCompletionRequestMessage, ChatCompletionUserMessageContent,
+use crate::{
+    AppState,
+    auth::{AuthenticatedKey, SessionNamespace},
+    concurrency::ModelPermit,
+    config::ModelDefaults,
+    database::{
+        ChatMessage, ExportFormat, MessageRole, RequestLogEntry, SearchMatch, SessionFilter,
+        SessionMetadata, SessionSummary, SessionUsage, rfc3339,
+    },
+    dual_debug, dual_error, dual_info, dual_warn,
+    error::{ServerError, ServerResult},
+    moderation,
+    response_cache::ResponseCache,
+    server::{RoutingPolicy, ServerId, ServerKind, TargetServerInfo},
+    session_lock::SessionGuard,
+    telemetry,
+};
+use axum::{
+    Extension, Json,
+    body::Body,
+    extract::{Query, State},
+    http::StatusCode,
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use axum::{
+    RequestExt,
+    extract::RawPathParams,
+    http::{HeaderMap, Request},
+    middleware::Next,
+};
+use bytes::Bytes;
+use endpoints::{
+    chat::{
+        ChatCompletionRequest, ChatCompletionRequestMessage, ChatCompletionUserMessageContent,
+        ContentPart, Function, Image, ImageContentPart, StreamOptions, TextContentPart, Tool,
+        ToolCall, ToolChoice,
+    },
+    common::Usage,
 };
+use futures_util::{StreamExt, stream::BoxStream};
+use reqwest::header::{
+    AUTHORIZATION, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_TYPE, RETRY_AFTER,
+};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use crate::{AppState, error::{ServerResult, ServerError}, server::{ServerKind, RoutingPolicy}};
-use axum::http::HeaderMap;
-use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use std::{
+    collections::{BTreeMap, HashSet},
+    convert::Infallible,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{select, sync::mpsc};
+use tracing::Instrument;
+
+/// System prompt used when no request, session, model or global prompt is set
+const DEFAULT_SYSTEM_PROMPT: &str =
+    "You are an AI assistant. Answer as helpfully and concisely as possible.";
+
+/// Marker appended to a streamed reply that was cut short before the downstream finished
+const INTERRUPTED_REPLY_MARKER: &str = " [interrupted]";
+/// Last event of a stream whose chat server went quiet for too long
+pub(super) const TIMEOUT_EVENT: &str = "data: [TIMEOUT]\n\n";
 
 #[derive(Debug, Deserialize)]
 pub struct ChatRequest {
     session_id: String,
+    /// May be empty when the turn only sends `tool_results`
+    #[serde(default)]
     user_message: String,
-    /// Optional model name; if absent we pick the first registered chat model
+    /// Optional model name; if absent a model with every `capabilities` tag is picked
     #[serde(default)]
     model: Option<String>,
+    /// Tags the chat server of the model must declare when `model` is not set, e.g. "vision"
+    #[serde(default)]
+    capabilities: Vec<String>,
+    /// Smallest context window, in tokens, of the model when `model` is not set
+    #[serde(default)]
+    min_context_length: Option<u64>,
+    /// Stream the reply back as server-sent events instead of a single JSON body
+    #[serde(default)]
+    stream: Option<bool>,
+    /// Sampling temperature, 0.0 to 2.0
+    #[serde(default)]
+    temperature: Option<f64>,
+    /// Nucleus sampling probability mass, 0.0 to 1.0
+    #[serde(default)]
+    top_p: Option<f64>,
+    /// Maximum number of tokens to generate
+    #[serde(default)]
+    max_tokens: Option<u32>,
+    /// Up to four sequences where the downstream server stops generating
+    #[serde(default)]
+    stop: Option<Vec<String>>,
+    /// Most recent turns included in the prompt, in place of `[responses] history_limit`
+    #[serde(default)]
+    history_limit: Option<usize>,
+    /// Number of replies to generate, at most `[responses] max_choices`; only the first is saved
+    #[serde(default)]
+    n: Option<u32>,
+    /// Penalty on tokens already present in the text, -2.0 to 2.0
+    #[serde(default)]
+    presence_penalty: Option<f64>,
+    /// Penalty on tokens by how often they appear in the text, -2.0 to 2.0
+    #[serde(default)]
+    frequency_penalty: Option<f64>,
+    /// Images sent along with `user_message`, as http(s) URLs or base64 `data:image/` URIs
+    #[serde(default)]
+    images: Vec<String>,
+    /// Tools the model may call, forwarded downstream as is
+    #[serde(default)]
+    tools: Option<Vec<Tool>>,
+    /// Which tool the model calls, if any
+    #[serde(default)]
+    tool_choice: Option<ToolChoice>,
+    /// Results of the tool calls of the previous reply, sent ahead of `user_message`
+    #[serde(default)]
+    tool_results: Vec<ToolResult>,
+    /// System prompt of this turn only, in place of the session's; saved with the turn but not
+    /// as the session's prompt
+    #[serde(default)]
+    system_prompt: Option<String>,
+    /// `false` bypasses the response cache; streamed requests never use it
+    #[serde(default)]
+    cache: Option<bool>,
+    /// Sampler seed, a non-negative integer, for reproducible replies on servers that honor it
+    #[serde(default)]
+    seed: Option<i64>,
+    /// Build the downstream request and return it instead of sending it; nothing is saved
+    #[serde(default)]
+    dry_run: bool,
+    /// Start of the reply, sent as a trailing assistant message for the model to continue; needs a
+    /// chat server tagged "prefill" or a text completion server. The saved reply begins with it.
+    #[serde(default)]
+    prefill: Option<String>,
+    /// Id or URL of the chat server that answers the turn, instead of the one the routing strategy
+    /// would pick; only for requests that reach every session
+    #[serde(default)]
+    server: Option<String>,
+    /// Turn answered again by a regeneration, whose next version the reply is saved as
+    #[serde(skip)]
+    revises: Option<ChatMessage>,
+}
+
+/// A chat request as sent downstream: [`ChatCompletionRequest`] with the parameters it has no
+/// field for
+#[derive(Debug, Default, Serialize)]
+struct DownstreamRequest {
+    #[serde(flatten)]
+    chat: ChatCompletionRequest,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+}
+impl From<ChatCompletionRequest> for DownstreamRequest {
+    fn from(chat: ChatCompletionRequest) -> Self {
+        Self { chat, seed: None }
+    }
+}
+impl std::ops::Deref for DownstreamRequest {
+    type Target = ChatCompletionRequest;
+
+    fn deref(&self) -> &ChatCompletionRequest {
+        &self.chat
+    }
+}
+
+/// Output of a tool call the client executed
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ToolResult {
+    tool_call_id: String,
+    content: String,
+}
+
+/// Maximum number of stop sequences accepted by `ChatRequest::stop`
+const MAX_STOP_SEQUENCES: usize = 4;
+/// Range of `ChatRequest::presence_penalty` and `ChatRequest::frequency_penalty`
+const PENALTY_RANGE: std::ops::RangeInclusive<f64> = -2.0..=2.0;
+
+/// Stands in for each image of a user message in the stored history, which only keeps text
+const IMAGE_PLACEHOLDER: &str = "[image]";
+
+impl ChatRequest {
+    pub(super) fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    #[cfg(test)]
+    pub(super) fn user_message(&self) -> &str {
+        &self.user_message
+    }
+
+    /// Checks the sampling parameters before they are forwarded downstream
+    fn validate_sampling(&self) -> ServerResult<()> {
+        if let Some(temperature) = self.temperature
+            && !(0.0..=2.0).contains(&temperature)
+        {
+            return Err(ServerError::InvalidRequest(format!(
+                "`temperature` must be between 0.0 and 2.0, got {temperature}"
+            )));
+        }
+        if let Some(top_p) = self.top_p
+            && !(0.0..=1.0).contains(&top_p)
+        {
+            return Err(ServerError::InvalidRequest(format!(
+                "`top_p` must be between 0.0 and 1.0, got {top_p}"
+            )));
+        }
+        if let Some(max_tokens) = self.max_tokens
+            && (max_tokens == 0 || i32::try_from(max_tokens).is_err())
+        {
+            return Err(ServerError::InvalidRequest(format!(
+                "`max_tokens` must be between 1 and {}, got {max_tokens}",
+                i32::MAX
+            )));
+        }
+        if self.n == Some(0) {
+            return Err(ServerError::InvalidRequest(
+                "`n` must be at least 1".to_string(),
+            ));
+        }
+        if let Some(seed) = self.seed
+            && seed < 0
+        {
+            return Err(ServerError::InvalidRequest(format!(
+                "`seed` must not be negative, got {seed}"
+            )));
+        }
+        if self.n.is_some_and(|n| n > 1) && self.stream == Some(true) {
+            return Err(ServerError::InvalidRequest(
+                "`n` above 1 is not supported with `stream`".to_string(),
+            ));
+        }
+        for (name, penalty) in [
+            ("presence_penalty", self.presence_penalty),
+            ("frequency_penalty", self.frequency_penalty),
+        ] {
+            if let Some(penalty) = penalty
+                && !PENALTY_RANGE.contains(&penalty)
+            {
+                return Err(ServerError::InvalidRequest(format!(
+                    "`{name}` must be between -2.0 and 2.0, got {penalty}"
+                )));
+            }
+        }
+        if let Some(stop) = &self.stop {
+            if stop.len() > MAX_STOP_SEQUENCES {
+                return Err(ServerError::InvalidRequest(format!(
+                    "`stop` accepts at most {MAX_STOP_SEQUENCES} sequences, got {}",
+                    stop.len()
+                )));
+            }
+            if stop.iter().any(|s| s.is_empty()) {
+                return Err(ServerError::InvalidRequest(
+                    "`stop` sequences must not be empty".to_string(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that every image is an http(s) URL or a base64 image data URI
+    fn validate_images(&self) -> ServerResult<()> {
+        for image in &self.images {
+            let valid = (image.starts_with("data:image/") && image.contains(";base64,"))
+                || reqwest::Url::parse(image)
+                    .is_ok_and(|url| matches!(url.scheme(), "http" | "https"));
+            if !valid {
+                let shown: String = image.chars().take(64).collect();
+                return Err(ServerError::InvalidRequest(format!(
+                    "`images` must hold http(s) URLs or base64 data URIs, got `{shown}`"
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that every tool result names the tool call it answers
+    fn validate_tool_results(&self) -> ServerResult<()> {
+        if self
+            .tool_results
+            .iter()
+            .any(|result| result.tool_call_id.is_empty())
+        {
+            return Err(ServerError::InvalidRequest(
+                "`tool_results` must each have a `tool_call_id`".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Trims `user_message`, and replaces it with `continue_message` if that leaves it empty while
+    /// the request has no images or tool results to send instead. Without a `continue_message` the
+    /// request is rejected, rather than sending and saving an empty turn.
+    fn normalize_message(&mut self, continue_message: Option<&str>) -> ServerResult<()> {
+        let trimmed = self.user_message.trim();
+        if trimmed.len() != self.user_message.len() {
+            self.user_message = trimmed.to_string();
+        }
+        if !self.user_message.is_empty() || !self.images.is_empty() || !self.tool_results.is_empty()
+        {
+            return Ok(());
+        }
+        match continue_message {
+            Some(message) => {
+                self.user_message = message.to_string();
+                Ok(())
+            }
+            None => Err(ServerError::InvalidRequest(
+                "`user_message` is empty".to_string(),
+            )),
+        }
+    }
+
+    /// Checks that neither `user_message` nor a tool result is longer than `max_chars`, which
+    /// would otherwise be sent again with every later turn; 0 allows any length
+    fn validate_length(&self, max_chars: usize) -> ServerResult<()> {
+        if max_chars == 0 {
+            return Ok(());
+        }
+        let too_long = |text: &str| text.chars().count() > max_chars;
+        if too_long(&self.user_message) {
+            return Err(ServerError::PayloadTooLarge(format!(
+                "`user_message` is longer than {max_chars} characters"
+            )));
+        }
+        if self
+            .tool_results
+            .iter()
+            .any(|result| too_long(&result.content))
+        {
+            return Err(ServerError::PayloadTooLarge(format!(
+                "a tool result is longer than {max_chars} characters"
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// `tool_results` as stored with the turn, `None` if there are none
+    fn stored_tool_results(&self) -> Option<String> {
+        if self.tool_results.is_empty() {
+            return None;
+        }
+        serde_json::to_string(&self.tool_results).ok()
+    }
+
+    /// Content of the new user message: plain text, or text and image parts with images
+    fn user_content(&self) -> ChatCompletionUserMessageContent {
+        if self.images.is_empty() {
+            return ChatCompletionUserMessageContent::Text(self.user_message.clone());
+        }
+
+        let mut parts = vec![ContentPart::Text(TextContentPart::new(
+            self.user_message.clone(),
+        ))];
+        parts.extend(self.images.iter().map(|url| {
+            ContentPart::Image(ImageContentPart::new(Image {
+                url: url.clone(),
+                detail: None,
+            }))
+        }));
+        ChatCompletionUserMessageContent::Parts(parts)
+    }
+
+    /// User message as stored in the history, with a placeholder line per image
+    fn stored_user_message(&self) -> String {
+        let mut message = self.user_message.clone();
+        for _ in &self.images {
+            message.push('\n');
+            message.push_str(IMAGE_PLACEHOLDER);
+        }
+        message
+    }
+
+    /// Fills the parameters the request leaves unset from the defaults of its model
+    fn apply_defaults(&mut self, defaults: &ModelDefaults) {
+        self.temperature = self.temperature.or(defaults.temperature);
+        self.top_p = self.top_p.or(defaults.top_p);
+        self.max_tokens = self.max_tokens.or(defaults.max_tokens);
+        if self.stop.is_none() {
+            self.stop = defaults.stop.clone();
+        }
+        self.presence_penalty = self.presence_penalty.or(defaults.presence_penalty);
+        self.frequency_penalty = self.frequency_penalty.or(defaults.frequency_penalty);
+    }
 }
 
 #[derive(Debug, Serialize)]
 pub struct ChatResponse {
     reply: String,
+    /// Model that answered
+    model: String,
+    /// Every reply generated, the first being `reply`; only present when more than one was asked
+    /// for with `n`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    choices: Option<Vec<String>>,
+    /// URL of the chat server that answered; absent for a reply from the response cache
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server: Option<String>,
+    /// Tokens used by the downstream request, as reported by the chat server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<Usage>,
+    /// Number of the oldest turns left out of the prompt, beyond `max_history_turns` or to fit
+    /// `max_context_tokens`
+    dropped_turns: usize,
+    /// Tools the model calls; their results go in `tool_results` of the next turn
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ToolCall>,
+    /// Why the model stopped, as reported by the chat server, e.g. `length` for a reply cut at the
+    /// token limit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finish_reason: Option<String>,
+}
+
+/// Reply to a dry run: the request a turn would send downstream
+#[derive(Debug, Serialize)]
+pub struct DryRunResponse {
+    model: String,
+    /// Chat completion request, `messages` as built from the system prompt, the summary, the
+    /// history left after truncation and the new message
+    request: DownstreamRequest,
+    /// Number of the oldest turns left out of `messages`
+    dropped_turns: usize,
+    /// Estimated tokens of the prompt
+    prompt_tokens: usize,
 }
 
 #[derive(Debug, Serialize)]
 pub struct ChatHistoryResponse {
     session_id: String,
     messages: Vec<String>,
+    /// Always `true`; `GET /sessions/{session_id}/messages` returns structured messages
+    deprecated: bool,
+    /// Total number of stored turns in the session
+    total: i64,
+    limit: i64,
+    offset: i64,
+}
+
+/// Header carrying `dropped_turns` on streamed replies, whose body is the raw SSE stream
+pub(super) const DROPPED_TURNS_HEADER: &str = "x-dropped-turns";
+/// Header of a streamed reply with the model that answers it
+pub(super) const MODEL_HEADER: &str = "x-model";
+/// Header of a streamed reply with the URL of the chat server that answers it
+const SERVER_HEADER: &str = "x-server";
+/// Request header naming a turn, so a retry of it is answered once
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+/// Header of a reply replayed for a repeated idempotency key
+const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+
+/// Number of turns returned by `get_chat_history` when no `limit` is given
+const DEFAULT_HISTORY_PAGE_SIZE: i64 = 50;
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryQuery {
+    #[serde(default)]
+    limit: Option<i64>,
+    /// Offset counted from the oldest turn; defaults to the most recent page
+    #[serde(default)]
+    offset: Option<i64>,
+}
+
+/// A listed session
+#[derive(Debug, Serialize)]
+pub struct SessionEntry {
+    session_id: String,
+    #[serde(with = "rfc3339")]
+    updated_at: chrono::DateTime<chrono::Utc>,
+    message_count: i64,
+    /// Start of the last reply, with `?preview=true`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preview: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct SessionsResponse {
-    sessions: Vec<String>,
+    sessions: Vec<SessionEntry>,
 }
 
 pub async fn handle_response(
     State(state): State<Arc<AppState>>,
+    namespace: SessionNamespace,
+    key: Option<Extension<AuthenticatedKey>>,
+    headers: HeaderMap,
+    Json(mut payload): Json<ChatRequest>,
+) -> ServerResult<Response> {
+    metrics::counter!(telemetry::REQUESTS_TOTAL).increment(1);
+    validate_session_id(&payload.session_id)?;
+    payload.session_id = namespace.scope(&payload.session_id);
+
+    // a retry of an answered non-streamed request gets the same reply, without a new turn
+    let idempotency_key = match (&state.idempotency, headers.get(IDEMPOTENCY_KEY_HEADER)) {
+        (Some(_), Some(key)) if payload.stream != Some(true) && !payload.dry_run => Some(
+            ResponseCache::idempotency_key(&payload.session_id, key.as_bytes()),
+        ),
+        _ => None,
+    };
+    if let Some(reply) = replayed_reply(&state, idempotency_key) {
+        return Ok(reply);
+    }
+    check_request(&state, &headers, &namespace, key.as_deref(), &mut payload).await?;
+
+    // turns of a session are answered one at a time, so each one sees the previous turn saved
+    let session_guard = state.session_locks.lock(&payload.session_id).await;
+    // the first of concurrent retries may have been answered while this one waited
+    if let Some(reply) = replayed_reply(&state, idempotency_key) {
+        return Ok(reply);
+    }
+    let response = respond(Arc::clone(&state), headers, payload, session_guard).await?;
+    // a fallback reply is not replayed, a retry may find a chat server
+    match idempotency_key {
+        Some(key) if response.status().is_success() => remember_reply(&state, key, response).await,
+        _ => Ok(response),
+    }
+}
+
+/// The stored reply to the request with idempotency key `key`, if any
+fn replayed_reply(state: &AppState, key: Option<u64>) -> Option<Response> {
+    let reply = state.idempotency.as_ref()?.get(key?)?;
+    dual_info!("Replaying the reply to a repeated idempotency key");
+    Some(([(IDEMPOTENT_REPLAYED_HEADER, "true")], Json(reply)).into_response())
+}
+
+/// Stores a reply under the idempotency key of its request and returns it
+async fn remember_reply(state: &AppState, key: u64, response: Response) -> ServerResult<Response> {
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .map_err(|e| ServerError::Operation(format!("Failed to read the reply: {e}")))?;
+    let reply: Value = serde_json::from_slice(&body)
+        .map_err(|e| ServerError::Operation(format!("Failed to parse the reply: {e}")))?;
+    if let Some(idempotency) = &state.idempotency {
+        idempotency.insert(key, reply.clone());
+    }
+    Ok(Json(reply).into_response())
+}
+
+/// Longest session id accepted by the routes
+pub(crate) const MAX_SESSION_ID_LEN: usize = 128;
+
+/// Rejects a session id that is empty, longer than [`MAX_SESSION_ID_LEN`] or has a character other
+/// than an ASCII letter, a digit, `-`, `_`, `.` or `:`
+pub(crate) fn validate_session_id(session_id: &str) -> ServerResult<()> {
+    if session_id.is_empty() {
+        return Err(ServerError::InvalidRequest(
+            "`session_id` is empty".to_string(),
+        ));
+    }
+    if session_id.len() > MAX_SESSION_ID_LEN {
+        return Err(ServerError::InvalidRequest(format!(
+            "`session_id` is longer than {MAX_SESSION_ID_LEN} characters"
+        )));
+    }
+    if let Some(c) = session_id
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':')))
+    {
+        return Err(ServerError::InvalidRequest(format!(
+            "`session_id` contains the character {c:?}"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Rejects a request whose `{session_id}` path parameter is not a valid session id, so no
+/// session route has to check it itself
+pub(crate) async fn check_session_path(
+    mut req: Request<Body>,
+    next: Next,
+) -> ServerResult<Response> {
+    if let Ok(params) = req.extract_parts::<RawPathParams>().await
+        && let Some((_, session_id)) = params.iter().find(|(name, _)| *name == "session_id")
+    {
+        validate_session_id(session_id)?;
+    }
+
+    Ok(next.run(req).await)
+}
+
+/// Validates a `/responses` request of a caller reaching `namespace`, trimming its user message,
+/// and takes it from the rate limits of its session and of `key`, the API key it was
+/// authenticated with
+pub(super) async fn check_request(
+    state: &AppState,
+    headers: &HeaderMap,
+    namespace: &SessionNamespace,
+    key: Option<&AuthenticatedKey>,
+    payload: &mut ChatRequest,
+) -> ServerResult<()> {
+    let continue_message = state.config.read().await.responses.continue_message.clone();
+    payload.normalize_message(continue_message.as_deref())?;
+    // steering traffic to a server is for debugging, not for users of their own sessions
+    if payload.server.is_some() && namespace.is_scoped() {
+        return Err(ServerError::Forbidden(
+            "`server` needs admin access".to_string(),
+        ));
+    }
+    payload.validate_sampling()?;
+    payload.validate_images()?;
+    payload.validate_tool_results()?;
+    payload.validate_length(state.config.read().await.limits.max_message_chars)?;
+
+    if let Some(limiter) = &state.rate_limiter {
+        let mut keys = vec![format!("session:{}", payload.session_id)];
+        if state.config.read().await.rate_limit.by_api_key
+            && let Some(AuthenticatedKey(key)) = key
+        {
+            keys.push(format!("api_key:{key}"));
+        }
+        if !limiter.try_acquire(&keys) {
+            dual_warn!("Rate limit exceeded for session {}", payload.session_id);
+            return Err(ServerError::RateLimited(format!(
+                "too many requests for session {}",
+                payload.session_id
+            )));
+        }
+    }
+
+    // a dry run calls no server
+    if payload.dry_run {
+        return Ok(());
+    }
+    moderate(state, headers, &payload.user_message).await
+}
+
+/// Rejects `text` if it matches the moderation blocklist or a registered `moderation` server
+/// flags it; does nothing unless moderation is enabled
+pub(super) async fn moderate(
+    state: &AppState,
+    headers: &HeaderMap,
+    text: &str,
+) -> ServerResult<()> {
+    let Some(moderator) = &state.moderator else {
+        return Ok(());
+    };
+    if moderator.is_blocked(text) {
+        dual_warn!("Rejected a message matching the moderation blocklist");
+        return Err(ServerError::Flagged(
+            "the message matches the blocklist".to_string(),
+        ));
+    }
+
+    let moderation_server = match state.server_group.read().await.get(&ServerKind::moderation) {
+        Some(group) => group.next().await?,
+        None => return Ok(()),
+    };
+    let url = format!(
+        "{}/moderations",
+        moderation_server.url.trim_end_matches('/')
+    );
+    let timeout = Duration::from_secs(state.config.read().await.responses.request_timeout_secs);
+    let mut request = state
+        .http_client
+        .post(&url)
+        .timeout(timeout)
+        .json(&serde_json::json!({ "input": text }));
+    if let Some((name, value)) = &moderation_server.auth {
+        request = request.header(name, value);
+    } else if let Some(auth) = headers.get("authorization").and_then(|h| h.to_str().ok()) {
+        request = request.header(AUTHORIZATION, auth);
+    }
+
+    let response = request.send().await.map_err(|e| {
+        moderation_server.breaker.record_failure();
+        ServerError::Operation(format!(
+            "Moderation server {} failed: {e}",
+            moderation_server.url
+        ))
+    })?;
+    let status = response.status();
+    if !status.is_success() {
+        let text = response.text().await.unwrap_or_default();
+        if status.is_server_error() {
+            moderation_server.breaker.record_failure();
+        }
+        return Err(ServerError::UpstreamFailed(status.as_u16(), text));
+    }
+    moderation_server.breaker.record_success();
+    let reply: Value = response
+        .json()
+        .await
+        .map_err(|e| ServerError::UpstreamMalformed(format!("invalid moderation reply: {e}")))?;
+
+    match moderation::flagged_categories(&reply).map_err(ServerError::UpstreamMalformed)? {
+        Some(categories) => {
+            dual_warn!(
+                "Moderation server {} flagged a message: {categories:?}",
+                moderation_server.url
+            );
+            let reason = if categories.is_empty() {
+                "the message was flagged".to_string()
+            } else {
+                categories.join(", ")
+            };
+            Err(ServerError::Flagged(reason))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Answers a checked request while holding the lock of its session.
+///
+/// The lock is released once the turn is saved; for a streamed reply that is when the stream ends.
+#[tracing::instrument(
+    name = "responses",
+    skip_all,
+    fields(session_id = %payload.session_id, model)
+)]
+pub(super) async fn respond(
+    state: Arc<AppState>,
     headers: HeaderMap,
-    Json(payload): Json<ChatRequest>,
-) -> ServerResult<Json<ChatResponse>> {
+    mut payload: ChatRequest,
+    session_guard: SessionGuard,
+) -> ServerResult<Response> {
     // 1. Determine model
-    let model = if let Some(m) = payload.model.clone() {
-        m
-    } else {
-        // choose first known model from state.models; if empty -> error
-        let models_map = state.models.read().await;
-        let first = models_map.values().flat_map(|v| v.iter()).next();
-        match first {
-            Some(m) => m.id.clone(),
-            None => return Err(ServerError::Operation("No chat model registered".into())),
+    let ModelRoute {
+        model,
+        servers,
+        target,
+    } = match select_model(&state, &payload).await {
+        Ok(route) => route,
+        Err(e) => {
+            let model = payload.model.clone().unwrap_or_default();
+            return fallback_reply(&state, &payload, model, e, session_guard).await;
         }
     };
 
-    // 2. Build full history messages including system prompt
-    const SYSTEM_PROMPT: &str = "You are an AI assistant. Answer as helpfully and concisely as possible.";
+    tracing::Span::current().record("model", model.as_str());
+    metrics::counter!(telemetry::MODEL_REQUESTS_TOTAL, "model" => model.clone()).increment(1);
+    dual_info!("Using model {} for session {}", model, payload.session_id);
+
+    // model defaults fill in what the request leaves unset
+    let defaults = state
+        .model_defaults
+        .read()
+        .await
+        .get(&model)
+        .cloned()
+        .unwrap_or_default();
+    payload.apply_defaults(&defaults);
+
+    // 2. Build full history messages including the system prompt
+    let session_prompt = match state
+        .chat_storage
+        .get_system_prompt(&payload.session_id)
+        .await
+    {
+        Ok(prompt) => prompt,
+        Err(e) => {
+            dual_warn!(
+                "Failed to load the system prompt of session {}: {e}",
+                payload.session_id
+            );
+            None
+        }
+    };
+    let global_prompt = state.config.read().await.responses.system_prompt.clone();
+    let system_prompt = resolve_system_prompt(
+        payload.system_prompt.as_deref(),
+        session_prompt.as_deref(),
+        defaults.system_prompt.as_deref(),
+        global_prompt.as_deref(),
+    );
     let mut messages: Vec<ChatCompletionRequestMessage> = Vec::new();
     messages.push(ChatCompletionRequestMessage::new_system_message(
-        SYSTEM_PROMPT.to_string(),
+        system_prompt.clone(),
         None,
     ));
+    // saved with the turn, so its transcript shows the prompt it was answered with
+    payload.system_prompt = Some(system_prompt.clone());
+    let prompt_logging = state.config.read().await.responses.prompt_logging;
+    if let (Some(system), Some(user)) = (
+        prompt_logging.render(&system_prompt),
+        prompt_logging.render(&payload.user_message),
+    ) {
+        dual_info!(
+            "Prompt of session {}: system prompt {system:?}, user message {user:?}",
+            payload.session_id
+        );
+    }
 
-    // previous turns
-    if let Ok(pairs) = state.chat_storage.get_session_pairs(&payload.session_id).await {
-        for (user, bot) in pairs.into_iter() {
-            let user_msg = ChatCompletionRequestMessage::new_user_message(
-                ChatCompletionUserMessageContent::Text(user),
-                None,
-            );
-            let assistant_msg = ChatCompletionRequestMessage::new_assistant_message(
-                Some(bot),
-                None,
+    // the summary of the oldest turns stands in for them, right after the system prompt
+    let (mut turns, capped_turns) = load_turns(&state, &payload.session_id).await;
+    // a regenerated turn is sent as the new user message, so none of its versions is history
+    if let Some(revised) = &payload.revises {
+        let original = revised.parent_id.or(revised.id);
+        turns.retain(|turn| turn.parent_id.or(turn.id) != original);
+    }
+    let summarize = !payload.dry_run;
+    let (summary, turns) = compact_history(
+        &state,
+        &headers,
+        &payload.session_id,
+        &model,
+        servers.as_ref(),
+        turns,
+        summarize,
+    )
+    .await;
+    let context = match &summary {
+        Some(summary) => {
+            let context = summary_context(summary);
+            messages.push(ChatCompletionRequestMessage::new_system_message(
+                context.clone(),
                 None,
-            );
-            messages.push(user_msg);
-            messages.push(assistant_msg);
+            ));
+            format!("{system_prompt}\n{context}")
         }
+        None => system_prompt.clone(),
+    };
+
+    // at most `history_limit` of the most recent turns, whatever their size
+    let history_limit = payload
+        .history_limit
+        .or(state.config.read().await.responses.history_limit);
+    let (turns, limited_turns) = limit_history(turns, history_limit);
+    if limited_turns > 0 {
+        dual_debug!(
+            "Left out {limited_turns} old turn(s) of session {} beyond history_limit",
+            payload.session_id
+        );
     }
-    // new user message
-    messages.push(ChatCompletionRequestMessage::new_user_message(
-        ChatCompletionUserMessageContent::Text(payload.user_message.clone()),
-        None,
-    ));
 
-    // 3. Prepare downstream request (non-stream)
-    let request_body = ChatCompletionRequest {
+    // previous turns, oldest dropped first when they don't fit the context budget
+    let pairs: Vec<(String, String)> = turns
+        .iter()
+        .map(|turn| (turn.user_message.clone(), turn.bot_reply.clone()))
+        .collect();
+    let max_context_tokens = state.config.read().await.responses.max_context_tokens;
+    let estimate = state.token_estimator.clone();
+    let (_, trimmed_turns) = match max_context_tokens {
+        Some(max_tokens) => trim_history_to_budget(
+            pairs,
+            &context,
+            &payload.user_message,
+            max_tokens,
+            estimate.as_ref(),
+        ),
+        None => (pairs, 0),
+    };
+    if trimmed_turns > 0 {
+        dual_info!(
+            "Dropped {} old turn(s) of session {} to fit the context budget",
+            trimmed_turns,
+            payload.session_id
+        );
+    }
+    let dropped_turns = capped_turns + limited_turns + trimmed_turns;
+    // stands in for the prompt usage of a stream that reports none
+    let mut prompt_tokens = estimate(&context) + estimate(&payload.user_message);
+    for turn in turns.into_iter().skip(trimmed_turns) {
+        prompt_tokens += estimate(&turn.user_message) + estimate(&turn.bot_reply);
+        messages.extend(turn_messages(turn));
+    }
+    prompt_tokens += payload
+        .tool_results
+        .iter()
+        .map(|result| estimate(&result.content))
+        .sum::<usize>();
+    // results of the tools called by the last reply, then the new user message
+    messages.extend(payload.tool_results.iter().map(ToolResult::to_message));
+    if !payload.user_message.is_empty() || payload.tool_results.is_empty() {
+        messages.push(ChatCompletionRequestMessage::new_user_message(
+            payload.user_content(),
+            None,
+        ));
+    }
+    if let Some(prefill) = &payload.prefill {
+        messages.push(ChatCompletionRequestMessage::new_assistant_message(
+            Some(prefill.clone()),
+            None,
+            None,
+        ));
+    }
+
+    // 3. Prepare downstream request
+    let stream = payload.stream.unwrap_or(false);
+    let max_choices = state.config.read().await.responses.max_choices.max(1);
+    let n = payload.n.unwrap_or(1);
+    if n > max_choices {
+        dual_info!(
+            "Asking for {max_choices} of the {n} replies requested by session {}",
+            payload.session_id
+        );
+    }
+    let chat_request = ChatCompletionRequest {
         model: Some(model.clone()),
         messages,
-        stream: Some(false),
+        stream: Some(stream),
+        temperature: payload.temperature,
+        top_p: payload.top_p,
+        n_choice: Some(u64::from(n.min(max_choices))),
+        max_completion_tokens: payload.max_tokens.map(|n| n as i32),
+        stop: payload.stop.clone(),
+        presence_penalty: payload.presence_penalty,
+        frequency_penalty: payload.frequency_penalty,
+        tools: payload.tools.clone(),
+        tool_choice: payload.tool_choice.clone(),
+        // ask for the usage in the final chunk of a stream
+        stream_options: stream.then_some(StreamOptions {
+            include_usage: Some(true),
+        }),
         ..Default::default()
     };
+    let request_body = DownstreamRequest {
+        chat: chat_request,
+        seed: payload.seed,
+    };
+    if payload.dry_run {
+        dual_info!(
+            "Returning the request of a dry run for session {}",
+            payload.session_id
+        );
+        return Ok(Json(DryRunResponse {
+            model,
+            request: request_body,
+            dropped_turns,
+            prompt_tokens,
+        })
+        .into_response());
+    }
 
-    // 4. Pick chat server
-    // Acquire a downstream chat server (required now, no fallback)
-    let chat_server = {
-        let servers = state.server_group.read().await;
-        let chat_group = servers.get(&ServerKind::chat).ok_or_else(|| ServerError::Operation("No chat server available".into()))?;
-        chat_group.next().await.map_err(|e| ServerError::Operation(format!("Failed to acquire chat server: {e}")))?
+    // an identical non-streamed request may have been answered before
+    let cache_key = match &state.response_cache {
+        // a reply pinned to a server must come from it
+        Some(_) if !stream && payload.cache != Some(false) && payload.server.is_none() => {
+            ResponseCache::key(&request_body)
+        }
+        _ => None,
     };
+    let cached = cache_key.and_then(|key| state.response_cache.as_ref()?.get(key));
+
+    let (value, server, latency) = match cached {
+        Some(value) => {
+            dual_info!(
+                "Answering session {} from the response cache",
+                payload.session_id
+            );
+            (value, None, None)
+        }
+        None => {
+            // a model at its concurrency limit takes no more requests until one is done
+            let permit = match &state.model_limiter {
+                Some(limiter) => {
+                    limiter
+                        .acquire(&model, state.lists_model(&model).await)
+                        .await
+                }
+                None => Ok(None),
+            };
+            let permit = match permit {
+                Ok(permit) => permit,
+                Err(e) => {
+                    dual_warn!("Refused session {}: {e}", payload.session_id);
+                    return fallback_reply(&state, &payload, model, e, session_guard).await;
+                }
+            };
 
-    // Send request to downstream
-    let url = format!("{}/chat/completions", chat_server.url.trim_end_matches('/'));
-    let mut client = reqwest::Client::new().post(&url).header(CONTENT_TYPE, "application/json");
-    if let Some(api_key) = &chat_server.api_key { if !api_key.is_empty() { client = client.header(AUTHORIZATION, api_key); }} else if let Some(auth) = headers.get("authorization").and_then(|h| h.to_str().ok()) { client = client.header(AUTHORIZATION, auth);}    
-    let resp = client.json(&request_body).send().await.map_err(|e| ServerError::Operation(format!("Downstream request failed: {e}")))?;
-    if !resp.status().is_success() {
-        let status = resp.status();
-        let text = resp.text().await.unwrap_or_default();
-        return Err(ServerError::Operation(format!("Downstream chat error {status}: {text}")));
+            // 4. Send to a downstream chat server, retrying transient failures on the next server
+            let started = std::time::Instant::now();
+            let sent = send_with_retry(
+                &state,
+                &headers,
+                &payload.session_id,
+                &request_body,
+                servers.as_ref(),
+                target,
+            )
+            .await;
+            let (chat_server, resp) = match sent {
+                Ok(sent) => sent,
+                Err(e) => return fallback_reply(&state, &payload, model, e, session_guard).await,
+            };
+
+            // 5. Stream the reply back as it arrives; the turn is persisted once the stream ends
+            if stream {
+                let reply = StreamedReply {
+                    model,
+                    dropped_turns,
+                    prompt_tokens,
+                    permit,
+                };
+                return stream_reply(
+                    state,
+                    payload,
+                    reply,
+                    chat_server,
+                    resp,
+                    started,
+                    session_guard,
+                );
+            }
+
+            // bounded by the request timeout; dropping `chat_server` on error releases its
+            // connection slot
+            let value: Value = resp.json().await.map_err(|e| {
+                if e.is_timeout() {
+                    ServerError::Timeout(format!(
+                        "Chat server {} did not finish the reply in time",
+                        chat_server.url
+                    ))
+                } else {
+                    ServerError::Operation(format!("Failed to parse downstream response JSON: {e}"))
+                }
+            })?;
+            let latency = started.elapsed();
+            let value = read_reply(value, &chat_server)?;
+            // the downstream call is complete, release the server's connection slot and the model's
+            let server = chat_server.url.clone();
+            drop(chat_server);
+            drop(permit);
+            record_usage(&state, &payload.session_id, parse_usage(&value)).await;
+            (value, Some(server), Some(latency))
+        }
+    };
+    // an error or malformed body fails the request instead of being saved or cached as a reply
+    let completion = parse_completion(&value)?;
+    if let (Some(_), Some(cache), Some(key)) = (&server, &state.response_cache, cache_key) {
+        cache.insert(key, value.clone());
     }
-    let value: Value = resp.json().await.map_err(|e| ServerError::Operation(format!("Failed to parse downstream response JSON: {e}")))?;
-    let bot_reply = value
-        .get("choices")
-        .and_then(|c| c.get(0))
-        .and_then(|c0| c0.get("message"))
-        .and_then(|m| m.get("content"))
-        .and_then(|c| c.as_str())
-        .unwrap_or("(no content)")
-        .to_string();
+    let usage = completion.usage;
+    let mut messages = completion.choices.unwrap_or_default();
+    let message = value.pointer("/choices/0/message");
+    let tool_calls = messages[0].message.tool_calls.take().unwrap_or_default();
+    let finish_reason = messages[0].finish_reason.take();
+    if finish_reason.as_deref() == Some("length") {
+        dual_warn!(
+            "The reply to session {} was cut at the token limit",
+            payload.session_id
+        );
+    }
+    // every reply continues the prefill
+    let prefill = payload.prefill.as_deref().unwrap_or_default();
+    let choices: Vec<String> = messages
+        .into_iter()
+        .map(|choice| format!("{prefill}{}", choice.message.content.unwrap_or_default()))
+        .collect();
+    let bot_reply = choices[0].clone();
+    let choices = (choices.len() > 1).then_some(choices);
 
-    // 6. Persist turn
-    if let Err(e) = state.chat_storage.save_conversation(&payload.session_id, &payload.user_message, &bot_reply).await {
-        eprintln!("Failed to save conversation: {e}");
+    // 6. Persist turn; of several choices the first is the canonical reply, with the raw
+    // assistant message if it calls tools
+    let turn = ChatMessage {
+        tool_results: payload.stored_tool_results(),
+        assistant_message: message
+            .filter(|_| !tool_calls.is_empty())
+            .map(Value::to_string),
+        system_prompt: payload.system_prompt.clone(),
+        truncated: finish_reason.as_deref() == Some("length"),
+        latency_ms: latency.map(|latency| latency.as_millis() as i64),
+        seed: payload.seed,
+        ..ChatMessage::new(
+            &payload.session_id,
+            &payload.stored_user_message(),
+            &bot_reply,
+        )
+    };
+    let turn = match &payload.revises {
+        Some(previous) => turn.next_version_of(previous),
+        None => turn,
+    };
+    if let Err(e) = state.chat_storage.save_turn(turn).await {
+        dual_error!("Failed to save conversation: {e}");
     }
+    drop(session_guard);
 
-    Ok(Json(ChatResponse { reply: bot_reply }))
+    let response = ChatResponse {
+        reply: bot_reply,
+        model,
+        choices,
+        server,
+        usage,
+        dropped_turns,
+        tool_calls,
+        finish_reason,
+    };
+    Ok(Json(response).into_response())
 }
 
-pub async fn get_chat_history(
-    State(state): State<Arc<AppState>>,
-    axum::extract::Path(session_id): axum::extract::Path<String>,
-) -> Result<Json<ChatHistoryResponse>, StatusCode> {
-    match state.chat_storage.get_conversation_history(&session_id).await {
-        Ok(messages) => Ok(Json(ChatHistoryResponse {
-            session_id,
-            messages,
-        })),
-        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+/// Answers with `[responses] fallback_reply`, with a 503, when `err` means no chat server could
+/// take the request; fails with `err` otherwise or when no fallback reply is set.
+///
+/// The reply takes the shape the client asked for: a [`ChatResponse`], or a stream of one chunk.
+async fn fallback_reply(
+    state: &AppState,
+    payload: &ChatRequest,
+    model: String,
+    err: ServerError,
+    session_guard: SessionGuard,
+) -> ServerResult<Response> {
+    let (reply, persist) = {
+        let config = &state.config.read().await.responses;
+        (config.fallback_reply.clone(), config.persist_fallback_reply)
+    };
+    let Some(reply) = reply.filter(|_| err.is_unavailable() && !payload.dry_run) else {
+        return Err(err);
+    };
+    dual_warn!(
+        "Answering session {} with the fallback reply: {err}",
+        payload.session_id
+    );
+
+    if persist {
+        let turn = ChatMessage {
+            system_prompt: payload.system_prompt.clone(),
+            ..ChatMessage::new(&payload.session_id, &payload.stored_user_message(), &reply)
+        };
+        let turn = match &payload.revises {
+            Some(previous) => turn.next_version_of(previous),
+            None => turn,
+        };
+        if let Err(e) = state.chat_storage.save_turn(turn).await {
+            dual_error!("Failed to save conversation: {e}");
+        }
+    }
+    drop(session_guard);
+
+    if payload.stream == Some(true) {
+        let chunk = serde_json::json!({
+            "object": "chat.completion.chunk",
+            "model": model,
+            "choices": [{
+                "index": 0,
+                "delta": { "role": "assistant", "content": reply },
+                "finish_reason": "stop",
+            }],
+        });
+        return Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header(CONTENT_TYPE, "text/event-stream")
+            .header(CACHE_CONTROL, "no-cache")
+            .header(MODEL_HEADER, model)
+            .body(Body::from(format!("data: {chunk}\n\ndata: [DONE]\n\n")))
+            .map_err(|e| ServerError::Operation(format!("Failed to create the response: {e}")));
     }
+    let response = ChatResponse {
+        reply,
+        model,
+        choices: None,
+        server: None,
+        usage: None,
+        dropped_turns: 0,
+        tool_calls: Vec::new(),
+        finish_reason: None,
+    };
+    Ok((StatusCode::SERVICE_UNAVAILABLE, Json(response)).into_response())
 }
 
-pub async fn get_all_sessions(
-    State(state): State<Arc<AppState>>,
-) -> Result<Json<SessionsResponse>, StatusCode> {
-    match state.chat_storage.get_all_sessions().await {
-        Ok(sessions) => Ok(Json(SessionsResponse { sessions })),
-        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+/// Keeps the `limit` most recent turns, if a limit is set, and returns them with the number left
+/// out
+fn limit_history<T>(mut turns: Vec<T>, limit: Option<usize>) -> (Vec<T>, usize) {
+    let Some(limit) = limit else {
+        return (turns, 0);
+    };
+    let dropped = turns.len().saturating_sub(limit);
+    (turns.split_off(dropped), dropped)
+}
+
+/// Loads the most recent turns of a session, at most `[limits] max_history_turns`, and returns
+/// them with the number of older turns left out
+async fn load_turns(state: &AppState, session_id: &str) -> (Vec<ChatMessage>, usize) {
+    let max_turns = state.config.read().await.limits.max_history_turns;
+    if max_turns == 0 {
+        let turns = state
+            .chat_storage
+            .get_session_turns(session_id)
+            .await
+            .unwrap_or_default();
+        return (turns, 0);
+    }
+
+    match state
+        .chat_storage
+        .get_messages_page(session_id, max_turns as i64, None)
+        .await
+    {
+        Ok((turns, total, _)) => {
+            let capped = (total as usize).saturating_sub(turns.len());
+            if capped > 0 {
+                dual_info!(
+                    "Left out {capped} old turn(s) of session {session_id} beyond max_history_turns"
+                );
+            }
+            (turns, capped)
+        }
+        Err(e) => {
+            dual_warn!("Failed to load the history of session {session_id}: {e}");
+            (Vec::new(), 0)
+        }
     }
 }
 
-pub async fn delete_session(
-    State(state): State<Arc<AppState>>,
-    axum::extract::Path(session_id): axum::extract::Path<String>,
-) -> StatusCode {
-    match state.chat_storage.delete_session(&session_id).await {
-        Ok(_) => StatusCode::OK,
-        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+/// Model of a turn and the chat servers that may answer it
+struct ModelRoute {
+    model: String,
+    /// Servers hosting `model`; `None` when the request names the model
+    servers: Option<HashSet<ServerId>>,
+    /// Server picked to decide the model, tried first
+    target: Option<TargetServerInfo>,
+}
+
+/// Picks the model of a turn: the `model` of the request, or else the model of a chat server
+/// declaring every tag of `capabilities` and a context of at least `min_context_length` tokens.
+/// Among several such servers the routing strategy picks one, and that server answers first.
+async fn select_model(state: &AppState, payload: &ChatRequest) -> ServerResult<ModelRoute> {
+    if let Some(name) = &payload.server {
+        return pinned_route(state, payload, name).await;
+    }
+    if let Some(model) = payload.model.clone() {
+        // only the servers able to continue a prefilled reply may answer one
+        let servers = match payload.prefill {
+            Some(_) => Some(prefill_servers(state).await?),
+            None => None,
+        };
+        return Ok(ModelRoute {
+            model,
+            servers,
+            target: None,
+        });
     }
+
+    let server_kinds = state.config.read().await.responses.server_kinds.clone();
+    let servers = state.server_group.read().await;
+    // the group of the first kind with a server available, or else of the first registered
+    let groups: Vec<_> = server_kinds
+        .iter()
+        .filter_map(|kind| servers.get(kind))
+        .collect();
+    let mut chat_group = groups.first().copied();
+    for group in &groups {
+        if !group.is_empty().await {
+            chat_group = Some(*group);
+            break;
+        }
+    }
+    let chat_group =
+        chat_group.ok_or_else(|| ServerError::NotFoundServer(ServerKind::chat.to_string()))?;
+    let mut capable = chat_group
+        .servers_with_capabilities(&payload.capabilities, payload.min_context_length)
+        .await;
+    if payload.prefill.is_some() {
+        let prefill = chat_group
+            .servers_where(|server| server.supports_prefill())
+            .await;
+        capable.retain(|id| prefill.contains(id));
+    }
+
+    // servers by the model they serve; a server with several models serves the first by id
+    let mut hosts: BTreeMap<String, HashSet<ServerId>> = BTreeMap::new();
+    {
+        let models_map = state.models.read().await;
+        for id in capable {
+            if let Some(model) = models_map
+                .get(&id)
+                .and_then(|models| models.iter().map(|m| &m.id).min())
+            {
+                hosts.entry(model.clone()).or_default().insert(id);
+            }
+        }
+    }
+    if hosts.is_empty() {
+        if payload.capabilities.is_empty()
+            && payload.min_context_length.is_none()
+            && payload.prefill.is_none()
+        {
+            return Err(ServerError::NotFoundServer(ServerKind::chat.to_string()));
+        }
+        let mut required = payload.capabilities.join(", ");
+        if payload.prefill.is_some() {
+            if !required.is_empty() {
+                required.push_str(", ");
+            }
+            required.push_str("prefill");
+        }
+        if let Some(min) = payload.min_context_length {
+            if !required.is_empty() {
+                required.push_str(", ");
+            }
+            required.push_str(&format!("context of {min} tokens"));
+        }
+        let err = ServerError::NoServerAvailable(format!("chat model with {required}"));
+        dual_warn!("{}", err);
+        return Err(err);
+    }
+
+    let candidates: HashSet<ServerId> = hosts.values().flatten().cloned().collect();
+    let target = chat_group
+        .next_among(&payload.session_id, &candidates)
+        .await?;
+    let (model, servers) = hosts
+        .into_iter()
+        .find(|(_, servers)| servers.contains(&target.id))
+        .ok_or_else(|| ServerError::Operation(format!("Server {} hosts no model", target.id)))?;
+    Ok(ModelRoute {
+        model,
+        servers: Some(servers),
+        target: Some(target),
+    })
+}
+
+/// Route of a request pinned to the server `name`, an id or URL, bypassing the routing strategy:
+/// the server answers alone, with the `model` of the request or else its own. Fails unless the
+/// server is registered under one of the `[responses] server_kinds` and available.
+async fn pinned_route(
+    state: &AppState,
+    payload: &ChatRequest,
+    name: &str,
+) -> ServerResult<ModelRoute> {
+    let server_kinds = state.config.read().await.responses.server_kinds.clone();
+    let (mut registered, mut available, mut prefill) = (Vec::new(), Vec::new(), Vec::new());
+    {
+        let groups = state.server_group.read().await;
+        for group in server_kinds.iter().filter_map(|kind| groups.get(kind)) {
+            registered.extend(group.servers_where(|server| server.is_named(name)).await);
+            available.extend(
+                group
+                    .servers_where(|server| {
+                        server.is_named(name) && server.health_status.is_available()
+                    })
+                    .await,
+            );
+            prefill.extend(
+                group
+                    .servers_where(|server| server.is_named(name) && server.supports_prefill())
+                    .await,
+            );
+        }
+    }
+    let Some(id) = registered.into_iter().next() else {
+        return Err(ServerError::InvalidRequest(format!(
+            "no chat server `{name}` is registered"
+        )));
+    };
+    if !available.contains(&id) {
+        let err =
+            ServerError::NoServerAvailable(format!("chat server `{name}`, which is unhealthy"));
+        dual_warn!("{}", err);
+        return Err(err);
+    }
+    if payload.prefill.is_some() && !prefill.contains(&id) {
+        return Err(ServerError::InvalidRequest(format!(
+            "`prefill` needs a server able to continue a reply, and `{name}` is not"
+        )));
+    }
+
+    let model = match payload.model.clone() {
+        Some(model) => model,
+        None => state
+            .models
+            .read()
+            .await
+            .get(&id)
+            .and_then(|models| models.iter().map(|m| m.id.clone()).min())
+            .ok_or_else(|| ServerError::Operation(format!("Server {id} hosts no model")))?,
+    };
+    dual_info!(
+        "Pinning session {} to the chat server {id}",
+        payload.session_id
+    );
+    Ok(ModelRoute {
+        model,
+        servers: Some(HashSet::from([id])),
+        target: None,
+    })
+}
+
+/// Ids of the servers of the `[responses] server_kinds` that can continue a prefilled reply; see
+/// [`Server::supports_prefill`](crate::server::Server::supports_prefill)
+async fn prefill_servers(state: &AppState) -> ServerResult<HashSet<ServerId>> {
+    let server_kinds = state.config.read().await.responses.server_kinds.clone();
+    let groups = state.server_group.read().await;
+    let mut ids = HashSet::new();
+    for group in server_kinds.iter().filter_map(|kind| groups.get(kind)) {
+        ids.extend(
+            group
+                .servers_where(|server| server.supports_prefill())
+                .await,
+        );
+    }
+    if ids.is_empty() {
+        return Err(ServerError::InvalidRequest(
+            "`prefill` needs a chat server tagged \"prefill\" or a text completion server, and \
+             none is registered"
+                .to_string(),
+        ));
+    }
+    Ok(ids)
+}
+
+/// Picks the system prompt of a turn from the most specific source that sets one:
+///
+/// 1. `system_prompt` of the request, for this turn only
+/// 2. the prompt stored for the session
+/// 3. `system_prompt` of the model's `[model_defaults.<model_id>]`
+/// 4. the global default, `[responses] system_prompt` or `LLAMA_NEXUS_SYSTEM_PROMPT`
+/// 5. the built-in [`DEFAULT_SYSTEM_PROMPT`]
+///
+/// Empty prompts count as unset.
+fn resolve_system_prompt(
+    request: Option<&str>,
+    session: Option<&str>,
+    model: Option<&str>,
+    global: Option<&str>,
+) -> String {
+    [request, session, model, global]
+        .into_iter()
+        .flatten()
+        .find(|prompt| !prompt.is_empty())
+        .unwrap_or(DEFAULT_SYSTEM_PROMPT)
+        .to_string()
+}
+
+/// Prompt asking the chat model to fold turns into the running summary of a session
+const SUMMARY_PROMPT: &str = "Summarize the conversation below for your own later reference. \
+    Keep the facts, decisions, names and open questions the user may come back to, and drop small \
+    talk. If a previous summary is given, merge it in. Reply with the summary only.";
+
+/// Context message carrying the running summary of a session
+fn summary_context(summary: &str) -> String {
+    format!("Summary of the earlier conversation:\n{summary}")
+}
+
+/// Transcript of `turns` sent to be summarized, after the previous summary if there is one
+fn summary_transcript(previous: Option<&str>, turns: &[ChatMessage]) -> String {
+    let mut transcript = String::new();
+    if let Some(previous) = previous {
+        transcript.push_str(&format!("Previous summary:\n{previous}\n\nConversation:\n"));
+    }
+    for turn in turns {
+        transcript.push_str(&format!(
+            "User: {}\nAssistant: {}\n",
+            turn.user_message, turn.bot_reply
+        ));
+    }
+    transcript
+}
+
+/// Returns the running summary of a session and the turns it does not cover.
+///
+/// Once more than `summarize_after_turns` turns are left, the oldest of them, at least
+/// `summarize_turns`, are folded into the summary by the chat model and the new summary is stored.
+/// If that fails the turns are kept as they are and summarizing is tried again on the next turn.
+/// Summaries refer to database ids, so without a database nothing is summarized; nor is anything
+/// unless `summarize`, the stored summary being used as it is.
+async fn compact_history(
+    state: &Arc<AppState>,
+    headers: &HeaderMap,
+    session_id: &str,
+    model: &str,
+    servers: Option<&HashSet<ServerId>>,
+    turns: Vec<ChatMessage>,
+    summarize: bool,
+) -> (Option<String>, Vec<ChatMessage>) {
+    let stored = match state.chat_storage.get_session_summary(session_id).await {
+        Ok(stored) => stored,
+        Err(e) => {
+            dual_warn!("Failed to load the summary of session {session_id}: {e}");
+            None
+        }
+    };
+    let (summary, mut turns) = match stored {
+        Some(stored) => {
+            let turns = turns
+                .into_iter()
+                .filter(|turn| turn.id.is_some_and(|id| id > stored.summarized_until))
+                .collect::<Vec<_>>();
+            (Some(stored.summary), turns)
+        }
+        None => (None, turns),
+    };
+
+    let (after_turns, batch_turns) = {
+        let config = state.config.read().await;
+        (
+            config.responses.summarize_after_turns,
+            config.responses.summarize_turns,
+        )
+    };
+    let Some(after_turns) = after_turns else {
+        return (summary, turns);
+    };
+    if !summarize || !state.chat_storage.has_database() || turns.len() <= after_turns {
+        return (summary, turns);
+    }
+
+    let batch = batch_turns.max(turns.len() - after_turns).min(turns.len());
+    let Some(summarized_until) = turns[batch - 1].id else {
+        return (summary, turns);
+    };
+    let text = match request_summary(
+        state,
+        headers,
+        session_id,
+        model,
+        servers,
+        summary.as_deref(),
+        &turns[..batch],
+    )
+    .await
+    {
+        Ok(text) => text,
+        Err(e) => {
+            dual_warn!("Failed to summarize {batch} turn(s) of session {session_id}: {e}");
+            return (summary, turns);
+        }
+    };
+
+    let stored = SessionSummary {
+        session_id: session_id.to_string(),
+        summary: text,
+        summarized_until,
+        updated_at: chrono::Utc::now(),
+    };
+    if let Err(e) = state.chat_storage.set_session_summary(&stored).await {
+        dual_error!("Failed to save the summary of session {session_id}: {e}");
+    }
+    dual_info!("Summarized {batch} turn(s) of session {session_id}");
+    turns.drain(..batch);
+    (Some(stored.summary), turns)
+}
+
+/// Asks the chat model to fold `turns` into the `previous` summary of a session
+async fn request_summary(
+    state: &Arc<AppState>,
+    headers: &HeaderMap,
+    session_id: &str,
+    model: &str,
+    servers: Option<&HashSet<ServerId>>,
+    previous: Option<&str>,
+    turns: &[ChatMessage],
+) -> ServerResult<String> {
+    let request_body = ChatCompletionRequest {
+        model: Some(model.to_string()),
+        messages: vec![
+            ChatCompletionRequestMessage::new_system_message(SUMMARY_PROMPT.to_string(), None),
+            ChatCompletionRequestMessage::new_user_message(
+                ChatCompletionUserMessageContent::Text(summary_transcript(previous, turns)),
+                None,
+            ),
+        ],
+        stream: Some(false),
+        ..Default::default()
+    };
+
+    let (chat_server, resp) = send_with_retry(
+        state,
+        headers,
+        session_id,
+        &request_body.into(),
+        servers,
+        None,
+    )
+    .await?;
+    let value: Value = resp.json().await.map_err(|e| {
+        ServerError::Operation(format!("Failed to parse the summary response JSON: {e}"))
+    })?;
+    let value = read_reply(value, &chat_server)?;
+    drop(chat_server);
+    record_usage(state, session_id, parse_usage(&value)).await;
+
+    let mut choices = parse_completion(&value)?.choices.unwrap_or_default();
+    let summary = choices.swap_remove(0).message.content.unwrap_or_default();
+    match summary.trim() {
+        "" => Err(ServerError::Operation(
+            "the chat server returned an empty summary".to_string(),
+        )),
+        summary => Ok(summary.to_string()),
+    }
+}
+
+/// Send the chat request downstream, retrying 5xx responses and network errors.
+///
+/// Each attempt asks the chat server group for a server, so a retry goes to another server when
+/// one is available and a server quarantined by a connection failure or behind an open circuit
+/// breaker is skipped. With sticky
+/// routing the session keeps its server unless that server is quarantined. Attempts wait
+/// with jittered exponential backoff and are bounded by `request_timeout_secs`; a last attempt
+/// timing out fails with `ServerError::Timeout`, a 504 for the client.
+/// A 429 is retried too, unless `retry_rate_limited` is off, after the delay of its `Retry-After`
+/// header capped at `max_retry_after_secs`. Any other 4xx response is returned as an error right
+/// away. `servers` limits the servers asked, and
+/// `first`, a server already picked, takes the first attempt.
+async fn send_with_retry(
+    state: &Arc<AppState>,
+    headers: &HeaderMap,
+    session_id: &str,
+    request_body: &DownstreamRequest,
+    servers: Option<&HashSet<ServerId>>,
+    mut first: Option<TargetServerInfo>,
+) -> ServerResult<(TargetServerInfo, reqwest::Response)> {
+    let (config, log_requests) = {
+        let config = state.config.read().await;
+        (config.responses.clone(), config.request_log.enabled)
+    };
+    let max_attempts = config.max_attempts.max(1);
+    let request_timeout = Duration::from_secs(config.request_timeout_secs);
+    // serialized once, so every attempt and the request log carry the same bytes
+    let chat_body = serde_json::to_vec(request_body)
+        .map(Bytes::from)
+        .map_err(|e| {
+            ServerError::Operation(format!("Failed to serialize the chat request: {e}"))
+        })?;
+    let mut completion_body = None;
+
+    let mut attempt = 1;
+    loop {
+        // delay asked by a rate-limited server before the next attempt
+        let mut retry_after = None;
+        let chat_server = match first.take() {
+            Some(chat_server) => chat_server,
+            None => pick_server(state, session_id, servers, &config.server_kinds).await?,
+        };
+
+        // a text completion server gets the messages as a prompt
+        let (url, body) = if chat_server.kind == ServerKind::completion {
+            dual_info!(
+                "Answering session {session_id} with the completion server {}",
+                chat_server.url
+            );
+            let body = completion_body.get_or_insert_with(|| completion_request(request_body));
+            (chat_server.completions_url.clone(), body.clone())
+        } else {
+            (chat_server.chat_url.clone(), chat_body.clone())
+        };
+        let mut request = state
+            .http_client
+            .post(&url)
+            .header(CONTENT_TYPE, "application/json");
+        // a whole reply is bounded by the timeout; a stream only until it starts
+        if request_body.stream != Some(true) {
+            request = request.timeout(request_timeout);
+        }
+        if let Some((name, value)) = &chat_server.auth {
+            request = request.header(name, value);
+        } else if let Some(auth) = headers.get("authorization").and_then(|h| h.to_str().ok()) {
+            request = request.header(AUTHORIZATION, auth);
+        }
+
+        let attempt_span = tracing::info_span!(
+            "downstream",
+            server = %chat_server.url,
+            kind = %chat_server.kind,
+            model = request_body.model.as_deref(),
+            attempt,
+            status = tracing::field::Empty,
+            otel.kind = "client",
+            otel.status_code = tracing::field::Empty,
+        );
+        // the downstream server continues the trace of the attempt
+        request = request.headers(telemetry::trace_headers(&attempt_span));
+        let start = std::time::Instant::now();
+        let result = tokio::time::timeout(request_timeout, request.body(body.clone()).send())
+            .instrument(attempt_span.clone())
+            .await;
+        match &result {
+            Ok(Ok(resp)) => {
+                attempt_span.record("status", resp.status().as_u16());
+                if !resp.status().is_success() {
+                    attempt_span.record("otel.status_code", "ERROR");
+                }
+            }
+            _ => {
+                attempt_span.record("otel.status_code", "ERROR");
+            }
+        }
+        let server_label = chat_server.url.clone();
+        metrics::histogram!(telemetry::DOWNSTREAM_LATENCY_SECONDS, "server" => server_label)
+            .record(start.elapsed().as_secs_f64());
+        // connection failures are left to the health checks and the circuit breaker
+        if !matches!(result, Ok(Err(_))) {
+            chat_server.latency.record(start.elapsed());
+        }
+        attempt_span.in_scope(|| match &result {
+            Ok(Ok(resp)) => dual_info!(
+                "Chat server {} responded with {} in {}ms",
+                chat_server.url,
+                resp.status().as_u16(),
+                start.elapsed().as_millis()
+            ),
+            Ok(Err(e)) => dual_warn!(
+                "Chat server {} failed after {}ms: {e}",
+                chat_server.url,
+                start.elapsed().as_millis()
+            ),
+            Err(_) => dual_warn!(
+                "Chat server {} timed out after {}ms",
+                chat_server.url,
+                start.elapsed().as_millis()
+            ),
+        });
+        if log_requests {
+            log_request(
+                state,
+                RequestLogEntry {
+                    id: None,
+                    session_id: session_id.to_string(),
+                    model: request_body.model.clone(),
+                    server_url: chat_server.url.clone(),
+                    request: String::from_utf8_lossy(&body).into_owned(),
+                    status: match &result {
+                        Ok(Ok(resp)) => Some(i32::from(resp.status().as_u16())),
+                        _ => None,
+                    },
+                    latency_ms: start.elapsed().as_millis() as i64,
+                    timestamp: chrono::Utc::now(),
+                },
+            );
+        }
+
+        let err = match result {
+            Ok(Ok(resp)) if resp.status().is_success() => {
+                chat_server.health.record_success();
+                chat_server.breaker.record_success();
+                return Ok((chat_server, resp));
+            }
+            Ok(Ok(resp))
+                if resp.status() == StatusCode::TOO_MANY_REQUESTS && config.retry_rate_limited =>
+            {
+                // the server is up, only busy
+                chat_server.breaker.record_success();
+                retry_after = resp
+                    .headers()
+                    .get(RETRY_AFTER)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| parse_retry_after(value, chrono::Utc::now()))
+                    .map(|delay| delay.min(Duration::from_secs(config.max_retry_after_secs)));
+                let text = resp.text().await.unwrap_or_default();
+                ServerError::UpstreamRejected(StatusCode::TOO_MANY_REQUESTS.as_u16(), text)
+            }
+            Ok(Ok(resp)) => {
+                let status = resp.status();
+                let text = resp.text().await.unwrap_or_default();
+                if !status.is_server_error() {
+                    // the server is up, the request itself was rejected
+                    chat_server.breaker.record_success();
+                    return Err(ServerError::UpstreamRejected(status.as_u16(), text));
+                }
+                chat_server.breaker.record_failure();
+                ServerError::UpstreamFailed(status.as_u16(), text)
+            }
+            Ok(Err(e)) => {
+                if e.is_connect() {
+                    chat_server.health.record_failure();
+                }
+                chat_server.breaker.record_failure();
+                if e.is_timeout() {
+                    ServerError::Timeout(format!(
+                        "Chat server {} did not respond in time: {e}",
+                        chat_server.url
+                    ))
+                } else if e.is_connect() {
+                    ServerError::Unreachable(format!("chat server {}: {e}", chat_server.url))
+                } else {
+                    ServerError::Operation(format!("Downstream request failed: {e}"))
+                }
+            }
+            Err(_) => {
+                chat_server.breaker.record_failure();
+                ServerError::Timeout(format!(
+                    "Chat server {} did not respond within {}s",
+                    chat_server.url,
+                    request_timeout.as_secs()
+                ))
+            }
+        };
+
+        if attempt >= max_attempts {
+            return Err(err);
+        }
+
+        let delay = retry_after.unwrap_or_else(|| {
+            retry_backoff(
+                attempt,
+                Duration::from_millis(config.retry_base_delay_ms),
+                Duration::from_millis(config.retry_max_delay_ms),
+                jitter(),
+            )
+        });
+        dual_warn!(
+            "Attempt {}/{} on chat server {} failed, retrying in {}ms: {}",
+            attempt,
+            max_attempts,
+            chat_server.id,
+            delay.as_millis(),
+            err
+        );
+        // release the server before waiting so it is not counted as busy
+        drop(chat_server);
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Picks the server of an attempt from the groups of `kinds` in order, asking a kind only when no
+/// server of the kinds before it is available
+async fn pick_server(
+    state: &AppState,
+    session_id: &str,
+    servers: Option<&HashSet<ServerId>>,
+    kinds: &[ServerKind],
+) -> ServerResult<TargetServerInfo> {
+    let groups = state.server_group.read().await;
+    let mut err = ServerError::NotFoundServer(ServerKind::chat.to_string());
+    for kind in kinds {
+        let Some(group) = groups.get(kind) else {
+            continue;
+        };
+        let picked = match servers {
+            Some(servers) => group.next_among(session_id, servers).await,
+            None => group.next_for_session(session_id).await,
+        };
+        match picked {
+            Err(e @ (ServerError::NotFoundServer(_) | ServerError::NoServerAvailable(_))) => {
+                dual_debug!("No {kind} server available for session {session_id}: {e}");
+                err = e;
+            }
+            picked => return picked,
+        }
+    }
+    Err(err)
+}
+
+/// Parameters of a chat request passed on to a text completion server
+const COMPLETION_PARAMETERS: &[&str] = &[
+    "model",
+    "stream",
+    "stream_options",
+    "temperature",
+    "top_p",
+    "n",
+    "max_tokens",
+    "max_completion_tokens",
+    "stop",
+    "presence_penalty",
+    "frequency_penalty",
+    "seed",
+    "user",
+];
+
+/// The text completion request standing for a chat request: the messages become a transcript
+/// prompt ending where the assistant replies, stopped before it writes the next user turn.
+/// Parameters without a text completion equivalent, e.g. `tools`, are left out.
+fn completion_request(request: &DownstreamRequest) -> Bytes {
+    let mut body = serde_json::to_value(request).unwrap_or_default();
+    let Some(fields) = body.as_object_mut() else {
+        return Bytes::new();
+    };
+    let messages = fields.remove("messages").unwrap_or_default();
+    fields.retain(|key, value| COMPLETION_PARAMETERS.contains(&key.as_str()) && !value.is_null());
+    if let Some(max_tokens) = fields.remove("max_completion_tokens") {
+        fields.insert("max_tokens".to_string(), max_tokens);
+    }
+    fields
+        .entry("stop")
+        .or_insert_with(|| serde_json::json!(["\nUser:"]));
+    fields.insert(
+        "prompt".to_string(),
+        Value::from(completion_prompt(
+            messages.as_array().map_or(&[], Vec::as_slice),
+        )),
+    );
+    Bytes::from(body.to_string())
+}
+
+/// Transcript of chat messages for a text completion: a `Role: content` paragraph per message
+/// with text, then `Assistant:` for the reply, followed by the content of a trailing assistant
+/// message
+fn completion_prompt(messages: &[Value]) -> String {
+    let mut prompt = String::new();
+    // a trailing assistant message is a prefill, continued rather than followed by a reply
+    let (messages, prefill) = match messages.split_last() {
+        Some((last, rest)) if last.get("role").and_then(Value::as_str) == Some("assistant") => {
+            (rest, last.get("content").and_then(Value::as_str))
+        }
+        _ => (messages, None),
+    };
+    for message in messages {
+        let role = match message.get("role").and_then(Value::as_str) {
+            Some("system") => "System",
+            Some("assistant") => "Assistant",
+            Some("tool") => "Tool",
+            _ => "User",
+        };
+        let content = match message.get("content") {
+            Some(Value::String(text)) => text.clone(),
+            Some(Value::Array(parts)) => parts
+                .iter()
+                .filter_map(|part| part.get("text").and_then(Value::as_str))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            _ => String::new(),
+        };
+        if !content.is_empty() {
+            prompt.push_str(&format!("{role}: {content}\n\n"));
+        }
+    }
+    prompt.push_str("Assistant:");
+    if let Some(prefill) = prefill {
+        prompt.push_str(&format!(" {prefill}"));
+    }
+    prompt
+}
+
+/// The chat completion equivalent to a text completion, or the stream chunk with `field`
+/// `delta`: the `text` of each choice becomes the content of its assistant `field`
+fn chat_from_text(mut completion: Value, field: &str) -> Value {
+    if let Some(choices) = completion.get_mut("choices").and_then(Value::as_array_mut) {
+        for choice in choices.iter_mut().filter_map(Value::as_object_mut) {
+            if let Some(text) = choice.remove("text") {
+                choice.insert(
+                    field.to_string(),
+                    serde_json::json!({ "role": "assistant", "content": text }),
+                );
+            }
+        }
+    }
+    if completion.get("object").is_some() {
+        completion["object"] = Value::from(if field == "delta" {
+            "chat.completion.chunk"
+        } else {
+            "chat.completion"
+        });
+    }
+    completion
+}
+
+/// Backoff before retry number `attempt` (1-based): `base` doubled per retry and capped at `max`,
+/// scaled into its upper half by `jitter` in `[0, 1)` so concurrent retries spread out.
+fn retry_backoff(attempt: u32, base: Duration, max: Duration, jitter: f64) -> Duration {
+    let delay = base.saturating_mul(1 << (attempt - 1).min(16)).min(max);
+    delay.mul_f64(0.5 + jitter.clamp(0.0, 1.0) / 2.0)
+}
+
+/// Delay asked by a `Retry-After` header, in seconds or as an HTTP date; `None` if it is
+/// neither. A date in the past asks for no delay.
+fn parse_retry_after(value: &str, now: chrono::DateTime<chrono::Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&chrono::Utc) - now)
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+/// A pseudo-random number in `[0, 1)`, good enough to spread retries
+fn jitter() -> f64 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or_default();
+    f64::from(nanos % 1_000_000) / 1_000_000.0
+}
+
+/// Stores a downstream request in the request log from a spawned task, so the reply never waits
+/// on the write
+fn log_request(state: &Arc<AppState>, entry: RequestLogEntry) {
+    let tasks = state.tasks.clone();
+    let state = Arc::clone(state);
+    tasks.spawn(async move {
+        if let Err(e) = state.chat_storage.log_request(&entry).await {
+            dual_warn!(
+                "Failed to log the request of session {} to {}: {e}",
+                entry.session_id,
+                entry.server_url
+            );
+        }
+    });
+}
+
+/// Forward the downstream SSE chunks to the client while accumulating the reply text.
+///
+/// The downstream body is read in a spawned task so the turn is saved even if the client goes
+/// away. A client disconnect or a downstream error drops the downstream stream (aborting the
+/// connection) and the partial reply is saved with [`INTERRUPTED_REPLY_MARKER`] appended. If the
+/// downstream goes quiet for `attempt_timeout_secs`, the stream ends with [`TIMEOUT_EVENT`]
+/// instead of an error and the partial reply is saved as is. Either way the turn is marked
+/// truncated.
+///
+/// A chat server answering with a plain JSON body instead of an event stream has its reply
+/// forwarded as a single chunk followed by `[DONE]`.
+///
+/// The turn is saved with the time from `started`, when the request was sent, to the end of the
+/// stream as its latency.
+///
+/// If the stream reports no usage and `[responses] estimate_stream_usage` is set, a final chunk
+/// with the usage estimated by the token estimator of the state is sent before `[DONE]`, and
+/// recorded as the usage of the turn.
+fn stream_reply(
+    state: Arc<AppState>,
+    payload: ChatRequest,
+    reply_info: StreamedReply,
+    chat_server: TargetServerInfo,
+    resp: reqwest::Response,
+    started: std::time::Instant,
+    session_guard: SessionGuard,
+) -> ServerResult<Response> {
+    let (tx, mut rx) = mpsc::channel::<Result<Bytes, std::io::Error>>(32);
+    let server = chat_server.url.clone();
+    let StreamedReply {
+        model,
+        dropped_turns,
+        prompt_tokens,
+        permit,
+    } = reply_info;
+    let chunk_model = model.clone();
+    // chunks of a text completion stream are passed on in chat form
+    let text_stream = chat_server.kind == ServerKind::completion;
+
+    let span = tracing::Span::current();
+    let tasks = state.tasks.clone();
+    tasks.spawn(
+        async move {
+            let mut ds_stream = if is_event_stream(resp.headers()) {
+                resp.bytes_stream()
+                    .map(|item| item.map_err(std::io::Error::other))
+                    .boxed()
+            } else {
+                dual_warn!(
+                    "Chat server {} did not stream its reply, forwarding it as a single event",
+                    chat_server.url
+                );
+                futures_util::stream::once(completion_events(resp, text_stream)).boxed()
+            };
+            let mut pending: Vec<u8> = Vec::new();
+            let mut reply = String::new();
+            let mut usage = None;
+            let mut tool_calls: Vec<ToolCall> = Vec::new();
+            let mut completed = false;
+            let mut timed_out = false;
+            let mut usage_estimated = false;
+            let mut finish_reason = None;
+            let (idle_timeout, estimate_usage) = {
+                let config = &state.config.read().await.responses;
+                (
+                    Duration::from_secs(config.attempt_timeout_secs),
+                    config.estimate_stream_usage,
+                )
+            };
+            let estimated_usage = |reply: &str, tool_calls: &[ToolCall]| {
+                let mut completion_tokens = (state.token_estimator)(reply);
+                if !tool_calls.is_empty() {
+                    let calls = serde_json::to_string(tool_calls).unwrap_or_default();
+                    completion_tokens += (state.token_estimator)(&calls);
+                }
+                Usage {
+                    prompt_tokens: prompt_tokens as u64,
+                    completion_tokens: completion_tokens as u64,
+                    total_tokens: (prompt_tokens + completion_tokens) as u64,
+                }
+            };
+
+            loop {
+                let item = select! {
+                    item = tokio::time::timeout(idle_timeout, ds_stream.next()) => match item {
+                        Ok(item) => item,
+                        Err(_) => {
+                            let secs = idle_timeout.as_secs();
+                            dual_error!("Chat server {} sent no data for {secs}s", chat_server.url);
+                            // the client keeps what was generated so far
+                            let _ = tx.send(Ok(Bytes::from_static(TIMEOUT_EVENT.as_bytes()))).await;
+                            timed_out = true;
+                            break;
+                        }
+                    },
+                    _ = tx.closed() => {
+                        let session_id = &payload.session_id;
+                        dual_warn!("Client disconnected from the stream of session {session_id}");
+                        break;
+                    }
+                };
+
+                match item {
+                    Some(Ok(bytes)) => {
+                        // collect complete SSE lines, forwarded once whole; a line may be split
+                        // across chunks
+                        pending.extend_from_slice(&bytes);
+                        let mut forwarded = Vec::new();
+                        while let Some(pos) = pending.iter().position(|b| *b == b'\n') {
+                            let line: Vec<u8> = pending.drain(..=pos).collect();
+                            let text = String::from_utf8_lossy(&line);
+                            if is_sse_done(&text) && usage.is_none() && estimate_usage {
+                                let estimated = estimated_usage(&reply, &tool_calls);
+                                let event = usage_event(&chunk_model, &estimated);
+                                forwarded.extend_from_slice(event.as_bytes());
+                                usage = Some(estimated);
+                                usage_estimated = true;
+                            }
+                            let mut chunk = parse_sse_data(&text);
+                            if text_stream {
+                                chunk = chunk.map(|chunk| chat_from_text(chunk, "delta"));
+                            }
+                            match &chunk {
+                                Some(chunk) if text_stream => forwarded
+                                    .extend_from_slice(format!("data: {chunk}\n").as_bytes()),
+                                _ => forwarded.extend_from_slice(&line),
+                            }
+                            if let Some(chunk) = chunk {
+                                if let Some(delta) = sse_delta(&chunk) {
+                                    reply.push_str(&delta);
+                                }
+                                accumulate_tool_call_deltas(&mut tool_calls, &chunk);
+                                let reason = chunk.pointer("/choices/0/finish_reason");
+                                if let Some(reason) = reason.and_then(Value::as_str) {
+                                    finish_reason = Some(reason.to_string());
+                                }
+                                // only the final chunk carries the usage
+                                if let Some(chunk_usage) = parse_usage(&chunk) {
+                                    usage = Some(chunk_usage);
+                                }
+                            }
+                        }
+
+                        if !forwarded.is_empty()
+                            && tx.send(Ok(Bytes::from(forwarded))).await.is_err()
+                        {
+                            let session_id = &payload.session_id;
+                            dual_warn!(
+                                "Client disconnected from the stream of session {session_id}"
+                            );
+                            break;
+                        }
+                    }
+                    Some(Err(e)) => {
+                        dual_error!("Failed to read the downstream stream: {e}");
+                        let _ = tx.send(Err(e)).await;
+                        break;
+                    }
+                    None => {
+                        // a last line without a newline, then the usage of a stream that ended
+                        // without `[DONE]`
+                        let mut rest = std::mem::take(&mut pending);
+                        if usage.is_none() && estimate_usage {
+                            let estimated = estimated_usage(&reply, &tool_calls);
+                            rest.extend_from_slice(
+                                usage_event(&chunk_model, &estimated).as_bytes(),
+                            );
+                            usage = Some(estimated);
+                            usage_estimated = true;
+                        }
+                        if !rest.is_empty() {
+                            let _ = tx.send(Ok(Bytes::from(rest))).await;
+                        }
+                        completed = true;
+                        break;
+                    }
+                }
+            }
+
+            // dropping the downstream stream closes the connection so the backend stops generating
+            drop(ds_stream);
+            drop(chat_server);
+            drop(permit);
+            let latency = started.elapsed();
+
+            if !completed && !timed_out {
+                reply.push_str(INTERRUPTED_REPLY_MARKER);
+            }
+            // the tokens of a cut stream were still generated
+            if usage.is_none() && estimate_usage {
+                usage = Some(estimated_usage(&reply, &tool_calls));
+                usage_estimated = true;
+            }
+            if usage_estimated {
+                let session_id = &payload.session_id;
+                dual_debug!("Estimated the token usage of the stream of session {session_id}");
+            }
+            record_usage(&state, &payload.session_id, usage).await;
+            let assistant_message = (!tool_calls.is_empty()).then(|| {
+                serde_json::json!({
+                    "role": "assistant",
+                    "content": (!reply.is_empty()).then_some(&reply),
+                    "tool_calls": tool_calls,
+                })
+                .to_string()
+            });
+            // the client sent the prefill, the stream carries only its continuation
+            let saved_reply = format!("{}{reply}", payload.prefill.as_deref().unwrap_or_default());
+            let turn = ChatMessage {
+                tool_results: payload.stored_tool_results(),
+                assistant_message,
+                system_prompt: payload.system_prompt.clone(),
+                truncated: !completed || finish_reason.as_deref() == Some("length"),
+                latency_ms: Some(latency.as_millis() as i64),
+                seed: payload.seed,
+                ..ChatMessage::new(
+                    &payload.session_id,
+                    &payload.stored_user_message(),
+                    &saved_reply,
+                )
+            };
+            let turn = match &payload.revises {
+                Some(previous) => turn.next_version_of(previous),
+                None => turn,
+            };
+            if let Err(e) = state.chat_storage.save_turn(turn).await {
+                dual_error!("Failed to save conversation: {e}");
+            } else {
+                dual_info!("Saved streamed turn for session {}", payload.session_id);
+            }
+            drop(session_guard);
+        }
+        .instrument(span),
+    );
+
+    let body = Body::from_stream(futures_util::stream::poll_fn(move |cx| rx.poll_recv(cx)));
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/event-stream")
+        .header(CACHE_CONTROL, "no-cache")
+        .header(DROPPED_TURNS_HEADER, dropped_turns)
+        .header(MODEL_HEADER, model)
+        .header(SERVER_HEADER, server)
+        .body(body)
+        .map_err(|e| ServerError::Operation(format!("Failed to create the response: {e}")))
+}
+
+/// What [`stream_reply`] reports about the reply besides its events
+struct StreamedReply {
+    model: String,
+    dropped_turns: usize,
+    /// Estimated prompt tokens, used if the stream reports no usage
+    prompt_tokens: usize,
+    /// Slot of the request on its model, held until the stream ends
+    permit: Option<ModelPermit>,
+}
+
+/// Whether an SSE line is the `[DONE]` sentinel ending a stream
+fn is_sse_done(line: &str) -> bool {
+    line.trim()
+        .strip_prefix("data:")
+        .is_some_and(|data| data.trim() == "[DONE]")
+}
+
+/// Final stream chunk reporting `usage` estimated by the gateway, without choices as sent with
+/// `stream_options.include_usage`
+fn usage_event(model: &str, usage: &Usage) -> String {
+    let chunk = serde_json::json!({
+        "object": "chat.completion.chunk",
+        "model": model,
+        "choices": [],
+        "usage": usage,
+        "usage_estimated": true,
+    });
+    format!("data: {chunk}\n\n")
+}
+
+/// Whether a response declares a `text/event-stream` body; a missing content type counts as one
+fn is_event_stream(headers: &reqwest::header::HeaderMap) -> bool {
+    let Some(content_type) = headers.get(CONTENT_TYPE) else {
+        return true;
+    };
+    content_type
+        .to_str()
+        .ok()
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("text/event-stream"))
+}
+
+/// Reads a non-streamed chat completion, or text completion if `text`, and turns it into the SSE
+/// events of a stream: one chunk carrying the whole reply, then `[DONE]`
+async fn completion_events(resp: reqwest::Response, text: bool) -> Result<Bytes, std::io::Error> {
+    let body = resp.bytes().await.map_err(std::io::Error::other)?;
+    let completion: Value = serde_json::from_slice(&body).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("neither an event stream nor a JSON reply: {e}"),
+        )
+    })?;
+    let completion = if text {
+        chat_from_text(completion, "message")
+    } else {
+        completion
+    };
+    let chunk = completion_chunk(completion);
+    Ok(Bytes::from(format!("data: {chunk}\n\ndata: [DONE]\n\n")))
+}
+
+/// The stream chunk equivalent to a chat completion: the `message` of each choice becomes its
+/// `delta`, with its tool calls numbered
+fn completion_chunk(mut completion: Value) -> Value {
+    completion["object"] = Value::from("chat.completion.chunk");
+    if let Some(choices) = completion.get_mut("choices").and_then(Value::as_array_mut) {
+        for choice in choices.iter_mut().filter_map(Value::as_object_mut) {
+            let mut delta = choice
+                .remove("message")
+                .unwrap_or_else(|| serde_json::json!({}));
+            if let Some(calls) = delta.get_mut("tool_calls").and_then(Value::as_array_mut) {
+                for (index, call) in calls.iter_mut().enumerate() {
+                    call["index"] = Value::from(index);
+                }
+            }
+            choice.insert("delta".to_string(), delta);
+        }
+    }
+    completion
+}
+
+/// Counts the tokens of a text, for budgets and for the usage a chat server does not report
+pub(crate) type TokenEstimator = Arc<dyn Fn(&str) -> usize + Send + Sync>;
+
+/// Rough token estimate of a text: one token per four characters, rounded up.
+pub(crate) fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Drop the oldest turns of `pairs` until the prompt fits in `max_tokens`.
+///
+/// The system prompt and the new user message are always kept, even if they alone exceed the
+/// budget. `estimate` counts the tokens of a single message, so a real tokenizer can be plugged in
+/// instead of [`estimate_tokens`]. Returns the kept turns and the number of turns dropped.
+pub(crate) fn trim_history_to_budget(
+    pairs: Vec<(String, String)>,
+    system_prompt: &str,
+    user_message: &str,
+    max_tokens: usize,
+    estimate: impl Fn(&str) -> usize,
+) -> (Vec<(String, String)>, usize) {
+    let mut budget = max_tokens.saturating_sub(estimate(system_prompt) + estimate(user_message));
+
+    // walk back from the newest turn and keep as many as fit
+    let mut kept = 0;
+    for (user, bot) in pairs.iter().rev() {
+        let cost = estimate(user) + estimate(bot);
+        if cost > budget {
+            break;
+        }
+        budget -= cost;
+        kept += 1;
+    }
+
+    let dropped = pairs.len() - kept;
+    (pairs.into_iter().skip(dropped).collect(), dropped)
+}
+
+/// Parse the JSON payload of a single SSE `data:` line; `None` for other lines and `[DONE]`.
+pub(super) fn parse_sse_data(line: &str) -> Option<Value> {
+    let data = line.trim().strip_prefix("data:")?.trim();
+    if data.is_empty() || data == "[DONE]" {
+        return None;
+    }
+
+    serde_json::from_str(data).ok()
+}
+
+/// Extract the `choices[0].delta.content` text from a stream chunk.
+pub(super) fn sse_delta(chunk: &Value) -> Option<String> {
+    chunk
+        .get("choices")
+        .and_then(|c| c.get(0))
+        .and_then(|c0| c0.get("delta"))
+        .and_then(|d| d.get("content"))
+        .and_then(|c| c.as_str())
+        .map(|s| s.to_string())
+}
+
+/// A non-streamed chat completion of a downstream server, as far as `/responses` reads it
+#[derive(Debug, Deserialize)]
+struct ChatCompletionResponse {
+    /// At least one once read by [`parse_completion`]
+    #[serde(default)]
+    choices: Option<Vec<CompletionChoice>>,
+    /// Tokens used, as reported by the server; a malformed usage counts as none
+    #[serde(default, deserialize_with = "lenient_usage")]
+    usage: Option<Usage>,
+    /// Sent instead of `choices` by servers that report failures with a success status
+    #[serde(default)]
+    error: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionChoice {
+    message: CompletionMessage,
+    /// Why the model stopped: `stop`, `length` when cut by the token limit, `tool_calls`, ...
+    #[serde(default)]
+    finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionMessage {
+    /// `None` when the message only calls tools
+    #[serde(default)]
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Option<Vec<ToolCall>>,
+}
+
+/// A downstream completion in the OpenAI shape read by [`parse_completion`].
+///
+/// The completion of a server with a `content_path` is rebuilt as a single choice with the text at
+/// `pointer`, keeping its `usage`; one without that text is left as is, so an `error` it carries
+/// is still reported.
+fn read_completion(value: Value, pointer: Option<&str>) -> Result<Value, ServerError> {
+    let Some(pointer) = pointer else {
+        return Ok(value);
+    };
+    match value.pointer(pointer) {
+        Some(Value::String(content)) => Ok(serde_json::json!({
+            "choices": [{ "message": { "role": "assistant", "content": content } }],
+            "usage": value.get("usage"),
+        })),
+        Some(content) => Err(ServerError::UpstreamMalformed(format!(
+            "the content at {pointer} is not text: {content}"
+        ))),
+        None if value.get("error").is_some() => Ok(value),
+        None => Err(ServerError::UpstreamMalformed(format!(
+            "no content at {pointer}"
+        ))),
+    }
+}
+
+/// The chat completion in the reply of `server`, converted from a text completion if the server
+/// is of kind `completion`
+fn read_reply(value: Value, server: &TargetServerInfo) -> Result<Value, ServerError> {
+    let value = read_completion(value, server.content_pointer.as_deref())?;
+    Ok(match server.kind {
+        ServerKind::completion => chat_from_text(value, "message"),
+        _ => value,
+    })
+}
+
+/// Reads a downstream completion with at least one choice.
+///
+/// A completion carrying an `error` object instead of choices fails with the upstream message,
+/// and one without a message in each choice fails as malformed. A message with empty or no
+/// content is valid.
+fn parse_completion(value: &Value) -> Result<ChatCompletionResponse, ServerError> {
+    let completion = ChatCompletionResponse::deserialize(value)
+        .map_err(|e| ServerError::UpstreamMalformed(e.to_string()))?;
+    match (&completion.choices, &completion.error) {
+        (Some(choices), _) if !choices.is_empty() => Ok(completion),
+        (_, Some(error)) => {
+            let message = match &error {
+                Value::String(message) => message.clone(),
+                error => match error.get("message").and_then(Value::as_str) {
+                    Some(message) => message.to_string(),
+                    None => error.to_string(),
+                },
+            };
+            Err(ServerError::UpstreamError(message))
+        }
+        (Some(_), None) => Err(ServerError::UpstreamMalformed("no choices".to_string())),
+        (None, None) => Err(ServerError::UpstreamMalformed(
+            "missing choices".to_string(),
+        )),
+    }
+}
+
+/// Reads a `usage` object, or `None` for a null or malformed one
+fn lenient_usage<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Usage>, D::Error> {
+    let usage = Option::<Value>::deserialize(deserializer)?;
+    Ok(usage.and_then(|usage| serde_json::from_value(usage).ok()))
+}
+
+/// Extract the token `usage` of a response or stream chunk, if the server reported it.
+fn parse_usage(value: &Value) -> Option<Usage> {
+    value
+        .get("usage")
+        .filter(|usage| !usage.is_null())
+        .and_then(|usage| serde_json::from_value(usage.clone()).ok())
+}
+
+/// Add the usage of a downstream request to the totals of its session
+async fn record_usage(state: &AppState, session_id: &str, usage: Option<Usage>) {
+    let Some(usage) = usage else {
+        dual_debug!("The chat server reported no token usage for session {session_id}");
+        return;
+    };
+    if let Err(e) = state.chat_storage.record_usage(session_id, &usage).await {
+        dual_error!("Failed to record the token usage of session {session_id}: {e}");
+    }
+}
+
+pub async fn get_chat_history(
+    State(state): State<Arc<AppState>>,
+    namespace: SessionNamespace,
+    axum::extract::Path(session_id): axum::extract::Path<String>,
+    Query(query): Query<HistoryQuery>,
+) -> ServerResult<Json<ChatHistoryResponse>> {
+    let limit = query.limit.unwrap_or(DEFAULT_HISTORY_PAGE_SIZE);
+    check_page(limit, query.offset)?;
+
+    match state
+        .chat_storage
+        .get_conversation_history_page(&namespace.scope(&session_id), limit, query.offset)
+        .await
+    {
+        Ok((messages, total, offset)) => Ok(Json(ChatHistoryResponse {
+            session_id,
+            messages,
+            deprecated: true,
+            total,
+            limit,
+            offset,
+        })),
+        Err(e) => {
+            dual_error!("Failed to load the history of session {session_id}: {e}");
+            Err(ServerError::Operation(format!(
+                "Failed to load the history: {e}"
+            )))
+        }
+    }
+}
+
+/// Rejects a page of history with a `limit` below 1 or a negative `offset`
+fn check_page(limit: i64, offset: Option<i64>) -> ServerResult<()> {
+    if limit <= 0 {
+        return Err(ServerError::InvalidRequest(
+            "`limit` must be at least 1".to_string(),
+        ));
+    }
+    if offset.is_some_and(|o| o < 0) {
+        return Err(ServerError::InvalidRequest(
+            "`offset` must not be negative".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionMessagesResponse {
+    session_id: String,
+    messages: Vec<ChatMessage>,
+    /// Total number of stored turns in the session
+    total: i64,
+    limit: i64,
+    offset: i64,
+}
+
+/// Returns one page of the turns of a session with their ids and timestamps
+pub async fn get_session_messages(
+    State(state): State<Arc<AppState>>,
+    namespace: SessionNamespace,
+    axum::extract::Path(session_id): axum::extract::Path<String>,
+    Query(query): Query<HistoryQuery>,
+) -> ServerResult<Json<SessionMessagesResponse>> {
+    let limit = query.limit.unwrap_or(DEFAULT_HISTORY_PAGE_SIZE);
+    check_page(limit, query.offset)?;
+
+    match state
+        .chat_storage
+        .get_messages_page(&namespace.scope(&session_id), limit, query.offset)
+        .await
+    {
+        Ok((mut messages, total, offset)) => Ok(Json(SessionMessagesResponse {
+            messages: {
+                messages
+                    .iter_mut()
+                    .for_each(|m| m.session_id = session_id.clone());
+                messages
+            },
+            session_id,
+            total,
+            limit,
+            offset,
+        })),
+        Err(e) => {
+            dual_error!("Failed to load the messages of session {session_id}: {e}");
+            Err(ServerError::Operation(format!(
+                "Failed to load the messages: {e}"
+            )))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryStreamQuery {
+    /// Only turns saved after this time, to resume an interrupted replay
+    #[serde(default)]
+    since: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Streams the stored turns of a session as server-sent events, oldest first.
+///
+/// Each turn is a `message` event with the [`ChatMessage`] as JSON and its timestamp as the event
+/// id, so a client can resume with `?since=`. The stream ends with a `done` event, or an `error`
+/// event if reading the history fails.
+pub async fn stream_session_history(
+    State(state): State<Arc<AppState>>,
+    namespace: SessionNamespace,
+    axum::extract::Path(session_id): axum::extract::Path<String>,
+    Query(query): Query<HistoryStreamQuery>,
+) -> ServerResult<Sse<BoxStream<'static, Result<Event, Infallible>>>> {
+    let turns = match state
+        .chat_storage
+        .stream_session_turns(&namespace.scope(&session_id), query.since)
+        .await
+    {
+        Ok(turns) => turns,
+        Err(e) => {
+            dual_error!("Failed to read the history of session {session_id}: {e}");
+            return Err(ServerError::Operation(format!(
+                "Failed to read the history: {e}"
+            )));
+        }
+    };
+
+    let events = turns
+        .map(move |turn| match turn {
+            Ok(turn) => {
+                let turn = ChatMessage {
+                    session_id: session_id.clone(),
+                    ..turn
+                };
+                let event = Event::default()
+                    .event("message")
+                    .id(rfc3339::format(&turn.timestamp));
+                Ok(event
+                    .json_data(&turn)
+                    .unwrap_or_else(|e| Event::default().event("error").data(e.to_string())))
+            }
+            Err(e) => {
+                dual_error!("Failed to read a turn of session {session_id}: {e}");
+                Ok(Event::default().event("error").data(e.to_string()))
+            }
+        })
+        .chain(futures_util::stream::once(async {
+            Ok(Event::default().event("done").data(""))
+        }))
+        .boxed();
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Lists the sessions of the user of the request with stored history, filtered by `updated_after`
+/// and `updated_before` and ordered by `sort`, most recently updated first by default
+pub async fn get_all_sessions(
+    State(state): State<Arc<AppState>>,
+    namespace: SessionNamespace,
+    Query(mut filter): Query<SessionFilter>,
+) -> ServerResult<Json<SessionsResponse>> {
+    filter.prefix = namespace.prefix().map(str::to_string);
+    match state.chat_storage.list_sessions(&filter).await {
+        Ok(sessions) => Ok(Json(SessionsResponse {
+            sessions: sessions
+                .into_iter()
+                .filter_map(|metadata| {
+                    Some(SessionEntry {
+                        session_id: namespace.unscope(&metadata.session_id)?.to_string(),
+                        updated_at: metadata.updated_at,
+                        message_count: metadata.message_count,
+                        preview: metadata.preview,
+                    })
+                })
+                .collect(),
+        })),
+        Err(e) => {
+            dual_error!("Failed to list sessions: {e}");
+            Err(ServerError::Operation(format!(
+                "Failed to list sessions: {e}"
+            )))
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DetailedSessionsResponse {
+    sessions: Vec<SessionMetadata>,
+}
+
+pub async fn get_sessions_detailed(
+    State(state): State<Arc<AppState>>,
+    namespace: SessionNamespace,
+    Query(mut filter): Query<SessionFilter>,
+) -> ServerResult<Json<DetailedSessionsResponse>> {
+    filter.prefix = namespace.prefix().map(str::to_string);
+    match state.chat_storage.list_sessions(&filter).await {
+        Ok(sessions) => Ok(Json(DetailedSessionsResponse {
+            sessions: sessions
+                .into_iter()
+                .filter_map(|metadata| {
+                    let session_id = namespace.unscope(&metadata.session_id)?.to_string();
+                    Some(SessionMetadata {
+                        session_id,
+                        ..metadata
+                    })
+                })
+                .collect(),
+        })),
+        Err(e) => {
+            dual_error!("Failed to list session metadata: {e}");
+            Err(ServerError::Operation(format!(
+                "Failed to list sessions: {e}"
+            )))
+        }
+    }
+}
+
+/// Returns the request defaults of a model; empty for a registered model without any
+pub async fn get_model_defaults(
+    State(state): State<Arc<AppState>>,
+    axum::extract::Path(model_id): axum::extract::Path<String>,
+) -> ServerResult<Json<ModelDefaults>> {
+    if let Some(defaults) = state.model_defaults.read().await.get(&model_id) {
+        return Ok(Json(defaults.clone()));
+    }
+
+    let models = state.models.read().await;
+    if models.values().flatten().any(|m| m.id == model_id) {
+        Ok(Json(ModelDefaults::default()))
+    } else {
+        Err(ServerError::NotFound(format!(
+            "no model `{model_id}` is registered"
+        )))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SessionTitleBody {
+    /// The title of the session; `null` generates one from the next message
+    #[serde(default)]
+    title: Option<String>,
+}
+
+pub async fn set_session_title(
+    State(state): State<Arc<AppState>>,
+    namespace: SessionNamespace,
+    axum::extract::Path(session_id): axum::extract::Path<String>,
+    Json(body): Json<SessionTitleBody>,
+) -> ServerResult<StatusCode> {
+    match state
+        .chat_storage
+        .set_session_title(&namespace.scope(&session_id), body.title.as_deref())
+        .await
+    {
+        Ok(_) => Ok(StatusCode::OK),
+        Err(e) => {
+            dual_error!("Failed to set the title of session {session_id}: {e}");
+            Err(ServerError::Operation(format!(
+                "Failed to set the title: {e}"
+            )))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    q: String,
+    /// Restrict the search to one session
+    #[serde(default)]
+    session_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchResponse {
+    query: String,
+    matches: Vec<SearchMatch>,
+}
+
+pub async fn search_chat_history(
+    State(state): State<Arc<AppState>>,
+    namespace: SessionNamespace,
+    Query(query): Query<SearchQuery>,
+) -> ServerResult<Json<SearchResponse>> {
+    if query.q.trim().is_empty() {
+        return Err(ServerError::InvalidRequest("`q` is empty".to_string()));
+    }
+
+    match state
+        .chat_storage
+        .search_messages(
+            &query.q,
+            query.session_id.map(|id| namespace.scope(&id)).as_deref(),
+        )
+        .await
+    {
+        Ok(matches) => Ok(Json(SearchResponse {
+            query: query.q,
+            matches: matches
+                .into_iter()
+                .filter_map(|m| {
+                    let session_id = namespace.unscope(&m.session_id)?.to_string();
+                    Some(SearchMatch { session_id, ..m })
+                })
+                .collect(),
+        })),
+        Err(e) => {
+            dual_error!("Failed to search chat history: {e}");
+            Err(ServerError::Operation(format!(
+                "Failed to search the history: {e}"
+            )))
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SystemPromptBody {
+    /// The system prompt for the session; `null` restores the default prompt
+    #[serde(default)]
+    system_prompt: Option<String>,
+}
+
+pub async fn get_system_prompt(
+    State(state): State<Arc<AppState>>,
+    namespace: SessionNamespace,
+    axum::extract::Path(session_id): axum::extract::Path<String>,
+) -> ServerResult<Json<SystemPromptBody>> {
+    match state
+        .chat_storage
+        .get_system_prompt(&namespace.scope(&session_id))
+        .await
+    {
+        Ok(system_prompt) => Ok(Json(SystemPromptBody { system_prompt })),
+        Err(e) => {
+            dual_error!("Failed to load the system prompt of session {session_id}: {e}");
+            Err(ServerError::Operation(format!(
+                "Failed to load the system prompt: {e}"
+            )))
+        }
+    }
+}
+
+pub async fn set_system_prompt(
+    State(state): State<Arc<AppState>>,
+    namespace: SessionNamespace,
+    axum::extract::Path(session_id): axum::extract::Path<String>,
+    Json(body): Json<SystemPromptBody>,
+) -> ServerResult<StatusCode> {
+    match state
+        .chat_storage
+        .set_system_prompt(&namespace.scope(&session_id), body.system_prompt.as_deref())
+        .await
+    {
+        Ok(_) => Ok(StatusCode::OK),
+        Err(e) => {
+            dual_error!("Failed to set the system prompt of session {session_id}: {e}");
+            Err(ServerError::Operation(format!(
+                "Failed to set the system prompt: {e}"
+            )))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteSessionQuery {
+    /// Erase the history for good instead of marking it deleted
+    #[serde(default)]
+    hard: bool,
+}
+
+pub async fn delete_session(
+    State(state): State<Arc<AppState>>,
+    namespace: SessionNamespace,
+    axum::extract::Path(session_id): axum::extract::Path<String>,
+    Query(query): Query<DeleteSessionQuery>,
+) -> ServerResult<StatusCode> {
+    let stored_id = namespace.scope(&session_id);
+    let result = if query.hard {
+        state.chat_storage.erase_session(&stored_id).await
+    } else {
+        state.chat_storage.delete_session(&stored_id).await
+    };
+
+    match result {
+        Ok(_) => Ok(StatusCode::OK),
+        Err(e) => {
+            dual_error!("Failed to delete session {session_id}: {e}");
+            Err(ServerError::Operation(format!(
+                "Failed to delete the session: {e}"
+            )))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteStaleSessionsQuery {
+    /// Inactivity after which a session is deleted, e.g. `90s`, `30m`, `12h`, `7d` or `2w`
+    #[serde(deserialize_with = "deserialize_duration")]
+    older_than: Duration,
+    /// Erase the histories for good instead of marking them deleted
+    #[serde(default)]
+    hard: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeleteStaleSessionsResponse {
+    deleted: Vec<String>,
+}
+
+/// Deletes every session not updated for `older_than`, in one transaction with a database
+pub async fn delete_stale_sessions(
+    State(state): State<Arc<AppState>>,
+    namespace: SessionNamespace,
+    Query(query): Query<DeleteStaleSessionsQuery>,
+) -> ServerResult<Json<DeleteStaleSessionsResponse>> {
+    // the sessions of other subjects are out of reach of a scoped token
+    if namespace.is_scoped() {
+        return Err(ServerError::Forbidden(
+            "deleting stale sessions needs admin access".to_string(),
+        ));
+    }
+    match state
+        .chat_storage
+        .delete_sessions_older_than(query.older_than, query.hard)
+        .await
+    {
+        Ok(deleted) => {
+            dual_info!(
+                "Deleted {} session(s) inactive for {:?}",
+                deleted.len(),
+                query.older_than
+            );
+            Ok(Json(DeleteStaleSessionsResponse { deleted }))
+        }
+        Err(e) => {
+            dual_error!("Failed to delete stale sessions: {e}");
+            Err(ServerError::Operation(format!(
+                "Failed to delete stale sessions: {e}"
+            )))
+        }
+    }
+}
+
+/// Parses a duration of a number and a unit, `s`, `m`, `h`, `d` or `w`; a bare number is seconds
+fn parse_duration(text: &str) -> Option<Duration> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: u64 = number.parse().ok()?;
+    let unit_secs = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86_400,
+        "w" => 604_800,
+        _ => return None,
+    };
+    Some(Duration::from_secs(number.checked_mul(unit_secs)?))
+}
+
+fn deserialize_duration<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Duration, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    parse_duration(&text)
+        .ok_or_else(|| serde::de::Error::custom(format!("invalid duration `{text}`")))
+}
+
+#[derive(Debug, Serialize)]
+pub struct RestoreResponse {
+    session_id: String,
+    restored: u64,
+}
+
+pub async fn restore_session(
+    State(state): State<Arc<AppState>>,
+    namespace: SessionNamespace,
+    axum::extract::Path(session_id): axum::extract::Path<String>,
+) -> ServerResult<Json<RestoreResponse>> {
+    match state
+        .chat_storage
+        .restore_session(&namespace.scope(&session_id))
+        .await
+    {
+        Ok(0) => Err(ServerError::NotFound(format!(
+            "session {session_id} has nothing to restore"
+        ))),
+        Ok(restored) => Ok(Json(RestoreResponse {
+            session_id,
+            restored,
+        })),
+        Err(e) => {
+            dual_error!("Failed to restore session {session_id}: {e}");
+            Err(ServerError::Operation(format!(
+                "Failed to restore the session: {e}"
+            )))
+        }
+    }
+}
+
+/// Deletes one turn of a session by id
+pub async fn delete_session_message(
+    State(state): State<Arc<AppState>>,
+    namespace: SessionNamespace,
+    axum::extract::Path((session_id, message_id)): axum::extract::Path<(String, i64)>,
+) -> ServerResult<StatusCode> {
+    match state
+        .chat_storage
+        .delete_message(&namespace.scope(&session_id), message_id)
+        .await
+    {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(ServerError::NotFound(format!(
+            "session {session_id} has no message {message_id}"
+        ))),
+        Err(e) => {
+            dual_error!("Failed to delete message {message_id} of session {session_id}: {e}");
+            Err(ServerError::Operation(format!(
+                "Failed to delete the message: {e}"
+            )))
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct MessageVersionsResponse {
+    session_id: String,
+    message_id: i64,
+    /// Every version of the turn, oldest first; the last one is in the history
+    versions: Vec<ChatMessage>,
+}
+
+/// Returns every version of a turn, from the original to the latest regeneration; `message_id`
+/// may be the id of any of them
+pub async fn get_message_versions(
+    State(state): State<Arc<AppState>>,
+    namespace: SessionNamespace,
+    axum::extract::Path((session_id, message_id)): axum::extract::Path<(String, i64)>,
+) -> ServerResult<Json<MessageVersionsResponse>> {
+    match state
+        .chat_storage
+        .get_message_versions(&namespace.scope(&session_id), message_id)
+        .await
+    {
+        Ok(versions) if versions.is_empty() => Err(ServerError::NotFound(format!(
+            "session {session_id} has no message {message_id}"
+        ))),
+        Ok(mut versions) => {
+            versions
+                .iter_mut()
+                .for_each(|m| m.session_id = session_id.clone());
+            Ok(Json(MessageVersionsResponse {
+                session_id,
+                message_id,
+                versions,
+            }))
+        }
+        Err(e) => {
+            dual_error!(
+                "Failed to load the versions of message {message_id} of session {session_id}: {e}"
+            );
+            Err(ServerError::Operation(format!(
+                "Failed to load the versions: {e}"
+            )))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AppendMessageRequest {
+    role: MessageRole,
+    /// Text of a `user`, `assistant` or `system` message
+    #[serde(default)]
+    content: String,
+    /// Results of a `tool` message
+    #[serde(default)]
+    tool_results: Vec<ToolResult>,
+}
+
+/// Appends a single message to a session without sending anything to a model, e.g. a system
+/// notice or the results of tools the client ran
+pub async fn append_session_message(
+    State(state): State<Arc<AppState>>,
+    namespace: SessionNamespace,
+    axum::extract::Path(session_id): axum::extract::Path<String>,
+    Json(request): Json<AppendMessageRequest>,
+) -> ServerResult<StatusCode> {
+    let content = match request.role {
+        MessageRole::Turn => {
+            return Err(ServerError::InvalidRequest(
+                "`role` must be `user`, `assistant`, `system` or `tool`".to_string(),
+            ));
+        }
+        MessageRole::Tool => {
+            if request.tool_results.is_empty()
+                || request
+                    .tool_results
+                    .iter()
+                    .any(|r| r.tool_call_id.is_empty())
+            {
+                return Err(ServerError::InvalidRequest(
+                    "a `tool` message needs `tool_results`, each with a `tool_call_id`".to_string(),
+                ));
+            }
+            serde_json::to_string(&request.tool_results)
+                .map_err(|e| ServerError::Operation(e.to_string()))?
+        }
+        _ => request.content,
+    };
+    let max_chars = state.config.read().await.limits.max_message_chars;
+    if max_chars > 0 && content.chars().count() > max_chars {
+        return Err(ServerError::PayloadTooLarge(format!(
+            "the message is longer than {max_chars} characters"
+        )));
+    }
+
+    state
+        .chat_storage
+        .save_single_message(&namespace.scope(&session_id), request.role, &content)
+        .await
+        .map_err(|e| {
+            ServerError::Operation(format!(
+                "Failed to save a message of session {session_id}: {e}"
+            ))
+        })?;
+    Ok(StatusCode::CREATED)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegenerateRequest {
+    /// Corrected user message of the turn; the original one is sent again if absent
+    #[serde(default)]
+    user_message: Option<String>,
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    stream: Option<bool>,
+    /// Images of the turn; the history only keeps placeholders, so they have to be sent again
+    #[serde(default)]
+    images: Vec<String>,
+    #[serde(default)]
+    tools: Option<Vec<Tool>>,
+    #[serde(default)]
+    tool_choice: Option<ToolChoice>,
+    /// Sampler seed; the seed saved with the turn if absent, so the reply can be reproduced
+    #[serde(default)]
+    seed: Option<i64>,
+}
+
+/// Drops every turn after a turn, then sends the turn again and saves the new reply.
+///
+/// Where the storage keeps versions, the new reply is saved as the next version of the turn and
+/// the earlier versions stay readable; otherwise the turn is dropped with the later ones. The
+/// reply is returned as by `/responses`. The turns are dropped before the request goes
+/// downstream, so they stay dropped if it fails.
+pub async fn regenerate_message(
+    State(state): State<Arc<AppState>>,
+    namespace: SessionNamespace,
+    key: Option<Extension<AuthenticatedKey>>,
+    headers: HeaderMap,
+    axum::extract::Path((session_id, message_id)): axum::extract::Path<(String, i64)>,
+    Json(body): Json<RegenerateRequest>,
+) -> ServerResult<Response> {
+    let session_id = namespace.scope(&session_id);
+    let session_guard = state.session_locks.lock(&session_id).await;
+    let message = state
+        .chat_storage
+        .get_message(&session_id, message_id)
+        .await
+        .map_err(|e| ServerError::Operation(format!("Failed to load message {message_id}: {e}")))?
+        .ok_or_else(|| {
+            ServerError::NotFound(format!("message {message_id} of session {session_id}"))
+        })?;
+
+    let mut payload = ChatRequest {
+        session_id,
+        user_message: body
+            .user_message
+            .unwrap_or_else(|| strip_image_placeholders(&message.user_message).to_string()),
+        model: body.model,
+        capabilities: Vec::new(),
+        min_context_length: None,
+        stream: body.stream,
+        temperature: None,
+        top_p: None,
+        max_tokens: None,
+        stop: None,
+        history_limit: None,
+        n: None,
+        presence_penalty: None,
+        frequency_penalty: None,
+        images: body.images,
+        tools: body.tools,
+        tool_choice: body.tool_choice,
+        // the stored tool results of the turn are sent again
+        tool_results: message
+            .tool_results
+            .as_deref()
+            .and_then(|json| serde_json::from_str(json).ok())
+            .unwrap_or_default(),
+        system_prompt: None,
+        // a regenerated reply must not be the cached one
+        cache: Some(false),
+        seed: body.seed.or(message.seed),
+        dry_run: false,
+        prefill: None,
+        server: None,
+        revises: None,
+    };
+    check_request(&state, &headers, &namespace, key.as_deref(), &mut payload).await?;
+
+    let session_id = &payload.session_id;
+    let versioned = state
+        .chat_storage
+        .keeps_versions()
+        .await
+        .map_err(|e| ServerError::Operation(format!("Failed to open the storage: {e}")))?;
+    let dropped = state
+        .chat_storage
+        .truncate_after(session_id, message_id, !versioned)
+        .await
+        .map_err(|e| {
+            ServerError::Operation(format!("Failed to truncate session {session_id}: {e}"))
+        })?;
+    dual_info!(
+        "Regenerating from message {message_id} of session {session_id}, dropped {dropped} turn(s)"
+    );
+    payload.revises = versioned.then_some(message);
+
+    respond(state, headers, payload, session_guard).await
+}
+
+impl ToolResult {
+    fn to_message(&self) -> ChatCompletionRequestMessage {
+        ChatCompletionRequestMessage::new_tool_message(
+            self.content.clone(),
+            Some(self.tool_call_id.clone()),
+        )
+    }
+}
+
+/// Replays a stored turn as request messages: its tool results, user message and reply. A single
+/// message is replayed on its own, with its role.
+fn turn_messages(turn: ChatMessage) -> Vec<ChatCompletionRequestMessage> {
+    let tool_results: Vec<ToolResult> = turn
+        .tool_results
+        .as_deref()
+        .and_then(|json| serde_json::from_str(json).ok())
+        .unwrap_or_default();
+    let mut messages: Vec<_> = tool_results.iter().map(ToolResult::to_message).collect();
+    match turn.role {
+        MessageRole::Turn | MessageRole::Assistant => {}
+        MessageRole::User => {
+            messages.push(ChatCompletionRequestMessage::new_user_message(
+                ChatCompletionUserMessageContent::Text(turn.user_message),
+                None,
+            ));
+            return messages;
+        }
+        MessageRole::System => {
+            return vec![ChatCompletionRequestMessage::new_system_message(
+                turn.bot_reply,
+                None,
+            )];
+        }
+        MessageRole::Tool => return messages,
+    }
+    if turn.role.is_turn() && (!turn.user_message.is_empty() || tool_results.is_empty()) {
+        messages.push(ChatCompletionRequestMessage::new_user_message(
+            ChatCompletionUserMessageContent::Text(turn.user_message),
+            None,
+        ));
+    }
+
+    let tool_calls = turn
+        .assistant_message
+        .as_deref()
+        .and_then(|json| serde_json::from_str::<Value>(json).ok())
+        .and_then(|message| {
+            serde_json::from_value::<Vec<ToolCall>>(message.get("tool_calls")?.clone()).ok()
+        })
+        .filter(|calls| !calls.is_empty());
+    // a reply calling tools may have no text
+    let content = (!turn.bot_reply.is_empty() || tool_calls.is_none()).then_some(turn.bot_reply);
+    messages.push(ChatCompletionRequestMessage::new_assistant_message(
+        content, None, tool_calls,
+    ));
+    messages
+}
+
+/// Merges the `tool_calls` deltas of a streamed chunk into `tool_calls`.
+///
+/// The first delta of a call carries its id and function name; the arguments arrive in pieces
+/// that are appended in order.
+fn accumulate_tool_call_deltas(tool_calls: &mut Vec<ToolCall>, chunk: &Value) {
+    let Some(deltas) = chunk
+        .pointer("/choices/0/delta/tool_calls")
+        .and_then(Value::as_array)
+    else {
+        return;
+    };
+    for delta in deltas {
+        let index = delta.get("index").and_then(Value::as_u64).unwrap_or(0) as usize;
+        while tool_calls.len() <= index {
+            tool_calls.push(ToolCall {
+                id: String::new(),
+                ty: "function".to_string(),
+                function: Function {
+                    name: String::new(),
+                    arguments: String::new(),
+                },
+            });
+        }
+        let call = &mut tool_calls[index];
+        if let Some(id) = delta.get("id").and_then(Value::as_str) {
+            call.id = id.to_string();
+        }
+        if let Some(name) = delta.pointer("/function/name").and_then(Value::as_str) {
+            call.function.name.push_str(name);
+        }
+        if let Some(arguments) = delta.pointer("/function/arguments").and_then(Value::as_str) {
+            call.function.arguments.push_str(arguments);
+        }
+    }
+}
+
+/// Removes the image placeholder lines `ChatRequest::stored_user_message` appends
+fn strip_image_placeholders(message: &str) -> &str {
+    let mut message = message;
+    while let Some(stripped) = message
+        .strip_suffix(IMAGE_PLACEHOLDER)
+        .and_then(|m| m.strip_suffix('\n'))
+    {
+        message = stripped;
+    }
+    message
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionExistsResponse {
+    exists: bool,
+    message_count: i64,
+}
+
+/// Whether a session has stored messages, and how many, without loading its history
+pub async fn session_exists(
+    State(state): State<Arc<AppState>>,
+    namespace: SessionNamespace,
+    axum::extract::Path(session_id): axum::extract::Path<String>,
+) -> ServerResult<Json<SessionExistsResponse>> {
+    match state
+        .chat_storage
+        .count_messages(&namespace.scope(&session_id))
+        .await
+    {
+        Ok(message_count) => Ok(Json(SessionExistsResponse {
+            exists: message_count > 0,
+            message_count,
+        })),
+        Err(e) => {
+            dual_error!("Failed to count the messages of session {session_id}: {e}");
+            Err(ServerError::Operation(format!(
+                "Failed to count the messages: {e}"
+            )))
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionStatsResponse {
+    session_id: String,
+    /// Stored turns answered by a downstream server, whose latency was recorded
+    timed_turns: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    p50_latency_ms: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    p95_latency_ms: Option<i64>,
+}
+
+/// The `p`th percentile of `sorted` by the nearest-rank method; `None` if it is empty
+fn percentile(sorted: &[i64], p: f64) -> Option<i64> {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted.get(rank.clamp(1, sorted.len().max(1)) - 1).copied()
+}
+
+/// Returns the median and 95th percentile of the downstream latency of a session's turns
+pub async fn get_session_stats(
+    State(state): State<Arc<AppState>>,
+    namespace: SessionNamespace,
+    axum::extract::Path(session_id): axum::extract::Path<String>,
+) -> ServerResult<Json<SessionStatsResponse>> {
+    let turns = match state
+        .chat_storage
+        .get_session_turns(&namespace.scope(&session_id))
+        .await
+    {
+        Ok(turns) => turns,
+        Err(e) => {
+            dual_error!("Failed to load the turns of session {session_id}: {e}");
+            return Err(ServerError::Operation(format!(
+                "Failed to load the turns: {e}"
+            )));
+        }
+    };
+    let mut latencies: Vec<i64> = turns.iter().filter_map(|turn| turn.latency_ms).collect();
+    latencies.sort_unstable();
+    Ok(Json(SessionStatsResponse {
+        session_id,
+        timed_turns: latencies.len(),
+        p50_latency_ms: percentile(&latencies, 50.0),
+        p95_latency_ms: percentile(&latencies, 95.0),
+    }))
+}
+
+/// Returns the cumulative token usage of a session
+pub async fn get_session_usage(
+    State(state): State<Arc<AppState>>,
+    namespace: SessionNamespace,
+    axum::extract::Path(session_id): axum::extract::Path<String>,
+) -> ServerResult<Json<SessionUsage>> {
+    match state
+        .chat_storage
+        .get_session_usage(&namespace.scope(&session_id))
+        .await
+    {
+        Ok(usage) => Ok(Json(usage)),
+        Err(e) => {
+            dual_error!("Failed to load the token usage of session {session_id}: {e}");
+            Err(ServerError::Operation(format!(
+                "Failed to load the token usage: {e}"
+            )))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+/// Returns the history of a session as a downloadable JSON or Markdown file
+pub async fn export_session(
+    State(state): State<Arc<AppState>>,
+    namespace: SessionNamespace,
+    axum::extract::Path(session_id): axum::extract::Path<String>,
+    Query(query): Query<ExportQuery>,
+) -> ServerResult<Response> {
+    text_export(&state, &namespace, &session_id, query.format).await
+}
+
+/// Format of a session bundle
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BundleFormat {
+    /// Zip archive of the transcript and its attachments, or the JSON export of a text-only session
+    #[default]
+    Zip,
+    Json,
+    Markdown,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BundleQuery {
+    #[serde(default)]
+    format: Option<BundleFormat>,
+}
+
+/// Returns a session along with its attachments. A text-only session is exported as
+/// [`export_session`] does; an explicit `format=zip` answers 501, since the images sent with a
+/// turn are forwarded to the chat server but not stored, so there is nothing to put in the archive.
+pub async fn export_session_bundle(
+    State(state): State<Arc<AppState>>,
+    namespace: SessionNamespace,
+    axum::extract::Path(session_id): axum::extract::Path<String>,
+    Query(query): Query<BundleQuery>,
+) -> ServerResult<Response> {
+    let format = match query.format {
+        None | Some(BundleFormat::Json) => ExportFormat::Json,
+        Some(BundleFormat::Markdown) => ExportFormat::Markdown,
+        Some(BundleFormat::Zip) => {
+            return Err(ServerError::NotImplemented(
+                "zip bundles need stored image attachments, which are not kept yet".to_string(),
+            ));
+        }
+    };
+    text_export(&state, &namespace, &session_id, format).await
+}
+
+/// Serves the JSON or Markdown export of a session as a downloadable file
+async fn text_export(
+    state: &AppState,
+    namespace: &SessionNamespace,
+    session_id: &str,
+    format: ExportFormat,
+) -> ServerResult<Response> {
+    let export = match state
+        .chat_storage
+        .export_session(&namespace.scope(session_id), format)
+        .await
+    {
+        Ok(Some(export)) => export,
+        Ok(None) => {
+            return Err(ServerError::NotFound(format!(
+                "session {session_id} has no stored turns"
+            )));
+        }
+        Err(e) => {
+            dual_error!("Failed to export session {session_id}: {e}");
+            return Err(ServerError::Operation(format!(
+                "Failed to export the session: {e}"
+            )));
+        }
+    };
+
+    let content_type = match format {
+        ExportFormat::Json => "application/json",
+        ExportFormat::Markdown => "text/markdown; charset=utf-8",
+    };
+    // keep the filename safe to quote whatever the session id contains
+    let stem: String = session_id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let disposition = format!(
+        "attachment; filename=\"session-{stem}.{}\"",
+        format.extension()
+    );
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, content_type)
+        .header(CONTENT_DISPOSITION, disposition)
+        .body(Body::from(export))
+        .map_err(|e| ServerError::Operation(format!("Failed to build the export: {e}")))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PruneQuery {
+    /// Number of most recent turns to keep
+    keep_last: usize,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ForkSessionBody {
+    /// Last turn copied to the fork; every turn is copied if absent
+    #[serde(default)]
+    until_message_id: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ForkResponse {
+    session_id: String,
+    forked_from: String,
+    copied: u64,
+}
+
+/// Copies the history of a session, up to `until_message_id` if given, into a new session with a
+/// generated id. A turn in progress on the session is waited for, so it is copied once saved.
+pub async fn fork_session(
+    State(state): State<Arc<AppState>>,
+    namespace: SessionNamespace,
+    axum::extract::Path(session_id): axum::extract::Path<String>,
+    body: Option<Json<ForkSessionBody>>,
+) -> ServerResult<(StatusCode, Json<ForkResponse>)> {
+    let Json(body) = body.unwrap_or_default();
+    let source_id = namespace.scope(&session_id);
+    let _session_guard = state.session_locks.lock(&source_id).await;
+
+    let fork_id = uuid::Uuid::new_v4().to_string();
+    match state
+        .chat_storage
+        .fork_session(
+            &source_id,
+            &namespace.scope(&fork_id),
+            body.until_message_id,
+        )
+        .await
+    {
+        Ok(Some(copied)) => {
+            dual_info!("Forked {copied} turn(s) of session {session_id} into session {fork_id}");
+            let fork = ForkResponse {
+                session_id: fork_id,
+                forked_from: session_id,
+                copied,
+            };
+            Ok((StatusCode::CREATED, Json(fork)))
+        }
+        Ok(None) => Err(ServerError::NotFound(format!(
+            "session {session_id} has no turns to fork"
+        ))),
+        Err(e) => {
+            dual_error!("Failed to fork session {session_id}: {e}");
+            Err(ServerError::Operation(format!(
+                "Failed to fork the session: {e}"
+            )))
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PruneResponse {
+    session_id: String,
+    deleted: u64,
+}
+
+pub async fn prune_session_history(
+    State(state): State<Arc<AppState>>,
+    namespace: SessionNamespace,
+    axum::extract::Path(session_id): axum::extract::Path<String>,
+    Query(query): Query<PruneQuery>,
+) -> ServerResult<Json<PruneResponse>> {
+    match state
+        .chat_storage
+        .prune_session(&namespace.scope(&session_id), query.keep_last)
+        .await
+    {
+        Ok(deleted) => Ok(Json(PruneResponse {
+            session_id,
+            deleted,
+        })),
+        Err(e) => {
+            dual_error!("Failed to prune session {session_id}: {e}");
+            Err(ServerError::Operation(format!(
+                "Failed to prune the session: {e}"
+            )))
+        }
+    }
+}
+
+#[test]
+fn test_parse_sse_delta() {
+    let parse_sse_delta = |line: &str| parse_sse_data(line).as_ref().and_then(sse_delta);
+
+    let line = r#"data: {"choices":[{"index":0,"delta":{"content":"Hel"}}]}"#;
+    assert_eq!(parse_sse_delta(line), Some("Hel".to_string()));
+
+    let line = r#"data:{"choices":[{"index":0,"delta":{"role":"assistant"}}]}"#;
+    assert_eq!(parse_sse_delta(line), None);
+
+    assert_eq!(parse_sse_delta("data: [DONE]"), None);
+    assert_eq!(parse_sse_delta(": keep-alive"), None);
+}
+
+#[test]
+fn test_completion_chunk() {
+    let mut headers = reqwest::header::HeaderMap::new();
+    assert!(is_event_stream(&headers));
+    headers.insert(
+        CONTENT_TYPE,
+        "text/event-stream; charset=utf-8".parse().unwrap(),
+    );
+    assert!(is_event_stream(&headers));
+    headers.insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    assert!(!is_event_stream(&headers));
+
+    let completion = serde_json::json!({
+        "object": "chat.completion",
+        "choices": [{"index": 0, "message": {"role": "assistant", "content": "Hi", "tool_calls": [
+            {"id": "c1", "type": "function", "function": {"name": "a", "arguments": "{}"}},
+            {"id": "c2", "type": "function", "function": {"name": "b", "arguments": "{}"}}
+        ]}, "finish_reason": "tool_calls"}],
+        "usage": {"prompt_tokens": 3, "completion_tokens": 1, "total_tokens": 4}
+    });
+    let chunk = completion_chunk(completion);
+    assert_eq!(chunk["object"], "chat.completion.chunk");
+    assert_eq!(sse_delta(&chunk).as_deref(), Some("Hi"));
+    assert!(parse_usage(&chunk).is_some());
+
+    let mut tool_calls = Vec::new();
+    accumulate_tool_call_deltas(&mut tool_calls, &chunk);
+    assert_eq!(
+        tool_calls
+            .iter()
+            .map(|call| call.function.name.as_str())
+            .collect::<Vec<_>>(),
+        ["a", "b"]
+    );
+}
+
+#[test]
+fn test_validate_length() {
+    let request = |json: &str| serde_json::from_str::<ChatRequest>(json).unwrap();
+    let short = request(r#"{"session_id": "s", "user_message": "héllo"}"#);
+    assert!(short.validate_length(5).is_ok());
+    assert!(short.validate_length(0).is_ok());
+    assert!(matches!(
+        short.validate_length(4),
+        Err(ServerError::PayloadTooLarge(_))
+    ));
+
+    let tool_result = request(
+        r#"{"session_id": "s", "tool_results": [{"tool_call_id": "c1", "content": "0123456789"}]}"#,
+    );
+    assert!(tool_result.validate_length(10).is_ok());
+    assert!(matches!(
+        tool_result.validate_length(9),
+        Err(ServerError::PayloadTooLarge(_))
+    ));
+}
+
+#[test]
+fn test_normalize_message() {
+    let request = |json: &str| serde_json::from_str::<ChatRequest>(json).unwrap();
+    let mut padded = request(r#"{"session_id": "s", "user_message": "  hello\n"}"#);
+    assert!(padded.normalize_message(None).is_ok());
+    assert_eq!(padded.user_message, "hello");
+
+    for message in ["", " \t\n "] {
+        let json = serde_json::json!({ "session_id": "s", "user_message": message }).to_string();
+        assert!(matches!(
+            request(&json).normalize_message(None),
+            Err(ServerError::InvalidRequest(_))
+        ));
+        let mut continued = request(&json);
+        assert!(continued.normalize_message(Some("Continue.")).is_ok());
+        assert_eq!(continued.user_message, "Continue.");
+    }
+
+    // a turn of tool results or images needs no text
+    let mut tool_result = request(
+        r#"{"session_id": "s", "tool_results": [{"tool_call_id": "c1", "content": "42"}]}"#,
+    );
+    assert!(tool_result.normalize_message(None).is_ok());
+    assert!(tool_result.user_message.is_empty());
+    let mut image = request(
+        r#"{"session_id": "s", "user_message": " ", "images": ["https://example.com/a.png"]}"#,
+    );
+    assert!(image.normalize_message(Some("Continue.")).is_ok());
+    assert!(image.user_message.is_empty());
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+    assert_eq!(parse_duration("30m"), Some(Duration::from_secs(1800)));
+    assert_eq!(parse_duration("7d"), Some(Duration::from_secs(7 * 86_400)));
+    assert_eq!(parse_duration("2w"), Some(Duration::from_secs(14 * 86_400)));
+    for invalid in ["", "d", "1.5h", "-1d", "3y", "99999999999999999999w"] {
+        assert_eq!(parse_duration(invalid), None, "{invalid}");
+    }
+}
+
+#[test]
+fn test_parse_usage() {
+    let chunk =
+        parse_sse_data(r#"data: {"choices":[{"index":0,"delta":{"content":"Hel"}}],"usage":null}"#)
+            .unwrap();
+    assert!(parse_usage(&chunk).is_none());
+
+    let line = concat!(
+        r#"data: {"choices":[],"#,
+        r#""usage":{"prompt_tokens":12,"completion_tokens":30,"total_tokens":42}}"#
+    );
+    let usage = parse_usage(&parse_sse_data(line).unwrap()).unwrap();
+    assert_eq!(
+        (
+            usage.prompt_tokens,
+            usage.completion_tokens,
+            usage.total_tokens
+        ),
+        (12, 30, 42)
+    );
+}
+
+#[test]
+fn test_parse_completion() {
+    let read_messages = |value: Value| {
+        let choices = parse_completion(&value).unwrap().choices.unwrap();
+        choices
+            .into_iter()
+            .map(|choice| choice.message)
+            .collect::<Vec<_>>()
+    };
+    let messages = read_messages(serde_json::json!({
+        "choices": [
+            { "message": { "role": "assistant", "content": "Hi" } },
+            { "message": { "role": "assistant", "content": "" } }
+        ]
+    }));
+    assert_eq!(messages[0].content.as_deref(), Some("Hi"));
+    // empty content is a valid reply
+    assert_eq!(messages[1].content.as_deref(), Some(""));
+
+    let messages = read_messages(serde_json::json!({
+        "choices": [{ "message": { "role": "assistant", "content": null, "tool_calls": [{
+            "id": "call_1",
+            "type": "function",
+            "function": { "name": "weather", "arguments": "{}" },
+        }] } }]
+    }));
+    assert!(messages[0].content.is_none());
+    assert_eq!(messages[0].tool_calls.as_ref().unwrap().len(), 1);
+
+    // the finish reason tells a reply cut at the token limit; a malformed usage counts as none
+    let completion = parse_completion(&serde_json::json!({
+        "choices": [{
+            "message": { "role": "assistant", "content": "Once upon" },
+            "finish_reason": "length",
+        }],
+        "usage": { "prompt_tokens": 5 }
+    }))
+    .unwrap();
+    assert_eq!(
+        completion.choices.unwrap()[0].finish_reason.as_deref(),
+        Some("length")
+    );
+    assert!(completion.usage.is_none());
+
+    // an error sent with a success status is surfaced with its message
+    let err = parse_completion(
+        &serde_json::json!({ "error": { "message": "model not loaded", "code": 500 } }),
+    );
+    assert!(
+        matches!(err, Err(ServerError::UpstreamError(message)) if message == "model not loaded")
+    );
+    let err = parse_completion(&serde_json::json!({ "error": "overloaded" }));
+    assert!(matches!(err, Err(ServerError::UpstreamError(message)) if message == "overloaded"));
+
+    for malformed in [
+        serde_json::json!({}),
+        serde_json::json!({ "choices": [] }),
+        serde_json::json!({ "choices": [{ "text": "Hi" }] }),
+        serde_json::json!({ "choices": "Hi" }),
+    ] {
+        assert!(
+            matches!(
+                parse_completion(&malformed),
+                Err(ServerError::UpstreamMalformed(_))
+            ),
+            "{malformed}"
+        );
+    }
+}
+
+#[test]
+fn test_read_completion() {
+    let openai =
+        serde_json::json!({ "choices": [{ "message": { "role": "assistant", "content": "Hi" } }] });
+    assert_eq!(read_completion(openai.clone(), None).unwrap(), openai);
+
+    let completion_style = serde_json::json!({
+        "choices": [{ "text": "Hello" }],
+        "usage": { "prompt_tokens": 3, "completion_tokens": 1, "total_tokens": 4 }
+    });
+    let value = read_completion(completion_style, Some("/choices/0/text")).unwrap();
+    let completion = parse_completion(&value).unwrap();
+    assert_eq!(
+        completion.choices.unwrap()[0].message.content.as_deref(),
+        Some("Hello")
+    );
+    assert_eq!(completion.usage.map(|usage| usage.total_tokens), Some(4));
+
+    let value = read_completion(serde_json::json!({ "text": "Hey" }), Some("/text")).unwrap();
+    let completion = parse_completion(&value).unwrap();
+    assert_eq!(
+        completion.choices.unwrap()[0].message.content.as_deref(),
+        Some("Hey")
+    );
+    assert!(completion.usage.is_none());
+
+    // errors are still reported, and other bodies are malformed
+    let value =
+        read_completion(serde_json::json!({ "error": "overloaded" }), Some("/text")).unwrap();
+    assert!(matches!(
+        parse_completion(&value),
+        Err(ServerError::UpstreamError(_))
+    ));
+    let err = read_completion(serde_json::json!({ "output": "Hi" }), Some("/text"));
+    assert!(matches!(err, Err(ServerError::UpstreamMalformed(_))));
+    let err = read_completion(serde_json::json!({ "text": ["Hi"] }), Some("/text"));
+    assert!(matches!(err, Err(ServerError::UpstreamMalformed(_))));
+}
+
+#[test]
+fn test_limit_history() {
+    let turns = vec![1, 2, 3, 4, 5];
+    assert_eq!(limit_history(turns.clone(), None), (vec![1, 2, 3, 4, 5], 0));
+    assert_eq!(limit_history(turns.clone(), Some(2)), (vec![4, 5], 3));
+    assert_eq!(
+        limit_history(turns.clone(), Some(10)),
+        (vec![1, 2, 3, 4, 5], 0)
+    );
+    assert_eq!(limit_history(turns, Some(0)), (vec![], 5));
+}
+
+#[test]
+fn test_trim_history_to_budget() {
+    let pairs: Vec<(String, String)> = (0..4)
+        .map(|i| (format!("question {i}"), format!("answer {i}")))
+        .collect();
+    let count = |text: &str| text.len();
+
+    // everything fits
+    let (kept, dropped) = trim_history_to_budget(pairs.clone(), "sys", "new", 1000, count);
+    assert_eq!((kept.len(), dropped), (4, 0));
+
+    // "sys" + "new" = 6, each turn costs 18 -> room for the two newest turns
+    let (kept, dropped) =
+        trim_history_to_budget(pairs.clone(), "sys", "new", 6 + 18 * 2 + 5, count);
+    assert_eq!(dropped, 2);
+    assert_eq!(kept[0].0, "question 2");
+    assert_eq!(kept[1].0, "question 3");
+
+    // system prompt and new message alone exceed the budget
+    let (kept, dropped) = trim_history_to_budget(pairs, "sys", "new", 4, count);
+    assert_eq!((kept.len(), dropped), (0, 4));
+
+    assert_eq!(estimate_tokens("abcde"), 2);
+}
+
+#[test]
+fn test_validate_sampling() {
+    let request = |extra: &str| -> ChatRequest {
+        serde_json::from_str(&format!(
+            r#"{{"session_id": "s", "user_message": "hi"{extra}}}"#
+        ))
+        .unwrap()
+    };
+
+    assert!(request("").validate_sampling().is_ok());
+    assert!(
+        request(r#", "temperature": 2.0, "top_p": 0.0, "max_tokens": 16, "stop": ["\n"]"#)
+            .validate_sampling()
+            .is_ok()
+    );
+
+    assert!(
+        request(r#", "temperature": 2.5"#)
+            .validate_sampling()
+            .is_err()
+    );
+    assert!(request(r#", "top_p": -0.1"#).validate_sampling().is_err());
+    assert!(request(r#", "max_tokens": 0"#).validate_sampling().is_err());
+    assert!(
+        request(r#", "stop": ["a", "b", "c", "d", "e"]"#)
+            .validate_sampling()
+            .is_err()
+    );
+    assert!(request(r#", "stop": [""]"#).validate_sampling().is_err());
+
+    assert!(
+        request(r#", "presence_penalty": -2.0, "frequency_penalty": 2.0"#)
+            .validate_sampling()
+            .is_ok()
+    );
+    let err = request(r#", "frequency_penalty": 2.5"#)
+        .validate_sampling()
+        .unwrap_err();
+    assert!(err.to_string().contains("frequency_penalty"));
+    assert!(
+        request(r#", "presence_penalty": -3"#)
+            .validate_sampling()
+            .is_err()
+    );
+
+    assert!(request(r#", "n": 3"#).validate_sampling().is_ok());
+    assert!(
+        request(r#", "n": 1, "stream": true"#)
+            .validate_sampling()
+            .is_ok()
+    );
+    assert!(request(r#", "n": 0"#).validate_sampling().is_err());
+    assert!(
+        request(r#", "n": 2, "stream": true"#)
+            .validate_sampling()
+            .is_err()
+    );
+    assert!(request(r#", "seed": 0"#).validate_sampling().is_ok());
+    assert!(request(r#", "seed": -1"#).validate_sampling().is_err());
+}
+
+#[test]
+fn test_retry_backoff() {
+    let base = Duration::from_millis(100);
+    let max = Duration::from_millis(1000);
+
+    assert_eq!(retry_backoff(1, base, max, 1.0), Duration::from_millis(100));
+    assert_eq!(retry_backoff(2, base, max, 1.0), Duration::from_millis(200));
+    assert_eq!(retry_backoff(3, base, max, 0.0), Duration::from_millis(200));
+    assert_eq!(retry_backoff(10, base, max, 1.0), max);
+    assert_eq!(retry_backoff(40, base, max, 0.0), max / 2);
+
+    let now = chrono::DateTime::parse_from_rfc3339("2015-10-21T07:28:00Z")
+        .unwrap()
+        .to_utc();
+    assert_eq!(
+        parse_retry_after("120", now),
+        Some(Duration::from_secs(120))
+    );
+    assert_eq!(
+        parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+        Some(Duration::from_secs(30))
+    );
+    assert_eq!(
+        parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+        Some(Duration::ZERO)
+    );
+    assert_eq!(parse_retry_after("soon", now), None);
+    assert_eq!(parse_retry_after("-1", now), None);
+}
+
+#[test]
+fn test_apply_model_defaults() {
+    let defaults = ModelDefaults {
+        system_prompt: Some("Be brief.".to_string()),
+        temperature: Some(0.2),
+        top_p: Some(0.9),
+        max_tokens: None,
+        stop: Some(vec!["###".to_string()]),
+        presence_penalty: Some(0.5),
+        frequency_penalty: None,
+    };
+    let mut request: ChatRequest = serde_json::from_str(
+        r#"{"session_id": "s", "user_message": "hi", "temperature": 1.0, "max_tokens": 64}"#,
+    )
+    .unwrap();
+    request.apply_defaults(&defaults);
+
+    assert_eq!(request.temperature, Some(1.0));
+    assert_eq!(request.top_p, Some(0.9));
+    assert_eq!(request.max_tokens, Some(64));
+    assert_eq!(request.stop, Some(vec!["###".to_string()]));
+    assert_eq!(request.presence_penalty, Some(0.5));
+    assert_eq!(request.frequency_penalty, None);
+}
+
+/// Serves `app` as a downstream server on a free local port and returns its base URL
+#[cfg(test)]
+pub(crate) async fn mock_chat_server(app: axum::Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!(
+        "http://127.0.0.1:{}/v1",
+        listener.local_addr().unwrap().port()
+    );
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    url
+}
+
+/// Default app state with the chat server at `url` registered
+#[cfg(test)]
+pub(crate) async fn state_with_chat_server(url: &str) -> Arc<AppState> {
+    use crate::{config::Config, info::ServerInfo};
+
+    let state = Arc::new(AppState::new(Config::default(), ServerInfo::default()));
+    let server = serde_json::from_value(serde_json::json!({ "url": url, "kind": "chat" })).unwrap();
+    state.register_downstream_server(server).await.unwrap();
+    state
+}
+
+#[tokio::test]
+async fn test_idempotency_key() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // a chat server counting the requests it answers
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&calls);
+    let app = axum::Router::new().route(
+        "/v1/chat/completions",
+        axum::routing::post(move || async move {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let message = serde_json::json!({ "role": "assistant", "content": "Hi" });
+            Json(serde_json::json!({ "choices": [{ "message": message }] }))
+        }),
+    );
+    let state = state_with_chat_server(&mock_chat_server(app).await).await;
+
+    let request = || {
+        serde_json::from_str::<ChatRequest>(
+            r#"{"session_id": "s", "user_message": "hi", "model": "m"}"#,
+        )
+        .unwrap()
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert(IDEMPOTENCY_KEY_HEADER, "k1".parse().unwrap());
+    let first = handle_response(
+        State(Arc::clone(&state)),
+        SessionNamespace::default(),
+        None,
+        headers.clone(),
+        Json(request()),
+    )
+    .await
+    .unwrap();
+    let retry = handle_response(
+        State(Arc::clone(&state)),
+        SessionNamespace::default(),
+        None,
+        headers.clone(),
+        Json(request()),
+    )
+    .await
+    .unwrap();
+    assert!(first.headers().get(IDEMPOTENT_REPLAYED_HEADER).is_none());
+    assert_eq!(retry.headers()[IDEMPOTENT_REPLAYED_HEADER], "true");
+    let body = |response: Response| axum::body::to_bytes(response.into_body(), usize::MAX);
+    assert_eq!(body(first).await.unwrap(), body(retry).await.unwrap());
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert_eq!(
+        state
+            .chat_storage
+            .get_session_pairs("s")
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+
+    // another key is another turn
+    headers.insert(IDEMPOTENCY_KEY_HEADER, "k2".parse().unwrap());
+    handle_response(
+        State(Arc::clone(&state)),
+        SessionNamespace::default(),
+        None,
+        headers,
+        Json(request()),
+    )
+    .await
+    .unwrap();
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+    assert_eq!(
+        state
+            .chat_storage
+            .get_session_pairs("s")
+            .await
+            .unwrap()
+            .len(),
+        2
+    );
+}
+
+#[tokio::test]
+async fn test_completion_server_fallback() {
+    use crate::{config::Config, info::ServerInfo, server::Server};
+
+    // a text completion server, streaming its reply in two chunks when asked to
+    let prompts = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let received = Arc::clone(&prompts);
+    let app = axum::Router::new().route(
+        "/v1/completions",
+        axum::routing::post(move |Json(body): Json<Value>| async move {
+            received.lock().unwrap().push(body.clone());
+            if body["stream"] == true {
+                let events = "data: {\"choices\":[{\"index\":0,\"text\":\" Hel\"}]}\n\n\
+                              data: {\"choices\":[{\"index\":0,\"text\":\"lo\"}]}\n\n\
+                              data: [DONE]\n\n";
+                return ([(CONTENT_TYPE, "text/event-stream")], events).into_response();
+            }
+            let choice = serde_json::json!({ "index": 0, "text": " Hi" });
+            Json(serde_json::json!({ "object": "text_completion", "choices": [choice] }))
+                .into_response()
+        }),
+    );
+    let url = mock_chat_server(app).await;
+
+    let state = Arc::new(AppState::new(Config::default(), ServerInfo::default()));
+    let server: Server =
+        serde_json::from_str(&format!(r#"{{"url": "{url}", "kind": "completion"}}"#)).unwrap();
+    state.register_downstream_server(server).await.unwrap();
+    let request = |stream: bool| {
+        let request = format!(
+            r#"{{"session_id": "s", "user_message": "hi", "model": "m", "stream": {stream}}}"#
+        );
+        Json(serde_json::from_str::<ChatRequest>(&request).unwrap())
+    };
+    let respond = |stream: bool| {
+        handle_response(
+            State(Arc::clone(&state)),
+            SessionNamespace::default(),
+            None,
+            HeaderMap::new(),
+            request(stream),
+        )
+    };
+
+    // without a fallback a missing chat server fails the request
+    assert!(matches!(
+        respond(false).await,
+        Err(ServerError::NotFoundServer(_))
+    ));
+    assert!(prompts.lock().unwrap().is_empty());
+
+    state.config.write().await.responses.server_kinds =
+        vec![ServerKind::chat, ServerKind::completion];
+    let body = |response: Response| axum::body::to_bytes(response.into_body(), usize::MAX);
+    let reply: Value =
+        serde_json::from_slice(&body(respond(false).await.unwrap()).await.unwrap()).unwrap();
+    assert_eq!(reply["reply"], " Hi");
+    {
+        let prompts = prompts.lock().unwrap();
+        let prompt = prompts[0]["prompt"].as_str().unwrap();
+        assert!(prompt.starts_with("System: "));
+        assert!(prompt.ends_with("User: hi\n\nAssistant:"));
+        assert_eq!(prompts[0]["stop"], serde_json::json!(["\nUser:"]));
+        assert!(prompts[0].get("messages").is_none());
+    }
+
+    // a streamed reply comes back as chat completion chunks
+    let events = body(respond(true).await.unwrap()).await.unwrap();
+    let deltas: Vec<String> = String::from_utf8_lossy(&events)
+        .lines()
+        .filter_map(parse_sse_data)
+        .filter_map(|chunk| sse_delta(&chunk))
+        .collect();
+    assert_eq!(deltas, vec![" Hel", "lo"]);
+    let prompt = prompts.lock().unwrap()[1]["prompt"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    assert!(prompt.contains("User: hi\n\nAssistant:  Hi\n\nUser: hi\n\nAssistant:"));
+    let pairs = state.chat_storage.get_session_pairs("s").await.unwrap();
+    assert_eq!(pairs.last().unwrap().1, " Hello");
+}
+
+#[tokio::test]
+async fn test_dry_run() {
+    use crate::{config::Config, info::ServerInfo};
+
+    let mut config = Config::default();
+    config.responses.history_limit = Some(2);
+    let state = Arc::new(AppState::new(config, ServerInfo::default()));
+    for i in 0..3 {
+        state
+            .chat_storage
+            .save_turn(ChatMessage::new("s", &format!("q{i}"), &format!("a{i}")))
+            .await
+            .unwrap();
+    }
+
+    // no chat server is registered, so the request is only built
+    let request = serde_json::json!({
+        "session_id": "s", "user_message": "q3", "model": "m", "stream": true, "dry_run": true
+    });
+    let payload = serde_json::from_value::<ChatRequest>(request).unwrap();
+    let response = handle_response(
+        State(Arc::clone(&state)),
+        SessionNamespace::default(),
+        None,
+        HeaderMap::new(),
+        Json(payload),
+    )
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let dry_run: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(dry_run["model"], "m");
+    assert_eq!(dry_run["dropped_turns"], 1);
+    assert!(dry_run["prompt_tokens"].as_u64().unwrap() > 0);
+    let messages = dry_run["request"]["messages"].as_array().unwrap();
+    let contents: Vec<&str> = messages
+        .iter()
+        .map(|message| message["content"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        contents,
+        [DEFAULT_SYSTEM_PROMPT, "q1", "a1", "q2", "a2", "q3"]
+    );
+    assert_eq!(dry_run["request"]["stream"], true);
+
+    // and nothing is saved
+    assert_eq!(
+        state
+            .chat_storage
+            .get_session_pairs("s")
+            .await
+            .unwrap()
+            .len(),
+        3
+    );
+}
+
+#[tokio::test]
+async fn test_prefill() {
+    use crate::{config::Config, info::ServerInfo, server::Server};
+
+    // chat servers continuing the reply from its prefilled start
+    let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let received = Arc::clone(&requests);
+    let app = axum::Router::new().route(
+        "/v1/chat/completions",
+        axum::routing::post(move |Json(body): Json<Value>| async move {
+            received.lock().unwrap().push(body);
+            let choice = serde_json::json!({
+                "index": 0,
+                "message": { "role": "assistant", "content": "lo!" },
+                "finish_reason": "stop",
+            });
+            Json(serde_json::json!({ "choices": [choice] }))
+        }),
+    );
+    let urls = [
+        mock_chat_server(app.clone()).await,
+        mock_chat_server(app).await,
+    ];
+
+    let state = Arc::new(AppState::new(Config::default(), ServerInfo::default()));
+    let ask = || {
+        let request =
+            r#"{"session_id": "s", "user_message": "Say hello", "model": "m", "prefill": "Hel"}"#;
+        let payload = serde_json::from_str::<ChatRequest>(request).unwrap();
+        handle_response(
+            State(Arc::clone(&state)),
+            SessionNamespace::default(),
+            None,
+            HeaderMap::new(),
+            Json(payload),
+        )
+    };
+
+    // a chat server not tagged "prefill" cannot take the request
+    let untagged: Server =
+        serde_json::from_str(&format!(r#"{{"url": "{}", "kind": "chat"}}"#, urls[0])).unwrap();
+    state.register_downstream_server(untagged).await.unwrap();
+    assert!(matches!(ask().await, Err(ServerError::InvalidRequest(_))));
+    assert!(requests.lock().unwrap().is_empty());
+
+    // a tagged one gets the prefill as the last message, and the saved reply begins with it
+    let tagged: Server = serde_json::from_str(&format!(
+        r#"{{"url": "{}", "kind": "chat", "tags": ["prefill"]}}"#,
+        urls[1]
+    ))
+    .unwrap();
+    state.register_downstream_server(tagged).await.unwrap();
+    for _ in 0..2 {
+        let response = ask().await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let reply: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(reply["reply"], "Hello!");
+        assert_eq!(reply["server"], urls[1]);
+        assert_eq!(reply["finish_reason"], "stop");
+    }
+    let last = requests.lock().unwrap()[0]["messages"]
+        .as_array()
+        .unwrap()
+        .last()
+        .unwrap()
+        .clone();
+    assert_eq!(
+        (last["role"].as_str(), last["content"].as_str()),
+        (Some("assistant"), Some("Hel"))
+    );
+    let pairs = state.chat_storage.get_session_pairs("s").await.unwrap();
+    assert_eq!(pairs[0], ("Say hello".to_string(), "Hello!".to_string()));
+
+    // a text completion server continues it from the end of the prompt
+    let messages = [
+        serde_json::json!({ "role": "user", "content": "Say hello" }),
+        serde_json::json!({ "role": "assistant", "content": "Hel" }),
+    ];
+    assert!(completion_prompt(&messages).ends_with("User: Say hello\n\nAssistant: Hel"));
+}
+
+#[tokio::test]
+async fn test_fallback_reply() {
+    use crate::{config::Config, info::ServerInfo, server::Server};
+
+    let mut config = Config::default();
+    config.responses.fallback_reply = Some("The assistant is temporarily unavailable".to_string());
+    config.responses.max_attempts = 1;
+    let state = Arc::new(AppState::new(config, ServerInfo::default()));
+    let ask = |request: &'static str| {
+        let payload = serde_json::from_str::<ChatRequest>(request).unwrap();
+        let state = Arc::clone(&state);
+        async move {
+            handle_response(
+                State(state),
+                SessionNamespace::default(),
+                None,
+                HeaderMap::new(),
+                Json(payload),
+            )
+            .await
+        }
+    };
+    let body = |response: Response| async move {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8_lossy(&body).to_string()
+    };
+
+    // with no chat server the fallback is the reply, and is not saved
+    let response = ask(r#"{"session_id": "s", "user_message": "hi", "model": "m"}"#)
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let reply: Value = serde_json::from_str(&body(response).await).unwrap();
+    assert_eq!(reply["reply"], "The assistant is temporarily unavailable");
+    assert_eq!(reply["model"], "m");
+    assert!(
+        state
+            .chat_storage
+            .get_session_pairs("s")
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    // a stream gets it as a single chunk
+    let response = ask(r#"{"session_id": "s", "user_message": "hi", "stream": true}"#)
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let events = body(response).await;
+    let chunk = events.lines().find_map(parse_sse_data).unwrap();
+    assert_eq!(
+        chunk["choices"][0]["delta"]["content"],
+        "The assistant is temporarily unavailable"
+    );
+    assert!(events.ends_with("data: [DONE]\n\n"));
+
+    // so does a request no registered server can be reached for, saved if asked
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    drop(listener);
+    let server: Server = serde_json::from_str(&format!(
+        r#"{{"url": "http://127.0.0.1:{port}/v1", "kind": "chat"}}"#
+    ))
+    .unwrap();
+    state.register_downstream_server(server).await.unwrap();
+    state.config.write().await.responses.persist_fallback_reply = true;
+    let response = ask(r#"{"session_id": "s", "user_message": "hello", "model": "m"}"#)
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let pairs = state.chat_storage.get_session_pairs("s").await.unwrap();
+    assert_eq!(
+        pairs,
+        [(
+            "hello".to_string(),
+            "The assistant is temporarily unavailable".to_string()
+        )]
+    );
+
+    // without a fallback reply the request fails, the server being marked down by now
+    state.config.write().await.responses.fallback_reply = None;
+    let err = ask(r#"{"session_id": "s", "user_message": "hi", "model": "m"}"#)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ServerError::NoServerAvailable(_)), "{err}");
+}
+
+#[tokio::test]
+async fn test_stream_usage_estimate() {
+    use crate::{config::Config, info::ServerInfo, server::Server};
+
+    // a chat server reporting the usage of its stream only when the user message asks for it
+    let app = axum::Router::new().route(
+        "/v1/chat/completions",
+        axum::routing::post(|Json(body): Json<Value>| async move {
+            let user = body["messages"].as_array().unwrap().last().unwrap()["content"].clone();
+            let usage = if user == "with usage" {
+                "data: {\"choices\":[],\
+                 \"usage\":{\"prompt_tokens\":7,\"completion_tokens\":2,\"total_tokens\":9}}\n\n"
+            } else {
+                ""
+            };
+            let events = format!(
+                "data: {{\"choices\":[{{\"index\":0,\"delta\":{{\"content\":\"Hel\"}}}}]}}\n\n\
+                 data: {{\"choices\":[{{\"index\":0,\"delta\":{{\"content\":\"lo\"}}}}]}}\n\n\
+                 {usage}data: [DONE]\n\n"
+            );
+            ([(CONTENT_TYPE, "text/event-stream")], events).into_response()
+        }),
+    );
+    let url = mock_chat_server(app).await;
+
+    let path = crate::database::TempDb::new();
+    let mut state = AppState::new_with_database(
+        Config::default(),
+        ServerInfo::default(),
+        path.to_str().unwrap(),
+    )
+    .await
+    .unwrap();
+    // one token per character, so the estimate is easy to check
+    state.token_estimator = Arc::new(|text: &str| text.chars().count());
+    let state = Arc::new(state);
+    let server: Server =
+        serde_json::from_str(&format!(r#"{{"url": "{url}", "kind": "chat"}}"#)).unwrap();
+    state.register_downstream_server(server).await.unwrap();
+    let stream = |session_id: &str, user_message: &str| {
+        let request = serde_json::json!({
+            "session_id": session_id, "user_message": user_message, "model": "m", "stream": true
+        });
+        let payload = serde_json::from_value::<ChatRequest>(request).unwrap();
+        let state = Arc::clone(&state);
+        async move {
+            let response = handle_response(
+                State(state),
+                SessionNamespace::default(),
+                None,
+                HeaderMap::new(),
+                Json(payload),
+            )
+            .await
+            .unwrap();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&body).to_string()
+        }
+    };
+    let usages = |body: &str| {
+        body.lines()
+            .filter_map(parse_sse_data)
+            .filter_map(|chunk| parse_usage(&chunk))
+            .collect::<Vec<_>>()
+    };
+
+    // the estimate comes right before `[DONE]`, with the prompt and the reply counted
+    let body = stream("s1", "hi").await;
+    let usage = usages(&body);
+    assert_eq!(usage.len(), 1);
+    let system_prompt = DEFAULT_SYSTEM_PROMPT.chars().count() as u64;
+    assert_eq!(
+        (usage[0].prompt_tokens, usage[0].completion_tokens),
+        (system_prompt + 2, 5)
+    );
+    assert!(body.contains(r#""usage_estimated":true"#));
+    assert!(body.ends_with("data: [DONE]\n\n"));
+
+    // a reported usage is passed on as is
+    let body = stream("s2", "with usage").await;
+    let usage = usages(&body);
+    assert_eq!(usage.len(), 1);
+    assert_eq!(usage[0].total_tokens, 9);
+    assert!(!body.contains("usage_estimated"));
+
+    // either is recorded for the session
+    state.tasks.close();
+    state.tasks.wait().await;
+    let recorded = state.chat_storage.get_session_usage("s1").await.unwrap();
+    assert_eq!((recorded.completion_tokens, recorded.requests), (5, 1));
+    assert_eq!(
+        state
+            .chat_storage
+            .get_session_usage("s2")
+            .await
+            .unwrap()
+            .total_tokens,
+        9
+    );
+
+    // without estimates a stream reporting no usage records none
+    state.config.write().await.responses.estimate_stream_usage = false;
+    let body = stream("s3", "hi").await;
+    assert!(usages(&body).is_empty());
+}
+
+#[tokio::test]
+async fn test_seed_reproduces_reply() {
+    use crate::{config::Config, info::ServerInfo, server::Server};
+
+    // a chat server whose reply depends only on the seed
+    let seeds = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let received = Arc::clone(&seeds);
+    let app = axum::Router::new().route(
+        "/v1/chat/completions",
+        axum::routing::post(move |Json(body): Json<Value>| async move {
+            received.lock().unwrap().push(body["seed"].clone());
+            let content = format!("seed {}", body["seed"]);
+            let message = serde_json::json!({ "role": "assistant", "content": content });
+            Json(serde_json::json!({ "choices": [{ "message": message }] }))
+        }),
+    );
+    let url = mock_chat_server(app).await;
+
+    let path = crate::database::TempDb::new();
+    let state = Arc::new(
+        AppState::new_with_database(
+            Config::default(),
+            ServerInfo::default(),
+            path.to_str().unwrap(),
+        )
+        .await
+        .unwrap(),
+    );
+    let server: Server =
+        serde_json::from_str(&format!(r#"{{"url": "{url}", "kind": "chat"}}"#)).unwrap();
+    state.register_downstream_server(server).await.unwrap();
+
+    let payload: ChatRequest = serde_json::from_str(
+        r#"{"session_id": "s", "user_message": "hi", "model": "m", "seed": 42}"#,
+    )
+    .unwrap();
+    handle_response(
+        State(Arc::clone(&state)),
+        SessionNamespace::default(),
+        None,
+        HeaderMap::new(),
+        Json(payload),
+    )
+    .await
+    .unwrap();
+    let turns = state.chat_storage.get_session_turns("s").await.unwrap();
+    assert_eq!(turns[0].seed, Some(42));
+    assert_eq!(turns[0].bot_reply, "seed 42");
+
+    // regenerating the turn sends its seed again
+    let path_params = axum::extract::Path(("s".to_string(), turns[0].id.unwrap()));
+    let regenerate = serde_json::from_str::<RegenerateRequest>(r#"{"model": "m"}"#).unwrap();
+    regenerate_message(
+        State(Arc::clone(&state)),
+        SessionNamespace::default(),
+        None,
+        HeaderMap::new(),
+        path_params,
+        Json(regenerate),
+    )
+    .await
+    .unwrap();
+    let turns = state.chat_storage.get_session_turns("s").await.unwrap();
+    assert_eq!(turns.len(), 1);
+    assert_eq!(
+        (turns[0].seed, turns[0].bot_reply.as_str()),
+        (Some(42), "seed 42")
+    );
+    assert_eq!(
+        *seeds.lock().unwrap(),
+        vec![Value::from(42), Value::from(42)]
+    );
+}
+
+#[tokio::test]
+async fn test_regenerate_prompt() {
+    use crate::{config::Config, info::ServerInfo, server::Server};
+
+    // a chat server keeping the messages it is sent
+    let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let received = Arc::clone(&requests);
+    let app = axum::Router::new().route(
+        "/v1/chat/completions",
+        axum::routing::post(move |Json(body): Json<Value>| async move {
+            received.lock().unwrap().push(body["messages"].clone());
+            let message = serde_json::json!({ "role": "assistant", "content": "again" });
+            Json(serde_json::json!({ "choices": [{ "message": message }] }))
+        }),
+    );
+    let url = mock_chat_server(app).await;
+
+    // versions are kept by the database storage
+    let path = crate::database::TempDb::new();
+    let state = Arc::new(
+        AppState::new_with_database(
+            Config::default(),
+            ServerInfo::default(),
+            path.to_str().unwrap(),
+        )
+        .await
+        .unwrap(),
+    );
+    let server: Server =
+        serde_json::from_str(&format!(r#"{{"url": "{url}", "kind": "chat"}}"#)).unwrap();
+    state.register_downstream_server(server).await.unwrap();
+    for (user_message, bot_reply) in [("q1", "a1"), ("q2", "a2")] {
+        let turn = ChatMessage::new("s", user_message, bot_reply);
+        state.chat_storage.save_turn(turn).await.unwrap();
+    }
+    let regenerate = |message_id: i64| {
+        let path_params = axum::extract::Path(("s".to_string(), message_id));
+        let body = serde_json::from_str::<RegenerateRequest>(r#"{"model": "m"}"#).unwrap();
+        regenerate_message(
+            State(Arc::clone(&state)),
+            SessionNamespace::default(),
+            None,
+            HeaderMap::new(),
+            path_params,
+            Json(body),
+        )
+    };
+    let system = serde_json::json!({ "role": "system", "content": DEFAULT_SYSTEM_PROMPT });
+    let user = |content: &str| serde_json::json!({ "role": "user", "content": content });
+    let assistant = |content: &str| serde_json::json!({ "role": "assistant", "content": content });
+
+    // the revised turn is left out of the history, whichever of its versions is regenerated
+    for _ in 0..2 {
+        let turns = state.chat_storage.get_session_turns("s").await.unwrap();
+        regenerate(turns[1].id.unwrap()).await.unwrap();
+        assert_eq!(
+            requests.lock().unwrap().pop().unwrap(),
+            serde_json::json!([system, user("q1"), assistant("a1"), user("q2")])
+        );
+    }
+    let turns = state.chat_storage.get_session_turns("s").await.unwrap();
+    assert_eq!(
+        (turns[1].version, turns[1].bot_reply.as_str()),
+        (3, "again")
+    );
+
+    // and so are the turns after it
+    regenerate(turns[0].id.unwrap()).await.unwrap();
+    assert_eq!(
+        requests.lock().unwrap().pop().unwrap(),
+        serde_json::json!([system, user("q1")])
+    );
+}
+
+#[tokio::test]
+async fn test_regenerate_route() {
+    use tower::ServiceExt;
+
+    let app = axum::Router::new().route(
+        "/v1/chat/completions",
+        axum::routing::post(|| async {
+            let message = serde_json::json!({ "role": "assistant", "content": "Hi" });
+            Json(serde_json::json!({ "choices": [{ "message": message }] }))
+        }),
+    );
+    let state = state_with_chat_server(&mock_chat_server(app).await).await;
+    let turn = ChatMessage::new("s", "hi", "hello");
+    state.chat_storage.save_turn(turn).await.unwrap();
+    let app = axum::Router::new()
+        .route(
+            "/sessions/{session_id}/messages/{message_id}/regenerate",
+            axum::routing::post(regenerate_message),
+        )
+        .with_state(Arc::clone(&state));
+    let regenerate = |uri: &str| {
+        let request = Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"model": "m"}"#))
+            .unwrap();
+        app.clone().oneshot(request)
+    };
+
+    // a turn the session does not have is not found
+    let response = regenerate("/sessions/s/messages/9/regenerate")
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = regenerate("/sessions/other/messages/1/regenerate")
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // a known one gets a new reply in place of its old one
+    let response = regenerate("/sessions/s/messages/1/regenerate")
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let reply: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(reply["reply"], "Hi");
+    let pairs = state.chat_storage.get_session_pairs("s").await.unwrap();
+    assert_eq!(pairs, [("hi".to_string(), "Hi".to_string())]);
+}
+
+#[tokio::test]
+async fn test_multiple_choices() {
+    // a chat server answering with as many choices as it is asked for
+    let requested = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let received = Arc::clone(&requested);
+    let app = axum::Router::new().route(
+        "/v1/chat/completions",
+        axum::routing::post(move |Json(body): Json<Value>| async move {
+            received.lock().unwrap().push(body["n"].clone());
+            let n = body["n"].as_u64().unwrap_or(1);
+            let choices: Vec<Value> = (0..n)
+                .map(|i| {
+                    let message =
+                        serde_json::json!({ "role": "assistant", "content": format!("reply {i}") });
+                    serde_json::json!({ "index": i, "message": message })
+                })
+                .collect();
+            Json(serde_json::json!({ "choices": choices }))
+        }),
+    );
+    let state = state_with_chat_server(&mock_chat_server(app).await).await;
+    let ask = |session_id: &str, extra: Value| {
+        let mut request = serde_json::json!({
+            "session_id": session_id, "user_message": "hi", "model": "m"
+        });
+        request
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        let payload = serde_json::from_value::<ChatRequest>(request).unwrap();
+        let state = Arc::clone(&state);
+        async move {
+            let response = handle_response(
+                State(state),
+                SessionNamespace::default(),
+                None,
+                HeaderMap::new(),
+                Json(payload),
+            )
+            .await?;
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            Ok::<_, ServerError>(serde_json::from_slice::<Value>(&body).unwrap())
+        }
+    };
+
+    // every choice is returned, and only the first is saved
+    let reply = ask("s1", serde_json::json!({ "n": 3 })).await.unwrap();
+    assert_eq!(reply["reply"], "reply 0");
+    assert_eq!(
+        reply["choices"],
+        serde_json::json!(["reply 0", "reply 1", "reply 2"])
+    );
+    let pairs = state.chat_storage.get_session_pairs("s1").await.unwrap();
+    assert_eq!(pairs, [("hi".to_string(), "reply 0".to_string())]);
+
+    // a single reply has no choices
+    let reply = ask("s2", serde_json::json!({})).await.unwrap();
+    assert!(reply.get("choices").is_none());
+
+    // more than `max_choices` are asked for as that many
+    state.config.write().await.responses.max_choices = 2;
+    let reply = ask("s3", serde_json::json!({ "n": 5 })).await.unwrap();
+    assert_eq!(reply["choices"], serde_json::json!(["reply 0", "reply 1"]));
+    assert_eq!(
+        *requested.lock().unwrap(),
+        [Value::from(3), Value::from(1), Value::from(2)]
+    );
+
+    // no choice at all, or several of a stream, are rejected before going downstream
+    for extra in [
+        serde_json::json!({ "n": 0 }),
+        serde_json::json!({ "n": 2, "stream": true }),
+    ] {
+        let err = ask("s4", extra).await.unwrap_err();
+        assert!(matches!(err, ServerError::InvalidRequest(_)), "{err}");
+    }
+    assert_eq!(requested.lock().unwrap().len(), 3);
+    assert!(
+        state
+            .chat_storage
+            .get_session_pairs("s4")
+            .await
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[tokio::test]
+async fn test_penalties_reach_downstream() {
+    // a chat server keeping the penalties it is sent
+    let penalties = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let received = Arc::clone(&penalties);
+    let app = axum::Router::new().route(
+        "/v1/chat/completions",
+        axum::routing::post(move |Json(body): Json<Value>| async move {
+            let sent = (
+                body["presence_penalty"].clone(),
+                body["frequency_penalty"].clone(),
+            );
+            received.lock().unwrap().push(sent);
+            let message = serde_json::json!({ "role": "assistant", "content": "Hi" });
+            Json(serde_json::json!({ "choices": [{ "message": message }] }))
+        }),
+    );
+    let state = state_with_chat_server(&mock_chat_server(app).await).await;
+    let ask = |presence_penalty: f64, frequency_penalty: f64| {
+        let request = serde_json::json!({
+            "session_id": "s",
+            "user_message": "hi",
+            "model": "m",
+            "presence_penalty": presence_penalty,
+            "frequency_penalty": frequency_penalty,
+        });
+        let payload = serde_json::from_value::<ChatRequest>(request).unwrap();
+        handle_response(
+            State(Arc::clone(&state)),
+            SessionNamespace::default(),
+            None,
+            HeaderMap::new(),
+            Json(payload),
+        )
+    };
+
+    ask(0.5, -2.0).await.unwrap();
+    assert_eq!(
+        *penalties.lock().unwrap(),
+        [(Value::from(0.5), Value::from(-2.0))]
+    );
+
+    // out of range, the request is rejected before going downstream
+    for (presence_penalty, frequency_penalty) in [(2.5, 0.0), (0.0, -2.1)] {
+        let err = ask(presence_penalty, frequency_penalty).await.unwrap_err();
+        assert!(matches!(err, ServerError::InvalidRequest(_)), "{err}");
+    }
+    assert_eq!(penalties.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_model_change_notifications() {
+    use crate::{ModelChange, config::Config, info::ServerInfo, server::Server};
+
+    let state = AppState::new(Config::default(), ServerInfo::default());
+    let mut changes = state.subscribe_model_changes();
+    let server: Server =
+        serde_json::from_str(r#"{"url": "http://127.0.0.1:1/v1", "kind": "chat"}"#).unwrap();
+    let server_id = server.id.clone();
+    state.register_downstream_server(server).await.unwrap();
+    let model = endpoints::models::Model {
+        id: "llama".into(),
+        created: 0,
+        object: "model".into(),
+        owned_by: "test".into(),
+    };
+    state.set_server_models(&server_id, vec![model]).await;
+    state
+        .unregister_downstream_server(&server_id)
+        .await
+        .unwrap();
+
+    let models = vec!["llama".to_string()];
+    assert_eq!(
+        changes.recv().await.unwrap(),
+        ModelChange::Updated {
+            server_id: server_id.clone(),
+            models: models.clone()
+        }
+    );
+    assert_eq!(
+        changes.recv().await.unwrap(),
+        ModelChange::Removed { server_id, models }
+    );
+    assert!(state.models.read().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_list_models() {
+    use crate::{config::Config, handlers::list_models, info::ServerInfo, server::Server};
+
+    let state = AppState::new(Config::default(), ServerInfo::default());
+    for (port, extra, models) in [
+        (
+            8001,
+            r#""tags": ["vision"], "context_length": 8192"#,
+            vec!["llama", "qwen"],
+        ),
+        (8002, r#""context_length": 32768"#, vec!["llama"]),
+    ] {
+        let server: Server = serde_json::from_str(&format!(
+            r#"{{"url": "http://127.0.0.1:{port}/v1", "kind": "chat", {extra}}}"#
+        ))
+        .unwrap();
+        let server_id = server.id.clone();
+        state.register_downstream_server(server).await.unwrap();
+        let models = models
+            .into_iter()
+            .map(|id| endpoints::models::Model {
+                id: id.into(),
+                created: 0,
+                object: "model".into(),
+                owned_by: "test".into(),
+            })
+            .collect();
+        state.set_server_models(&server_id, models).await;
+    }
+
+    let models = list_models(&state).await.unwrap();
+    let ids: Vec<_> = models.iter().map(|entry| entry.model.id.as_str()).collect();
+    assert_eq!(ids, ["llama", "qwen"]);
+    assert_eq!(models[0].capabilities, ["chat", "vision"]);
+    assert_eq!(models[0].context_length, Some(32768));
+    assert_eq!(models[1].context_length, Some(8192));
+
+    let json = serde_json::to_value(&models[1]).unwrap();
+    assert_eq!(json["id"], "qwen");
+    assert_eq!(json["object"], "model");
+    assert_eq!(json["context_length"], 8192);
+}
+
+#[tokio::test]
+async fn test_send_with_retry_times_out() {
+    use crate::{config::Config, info::ServerInfo, server::Server};
+
+    // a chat server that accepts connections but never answers
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let mut sockets = Vec::new();
+        while let Ok((socket, _)) = listener.accept().await {
+            sockets.push(socket);
+        }
+    });
+
+    let mut config = Config::default();
+    config.responses.max_attempts = 1;
+    config.responses.request_timeout_secs = 1;
+    let state = Arc::new(AppState::new(config, ServerInfo::default()));
+    let server: Server = serde_json::from_str(&format!(
+        r#"{{"url": "http://127.0.0.1:{port}/v1", "kind": "chat"}}"#
+    ))
+    .unwrap();
+    state.register_downstream_server(server).await.unwrap();
+
+    for stream in [false, true] {
+        let request_body = ChatCompletionRequest {
+            stream: Some(stream),
+            ..Default::default()
+        };
+        let result = send_with_retry(
+            &state,
+            &HeaderMap::new(),
+            "s",
+            &request_body.into(),
+            None,
+            None,
+        )
+        .await;
+        assert!(
+            matches!(result, Err(ServerError::Timeout(_))),
+            "stream: {stream}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_send_with_retry_honors_retry_after() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // a chat server rate limiting its first request for one second
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&calls);
+    let app = axum::Router::new().route(
+        "/v1/chat/completions",
+        axum::routing::post(move || async move {
+            if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(RETRY_AFTER, "1")],
+                    "slow down",
+                )
+                    .into_response();
+            }
+            let message = serde_json::json!({ "role": "assistant", "content": "Hi" });
+            Json(serde_json::json!({ "choices": [{ "message": message }] })).into_response()
+        }),
+    );
+    let state = state_with_chat_server(&mock_chat_server(app).await).await;
+    state.config.write().await.responses.max_attempts = 2;
+
+    let start = std::time::Instant::now();
+    let (_, response) = send_with_retry(
+        &state,
+        &HeaderMap::new(),
+        "s",
+        &DownstreamRequest::default(),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+    assert!(start.elapsed() >= Duration::from_secs(1));
+
+    // the last attempt passes the 429 on
+    calls.store(0, Ordering::SeqCst);
+    state.config.write().await.responses.max_attempts = 1;
+    let result = send_with_retry(
+        &state,
+        &HeaderMap::new(),
+        "s",
+        &DownstreamRequest::default(),
+        None,
+        None,
+    )
+    .await;
+    assert!(matches!(result, Err(ServerError::UpstreamRejected(429, _))));
+}
+
+#[tokio::test]
+async fn test_responses_fail_over() {
+    use crate::server::Server;
+
+    // a healthy chat server next to one refusing connections
+    let app = axum::Router::new().route(
+        "/v1/chat/completions",
+        axum::routing::post(|| async {
+            let message = serde_json::json!({ "role": "assistant", "content": "Hi" });
+            Json(serde_json::json!({ "choices": [{ "message": message }] }))
+        }),
+    );
+    let state = state_with_chat_server(&mock_chat_server(app).await).await;
+    let server: Server =
+        serde_json::from_str(r#"{"url": "http://127.0.0.1:1/v1", "kind": "chat"}"#).unwrap();
+    state.register_downstream_server(server).await.unwrap();
+
+    // whichever server a turn is sent to first, it is answered by the healthy one
+    for _ in 0..4 {
+        let request = r#"{"session_id": "s", "user_message": "hi", "model": "m"}"#;
+        let payload = Json(serde_json::from_str::<ChatRequest>(request).unwrap());
+        let response = handle_response(
+            State(Arc::clone(&state)),
+            SessionNamespace::default(),
+            None,
+            HeaderMap::new(),
+            payload,
+        )
+        .await
+        .unwrap();
+        let reply: Value = serde_json::from_slice(
+            &axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(reply["reply"], "Hi");
+    }
+    assert_eq!(
+        state
+            .chat_storage
+            .get_session_pairs("s")
+            .await
+            .unwrap()
+            .len(),
+        4
+    );
+}
+
+/// A chat server that streams one chunk, `Hel`, then keeps the stream open until the connection
+/// closes; returns its base URL and a receiver told when it does
+#[cfg(test)]
+async fn stalled_chat_server() -> (String, tokio::sync::oneshot::Receiver<()>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (closed_tx, closed_rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0u8; 64 * 1024];
+        let _ = socket.read(&mut buf).await;
+        let chunk = "data: {\"choices\":[{\"delta\":{\"content\":\"Hel\"}}]}\n\n";
+        let head = "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\n\
+                    transfer-encoding: chunked\r\n\r\n";
+        let response = format!("{head}{:x}\r\n{chunk}\r\n", chunk.len());
+        socket.write_all(response.as_bytes()).await.unwrap();
+        while matches!(socket.read(&mut buf).await, Ok(n) if n > 0) {}
+        let _ = closed_tx.send(());
+    });
+
+    (format!("http://127.0.0.1:{port}/v1"), closed_rx)
+}
+
+#[tokio::test]
+async fn test_stream_cancelled_on_client_disconnect() {
+    let (url, closed_rx) = stalled_chat_server().await;
+    let state = state_with_chat_server(&url).await;
+
+    let payload: ChatRequest = serde_json::from_str(
+        r#"{"session_id": "s1", "user_message": "hi", "model": "llama", "stream": true}"#,
+    )
+    .unwrap();
+    let response = handle_response(
+        State(Arc::clone(&state)),
+        SessionNamespace::default(),
+        None,
+        HeaderMap::new(),
+        Json(payload),
+    )
+    .await
+    .unwrap();
+    let mut body = response.into_body().into_data_stream();
+    let first = body.next().await.unwrap().unwrap();
+    assert!(String::from_utf8_lossy(&first).contains("Hel"));
+
+    // the client goes away while the chat server is still generating
+    drop(body);
+    tokio::time::timeout(Duration::from_secs(5), closed_rx)
+        .await
+        .expect("the downstream connection was not closed")
+        .unwrap();
+
+    state.tasks.close();
+    state.tasks.wait().await;
+    let turns = state.chat_storage.get_session_turns("s1").await.unwrap();
+    assert_eq!(turns[0].bot_reply, format!("Hel{INTERRUPTED_REPLY_MARKER}"));
+}
+
+#[tokio::test]
+async fn test_stream_timeout_keeps_partial_reply() {
+    use crate::{config::Config, info::ServerInfo, server::Server};
+
+    let (url, _closed_rx) = stalled_chat_server().await;
+    let mut config = Config::default();
+    config.responses.attempt_timeout_secs = 1;
+    let path = crate::database::TempDb::new();
+    let state = Arc::new(
+        AppState::new_with_database(config, ServerInfo::default(), path.to_str().unwrap())
+            .await
+            .unwrap(),
+    );
+    let server: Server =
+        serde_json::from_str(&format!(r#"{{"url": "{url}", "kind": "chat"}}"#)).unwrap();
+    state.register_downstream_server(server).await.unwrap();
+
+    let payload: ChatRequest = serde_json::from_str(
+        r#"{"session_id": "s1", "user_message": "hi", "model": "llama", "stream": true}"#,
+    )
+    .unwrap();
+    let response = handle_response(
+        State(Arc::clone(&state)),
+        SessionNamespace::default(),
+        None,
+        HeaderMap::new(),
+        Json(payload),
+    )
+    .await
+    .unwrap();
+
+    // the stream ends cleanly with the sentinel after the partial reply
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body = String::from_utf8_lossy(&body);
+    assert!(body.contains("Hel"));
+    assert!(body.ends_with(TIMEOUT_EVENT));
+
+    state.tasks.close();
+    state.tasks.wait().await;
+    let turns = state.chat_storage.get_session_turns("s1").await.unwrap();
+    assert_eq!(turns[0].bot_reply, "Hel");
+    assert!(turns[0].truncated);
+    // the latency of a stream runs until it ends
+    assert!(turns[0].latency_ms.is_some_and(|latency| latency >= 1000));
+    let Json(stats) = get_session_stats(
+        State(Arc::clone(&state)),
+        SessionNamespace::default(),
+        axum::extract::Path("s1".to_string()),
+    )
+    .await
+    .unwrap();
+    assert_eq!(stats.timed_turns, 1);
+    assert_eq!(stats.p50_latency_ms, turns[0].latency_ms);
+}
+
+#[test]
+fn test_percentile() {
+    let latencies: Vec<i64> = (1..=20).map(|i| i * 10).collect();
+    assert_eq!(percentile(&latencies, 50.0), Some(100));
+    assert_eq!(percentile(&latencies, 95.0), Some(190));
+    assert_eq!(percentile(&latencies, 100.0), Some(200));
+    assert_eq!(percentile(&[42], 95.0), Some(42));
+    assert_eq!(percentile(&[], 50.0), None);
+}
+
+#[test]
+fn test_validate_session_id() {
+    assert!(validate_session_id("chat-1_a.b:c").is_ok());
+    assert!(validate_session_id(&"a".repeat(MAX_SESSION_ID_LEN)).is_ok());
+    assert!(validate_session_id(&"a".repeat(MAX_SESSION_ID_LEN + 1)).is_err());
+    assert!(validate_session_id("").is_err());
+    assert!(validate_session_id("a b").is_err());
+    assert!(validate_session_id("../etc").is_err());
+    assert!(validate_session_id("sé").is_err());
+}
+
+#[test]
+fn test_prompt_logging() {
+    use crate::config::PromptLogging;
+
+    assert_eq!(
+        PromptLogging::Full.render("my card is 1234").as_deref(),
+        Some("my card is 1234")
+    );
+    assert_eq!(PromptLogging::None.render("my card is 1234"), None);
+    let redacted = PromptLogging::Redacted.render("my card is 1234").unwrap();
+    assert!(redacted.starts_with("<15 chars, hash "));
+    assert!(!redacted.contains("1234"));
+    assert_eq!(
+        PromptLogging::Redacted.render("my card is 1234").unwrap(),
+        redacted
+    );
+    assert_ne!(
+        PromptLogging::Redacted.render("my card is 5678").unwrap(),
+        redacted
+    );
+
+    let config: crate::config::ResponsesConfig =
+        serde_json::from_str(r#"{"prompt_logging": "full"}"#).unwrap();
+    assert_eq!(config.prompt_logging, PromptLogging::Full);
+    assert_eq!(
+        crate::config::ResponsesConfig::default().prompt_logging,
+        PromptLogging::Redacted
+    );
+}
+
+#[test]
+fn test_image_content() {
+    let request: ChatRequest = serde_json::from_value(serde_json::json!({
+        "session_id": "s",
+        "user_message": "What is this?",
+        "images": ["https://example.com/cat.png", "data:image/png;base64,iVBORw0KGgo="]
+    }))
+    .unwrap();
+    assert!(request.validate_images().is_ok());
+    let ChatCompletionUserMessageContent::Parts(parts) = request.user_content() else {
+        panic!("expected content parts");
+    };
+    assert_eq!(
+        parts.iter().map(|p| p.ty()).collect::<Vec<_>>(),
+        ["text", "image_url", "image_url"]
+    );
+    assert_eq!(
+        request.stored_user_message(),
+        "What is this?\n[image]\n[image]"
+    );
+    assert_eq!(
+        strip_image_placeholders(&request.stored_user_message()),
+        "What is this?"
+    );
+
+    let text_only: ChatRequest =
+        serde_json::from_str(r#"{"session_id": "s", "user_message": "hi"}"#).unwrap();
+    let content = text_only.user_content();
+    assert!(matches!(content, ChatCompletionUserMessageContent::Text(text) if text == "hi"));
+    assert_eq!(text_only.stored_user_message(), "hi");
+
+    for invalid in [
+        "not a url",
+        "ftp://example.com/cat.png",
+        "data:text/plain;base64,aGk=",
+    ] {
+        let request: ChatRequest = serde_json::from_str(&format!(
+            r#"{{"session_id": "s", "user_message": "hi", "images": ["{invalid}"]}}"#
+        ))
+        .unwrap();
+        assert!(request.validate_images().is_err(), "{invalid}");
+    }
+}
+
+#[test]
+fn test_tool_call_messages() {
+    // streamed tool calls arrive as deltas keyed by index
+    let mut tool_calls = Vec::new();
+    for chunk in [
+        concat!(
+            r#"data: {"choices":[{"delta":{"tool_calls":[{"index":0,"#,
+            r#""id":"call_1","type":"function","function":{"name":"weather","arguments":""}}]}}]}"#
+        ),
+        concat!(
+            r#"data: {"choices":[{"delta":{"tool_calls":[{"index":0,"#,
+            r#""function":{"arguments":"{\"city\":"}}]}}]}"#
+        ),
+        concat!(
+            r#"data: {"choices":[{"delta":{"tool_calls":[{"index":0,"#,
+            r#""function":{"arguments":"\"Paris\"}"}}]}}]}"#
+        ),
+        r#"data: {"choices":[{"delta":{"content":"ok"}}]}"#,
+    ] {
+        accumulate_tool_call_deltas(&mut tool_calls, &parse_sse_data(chunk).unwrap());
+    }
+    assert_eq!(tool_calls.len(), 1);
+    assert_eq!(tool_calls[0].id, "call_1");
+    assert_eq!(tool_calls[0].function.name, "weather");
+    assert_eq!(tool_calls[0].function.arguments, r#"{"city":"Paris"}"#);
+
+    // a stored tool call turn and the turn answering it replay as assistant and tool messages
+    let call_turn = ChatMessage {
+        assistant_message: Some(
+            serde_json::json!({"role": "assistant", "content": null, "tool_calls": tool_calls})
+                .to_string(),
+        ),
+        ..ChatMessage::new("s", "Weather in Paris?", "")
+    };
+    let result_turn = ChatMessage {
+        tool_results: Some(r#"[{"tool_call_id":"call_1","content":"sunny"}]"#.to_string()),
+        ..ChatMessage::new("s", "", "It is sunny.")
+    };
+    let roles = |turn| {
+        turn_messages(turn)
+            .iter()
+            .map(|m| m.role().to_string())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(roles(call_turn.clone()), ["user", "assistant"]);
+    assert_eq!(roles(result_turn), ["tool", "assistant"]);
+    let ChatCompletionRequestMessage::Assistant(assistant) = &turn_messages(call_turn)[1] else {
+        panic!("expected an assistant message");
+    };
+    assert_eq!(assistant.tool_calls().unwrap()[0].id, "call_1");
+    assert!(assistant.content().is_none());
+
+    // single messages replay with their own role
+    assert_eq!(
+        roles(ChatMessage::single(
+            "s",
+            MessageRole::System,
+            "The user joined."
+        )),
+        ["system"]
+    );
+    assert_eq!(
+        roles(ChatMessage::single("s", MessageRole::User, "Hi")),
+        ["user"]
+    );
+    assert_eq!(
+        roles(ChatMessage::single("s", MessageRole::Assistant, "Hello!")),
+        ["assistant"]
+    );
+    let results = r#"[{"tool_call_id":"call_1","content":"sunny"}]"#;
+    assert_eq!(
+        roles(ChatMessage::single("s", MessageRole::Tool, results)),
+        ["tool"]
+    );
+}
+
+#[test]
+fn test_resolve_system_prompt() {
+    assert_eq!(
+        resolve_system_prompt(
+            Some("request"),
+            Some("session"),
+            Some("model"),
+            Some("global")
+        ),
+        "request"
+    );
+    assert_eq!(
+        resolve_system_prompt(None, Some("session"), Some("model"), Some("global")),
+        "session"
+    );
+    assert_eq!(
+        resolve_system_prompt(Some(""), None, Some("model"), Some("global")),
+        "model"
+    );
+    assert_eq!(
+        resolve_system_prompt(None, Some(""), None, Some("global")),
+        "global"
+    );
+    assert_eq!(
+        resolve_system_prompt(None, None, None, None),
+        DEFAULT_SYSTEM_PROMPT
+    );
+}
+
+#[tokio::test]
+async fn test_sessions_namespaced_by_user() {
+    use crate::{config::Config, info::ServerInfo};
+
+    let state = Arc::new(AppState::new(Config::default(), ServerInfo::default()));
+    let (alice, bob) = (
+        SessionNamespace::for_user("alice"),
+        SessionNamespace::for_user("bob"),
+    );
+    for (namespace, reply) in [
+        (&alice, "for alice"),
+        (&bob, "for bob"),
+        (&SessionNamespace::default(), "anonymous"),
+    ] {
+        let turn = ChatMessage::new(&namespace.scope("s1"), "hi", reply);
+        state.chat_storage.save_turn(turn).await.unwrap();
+    }
+
+    let sessions = |namespace: &SessionNamespace| {
+        let (state, namespace) = (Arc::clone(&state), namespace.clone());
+        async move {
+            let Json(listed) =
+                get_all_sessions(State(state), namespace, Query(SessionFilter::default()))
+                    .await
+                    .unwrap();
+            listed
+                .sessions
+                .into_iter()
+                .map(|s| s.session_id)
+                .collect::<Vec<_>>()
+        }
+    };
+    assert_eq!(sessions(&alice).await, ["s1"]);
+    assert_eq!(sessions(&SessionNamespace::default()).await.len(), 3);
+
+    // the same session id reaches a different history for each user
+    let messages = |namespace: &SessionNamespace| {
+        let (state, namespace) = (Arc::clone(&state), namespace.clone());
+        async move {
+            let query = Query(HistoryQuery {
+                limit: None,
+                offset: None,
+            });
+            let Json(page) = get_session_messages(
+                State(state),
+                namespace,
+                axum::extract::Path("s1".to_string()),
+                query,
+            )
+            .await
+            .unwrap();
+            page.messages
+        }
+    };
+    let replies = messages(&bob).await;
+    assert_eq!(replies.len(), 1);
+    assert_eq!(
+        (
+            replies[0].session_id.as_str(),
+            replies[0].bot_reply.as_str()
+        ),
+        ("s1", "for bob")
+    );
+    assert_eq!(
+        messages(&SessionNamespace::default()).await[0].bot_reply,
+        "anonymous"
+    );
+
+    let exists = |session_id: &str| {
+        let (state, namespace, path) = (
+            Arc::clone(&state),
+            alice.clone(),
+            axum::extract::Path(session_id.to_string()),
+        );
+        async move {
+            session_exists(State(state), namespace, path)
+                .await
+                .unwrap()
+                .0
+        }
+    };
+    let found = exists("s1").await;
+    assert!(found.exists);
+    assert_eq!(found.message_count, 1);
+    let missing = exists("s2").await;
+    assert!(!missing.exists);
+    assert_eq!(missing.message_count, 0);
+
+    // a user cannot delete the stale sessions of everyone
+    let query = Query(DeleteStaleSessionsQuery {
+        older_than: Duration::ZERO,
+        hard: true,
+    });
+    let denied = delete_stale_sessions(State(Arc::clone(&state)), alice, query).await;
+    assert!(matches!(denied, Err(ServerError::Forbidden(_))));
+}
+
+#[tokio::test]
+async fn test_server_override() {
+    use crate::{config::Config, info::ServerInfo, server::Server};
+
+    // chat servers answering with their own number
+    let mut urls = Vec::new();
+    for i in 0..2 {
+        let app = axum::Router::new().route(
+            "/v1/chat/completions",
+            axum::routing::post(move || async move {
+                let message =
+                    serde_json::json!({ "role": "assistant", "content": format!("server {i}") });
+                let choice = serde_json::json!({ "index": 0, "message": message });
+                Json(serde_json::json!({ "choices": [choice] }))
+            }),
+        );
+        urls.push(mock_chat_server(app).await);
+    }
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let down = format!(
+        "http://127.0.0.1:{}/v1",
+        listener.local_addr().unwrap().port()
+    );
+    drop(listener);
+
+    let state = Arc::new(AppState::new(Config::default(), ServerInfo::default()));
+    for url in urls.iter().chain([&down]) {
+        let server: Server =
+            serde_json::from_str(&format!(r#"{{"url": "{url}", "kind": "chat"}}"#)).unwrap();
+        state.register_downstream_server(server).await.unwrap();
+    }
+    let ask = |server: &str, namespace: SessionNamespace| {
+        let request = serde_json::json!({
+            "session_id": "s", "user_message": "hi", "model": "m", "server": server
+        });
+        let payload = serde_json::from_value::<ChatRequest>(request).unwrap();
+        handle_response(
+            State(Arc::clone(&state)),
+            namespace,
+            None,
+            HeaderMap::new(),
+            Json(payload),
+        )
+    };
+
+    // every request goes to the named server, by URL or id
+    for _ in 0..3 {
+        let response = ask(&format!("{}/", urls[1]), SessionNamespace::default())
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let reply: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            (reply["reply"].as_str(), reply["server"].as_str()),
+            (Some("server 1"), Some(urls[1].as_str()))
+        );
+    }
+    let id = state.server_group.read().await[&ServerKind::chat]
+        .servers_where(|server| server.url == urls[0])
+        .await
+        .remove(0);
+    let response = ask(&id, SessionNamespace::default()).await.unwrap();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(
+        serde_json::from_slice::<Value>(&body).unwrap()["reply"],
+        "server 0"
+    );
+
+    // users of their own sessions may not pick a server
+    let err = ask(&urls[0], SessionNamespace::for_user("alice"))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ServerError::Forbidden(_)), "{err}");
+
+    // nor may anyone pick one not registered, or one that is down
+    let err = ask("http://127.0.0.1:1/v1", SessionNamespace::default())
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ServerError::InvalidRequest(_)), "{err}");
+    assert!(ask(&down, SessionNamespace::default()).await.is_err());
+    let err = ask(&down, SessionNamespace::default()).await.unwrap_err();
+    assert!(matches!(err, ServerError::NoServerAvailable(_)), "{err}");
+}
+
+#[tokio::test]
+async fn test_export_session_bundle() {
+    use crate::{config::Config, info::ServerInfo};
+
+    let state = Arc::new(AppState::new(Config::default(), ServerInfo::default()));
+    state
+        .chat_storage
+        .save_turn(ChatMessage::new("s1", "hi", "hello"))
+        .await
+        .unwrap();
+    let bundle = |session_id: &str, format: Option<BundleFormat>| {
+        let (state, path) = (
+            Arc::clone(&state),
+            axum::extract::Path(session_id.to_string()),
+        );
+        async move {
+            export_session_bundle(
+                State(state),
+                SessionNamespace::default(),
+                path,
+                Query(BundleQuery { format }),
+            )
+            .await
+        }
+    };
+
+    // a text-only session falls back to the plain export
+    let response = bundle("s1", None).await.unwrap();
+    assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+    assert_eq!(
+        response.headers()[CONTENT_DISPOSITION],
+        "attachment; filename=\"session-s1.json\""
+    );
+    let response = bundle("s1", Some(BundleFormat::Markdown)).await.unwrap();
+    assert_eq!(
+        response.headers()[CONTENT_TYPE],
+        "text/markdown; charset=utf-8"
+    );
+
+    assert!(matches!(
+        bundle("s1", Some(BundleFormat::Zip)).await,
+        Err(ServerError::NotImplemented(_))
+    ));
+    assert!(matches!(
+        bundle("s2", None).await,
+        Err(ServerError::NotFound(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_rate_limit_by_api_key() {
+    use crate::{config::Config, info::ServerInfo};
+
+    let mut config = Config::default();
+    config.rate_limit.requests_per_second = Some(0.001);
+    config.rate_limit.burst = 1;
+    config.rate_limit.by_api_key = true;
+    let state = AppState::new(config, ServerInfo::default());
+    let check = |session_id: &str, key: Option<&str>, authorization: &str| {
+        let state = &state;
+        let key = key.map(|key| AuthenticatedKey(key.to_string()));
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, authorization.parse().unwrap());
+        let request =
+            format!(r#"{{"session_id": "{session_id}", "user_message": "hi", "dry_run": true}}"#);
+        let mut payload: ChatRequest = serde_json::from_str(&request).unwrap();
+        async move {
+            check_request(
+                state,
+                &headers,
+                &SessionNamespace::default(),
+                key.as_ref(),
+                &mut payload,
+            )
+            .await
+        }
+    };
+
+    // the requests of a key share its limit across sessions, whatever header carried it
+    assert!(check("s1", Some("key-one"), "Bearer key-one").await.is_ok());
+    assert!(matches!(
+        check("s2", Some("key-one"), "Bearer  key-one ").await,
+        Err(ServerError::RateLimited(_))
+    ));
+    assert!(check("s3", Some("key-two"), "Bearer key-one").await.is_ok());
+    // an unauthenticated header is not a key
+    assert!(check("s4", None, "Bearer key-three").await.is_ok());
+    assert!(check("s5", None, "Bearer key-three").await.is_ok());
 }
@@ -0,0 +1,339 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use axum::{
+    Extension,
+    extract::{
+        Path, Query, State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::HeaderMap,
+    response::Response,
+};
+use futures_util::{
+    SinkExt, StreamExt,
+    stream::{SplitSink, SplitStream},
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::select;
+
+use super::responses::{
+    ChatRequest, DROPPED_TURNS_HEADER, MODEL_HEADER, TIMEOUT_EVENT, check_request, parse_sse_data,
+    respond, sse_delta,
+};
+use crate::{
+    AppState,
+    auth::{AuthenticatedKey, SessionNamespace},
+    dual_info, dual_warn,
+    error::ServerError,
+};
+
+/// Turns replayed to a client when it connects, unless `?history=` says otherwise
+const DEFAULT_REPLAYED_TURNS: i64 = 20;
+
+#[derive(Debug, Deserialize)]
+pub struct WsQuery {
+    /// Number of the most recent turns sent on connection; 0 sends none
+    #[serde(default)]
+    history: Option<i64>,
+}
+
+/// Outcome of streaming one reply to the client
+enum Streamed {
+    Done,
+    /// The client went away; the reply was cancelled and saved as interrupted
+    Disconnected,
+}
+
+/// `GET /ws/{session_id}`: interactive chat over a WebSocket.
+///
+/// Each text frame is one turn, either the user message itself or a JSON object with the fields of
+/// a `/responses` request except `session_id` and `stream`. Replies are streamed back as JSON
+/// frames: `delta` with each piece of content, `tool_calls` with the raw tool call deltas, then
+/// `done` with the model and whether the chat server timed out mid-reply, or `error` with the same
+/// body as an HTTP error. Turns go through the same validation, rate limits, session lock and
+/// history as `/responses`.
+pub async fn ws_handler(
+    State(state): State<Arc<AppState>>,
+    namespace: SessionNamespace,
+    key: Option<Extension<AuthenticatedKey>>,
+    Path(session_id): Path<String>,
+    Query(query): Query<WsQuery>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let replayed = query.history.unwrap_or(DEFAULT_REPLAYED_TURNS).max(0);
+    let key = key.map(|Extension(key)| key);
+    ws.on_upgrade(move |socket| {
+        chat_socket(socket, state, namespace, key, session_id, headers, replayed)
+    })
+}
+
+async fn chat_socket(
+    socket: WebSocket,
+    state: Arc<AppState>,
+    namespace: SessionNamespace,
+    key: Option<AuthenticatedKey>,
+    session_id: String,
+    headers: HeaderMap,
+    replayed: i64,
+) {
+    let session_id = namespace.scope(&session_id);
+    dual_info!("WebSocket connected for session {session_id}");
+    let (mut sender, mut receiver) = socket.split();
+
+    if replayed > 0 {
+        let turns = match state
+            .chat_storage
+            .get_messages_page(&session_id, replayed, None)
+            .await
+        {
+            Ok((turns, _, _)) => turns,
+            Err(e) => {
+                dual_warn!("Failed to load the history of session {session_id}: {e}");
+                Vec::new()
+            }
+        };
+        let frame = json!({ "type": "history", "turns": turns });
+        if send_json(&mut sender, &frame).await.is_err() {
+            return;
+        }
+    }
+
+    // frames received while a reply is streamed are answered in order afterwards
+    let mut queued: VecDeque<String> = VecDeque::new();
+    loop {
+        let text = match queued.pop_front() {
+            Some(text) => text,
+            None => match receiver.next().await {
+                Some(Ok(Message::Text(text))) => text.to_string(),
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                // pings are answered by the socket itself
+                Some(Ok(_)) => continue,
+            },
+        };
+
+        let streamed = match turn_request(&session_id, &text) {
+            Ok(payload) => {
+                let caller = (&namespace, key.as_ref());
+                answer_turn(
+                    &state,
+                    &headers,
+                    caller,
+                    payload,
+                    &mut sender,
+                    &mut receiver,
+                    &mut queued,
+                )
+                .await
+            }
+            Err(e) => send_json(&mut sender, &error_frame(&e))
+                .await
+                .map(|_| Streamed::Done),
+        };
+        if !matches!(streamed, Ok(Streamed::Done)) {
+            break;
+        }
+    }
+
+    dual_info!("WebSocket closed for session {session_id}");
+}
+
+/// The `/responses` request of a text frame
+fn turn_request(session_id: &str, text: &str) -> Result<ChatRequest, ServerError> {
+    let mut request = match serde_json::from_str::<Value>(text) {
+        Ok(Value::Object(fields)) => Value::Object(fields),
+        _ => json!({ "user_message": text }),
+    };
+    request["session_id"] = Value::from(session_id);
+    request["stream"] = Value::from(true);
+
+    serde_json::from_value(request)
+        .map_err(|e| ServerError::InvalidRequest(format!("invalid message: {e}")))
+}
+
+/// Streams the reply to one turn. Dropping the reply body when the client disconnects cancels the
+/// downstream request, as for a `/responses` client going away.
+async fn answer_turn(
+    state: &Arc<AppState>,
+    headers: &HeaderMap,
+    (namespace, key): (&SessionNamespace, Option<&AuthenticatedKey>),
+    mut payload: ChatRequest,
+    sender: &mut SplitSink<WebSocket, Message>,
+    receiver: &mut SplitStream<WebSocket>,
+    queued: &mut VecDeque<String>,
+) -> Result<Streamed, axum::Error> {
+    let response = match check_request(state, headers, namespace, key, &mut payload).await {
+        Ok(()) => {
+            let session_guard = state.session_locks.lock(payload.session_id()).await;
+            respond(Arc::clone(state), headers.clone(), payload, session_guard).await
+        }
+        Err(e) => Err(e),
+    };
+    let response = match response {
+        Ok(response) => response,
+        Err(e) => {
+            send_json(sender, &error_frame(&e)).await?;
+            return Ok(Streamed::Done);
+        }
+    };
+
+    let dropped_turns: usize = response
+        .headers()
+        .get(DROPPED_TURNS_HEADER)
+        .and_then(|value| value.to_str().ok()?.parse().ok())
+        .unwrap_or(0);
+    let model = response
+        .headers()
+        .get(MODEL_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let mut body = response.into_body().into_data_stream();
+
+    let mut pending: Vec<u8> = Vec::new();
+    let mut timed_out = false;
+    loop {
+        let chunk = select! {
+            chunk = body.next() => chunk,
+            incoming = receiver.next() => {
+                match incoming {
+                    Some(Ok(Message::Text(text))) => {
+                        queued.push_back(text.to_string());
+                        continue;
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                        return Ok(Streamed::Disconnected);
+                    }
+                    Some(Ok(_)) => continue,
+                }
+            }
+        };
+
+        match chunk {
+            Some(Ok(bytes)) => {
+                pending.extend_from_slice(&bytes);
+                while let Some(pos) = pending.iter().position(|b| *b == b'\n') {
+                    let line: Vec<u8> = pending.drain(..=pos).collect();
+                    let line = String::from_utf8_lossy(&line);
+                    if line.trim() == TIMEOUT_EVENT.trim() {
+                        timed_out = true;
+                    }
+                    let Some(chunk) = parse_sse_data(&line) else {
+                        continue;
+                    };
+                    if let Some(delta) = sse_delta(&chunk).filter(|delta| !delta.is_empty()) {
+                        // waiting for the client to take each frame holds back the downstream
+                        // stream
+                        let frame = json!({ "type": "delta", "content": delta });
+                        if send_json(sender, &frame).await.is_err() {
+                            return Ok(Streamed::Disconnected);
+                        }
+                    }
+                    if let Some(tool_calls) = chunk.pointer("/choices/0/delta/tool_calls") {
+                        let frame = json!({ "type": "tool_calls", "tool_calls": tool_calls });
+                        if send_json(sender, &frame).await.is_err() {
+                            return Ok(Streamed::Disconnected);
+                        }
+                    }
+                }
+            }
+            Some(Err(e)) => {
+                let err = ServerError::Operation(format!("The reply stream failed: {e}"));
+                send_json(sender, &error_frame(&err)).await?;
+                return Ok(Streamed::Done);
+            }
+            None => break,
+        }
+    }
+
+    let frame = json!({
+        "type": "done",
+        "model": model,
+        "dropped_turns": dropped_turns,
+        "truncated": timed_out,
+    });
+    send_json(sender, &frame).await?;
+    Ok(Streamed::Done)
+}
+
+fn error_frame(err: &ServerError) -> Value {
+    let mut frame = err.body();
+    frame["type"] = Value::from("error");
+    frame
+}
+
+async fn send_json(
+    sender: &mut SplitSink<WebSocket, Message>,
+    frame: &Value,
+) -> Result<(), axum::Error> {
+    sender.send(Message::Text(frame.to_string().into())).await
+}
+
+#[test]
+fn test_turn_request() {
+    let request = turn_request("s1", "hello").unwrap();
+    assert_eq!(request.session_id(), "s1");
+
+    let request = turn_request(
+        "s1",
+        r#"{"user_message": "hi", "model": "llama", "session_id": "other"}"#,
+    )
+    .unwrap();
+    assert_eq!(request.session_id(), "s1");
+
+    // a JSON object must be a valid request
+    assert!(turn_request("s1", r#"{"temperature": "hot"}"#).is_err());
+
+    let frame = error_frame(&ServerError::RateLimited("slow down".into()));
+    assert_eq!(frame["type"], "error");
+    assert_eq!(frame["error"]["type"], "rate_limit_error");
+}
+
+#[tokio::test]
+async fn test_turn_server_override() {
+    use crate::{config::Config, info::ServerInfo};
+
+    // a frame pinning a server goes through the same check as a `/responses` request
+    let state = AppState::new(Config::default(), ServerInfo::default());
+    let frame = r#"{"user_message": "hi", "model": "m", "server": "http://localhost:8001/v1"}"#;
+    let alice = SessionNamespace::for_user("alice");
+    let mut request = turn_request(&alice.scope("s1"), frame).unwrap();
+    let err = check_request(&state, &HeaderMap::new(), &alice, None, &mut request)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ServerError::Forbidden(_)), "{err}");
+
+    let mut request = turn_request("s1", frame).unwrap();
+    assert!(
+        check_request(
+            &state,
+            &HeaderMap::new(),
+            &SessionNamespace::default(),
+            None,
+            &mut request
+        )
+        .await
+        .is_ok()
+    );
+}
+
+#[tokio::test]
+async fn test_turn_empty_message() {
+    use crate::{config::Config, info::ServerInfo};
+
+    // a blank frame is rejected, or replaced by the continue message, as over HTTP
+    let state = AppState::new(Config::default(), ServerInfo::default());
+    let namespace = SessionNamespace::default();
+    let mut request = turn_request("s1", " \n ").unwrap();
+    let err = check_request(&state, &HeaderMap::new(), &namespace, None, &mut request)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ServerError::InvalidRequest(_)), "{err}");
+
+    state.config.write().await.responses.continue_message = Some("Continue.".to_string());
+    let mut request = turn_request("s1", r#"{"user_message": "  "}"#).unwrap();
+    check_request(&state, &HeaderMap::new(), &namespace, None, &mut request)
+        .await
+        .unwrap();
+    assert_eq!(request.user_message(), "Continue.");
+}
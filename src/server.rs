@@ -1,7 +1,10 @@
 use std::{
-    collections::HashSet,
-    sync::atomic::{AtomicUsize, Ordering},
-    time::{Duration, SystemTime},
+    collections::{HashMap, HashSet},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
 };
 
 use async_trait::async_trait;
@@ -10,8 +13,12 @@ use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
 use crate::{
-    HEALTH_CHECK_INTERVAL, dual_error, dual_warn,
+    circuit_breaker::CircuitBreaker,
+    config::{CircuitBreakerConfig, RoutingConfig},
+    dual_error, dual_warn,
     error::{ServerError, ServerResult},
+    latency::{self, LatencyTracker},
+    telemetry,
 };
 
 /// Timeout duration for health checks (in seconds)
@@ -24,20 +31,154 @@ pub(crate) struct ServerIdToRemove {
     pub server_id: ServerId,
 }
 
+/// Base quarantine of a server after its first consecutive failure
+const QUARANTINE_BASE: Duration = Duration::from_secs(5);
+/// Upper bound of the exponential quarantine backoff
+const QUARANTINE_MAX: Duration = Duration::from_secs(300);
+
 /// Represents the health status of a server
-#[derive(Debug, Clone)]
+///
+/// Every failure, from a health check or a request, quarantines the server for a period that
+/// doubles with each consecutive failure. A success clears the quarantine. The status is shared by
+/// all groups the server is registered in.
+#[derive(Debug, Default)]
 pub struct HealthStatus {
-    pub is_healthy: bool,
-    pub last_check: SystemTime,
+    state: std::sync::Mutex<HealthState>,
 }
 
-impl Default for HealthStatus {
-    fn default() -> Self {
-        Self {
-            is_healthy: true,
-            last_check: SystemTime::now(),
+#[derive(Debug, Default)]
+struct HealthState {
+    consecutive_failures: u32,
+    down_until: Option<Instant>,
+}
+
+impl HealthStatus {
+    /// Whether the server can take requests, i.e. it is not quarantined
+    pub fn is_available(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.down_until.is_none_or(|until| Instant::now() >= until)
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures = 0;
+        state.down_until = None;
+    }
+
+    /// Marks the server down and returns how long it is quarantined
+    pub fn record_failure(&self) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+        let quarantine = QUARANTINE_BASE
+            .saturating_mul(1 << (state.consecutive_failures - 1).min(16))
+            .min(QUARANTINE_MAX);
+        state.down_until = Some(Instant::now() + quarantine);
+        quarantine
+    }
+}
+
+/// Placeholder of the API key in `auth_format`
+const AUTH_KEY_PLACEHOLDER: &str = "{key}";
+
+/// Checks the auth header name and value format of a server
+fn validate_auth(header: Option<&str>, format: Option<&str>) -> Result<(), String> {
+    if let Some(header) = header
+        && reqwest::header::HeaderName::from_bytes(header.as_bytes()).is_err()
+    {
+        return Err(format!("`{header}` is not a valid header name"));
+    }
+    if let Some(format) = format
+        && !format.contains(AUTH_KEY_PLACEHOLDER)
+    {
+        return Err(format!("`auth_format` must contain {AUTH_KEY_PLACEHOLDER}"));
+    }
+    Ok(())
+}
+
+/// Path of chat completions on a server unless its registration sets `chat_path`
+const DEFAULT_CHAT_PATH: &str = "chat/completions";
+/// Path of embeddings on a server unless its registration sets `embeddings_path`
+const DEFAULT_EMBEDDINGS_PATH: &str = "embeddings";
+/// Path of text completions on a server unless its registration sets `completions_path`
+const DEFAULT_COMPLETIONS_PATH: &str = "completions";
+
+/// URL of `path` on the server at `base`. A relative path is appended to `base` and an absolute
+/// one replaces its path, e.g. `/v2/chat` on `http://host/v1` is `http://host/v2/chat`.
+pub(crate) fn endpoint_url(base: &str, path: &str) -> String {
+    let base = format!("{}/", base.trim_end_matches('/'));
+    match reqwest::Url::parse(&base).and_then(|url| url.join(path)) {
+        Ok(url) => url.to_string(),
+        Err(_) => format!("{base}{}", path.trim_start_matches('/')),
+    }
+}
+
+/// JSON pointer of the `content_path` of a server: either a JSON pointer itself, e.g.
+/// `/choices/0/text`, or dotted keys with bracketed indices, e.g. `choices[0].text`
+pub(crate) fn content_pointer(path: &str) -> Result<String, String> {
+    let invalid = || format!("invalid `content_path` `{path}`");
+    let path = path.trim();
+    if path.starts_with('/') {
+        return Ok(path.to_string());
+    }
+    if path.is_empty() {
+        return Err(invalid());
+    }
+
+    let mut pointer = String::new();
+    for segment in path.split('.') {
+        let (key, mut indices) = segment.split_at(segment.find('[').unwrap_or(segment.len()));
+        if key.is_empty() && indices.is_empty() {
+            return Err(invalid());
+        }
+        if !key.is_empty() {
+            pointer.push('/');
+            pointer.push_str(&key.replace('~', "~0").replace('/', "~1"));
+        }
+        while let Some(rest) = indices.strip_prefix('[') {
+            let (index, rest) = rest.split_once(']').ok_or_else(invalid)?;
+            if index.is_empty() || !index.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(invalid());
+            }
+            pointer.push('/');
+            pointer.push_str(index);
+            indices = rest;
+        }
+        if !indices.is_empty() {
+            return Err(invalid());
         }
     }
+    Ok(pointer)
+}
+
+/// Header name and value carrying `api_key`; `None` if the key is unset or empty
+fn downstream_auth(
+    api_key: Option<&str>,
+    header: Option<&str>,
+    format: Option<&str>,
+) -> Option<(String, String)> {
+    let api_key = api_key.filter(|key| !key.is_empty())?;
+    let name = header.unwrap_or("authorization").to_string();
+    let value = match format {
+        Some(format) => format.replace(AUTH_KEY_PLACEHOLDER, api_key),
+        None => api_key.to_string(),
+    };
+    Some((name, value))
+}
+
+/// New API key or auth header of a registered server; unset fields are kept
+#[derive(Debug, Clone, Default, Deserialize)]
+pub(crate) struct ServerAuthUpdate {
+    #[serde(default)]
+    pub api_key: Option<String>,
+    #[serde(default)]
+    pub auth_header: Option<String>,
+    #[serde(default)]
+    pub auth_format: Option<String>,
+}
+impl ServerAuthUpdate {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_auth(self.auth_header.as_deref(), self.auth_format.as_deref())
+    }
 }
 
 /// Represents a LlamaEdge API server
@@ -48,10 +189,40 @@ pub struct Server {
     pub kind: ServerKind,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub api_key: Option<String>,
+    /// Header carrying `api_key` downstream; `Authorization` if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_header: Option<String>,
+    /// Value of the auth header, with `{key}` standing for `api_key`, e.g. `Bearer {key}`; the
+    /// bare key if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_format: Option<String>,
+    /// Share of the requests the server gets under the weighted routing strategy
+    #[serde(skip_serializing_if = "Server::is_default_weight")]
+    pub weight: u32,
+    /// Capabilities of the models the server hosts, e.g. "vision" or "code"
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Context window of the models the server hosts, in tokens
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_length: Option<u64>,
+    /// Path of chat completions, relative to `url` unless it starts with `/`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chat_path: Option<String>,
+    /// Path of embeddings, relative to `url` unless it starts with `/`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub embeddings_path: Option<String>,
+    /// Path of text completions, relative to `url` unless it starts with `/`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completions_path: Option<String>,
+    /// Where the reply text is in non-streamed chat completions, e.g. `choices[0].text`;
+    /// `choices[0].message.content` if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_path: Option<String>,
+    /// Number of in-flight requests, shared by every group the server is registered in
     #[serde(skip)]
-    connections: AtomicUsize,
+    connections: Arc<AtomicUsize>,
     #[serde(skip)]
-    pub health_status: HealthStatus,
+    pub health_status: Arc<HealthStatus>,
 }
 impl<'de> Deserialize<'de> for Server {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
@@ -64,10 +235,39 @@ impl<'de> Deserialize<'de> for Server {
             url: String,
             kind: ServerKind,
             api_key: Option<String>,
+            #[serde(default)]
+            auth_header: Option<String>,
+            #[serde(default)]
+            auth_format: Option<String>,
+            #[serde(default = "Server::default_weight")]
+            weight: u32,
+            #[serde(default)]
+            tags: Vec<String>,
+            #[serde(default)]
+            context_length: Option<u64>,
+            #[serde(default)]
+            chat_path: Option<String>,
+            #[serde(default)]
+            embeddings_path: Option<String>,
+            #[serde(default)]
+            completions_path: Option<String>,
+            #[serde(default)]
+            content_path: Option<String>,
         }
 
         // Deserialize into the helper struct
         let helper = ServerHelper::deserialize(deserializer)?;
+        if helper.weight == 0 {
+            return Err(serde::de::Error::custom(
+                "The weight of a server must be at least 1",
+            ));
+        }
+        validate_auth(helper.auth_header.as_deref(), helper.auth_format.as_deref())
+            .map_err(serde::de::Error::custom)?;
+        let content_path = helper.content_path.filter(|path| !path.trim().is_empty());
+        if let Some(path) = &content_path {
+            content_pointer(path).map_err(serde::de::Error::custom)?;
+        }
 
         let kind = helper.kind.to_string().trim().replace(',', "-");
         let id = format!("{}-server-{}", kind, uuid::Uuid::new_v4());
@@ -78,8 +278,21 @@ impl<'de> Deserialize<'de> for Server {
             url: helper.url,
             kind: helper.kind,
             api_key: helper.api_key,
-            connections: AtomicUsize::new(0),
-            health_status: HealthStatus::default(),
+            auth_header: helper.auth_header,
+            auth_format: helper.auth_format,
+            weight: helper.weight,
+            tags: helper.tags,
+            context_length: helper.context_length,
+            chat_path: helper.chat_path.filter(|path| !path.trim().is_empty()),
+            embeddings_path: helper
+                .embeddings_path
+                .filter(|path| !path.trim().is_empty()),
+            completions_path: helper
+                .completions_path
+                .filter(|path| !path.trim().is_empty()),
+            content_path,
+            connections: Arc::new(AtomicUsize::new(0)),
+            health_status: Arc::new(HealthStatus::default()),
         })
     }
 }
@@ -90,29 +303,121 @@ impl Clone for Server {
             url: self.url.clone(),
             kind: self.kind,
             api_key: self.api_key.clone(),
-            connections: AtomicUsize::new(self.connections.load(Ordering::Relaxed)),
-            health_status: self.health_status.clone(),
+            auth_header: self.auth_header.clone(),
+            auth_format: self.auth_format.clone(),
+            weight: self.weight,
+            tags: self.tags.clone(),
+            context_length: self.context_length,
+            chat_path: self.chat_path.clone(),
+            embeddings_path: self.embeddings_path.clone(),
+            completions_path: self.completions_path.clone(),
+            content_path: self.content_path.clone(),
+            connections: Arc::clone(&self.connections),
+            health_status: Arc::clone(&self.health_status),
         }
     }
 }
 impl Server {
-    pub(crate) async fn check_health(&mut self) -> bool {
-        // If the server is currently healthy, check if a new health check is needed
-        if self.health_status.is_healthy {
-            let now = SystemTime::now();
-            if let Ok(duration) = now.duration_since(self.health_status.last_check) {
-                let check_interval =
-                    Duration::from_secs(*HEALTH_CHECK_INTERVAL.get().unwrap_or(&60));
-                if duration < check_interval {
-                    // If the time since last check is less than the interval, return current status
-                    return true;
-                }
-            }
+    fn default_weight() -> u32 {
+        1
+    }
+
+    fn is_default_weight(weight: &u32) -> bool {
+        *weight == Self::default_weight()
+    }
+
+    /// Header name and value sending the API key of the server downstream; `None` without a key
+    pub fn auth(&self) -> Option<(String, String)> {
+        downstream_auth(
+            self.api_key.as_deref(),
+            self.auth_header.as_deref(),
+            self.auth_format.as_deref(),
+        )
+    }
+
+    /// URL of the chat completions endpoint of the server
+    pub fn chat_url(&self) -> String {
+        endpoint_url(
+            &self.url,
+            self.chat_path.as_deref().unwrap_or(DEFAULT_CHAT_PATH),
+        )
+    }
+
+    /// URL of the embeddings endpoint of the server
+    pub fn embeddings_url(&self) -> String {
+        endpoint_url(
+            &self.url,
+            self.embeddings_path
+                .as_deref()
+                .unwrap_or(DEFAULT_EMBEDDINGS_PATH),
+        )
+    }
+
+    /// URL of the text completions endpoint of the server
+    pub fn completions_url(&self) -> String {
+        endpoint_url(
+            &self.url,
+            self.completions_path
+                .as_deref()
+                .unwrap_or(DEFAULT_COMPLETIONS_PATH),
+        )
+    }
+
+    /// JSON pointer of the reply text in non-streamed chat completions, if the server sets one
+    pub fn content_pointer(&self) -> Option<String> {
+        // the path was checked when the server was registered
+        content_pointer(self.content_path.as_deref()?).ok()
+    }
+
+    /// Applies an API key rotation or a change of the auth header
+    fn update_auth(&mut self, update: &ServerAuthUpdate) {
+        if let Some(api_key) = &update.api_key {
+            self.api_key = Some(api_key.clone());
+        }
+        if let Some(auth_header) = &update.auth_header {
+            self.auth_header = Some(auth_header.clone());
+        }
+        if let Some(auth_format) = &update.auth_format {
+            self.auth_format = Some(auth_format.clone());
         }
+    }
+
+    /// Whether the server has every tag of `tags`, compared case-insensitively, and a context of
+    /// at least `min_context_length` tokens. The kinds of the server count as tags.
+    pub fn has_capabilities(&self, tags: &[String], min_context_length: Option<u64>) -> bool {
+        let kinds = self.kind.to_string();
+        let has_tag = |tag: &String| {
+            self.tags
+                .iter()
+                .map(String::as_str)
+                .chain(kinds.split(','))
+                .any(|own| own.eq_ignore_ascii_case(tag))
+        };
+        let has_context = match min_context_length {
+            Some(min) => self.context_length.is_some_and(|length| length >= min),
+            None => true,
+        };
+        has_context && tags.iter().all(has_tag)
+    }
+
+    /// Whether the server continues a reply from a prefilled assistant message: a text completion
+    /// server does by nature, a chat server when tagged "prefill"
+    pub fn supports_prefill(&self) -> bool {
+        self.kind.contains(ServerKind::completion)
+            || self
+                .tags
+                .iter()
+                .any(|tag| tag.eq_ignore_ascii_case("prefill"))
+    }
+
+    /// Whether `name` is the id or the URL of the server; a trailing `/` of the URL is ignored
+    pub fn is_named(&self, name: &str) -> bool {
+        self.id == name || self.url.trim_end_matches('/') == name.trim_end_matches('/')
+    }
 
-        // Perform new health check
-        let client = reqwest::Client::new();
-        let health_url = format!("{}/info", self.url);
+    /// Probes `{url}{path}` and records the result in the health status of the server
+    pub(crate) async fn check_health(&self, client: &reqwest::Client, path: &str) -> bool {
+        let health_url = format!("{}{}", self.url.trim_end_matches('/'), path);
 
         // Use configured timeout duration
         let timeout = Duration::from_secs(TIMEOUT);
@@ -128,15 +433,24 @@ impl Server {
             }
             Err(e) => {
                 // Consider server healthy if error is timeout
-                dual_warn!("Health check: {} server {} is in use", self.kind, self.id);
+                if e.is_timeout() {
+                    dual_warn!("Health check: {} server {} is in use", self.kind, self.id);
+                }
                 e.is_timeout()
             }
         };
 
-        self.health_status = HealthStatus {
-            is_healthy,
-            last_check: SystemTime::now(),
-        };
+        if is_healthy {
+            self.health_status.record_success();
+        } else {
+            let quarantine = self.health_status.record_failure();
+            dual_warn!(
+                "Health check: {} server {} is down, quarantined for {}s",
+                self.kind,
+                self.id,
+                quarantine.as_secs()
+            );
+        }
 
         is_healthy
     }
@@ -157,6 +471,85 @@ fn test_deserialize_server() {
     assert_eq!(server.kind, ServerKind::chat);
 }
 
+#[test]
+fn test_endpoint_urls() {
+    let server: Server =
+        serde_json::from_str(r#"{"url": "http://localhost:8000/v1/", "kind": "chat"}"#).unwrap();
+    assert_eq!(
+        server.chat_url(),
+        "http://localhost:8000/v1/chat/completions"
+    );
+    assert_eq!(
+        server.embeddings_url(),
+        "http://localhost:8000/v1/embeddings"
+    );
+    assert_eq!(
+        server.completions_url(),
+        "http://localhost:8000/v1/completions"
+    );
+
+    let server: Server = serde_json::from_str(
+        r#"{"url": "http://localhost:8000/api", "kind": "chat,embeddings",
+            "chat_path": "/v1/chat/completions", "embeddings_path": "embed"}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        server.chat_url(),
+        "http://localhost:8000/v1/chat/completions"
+    );
+    assert_eq!(server.embeddings_url(), "http://localhost:8000/api/embed");
+
+    assert_eq!(
+        endpoint_url("http://localhost:8000", "chat/completions"),
+        "http://localhost:8000/chat/completions"
+    );
+    assert_eq!(
+        endpoint_url("http://localhost:8000/v1", "/chat"),
+        "http://localhost:8000/chat"
+    );
+}
+
+#[test]
+fn test_content_pointer() {
+    assert_eq!(
+        content_pointer("choices[0].text"),
+        Ok("/choices/0/text".to_string())
+    );
+    assert_eq!(content_pointer("text"), Ok("/text".to_string()));
+    assert_eq!(
+        content_pointer("output[1][0].content"),
+        Ok("/output/1/0/content".to_string())
+    );
+    assert_eq!(
+        content_pointer("[0].generated_text"),
+        Ok("/0/generated_text".to_string())
+    );
+    assert_eq!(content_pointer("a/b.c~d"), Ok("/a~1b/c~0d".to_string()));
+    assert_eq!(
+        content_pointer("/choices/0/text"),
+        Ok("/choices/0/text".to_string())
+    );
+    for invalid in [
+        "",
+        "choices..text",
+        "choices[x].text",
+        "choices[0",
+        "choices[0]x",
+        "choices[]",
+    ] {
+        assert!(content_pointer(invalid).is_err(), "{invalid}");
+    }
+
+    let server: Server = serde_json::from_str(
+        r#"{"url": "http://localhost:8000", "kind": "chat", "content_path": "choices[0].text"}"#,
+    )
+    .unwrap();
+    assert_eq!(server.content_pointer().as_deref(), Some("/choices/0/text"));
+    let invalid =
+        r#"{"url": "http://localhost:8000", "kind": "chat", "content_path": "choices[zero]"}"#;
+    assert!(serde_json::from_str::<Server>(invalid).is_err());
+}
+
 #[test]
 fn test_serialize_server() {
     let id = "chat-tts-29b6c973-d45a-4487-a3da-2e9b1f704fd9".to_string();
@@ -165,8 +558,17 @@ fn test_serialize_server() {
         url: "http://localhost:8000".to_string(),
         kind: ServerKind::chat | ServerKind::tts,
         api_key: None,
-        connections: AtomicUsize::new(0),
-        health_status: HealthStatus::default(),
+        auth_header: None,
+        auth_format: None,
+        weight: 1,
+        tags: Vec::new(),
+        context_length: None,
+        chat_path: None,
+        embeddings_path: None,
+        completions_path: None,
+        content_path: None,
+        connections: Arc::new(AtomicUsize::new(0)),
+        health_status: Arc::new(HealthStatus::default()),
     };
     let serialized = serde_json::to_string(&server).unwrap();
     assert_eq!(
@@ -180,13 +582,25 @@ fn test_serialize_server() {
         url: "http://localhost:8000".to_string(),
         kind: ServerKind::chat,
         api_key: Some("test-api-key".to_string()),
-        connections: AtomicUsize::new(0),
-        health_status: HealthStatus::default(),
+        auth_header: None,
+        auth_format: None,
+        weight: 3,
+        tags: Vec::new(),
+        context_length: None,
+        chat_path: None,
+        embeddings_path: None,
+        completions_path: None,
+        content_path: None,
+        connections: Arc::new(AtomicUsize::new(0)),
+        health_status: Arc::new(HealthStatus::default()),
     };
     let serialized = serde_json::to_string(&server).unwrap();
     assert_eq!(
         serialized,
-        r#"{"id":"chat-2424f42e-fcfb-458e-9a6a-ad419e24b5f5","url":"http://localhost:8000","kind":"chat","api_key":"test-api-key"}"#
+        concat!(
+            r#"{"id":"chat-2424f42e-fcfb-458e-9a6a-ad419e24b5f5","url":"http://localhost:8000","#,
+            r#""kind":"chat","api_key":"test-api-key","weight":3}"#,
+        )
     );
 }
 
@@ -200,6 +614,9 @@ bitflags! {
         const tts = 1 << 3;
         const translate = 1 << 4;
         const transcribe = 1 << 5;
+        const moderation = 1 << 6;
+        /// Text completions, taking a prompt instead of messages
+        const completion = 1 << 7;
     }
 }
 impl std::fmt::Display for ServerKind {
@@ -223,6 +640,12 @@ impl std::fmt::Display for ServerKind {
         if self.contains(ServerKind::transcribe) {
             kind_str.push_str("transcribe,");
         }
+        if self.contains(ServerKind::moderation) {
+            kind_str.push_str("moderation,");
+        }
+        if self.contains(ServerKind::completion) {
+            kind_str.push_str("completion,");
+        }
 
         if !kind_str.is_empty() {
             kind_str = kind_str.trim_end_matches(',').to_string();
@@ -246,6 +669,8 @@ impl std::str::FromStr for ServerKind {
                 "tts" => kind.set(Self::tts, true),
                 "translate" => kind.set(Self::translate, true),
                 "transcribe" => kind.set(Self::transcribe, true),
+                "moderation" => kind.set(Self::moderation, true),
+                "completion" => kind.set(Self::completion, true),
                 _ => return Err(ServerError::InvalidServerKind(s.to_string())),
             }
         }
@@ -277,6 +702,12 @@ impl Serialize for ServerKind {
         if self.contains(ServerKind::transcribe) {
             kind_str.push_str("transcribe,");
         }
+        if self.contains(ServerKind::moderation) {
+            kind_str.push_str("moderation,");
+        }
+        if self.contains(ServerKind::completion) {
+            kind_str.push_str("completion,");
+        }
 
         // Remove trailing comma if present
         if !kind_str.is_empty() {
@@ -316,6 +747,10 @@ fn test_serialize_server_kind() {
     let serialized = serde_json::to_string(&kind).unwrap();
     assert_eq!(serialized, "\"chat\"");
 
+    let kind = ServerKind::chat | ServerKind::completion;
+    let serialized = serde_json::to_string(&kind).unwrap();
+    assert_eq!(serialized, "\"chat,completion\"");
+
     // let kind = ServerKind::vdb;
     // let serialized = serde_json::to_string(&kind).unwrap();
     // assert_eq!(serialized, "\"vdb\"");
@@ -331,26 +766,101 @@ fn test_deserialize_server_kind() {
     let kind: ServerKind = serde_json::from_str(serialized).unwrap();
     assert_eq!(kind, ServerKind::chat);
 
+    let serialized = "\"completion\"";
+    let kind: ServerKind = serde_json::from_str(serialized).unwrap();
+    assert_eq!(kind, ServerKind::completion);
+
     // let serialized = "\"vdb\"";
     // let kind: ServerKind = serde_json::from_str(serialized).unwrap();
     // assert_eq!(kind, ServerKind::vdb);
 }
 
+/// Strategy used by a [`ServerGroup`] to pick the server for the next request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoutingStrategy {
+    /// Cycle through the servers in registration order
+    #[default]
+    RoundRobin,
+    /// Pick the server with the fewest in-flight requests, breaking ties in round-robin order
+    LeastConnections,
+    /// Spread requests in proportion to the server weights with smooth weighted round-robin
+    Weighted,
+    /// Send every request of a session to the same server with rendezvous hashing, so adding or
+    /// removing a server only moves the sessions mapped to it. Requests without a session use
+    /// least-connections.
+    Sticky,
+    /// Spread requests in inverse proportion to the average response latency of the servers, so
+    /// a server twice as slow gets half the requests
+    LatencyAware,
+}
+impl std::fmt::Display for RoutingStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RoutingStrategy::RoundRobin => write!(f, "round_robin"),
+            RoutingStrategy::LeastConnections => write!(f, "least_connections"),
+            RoutingStrategy::Weighted => write!(f, "weighted"),
+            RoutingStrategy::Sticky => write!(f, "sticky"),
+            RoutingStrategy::LatencyAware => write!(f, "latency_aware"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct ServerGroup {
     pub(crate) servers: RwLock<Vec<RwLock<Server>>>,
     pub(crate) healthy_servers: RwLock<HashSet<ServerId>>,
     ty: ServerKind,
+    strategy: RoutingStrategy,
+    // Round-robin cursor, also used as the tie-break start for least-connections
+    cursor: AtomicUsize,
+    // Current weights of the smooth weighted round-robin, by server id
+    current_weights: std::sync::Mutex<HashMap<ServerId, i64>>,
+    // Settings of the circuit breakers created for registered servers
+    breaker_config: CircuitBreakerConfig,
+    // Circuit breaker of each registered server, by server id
+    circuit_breakers: std::sync::Mutex<HashMap<ServerId, Arc<CircuitBreaker>>>,
+    // Smoothing and half-life of the latency averages
+    latency_smoothing: f64,
+    latency_half_life: Duration,
+    // Average response latency of each registered server, by server id
+    latencies: std::sync::Mutex<HashMap<ServerId, Arc<LatencyTracker>>>,
 }
 impl ServerGroup {
-    pub(crate) fn new(ty: ServerKind) -> Self {
+    pub(crate) fn new(ty: ServerKind, strategy: RoutingStrategy) -> Self {
         Self {
             servers: RwLock::new(Vec::new()),
             healthy_servers: RwLock::new(HashSet::new()),
             ty,
+            strategy,
+            cursor: AtomicUsize::new(0),
+            current_weights: std::sync::Mutex::new(HashMap::new()),
+            breaker_config: CircuitBreakerConfig::default(),
+            circuit_breakers: std::sync::Mutex::new(HashMap::new()),
+            latency_smoothing: RoutingConfig::default().latency_smoothing,
+            latency_half_life: Duration::from_secs(RoutingConfig::default().latency_half_life_secs),
+            latencies: std::sync::Mutex::new(HashMap::new()),
         }
     }
 
+    /// Strategy picking the server for each request
+    pub(crate) fn strategy(&self) -> RoutingStrategy {
+        self.strategy
+    }
+
+    /// Sets the circuit breaker settings of the servers registered from now on
+    pub(crate) fn with_circuit_breaker(mut self, config: CircuitBreakerConfig) -> Self {
+        self.breaker_config = config;
+        self
+    }
+
+    /// Sets how the average response latencies of the servers are tracked
+    pub(crate) fn with_latency_tracking(mut self, config: &RoutingConfig) -> Self {
+        self.latency_smoothing = config.latency_smoothing;
+        self.latency_half_life = Duration::from_secs(config.latency_half_life_secs);
+        self
+    }
+
     pub(crate) async fn register(&self, server: Server) -> ServerResult<()> {
         // check if the server is already registered
         if self.healthy_servers.read().await.contains(&server.id) {
@@ -360,6 +870,13 @@ impl ServerGroup {
         }
 
         self.healthy_servers.write().await.insert(server.id.clone());
+        self.circuit_breakers.lock().unwrap().insert(
+            server.id.clone(),
+            Arc::new(CircuitBreaker::new(
+                server.url.clone(),
+                &self.breaker_config,
+            )),
+        );
         self.servers.write().await.push(RwLock::new(server));
 
         Ok(())
@@ -391,6 +908,10 @@ impl ServerGroup {
             servers.swap_remove(idx);
         }
 
+        self.current_weights.lock().unwrap().remove(id_to_remove);
+        self.circuit_breakers.lock().unwrap().remove(id_to_remove);
+        self.latencies.lock().unwrap().remove(id_to_remove);
+
         // Remove the server from the healthy server set if found
         if !self.healthy_servers.write().await.remove(id_to_remove) {
             let err_msg = format!("Server not found: {id_to_remove}");
@@ -409,10 +930,124 @@ impl ServerGroup {
     pub(crate) async fn is_empty(&self) -> bool {
         self.healthy_servers.read().await.is_empty()
     }
+
+    /// Applies `update` to the server `server_id`; returns the updated server, `None` if the
+    /// group has no such server. Requests already sent keep the old key.
+    pub(crate) async fn update_auth(
+        &self,
+        server_id: &str,
+        update: &ServerAuthUpdate,
+    ) -> Option<Server> {
+        for server_lock in self.servers.read().await.iter() {
+            let mut server = server_lock.write().await;
+            if server.id == server_id {
+                server.update_auth(update);
+                return Some(server.clone());
+            }
+        }
+        None
+    }
+
+    /// Ids of the servers with every tag of `tags` and a context of at least
+    /// `min_context_length` tokens; see [`Server::has_capabilities`]
+    pub(crate) async fn servers_with_capabilities(
+        &self,
+        tags: &[String],
+        min_context_length: Option<u64>,
+    ) -> Vec<ServerId> {
+        self.servers_where(|server| server.has_capabilities(tags, min_context_length))
+            .await
+    }
+
+    /// Ids of the servers for which `keep` holds
+    pub(crate) async fn servers_where(&self, keep: impl Fn(&Server) -> bool) -> Vec<ServerId> {
+        let mut ids = Vec::new();
+        for server_lock in self.servers.read().await.iter() {
+            let server = server_lock.read().await;
+            if keep(&server) {
+                ids.push(server.id.clone());
+            }
+        }
+        ids
+    }
+
+    /// Whether any server of the group is registered and not quarantined by its health checks
+    pub(crate) async fn has_available_server(&self) -> bool {
+        for server_lock in self.servers.read().await.iter() {
+            if server_lock.read().await.health_status.is_available() {
+                return true;
+            }
+        }
+        false
+    }
 }
-#[async_trait]
-impl RoutingPolicy for ServerGroup {
-    async fn next(&self) -> Result<TargetServerInfo, ServerError> {
+impl ServerGroup {
+    /// Smooth weighted round-robin as in nginx: every candidate gains its weight, the one with the
+    /// highest current weight is picked and loses the total. Over any window of `total` picks each
+    /// server is chosen `weight` times, interleaved rather than in runs.
+    fn pick_weighted<'a>(
+        &self,
+        candidates: Vec<(&'a RwLock<Server>, ServerId, u32)>,
+    ) -> Option<&'a RwLock<Server>> {
+        let mut current_weights = self.current_weights.lock().unwrap();
+        let total: i64 = candidates
+            .iter()
+            .map(|(_, _, weight)| i64::from(*weight))
+            .sum();
+
+        let mut best: Option<(&RwLock<Server>, &ServerId, i64)> = None;
+        for (server_lock, id, weight) in candidates.iter() {
+            let current = current_weights.entry(id.clone()).or_insert(0);
+            *current += i64::from(*weight);
+            if best.is_none_or(|(_, _, max)| *current > max) {
+                best = Some((server_lock, id, *current));
+            }
+        }
+
+        let (server_lock, id, _) = best?;
+        *current_weights.get_mut(id).unwrap() -= total;
+        Some(server_lock)
+    }
+
+    /// Score of the server at `server_url` for `session_id` under rendezvous hashing; the highest
+    /// score wins. The url rather than the id is hashed, since a server gets a new id each time it
+    /// registers, and its sessions must stay on it across restarts and re-registrations.
+    fn sticky_score(session_id: &str, server_url: &str) -> u64 {
+        use std::hash::{Hash, Hasher};
+
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        session_id.hash(&mut hasher);
+        server_url.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Circuit breaker of the server `id`, created on first use
+    fn circuit_breaker(&self, id: &ServerId, url: &str) -> Arc<CircuitBreaker> {
+        let mut breakers = self.circuit_breakers.lock().unwrap();
+        let breaker = breakers
+            .entry(id.clone())
+            .or_insert_with(|| Arc::new(CircuitBreaker::new(url, &self.breaker_config)));
+        Arc::clone(breaker)
+    }
+
+    /// Latency average of the server `id`, created on first use
+    fn latency(&self, id: &ServerId) -> Arc<LatencyTracker> {
+        let mut latencies = self.latencies.lock().unwrap();
+        let latency = latencies.entry(id.clone()).or_insert_with(|| {
+            Arc::new(LatencyTracker::new(
+                self.latency_smoothing,
+                self.latency_half_life,
+            ))
+        });
+        Arc::clone(latency)
+    }
+
+    /// Picks a server by the routing strategy, only among `allowed` if given
+    async fn pick(
+        &self,
+        session_id: Option<&str>,
+        allowed: Option<&HashSet<ServerId>>,
+    ) -> Result<TargetServerInfo, ServerError> {
         let servers = self.servers.read().await;
         if servers.is_empty() {
             let err_msg = format!("No {} server found", self.ty);
@@ -420,36 +1055,154 @@ impl RoutingPolicy for ServerGroup {
             return Err(ServerError::NotFoundServer(self.ty.to_string()));
         }
 
-        let server_lock = if servers.len() == 1 {
-            servers.first().unwrap()
-        } else {
-            // Find server with minimum connections - need to read each server
+        let start = self.cursor.fetch_add(1, Ordering::Relaxed) % servers.len();
+        let strategy = match (self.strategy, session_id) {
+            (RoutingStrategy::Sticky, None) => RoutingStrategy::LeastConnections,
+            (strategy, _) => strategy,
+        };
+
+        // Servers whose half-open trial was taken by a concurrent request between the scan and
+        // the pick; the scan is repeated without them
+        let mut excluded: Vec<ServerId> = Vec::new();
+        loop {
+            // Scan from the cursor, skipping quarantined servers and open circuit breakers. For
+            // least-connections, servers with equal counts are used in turn. A sticky session
+            // whose server is skipped moves to the server with the next highest score until it
+            // is back.
+            let mut chosen = None;
             let mut min_connections = usize::MAX;
-            let mut min_server = &servers[0];
-
-            for server in servers.iter() {
-                let guard = server.read().await;
-                let connections = guard.connections.load(Ordering::Relaxed);
-                if connections < min_connections {
-                    min_connections = connections;
-                    min_server = server;
+            let mut max_score = None;
+            let mut weighted = Vec::new();
+            let mut averages = Vec::new();
+            for offset in 0..servers.len() {
+                let server_lock = &servers[(start + offset) % servers.len()];
+                let server = server_lock.read().await;
+                if !server.health_status.is_available()
+                    || excluded.contains(&server.id)
+                    || allowed.is_some_and(|allowed| !allowed.contains(&server.id))
+                    || !self
+                        .circuit_breaker(&server.id, &server.url)
+                        .allows_request()
+                {
+                    continue;
+                }
+
+                match strategy {
+                    RoutingStrategy::RoundRobin => {
+                        chosen = Some(server_lock);
+                        break;
+                    }
+                    RoutingStrategy::LeastConnections => {
+                        let connections = server.connections.load(Ordering::Relaxed);
+                        if connections < min_connections {
+                            min_connections = connections;
+                            chosen = Some(server_lock);
+                        }
+                    }
+                    RoutingStrategy::Weighted => {
+                        weighted.push((server_lock, server.id.clone(), server.weight));
+                    }
+                    RoutingStrategy::LatencyAware => {
+                        averages.push(self.latency(&server.id).average_ms());
+                        weighted.push((server_lock, server.id.clone(), 0));
+                    }
+                    RoutingStrategy::Sticky => {
+                        let score = Self::sticky_score(session_id.unwrap_or_default(), &server.url);
+                        if max_score.is_none_or(|max| score > max) {
+                            max_score = Some(score);
+                            chosen = Some(server_lock);
+                        }
+                    }
                 }
             }
-            min_server
-        };
 
-        // Access the chosen server
-        let target_server_info = {
-            let server = server_lock.write().await;
-            server.connections.fetch_add(1, Ordering::Relaxed);
-            TargetServerInfo {
+            if strategy == RoutingStrategy::LatencyAware {
+                for ((_, _, weight), inverse) in
+                    weighted.iter_mut().zip(latency::inverse_weights(&averages))
+                {
+                    *weight = inverse;
+                }
+            }
+            if matches!(
+                strategy,
+                RoutingStrategy::Weighted | RoutingStrategy::LatencyAware
+            ) {
+                chosen = self.pick_weighted(weighted);
+            }
+
+            let Some(server_lock) = chosen else {
+                let err = ServerError::NoServerAvailable(self.ty.to_string());
+                dual_error!("{}", err);
+                return Err(err);
+            };
+
+            // Access the chosen server
+            let server = server_lock.read().await;
+            let breaker = self.circuit_breaker(&server.id, &server.url);
+            if !breaker.try_acquire() {
+                excluded.push(server.id.clone());
+                continue;
+            }
+
+            return Ok(TargetServerInfo {
                 id: server.id.clone(),
                 url: server.url.clone(),
-                api_key: server.api_key.clone(),
-            }
-        };
+                kind: self.ty,
+                chat_url: server.chat_url(),
+                embeddings_url: server.embeddings_url(),
+                completions_url: server.completions_url(),
+                content_pointer: server.content_pointer(),
+                auth: server.auth(),
+                in_flight: Arc::new(InFlight::acquire(&server.connections, &server.url)),
+                health: Arc::clone(&server.health_status),
+                latency: self.latency(&server.id),
+                breaker,
+            });
+        }
+    }
+}
+
+#[async_trait]
+impl RoutingPolicy for ServerGroup {
+    async fn next(&self) -> Result<TargetServerInfo, ServerError> {
+        self.pick(None, None).await
+    }
 
-        Ok(target_server_info)
+    async fn next_for_session(&self, session_id: &str) -> Result<TargetServerInfo, ServerError> {
+        self.pick(Some(session_id), None).await
+    }
+
+    async fn next_among(
+        &self,
+        session_id: &str,
+        servers: &HashSet<ServerId>,
+    ) -> Result<TargetServerInfo, ServerError> {
+        self.pick(Some(session_id), Some(servers)).await
+    }
+}
+
+/// One in-flight request on a downstream server. The connection count of the server is
+/// incremented on creation and decremented when dropped, so early returns and errors release it.
+#[derive(Debug)]
+pub struct InFlight {
+    connections: Arc<AtomicUsize>,
+    // server url, the label of the in-flight gauge
+    server: String,
+}
+impl InFlight {
+    fn acquire(connections: &Arc<AtomicUsize>, server: &str) -> Self {
+        connections.fetch_add(1, Ordering::Relaxed);
+        metrics::gauge!(telemetry::SERVER_IN_FLIGHT, "server" => server.to_string()).increment(1);
+        Self {
+            connections: Arc::clone(connections),
+            server: server.to_string(),
+        }
+    }
+}
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+        metrics::gauge!(telemetry::SERVER_IN_FLIGHT, "server" => self.server.clone()).decrement(1);
     }
 }
 
@@ -458,10 +1211,437 @@ impl RoutingPolicy for ServerGroup {
 pub struct TargetServerInfo {
     pub id: ServerId,
     pub url: String,
-    pub api_key: Option<String>,
+    /// Kind of the server group the server was picked from
+    pub kind: ServerKind,
+    /// URL of the chat completions endpoint of the server
+    pub chat_url: String,
+    /// URL of the embeddings endpoint of the server
+    pub embeddings_url: String,
+    /// URL of the text completions endpoint of the server
+    pub completions_url: String,
+    /// JSON pointer of the reply text in non-streamed chat completions; the OpenAI shape if `None`
+    pub content_pointer: Option<String>,
+    /// Header name and value carrying the API key of the server, if it has one
+    pub auth: Option<(String, String)>,
+    /// Released once the last clone of this target is dropped
+    pub in_flight: Arc<InFlight>,
+    /// Health of the server, used to report request-time failures
+    pub health: Arc<HealthStatus>,
+    /// Circuit breaker of the server in the group it was picked from
+    pub breaker: Arc<CircuitBreaker>,
+    /// Average response latency of the server in the group it was picked from
+    pub latency: Arc<LatencyTracker>,
 }
 
 #[async_trait]
 pub(crate) trait RoutingPolicy: Sync + Send {
     async fn next(&self) -> Result<TargetServerInfo, ServerError>;
+
+    /// Picks the server for a request of `session_id`; only sticky routing takes the session into
+    /// account
+    async fn next_for_session(&self, session_id: &str) -> Result<TargetServerInfo, ServerError>;
+
+    /// Like [`Self::next_for_session`], among `servers` only
+    async fn next_among(
+        &self,
+        session_id: &str,
+        servers: &HashSet<ServerId>,
+    ) -> Result<TargetServerInfo, ServerError>;
+}
+
+#[tokio::test]
+async fn test_server_auth() {
+    let server: Server = serde_json::from_str(
+        r#"{"url": "http://localhost:8001", "kind": "chat", "api_key": "k1",
+            "auth_header": "x-api-key"}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        server.auth(),
+        Some(("x-api-key".to_string(), "k1".to_string()))
+    );
+
+    let bare: Server =
+        serde_json::from_str(r#"{"url": "http://localhost:8002", "kind": "chat", "api_key": ""}"#)
+            .unwrap();
+    assert_eq!(bare.auth(), None);
+
+    for invalid in [
+        r#""auth_header": "bad header""#,
+        r#""auth_format": "Bearer""#,
+    ] {
+        let json = format!(r#"{{"url": "http://localhost:8003", "kind": "chat", {invalid}}}"#);
+        assert!(serde_json::from_str::<Server>(&json).is_err(), "{invalid}");
+    }
+
+    // rotating the key applies to the next pick
+    let group = ServerGroup::new(ServerKind::chat, RoutingStrategy::RoundRobin);
+    let id = server.id.clone();
+    group.register(server).await.unwrap();
+    let update = ServerAuthUpdate {
+        api_key: Some("k2".to_string()),
+        auth_header: Some("Authorization".to_string()),
+        auth_format: Some("Bearer {key}".to_string()),
+    };
+    let updated = group.update_auth(&id, &update).await.unwrap();
+    assert_eq!(updated.api_key.as_deref(), Some("k2"));
+    assert_eq!(
+        group.next().await.unwrap().auth,
+        Some(("Authorization".to_string(), "Bearer k2".to_string()))
+    );
+    assert!(group.update_auth("unknown", &update).await.is_none());
+}
+
+#[tokio::test]
+async fn test_least_connections_routing() {
+    let group = ServerGroup::new(ServerKind::chat, RoutingStrategy::LeastConnections);
+    for port in [8001, 8002] {
+        let server: Server = serde_json::from_str(&format!(
+            r#"{{"url": "http://localhost:{port}", "kind": "chat"}}"#
+        ))
+        .unwrap();
+        group.register(server).await.unwrap();
+    }
+
+    // the first server stays busy, so both following requests go to the idle one
+    let busy = group.next().await.unwrap();
+    let first = group.next().await.unwrap();
+    assert_ne!(first.url, busy.url);
+    drop(first);
+    let second = group.next().await.unwrap();
+    assert_ne!(second.url, busy.url);
+
+    // once released, equal counts fall back to round-robin order
+    drop(busy);
+    drop(second);
+    let a = group.next().await.unwrap().url;
+    let b = group.next().await.unwrap().url;
+    assert_ne!(a, b);
+}
+
+#[tokio::test]
+async fn test_next_skips_quarantined_servers() {
+    let group = ServerGroup::new(ServerKind::chat, RoutingStrategy::RoundRobin);
+    for port in [8001, 8002] {
+        let server: Server = serde_json::from_str(&format!(
+            r#"{{"url": "http://localhost:{port}", "kind": "chat"}}"#
+        ))
+        .unwrap();
+        group.register(server).await.unwrap();
+    }
+
+    let down = group.next().await.unwrap();
+    assert_eq!(down.health.record_failure(), QUARANTINE_BASE);
+    assert_eq!(down.health.record_failure(), QUARANTINE_BASE * 2);
+    for _ in 0..4 {
+        assert_ne!(group.next().await.unwrap().url, down.url);
+    }
+
+    // every server down
+    let other = group.next().await.unwrap();
+    other.health.record_failure();
+    assert!(group.next().await.is_err());
+
+    // a success lifts the quarantine
+    down.health.record_success();
+    assert_eq!(group.next().await.unwrap().url, down.url);
+}
+
+#[tokio::test]
+async fn test_weighted_routing() {
+    let group = ServerGroup::new(ServerKind::chat, RoutingStrategy::Weighted);
+    for (port, weight) in [(8001, 5), (8002, 3), (8003, 2)] {
+        let server: Server = serde_json::from_str(&format!(
+            r#"{{"url": "http://localhost:{port}", "kind": "chat", "weight": {weight}}}"#
+        ))
+        .unwrap();
+        group.register(server).await.unwrap();
+    }
+
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for _ in 0..1000 {
+        *counts.entry(group.next().await.unwrap().url).or_default() += 1;
+    }
+    for (port, expected) in [(8001, 500), (8002, 300), (8003, 200)] {
+        let count = counts[&format!("http://localhost:{port}")];
+        assert!(
+            count.abs_diff(expected) <= 10,
+            "port {port}: {count} requests"
+        );
+    }
+
+    // a zero weight is rejected at registration
+    assert!(
+        serde_json::from_str::<Server>(
+            r#"{"url": "http://localhost:8004", "kind": "chat", "weight": 0}"#
+        )
+        .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_latency_aware_routing() {
+    let group = ServerGroup::new(ServerKind::chat, RoutingStrategy::LatencyAware);
+    for port in [8001, 8002] {
+        let server: Server = serde_json::from_str(&format!(
+            r#"{{"url": "http://localhost:{port}", "kind": "chat"}}"#
+        ))
+        .unwrap();
+        group.register(server).await.unwrap();
+    }
+
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for _ in 0..300 {
+        let target = group.next().await.unwrap();
+        // 8001 answers in 100ms, 8002 in 200ms
+        let latency = if target.url.ends_with("8001") {
+            100
+        } else {
+            200
+        };
+        target.latency.record(Duration::from_millis(latency));
+        *counts.entry(target.url).or_default() += 1;
+    }
+
+    // the faster server gets about twice the requests
+    let fast = counts["http://localhost:8001"];
+    let slow = counts["http://localhost:8002"];
+    assert!(fast.abs_diff(200) <= 10, "{fast} / {slow} requests");
+    assert_eq!(fast + slow, 300);
+    assert_eq!(RoutingStrategy::LatencyAware.to_string(), "latency_aware");
+}
+
+#[tokio::test]
+async fn test_sticky_routing() {
+    let group = ServerGroup::new(ServerKind::chat, RoutingStrategy::Sticky);
+    for port in [8001, 8002, 8003] {
+        let server: Server = serde_json::from_str(&format!(
+            r#"{{"url": "http://localhost:{port}", "kind": "chat"}}"#
+        ))
+        .unwrap();
+        group.register(server).await.unwrap();
+    }
+
+    let sessions: Vec<String> = (0..30).map(|i| format!("session-{i}")).collect();
+    let mut mapped = HashMap::new();
+    for session in &sessions {
+        let url = group.next_for_session(session).await.unwrap().url;
+        for _ in 0..3 {
+            assert_eq!(group.next_for_session(session).await.unwrap().url, url);
+        }
+        mapped.insert(session.clone(), url);
+    }
+    assert_eq!(mapped.values().collect::<HashSet<_>>().len(), 3);
+
+    // a quarantined server only moves its own sessions, and they come back once it recovers
+    let down = group.next_for_session(&sessions[0]).await.unwrap();
+    down.health.record_failure();
+    for session in &sessions {
+        let url = group.next_for_session(session).await.unwrap().url;
+        if mapped[session] == down.url {
+            assert_ne!(url, down.url);
+        } else {
+            assert_eq!(url, mapped[session]);
+        }
+    }
+    down.health.record_success();
+    assert_eq!(
+        group.next_for_session(&sessions[0]).await.unwrap().url,
+        down.url
+    );
+
+    // removing a server keeps the sessions of the others in place
+    let removed = mapped[&sessions[1]].clone();
+    let removed_id = group.servers.read().await.iter().find_map(|s| {
+        let server = s.try_read().unwrap();
+        (server.url == removed).then(|| server.id.clone())
+    });
+    group.unregister(removed_id.unwrap()).await.unwrap();
+    for session in &sessions {
+        if mapped[session] != removed {
+            assert_eq!(
+                group.next_for_session(session).await.unwrap().url,
+                mapped[session]
+            );
+        }
+    }
+
+    // registered again under a new id, the server gets its sessions back
+    let server: Server =
+        serde_json::from_str(&format!(r#"{{"url": "{removed}", "kind": "chat"}}"#)).unwrap();
+    group.register(server).await.unwrap();
+    for session in &sessions {
+        assert_eq!(
+            group.next_for_session(session).await.unwrap().url,
+            mapped[session]
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_capability_routing() {
+    let group = ServerGroup::new(ServerKind::chat, RoutingStrategy::RoundRobin);
+    for (port, extra) in [
+        (8001, r#""tags": ["Vision"], "context_length": 8192"#),
+        (
+            8002,
+            r#""tags": ["vision", "code"], "context_length": 32768"#,
+        ),
+        (8003, r#""context_length": 131072"#),
+    ] {
+        let server: Server = serde_json::from_str(&format!(
+            r#"{{"url": "http://localhost:{port}", "kind": "chat", {extra}}}"#
+        ))
+        .unwrap();
+        group.register(server).await.unwrap();
+    }
+    let group = &group;
+    let urls = |ids: Vec<ServerId>| async move {
+        let mut urls = Vec::new();
+        for server_lock in group.servers.read().await.iter() {
+            let server = server_lock.read().await;
+            if ids.contains(&server.id) {
+                urls.push(server.url.clone());
+            }
+        }
+        urls
+    };
+
+    let tags = |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect::<Vec<_>>();
+    let vision = group
+        .servers_with_capabilities(&tags(&["vision"]), None)
+        .await;
+    assert_eq!(
+        urls(vision.clone()).await,
+        ["http://localhost:8001", "http://localhost:8002"]
+    );
+    // the kind counts as a tag
+    assert_eq!(
+        group
+            .servers_with_capabilities(&tags(&["chat"]), None)
+            .await
+            .len(),
+        3
+    );
+    let long = group
+        .servers_with_capabilities(&tags(&["vision"]), Some(16384))
+        .await;
+    assert_eq!(urls(long).await, ["http://localhost:8002"]);
+    assert!(
+        group
+            .servers_with_capabilities(&tags(&["audio"]), None)
+            .await
+            .is_empty()
+    );
+
+    // routing only picks among the given servers
+    let allowed: HashSet<ServerId> = vision.into_iter().collect();
+    for _ in 0..6 {
+        let target = group.next_among("s", &allowed).await.unwrap();
+        assert!(allowed.contains(&target.id));
+    }
+    assert!(group.next_among("s", &HashSet::new()).await.is_err());
+}
+
+#[tokio::test]
+async fn test_next_skips_open_circuit_breakers() {
+    let group = ServerGroup::new(ServerKind::chat, RoutingStrategy::RoundRobin)
+        .with_circuit_breaker(CircuitBreakerConfig {
+            failure_threshold: 2,
+            cooldown_secs: 60,
+        });
+    for port in [8001, 8002] {
+        let server: Server = serde_json::from_str(&format!(
+            r#"{{"url": "http://localhost:{port}", "kind": "chat"}}"#
+        ))
+        .unwrap();
+        group.register(server).await.unwrap();
+    }
+
+    let failing = group.next().await.unwrap();
+    failing.breaker.record_failure();
+    assert!(group.next().await.is_ok());
+    assert_eq!(group.next().await.unwrap().url, failing.url);
+    failing.breaker.record_failure();
+    for _ in 0..4 {
+        assert_ne!(group.next().await.unwrap().url, failing.url);
+    }
+
+    // the breakers are per server, so the group fails only once every breaker is open
+    let other = group.next().await.unwrap();
+    other.breaker.record_failure();
+    other.breaker.record_failure();
+    assert!(group.next().await.is_err());
+
+    failing.breaker.record_success();
+    assert_eq!(group.next().await.unwrap().url, failing.url);
+}
+
+#[tokio::test]
+async fn test_shared_client_reuses_connections() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // a keep-alive server counting the connections it accepts
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&accepted);
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            counter.fetch_add(1, Ordering::Relaxed);
+            tokio::spawn(async move {
+                let mut buf = [0u8; 4096];
+                while let Ok(n) = socket.read(&mut buf).await {
+                    if n == 0
+                        || socket
+                            .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\n{}")
+                            .await
+                            .is_err()
+                    {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+
+    let client = crate::build_http_client(&crate::config::HttpClientConfig::default());
+    let server: Server = serde_json::from_str(&format!(
+        r#"{{"url": "http://127.0.0.1:{port}/v1", "kind": "chat"}}"#
+    ))
+    .unwrap();
+    for _ in 0..3 {
+        assert!(server.check_health(&client, "/models").await);
+    }
+    assert_eq!(accepted.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn test_routing_policy_per_kind() {
+    let routing: crate::config::RoutingConfig = serde_json::from_value(serde_json::json!({
+        "policy": "round_robin",
+        "policies": { "chat": "sticky", "embeddings": "least_connections" }
+    }))
+    .unwrap();
+    assert_eq!(
+        routing.policy_for(ServerKind::chat),
+        RoutingStrategy::Sticky
+    );
+    assert_eq!(
+        routing.policy_for(ServerKind::embeddings),
+        RoutingStrategy::LeastConnections
+    );
+    assert_eq!(
+        routing.policy_for(ServerKind::tts),
+        RoutingStrategy::RoundRobin
+    );
+
+    let group = ServerGroup::new(ServerKind::chat, routing.policy_for(ServerKind::chat));
+    assert_eq!(group.strategy(), RoutingStrategy::Sticky);
+
+    // a key that is not a server kind is rejected
+    let invalid = serde_json::from_value::<crate::config::RoutingConfig>(serde_json::json!({
+        "policies": { "vdb": "sticky" }
+    }));
+    assert!(invalid.is_err());
 }
@@ -0,0 +1,100 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+use crate::telemetry;
+
+type LockMap = Mutex<HashMap<String, Arc<AsyncMutex<()>>>>;
+
+/// Per-session locks serializing the turns of a session.
+///
+/// A session's entry lives only while a turn holds or waits for its lock: the last guard to be
+/// dropped removes it, so idle sessions take no memory.
+#[derive(Debug, Default)]
+pub struct SessionLocks {
+    locks: Arc<LockMap>,
+}
+
+impl SessionLocks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Waits until no other turn of `session_id` is in progress and locks the session
+    pub async fn lock(&self, session_id: &str) -> SessionGuard {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap();
+            let lock = Arc::clone(locks.entry(session_id.to_string()).or_default());
+            metrics::gauge!(telemetry::ACTIVE_SESSIONS).set(locks.len() as f64);
+            lock
+        };
+
+        SessionGuard {
+            session_id: session_id.to_string(),
+            guard: Some(lock.lock_owned().await),
+            locks: Arc::clone(&self.locks),
+        }
+    }
+
+    /// Number of sessions with a turn in progress or waiting
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.locks.lock().unwrap().len()
+    }
+}
+
+/// Lock on a session, released when dropped
+#[derive(Debug)]
+pub struct SessionGuard {
+    session_id: String,
+    guard: Option<OwnedMutexGuard<()>>,
+    locks: Arc<LockMap>,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        // the map is locked while the entry is checked, so no turn can pick up the lock meanwhile
+        let mut locks = self.locks.lock().unwrap();
+        self.guard = None;
+        if locks
+            .get(&self.session_id)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(&self.session_id);
+            metrics::gauge!(telemetry::ACTIVE_SESSIONS).set(locks.len() as f64);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_session_locks() {
+    use std::time::Duration;
+
+    let locks = Arc::new(SessionLocks::new());
+
+    // other sessions are not blocked
+    let first = locks.lock("s1").await;
+    let other = tokio::time::timeout(Duration::from_millis(50), locks.lock("s2")).await;
+    assert!(other.is_ok());
+    drop(other);
+
+    // a second turn of the same session waits for the first one
+    let waiting = tokio::spawn({
+        let locks = Arc::clone(&locks);
+        async move {
+            let _guard = locks.lock("s1").await;
+        }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!waiting.is_finished());
+    assert_eq!(locks.len(), 1);
+
+    drop(first);
+    waiting.await.unwrap();
+
+    // the entries of idle sessions are evicted
+    assert_eq!(locks.len(), 0);
+}
@@ -0,0 +1,253 @@
+use std::time::Duration;
+
+use axum::http::HeaderMap;
+use metrics::{Unit, describe_counter, describe_gauge, describe_histogram};
+use metrics_exporter_prometheus::{
+    Matcher, PrometheusBuilder, PrometheusHandle, PrometheusRecorder,
+};
+use once_cell::sync::OnceCell;
+use opentelemetry::{global, trace::TracerProvider};
+use opentelemetry_http::{HeaderExtractor, HeaderInjector};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    Resource,
+    propagation::TraceContextPropagator,
+    trace::{SdkTracerProvider, Tracer},
+};
+use tracing::Span;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::{Registry, reload};
+
+use crate::{
+    config::{MetricsConfig, TracingConfig},
+    dual_warn,
+};
+
+/// `/responses` requests received
+pub(crate) const REQUESTS_TOTAL: &str = "llama_nexus_requests_total";
+/// `/responses` requests by `model`
+pub(crate) const MODEL_REQUESTS_TOTAL: &str = "llama_nexus_model_requests_total";
+/// Time until a downstream chat server answers an attempt, by `server`
+pub(crate) const DOWNSTREAM_LATENCY_SECONDS: &str = "llama_nexus_downstream_latency_seconds";
+/// Error responses by OpenAI-style error `type`
+pub(crate) const ERRORS_TOTAL: &str = "llama_nexus_errors_total";
+/// Sessions with a turn in progress or waiting
+pub(crate) const ACTIVE_SESSIONS: &str = "llama_nexus_active_sessions";
+/// Requests in flight on each downstream `server`
+pub(crate) const SERVER_IN_FLIGHT: &str = "llama_nexus_server_in_flight";
+/// Requests in flight on each limited `model`
+pub(crate) const MODEL_IN_FLIGHT: &str = "llama_nexus_model_in_flight";
+/// Requests allowed in flight on each limited `model`
+pub(crate) const MODEL_CONCURRENCY_LIMIT: &str = "llama_nexus_model_concurrency_limit";
+/// Requests refused because their `model` was at its concurrency limit
+pub(crate) const MODEL_OVERLOADED_TOTAL: &str = "llama_nexus_model_overloaded_total";
+
+/// How often histograms are drained when `/metrics` is not scraped
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+// Renders the metrics of the global recorder, once installed
+static PROMETHEUS: OnceCell<PrometheusHandle> = OnceCell::new();
+
+/// Layer of the subscriber exporting spans; empty unless `[tracing]` is enabled
+pub(crate) type TracingLayer = Option<OpenTelemetryLayer<Registry, Tracer>>;
+
+// Fills the tracing layer of the subscriber once the config is loaded
+static TRACING_LAYER: OnceCell<reload::Handle<TracingLayer, Registry>> = OnceCell::new();
+// Exports the spans, once tracing is installed
+static TRACER_PROVIDER: OnceCell<SdkTracerProvider> = OnceCell::new();
+
+/// Builds a recorder using the latency buckets of `config`
+fn build_recorder(config: &MetricsConfig) -> anyhow::Result<PrometheusRecorder> {
+    let recorder = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full(DOWNSTREAM_LATENCY_SECONDS.to_string()),
+            &config.latency_buckets_secs,
+        )?
+        .build_recorder();
+    Ok(recorder)
+}
+
+/// Installs the global Prometheus recorder served by `/metrics`. Must be called once, from the
+/// runtime, before any metric is recorded.
+pub(crate) fn install(config: &MetricsConfig) -> anyhow::Result<()> {
+    let recorder = build_recorder(config)?;
+    let handle = recorder.handle();
+    metrics::set_global_recorder(recorder)?;
+
+    describe_counter!(REQUESTS_TOTAL, "Requests to /responses");
+    describe_counter!(MODEL_REQUESTS_TOTAL, "Requests to /responses by model");
+    describe_histogram!(
+        DOWNSTREAM_LATENCY_SECONDS,
+        Unit::Seconds,
+        "Time until a downstream chat server answers"
+    );
+    describe_counter!(ERRORS_TOTAL, "Error responses by error type");
+    describe_gauge!(ACTIVE_SESSIONS, "Sessions with a turn in progress");
+    describe_gauge!(
+        SERVER_IN_FLIGHT,
+        "Requests in flight on each downstream server"
+    );
+    describe_gauge!(
+        MODEL_IN_FLIGHT,
+        "Requests in flight on each model with a concurrency limit"
+    );
+    describe_gauge!(
+        MODEL_CONCURRENCY_LIMIT,
+        "Requests allowed in flight on each model with a concurrency limit"
+    );
+    describe_counter!(
+        MODEL_OVERLOADED_TOTAL,
+        "Requests refused because their model was at its concurrency limit"
+    );
+
+    let upkeep = handle.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(UPKEEP_INTERVAL).await;
+            upkeep.run_upkeep();
+        }
+    });
+
+    PROMETHEUS
+        .set(handle)
+        .map_err(|_| anyhow::anyhow!("metrics recorder already installed"))
+}
+
+/// The tracing layer of the global subscriber, empty until [`install_tracing`] fills it. Must be
+/// called once, when the subscriber is built.
+pub(crate) fn tracing_layer() -> reload::Layer<TracingLayer, Registry> {
+    let (layer, handle) = reload::Layer::new(None);
+    let _ = TRACING_LAYER.set(handle);
+    layer
+}
+
+/// Exports spans to the OTLP/HTTP endpoint of `config` and propagates W3C `traceparent`
+/// headers; does nothing unless tracing is enabled
+pub(crate) fn install_tracing(config: &TracingConfig) -> anyhow::Result<()> {
+    if !config.enabled {
+        return Ok(());
+    }
+
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(&config.endpoint)
+        .build()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(config.service_name.clone())
+                .build(),
+        )
+        .build();
+    let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")));
+    TRACING_LAYER
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("the subscriber has no tracing layer"))?
+        .reload(Some(layer))?;
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    TRACER_PROVIDER
+        .set(provider)
+        .map_err(|_| anyhow::anyhow!("tracing already installed"))
+}
+
+/// Exports the spans not sent yet; called on shutdown
+pub(crate) async fn shutdown_tracing() {
+    let Some(provider) = TRACER_PROVIDER.get().cloned() else {
+        return;
+    };
+    // the exporter blocks until the spans are sent
+    match tokio::task::spawn_blocking(move || provider.shutdown()).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => dual_warn!("Failed to export the last spans: {e}"),
+        Err(e) => dual_warn!("Failed to export the last spans: {e}"),
+    }
+}
+
+/// Makes `span` a child of the span of the `traceparent` header of an incoming request, if any
+pub(crate) fn continue_trace(span: &Span, headers: &HeaderMap) {
+    let parent =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    span.set_parent(parent);
+}
+
+/// Headers carrying the trace of `span` to a downstream server; empty unless tracing is enabled
+pub(crate) fn trace_headers(span: &Span) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let context = span.context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(&mut headers))
+    });
+    headers
+}
+
+/// `GET /metrics`: the metrics in the Prometheus text format
+pub(crate) async fn metrics_handler() -> String {
+    PROMETHEUS
+        .get()
+        .map(PrometheusHandle::render)
+        .unwrap_or_default()
+}
+
+#[test]
+fn test_latency_buckets() {
+    let config = MetricsConfig {
+        latency_buckets_secs: vec![0.5, 2.0],
+    };
+    let recorder = build_recorder(&config).unwrap();
+    metrics::with_local_recorder(&recorder, || {
+        metrics::histogram!(DOWNSTREAM_LATENCY_SECONDS, "server" => "http://a").record(1.0);
+        metrics::counter!(ERRORS_TOTAL, "type" => "timeout_error").increment(1);
+    });
+
+    let rendered = recorder.handle().render();
+    assert!(rendered.contains(
+        r#"llama_nexus_downstream_latency_seconds_bucket{server="http://a",le="0.5"} 0"#
+    ));
+    assert!(
+        rendered.contains(
+            r#"llama_nexus_downstream_latency_seconds_bucket{server="http://a",le="2"} 1"#
+        )
+    );
+    assert!(rendered.contains(r#"llama_nexus_errors_total{type="timeout_error"} 1"#));
+
+    // buckets must be given
+    assert!(
+        build_recorder(&MetricsConfig {
+            latency_buckets_secs: vec![]
+        })
+        .is_err()
+    );
+}
+
+#[test]
+fn test_trace_propagation() {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let provider = SdkTracerProvider::builder().build();
+    let subscriber = tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+    tracing::subscriber::with_default(subscriber, || {
+        let mut incoming = HeaderMap::new();
+        incoming.insert(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+                .parse()
+                .unwrap(),
+        );
+        let request = tracing::info_span!("request");
+        continue_trace(&request, &incoming);
+        let downstream = request.in_scope(|| tracing::info_span!("downstream"));
+
+        // the downstream call is a new span of the trace of the caller
+        let headers = trace_headers(&downstream);
+        let traceparent = headers["traceparent"].to_str().unwrap();
+        assert!(
+            traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"),
+            "{traceparent}"
+        );
+        assert!(!traceparent.contains("00f067aa0ba902b7"), "{traceparent}");
+    });
+}